@@ -5,18 +5,45 @@ extern crate rustos;
 
 use core::arch::asm;
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, Ordering};
 
 use rustos::fs::{self, OpenFlags};
-use rustos::{ipc, memory, panic as panic_runtime, process, syscall, uart, userspace};
+use rustos::signal::{self, SIGPIPE, SIGSEGV, SIGTERM, SIGUSR1};
+use rustos::{error, fdt, gic, ipc, memory, mmu, panic as panic_runtime, process, smp, syscall, timer, uart, userspace};
 
 type TestFn = fn();
 
 const TESTS: &[TestFn] = &[
     memory_allocation_is_page_aligned,
     memory_allocation_grows_monotonically,
+    memory_deallocation_returns_frames,
+    mapper_translates_and_unmaps_pages,
+    mapper_maps_blocks,
+    device_tree_describes_platform,
     process_creation_returns_distinct_pids,
+    user_stack_is_demand_paged,
+    fork_shares_pages_copy_on_write,
+    failed_forks_give_back_every_frame,
+    wait_reaps_exited_children,
+    user_registers_survive_context_switches,
+    stack_guards_catch_overflow,
+    context_switch_keeps_asid_tagged_entries,
+    gic_dispatches_requested_irqs,
+    timer_ticks_advance_monotonic_clock,
+    timer_preempts_spinning_process,
+    mlfq_favours_low_nice_and_demotes_cpu_hogs,
+    signal_handlers_run_and_return_through_sigreturn,
+    signals_stop_continue_and_kill_processes,
+    writing_to_a_closed_pipe_raises_sigpipe,
+    pipe_reads_sleep_until_data_arrives,
+    bad_user_pointers_fail_with_efault,
+    clone_threads_share_memory_and_keep_their_own_tls,
+    exec_maps_elf_images_with_arguments_and_auxv,
+    pie_executables_are_relocated_at_their_load_base,
     file_round_trip_preserves_payload,
     pipe_transports_data_between_ends,
+    // Last: once the other CPUs run, they take processes off the boot CPU
+    secondary_cpus_come_online_and_run_processes,
 ];
 
 #[no_mangle]
@@ -25,6 +52,8 @@ pub extern "C" fn kernel_main() -> ! {
     memory::init();
     process::init();
     syscall::init();
+    gic::init().expect("initialize GIC");
+    timer::init().expect("start timer");
     fs::init();
     ipc::init();
     userspace::init();
@@ -54,12 +83,514 @@ fn memory_allocation_grows_monotonically() {
     assert!(second >= first + 4096, "subsequent allocation should not overlap");
 }
 
+fn memory_deallocation_returns_frames() {
+    let before = memory::frame_stats();
+    let addr = memory::allocate_pages(3 * 4096).expect("allocate contiguous pages");
+    let during = memory::frame_stats();
+    assert_eq!(during.free_frames + 3, before.free_frames);
+
+    memory::deallocate_pages(addr, 3 * 4096).expect("free contiguous pages");
+    assert_eq!(memory::frame_stats(), before);
+    assert!(memory::deallocate_pages(addr, 4096).is_err(), "double free must be rejected");
+}
+
+fn mapper_translates_and_unmaps_pages() {
+    let mut allocator = memory::GlobalFrameAllocator;
+    let mut mapper = memory::Mapper::new(&mut allocator).expect("create address space");
+    let page = memory::Page::containing_address(memory::VirtAddr::new(0x0040_2000));
+    let frame = memory::allocate_frame().expect("allocate backing frame");
+
+    let flags = memory::PageTableFlags::user(false, true);
+    mapper.map_to(page, frame, flags, &mut allocator).expect("map page");
+    assert!(mapper.map_to(page, frame, flags, &mut allocator).is_err(), "double map must fail");
+
+    let translated = mapper.translate(memory::VirtAddr::new(0x0040_2abc)).expect("translate");
+    assert_eq!(translated.as_u64(), frame.start_address().as_u64() + 0xabc);
+
+    mapper.update_flags(page, memory::PageTableFlags::user(true, false)).expect("mprotect page");
+    let (_, new_flags) = mapper.translate_page(page).expect("still mapped");
+    assert!(new_flags.is_writable() && new_flags.contains(memory::PageTableFlags::UXN));
+
+    assert_eq!(mapper.unmap(page).expect("unmap page"), frame);
+    assert!(mapper.translate_page(page).is_none());
+    memory::deallocate_frame(frame);
+}
+
+fn mapper_maps_blocks() {
+    let mut allocator = memory::GlobalFrameAllocator;
+    let mut mapper = memory::Mapper::new(&mut allocator).expect("create address space");
+    let block = memory::PageSize::Size2MiB;
+    let virt = memory::VirtAddr::new(0x0060_0000);
+    let phys = memory::PhysAddr::new(0x4020_0000);
+
+    let flags = memory::PageTableFlags::user(true, false);
+    assert!(mapper.map_block(memory::VirtAddr::new(0x0060_1000), phys, block, flags, &mut allocator).is_err());
+    mapper.map_block(virt, phys, block, flags, &mut allocator).expect("map 2 MiB block");
+    assert_eq!(mapper.mapping_size(memory::VirtAddr::new(0x0070_0000)), Some(block));
+    let translated = mapper.translate(memory::VirtAddr::new(0x006a_b123)).expect("translate");
+    assert_eq!(translated.as_u64(), 0x402a_b123);
+    let page = memory::Page::containing_address(memory::VirtAddr::new(0x0061_0000));
+    assert!(mapper.map_to(page, memory::PhysFrame::containing_address(phys), flags, &mut allocator).is_err());
+
+    assert_eq!(mapper.unmap_block(virt, block), Ok(phys));
+    assert!(mapper.translate(virt).is_none());
+
+    // The linear map and large allocations use blocks too
+    let size = 2 * block.bytes() as usize;
+    let addr = memory::allocate_pages(size).expect("allocate framebuffer-sized buffer");
+    assert!(addr.is_multiple_of(block.bytes()));
+    let mapped = mmu::kernel_mapping_size(memory::VirtAddr::new(addr)).expect("linear map");
+    assert_ne!(mapped, memory::PageSize::Size4KiB);
+    memory::deallocate_pages(addr, size).expect("free buffer");
+}
+
+fn device_tree_describes_platform() {
+    let platform = fdt::platform();
+    let tree = fdt::boot_tree().expect("QEMU should pass a device tree");
+    assert!(tree.find_node("/chosen").is_some());
+    assert!(!platform.memory.is_empty(), "memory nodes should be discovered");
+    assert_eq!(platform.uart, Some(0x0900_0000));
+
+    let usable: u64 = memory::memory_map()
+        .iter()
+        .filter(|region| region.region_type == memory::MemoryRegionType::Usable)
+        .map(|region| region.size)
+        .sum();
+    let ram: u64 = platform.memory.iter().map(|&(_, size)| size).sum();
+    assert!(usable > 0 && usable < ram);
+}
+
 fn process_creation_returns_distinct_pids() {
     let pid_a = process::create_process(0x4000_0000, 4096).expect("create first process");
     let pid_b = process::create_process(0x4001_0000, 4096).expect("create second process");
     assert!(pid_b > pid_a, "process IDs should monotonically increase");
 }
 
+fn user_stack_is_demand_paged() {
+    let pid = spawn_program(program(&raw const user_stack_program, &raw const user_stack_program_end), &[]);
+    process::schedule();
+    assert_eq!(process::process_state(pid), Some(process::ProcessState::Zombie));
+
+    // The stack top and a page well below it were faulted in from EL0; the
+    // write past the largest stack hit the guard and killed the process
+    assert_eq!(read_user_u64(pid, DATA_ADDR), 0xfeed);
+    assert_eq!(read_user_u64(pid, DATA_ADDR + 8), 0xbeef);
+    assert_eq!(read_user_u64(pid, DATA_ADDR + 16), 0, "stack overflow ends the process");
+    assert!(process::translate(pid, process::USER_STACK_TOP - 3 * 4096 - 8).is_some());
+}
+
+fn fork_shares_pages_copy_on_write() {
+    let parent = spawn_program(program(&raw const user_fork_program, &raw const user_fork_program_end), &[]);
+    process::schedule();
+
+    // Each side stored fork's return value into its own copy of the data page
+    let child = read_user_u64(parent, DATA_ADDR) as u32;
+    assert!(child > parent, "parent sees the child's pid");
+    assert_eq!(read_user_u64(child, DATA_ADDR), 0, "fork returns 0 in the child");
+    assert_ne!(process::translate(parent, DATA_ADDR), process::translate(child, DATA_ADDR));
+
+    let code = process::translate(parent, CODE_ADDR).expect("parent text mapped");
+    assert_eq!(process::translate(child, CODE_ADDR), Some(code), "read-only text stays shared");
+    assert_eq!(memory::frame_ref_count(memory::PhysFrame::containing_address(code)), 2);
+}
+
+fn failed_forks_give_back_every_frame() {
+    // Stopped, so that only the children run
+    let parent = spawn_program(spin_program(), &0u64.to_le_bytes());
+    process::send_signal(parent, signal::SIGSTOP).expect("stop parent");
+    process::schedule();
+    assert_eq!(process::process_state(parent), Some(process::ProcessState::Stopped));
+
+    // The first fork maps the tables under the kernel stack slot the
+    // later ones reuse
+    let first = process::fork(parent).expect("fork with memory to spare");
+    process::send_signal(first, signal::SIGKILL).expect("kill first child");
+    process::schedule();
+    process::reap(first).expect("reap first child");
+
+    // Each limit runs out at a later allocation, until fork gets through
+    let before = memory::frame_stats();
+    let mut limit = 0;
+    let child = loop {
+        memory::set_frame_limit(Some(limit));
+        let result = process::fork(parent);
+        memory::set_frame_limit(None);
+        match result {
+            Ok(child) => break child,
+            Err(e) => assert_eq!(e, error::KernelError::OutOfMemory),
+        }
+        assert_eq!(memory::frame_stats(), before, "fork failing with {} frames left leaked some", limit);
+        limit += 1;
+    };
+    assert!(limit > 0);
+
+    for pid in [child, parent] {
+        process::send_signal(pid, signal::SIGKILL).expect("kill spinner");
+    }
+    process::schedule();
+    for pid in [child, parent] {
+        assert_eq!(process::reap(pid), Ok(signal::signal_status(signal::SIGKILL)));
+    }
+}
+
+fn wait_reaps_exited_children() {
+    let run = || {
+        let parent = spawn_program(program(&raw const user_wait_program, &raw const user_wait_program_end), &[]);
+        process::schedule();
+
+        assert_eq!(read_user_u64(parent, DATA_ADDR), -error::ECHILD as u64, "no children to wait for before fork");
+        let child = read_user_u64(parent, DATA_ADDR + 8) as u32;
+        assert_eq!(read_user_u64(parent, DATA_ADDR + 16), child as u64, "wait4 returns the child's pid");
+        assert_eq!(read_user_u64(parent, DATA_ADDR + 24) as u32, process::exit_status(42) as u32);
+        assert_eq!(read_user_u64(parent, DATA_ADDR + 32), -error::ECHILD as u64, "the child was reaped");
+        assert_eq!(process::process_state(child), None);
+
+        // Nothing waits for a process the kernel started
+        assert_eq!(process::process_state(parent), Some(process::ProcessState::Zombie));
+        assert_eq!(process::reap(parent), Ok(process::exit_status(7)));
+        assert_eq!(process::process_state(parent), None);
+        memory::frame_stats()
+    };
+
+    // The second run reuses what the first one freed
+    let first = run();
+    assert_eq!(run(), first, "reaping frees everything the processes held");
+}
+
+fn user_registers_survive_context_switches() {
+    let first = spawn_program(yield_program(), &0x1111u64.to_le_bytes());
+    let second = spawn_program(yield_program(), &0x2222u64.to_le_bytes());
+    process::schedule();
+
+    // Both yielded to each other with live general purpose and FP/SIMD state
+    for (pid, seed) in [(first, 0x1111), (second, 0x2222)] {
+        assert_eq!(process::process_state(pid), Some(process::ProcessState::Zombie));
+        assert_eq!(read_user_u64(pid, DATA_ADDR + 8), seed, "d0");
+        assert_eq!(read_user_u64(pid, DATA_ADDR + 16), seed, "v31.d[1]");
+        assert_eq!(read_user_u64(pid, DATA_ADDR + 24), seed + 1, "x19");
+        assert_eq!(read_user_u64(pid, DATA_ADDR + 32), seed + 2, "x28");
+    }
+}
+
+fn stack_guards_catch_overflow() {
+    let stack = mmu::allocate_kernel_stack().expect("allocate kernel stack");
+    assert_eq!(stack.base() % (2 * mmu::KERNEL_STACK_SIZE as u64), 0);
+    assert!(mmu::kernel_translate(memory::VirtAddr::new(stack.top() - 8)).is_some());
+    assert!(mmu::kernel_translate(memory::VirtAddr::new(stack.base() - 8)).is_none());
+    assert!(mmu::is_stack_guard(stack.base() - 8));
+    assert!(!mmu::is_stack_guard(stack.base()));
+    mmu::free_kernel_stack(stack);
+}
+
+fn context_switch_keeps_asid_tagged_entries() {
+    let first = process::spawn_kernel_thread(asid_thread).expect("spawn first thread");
+    let second = process::spawn_kernel_thread(asid_thread).expect("spawn second thread");
+    process::schedule();
+    assert_eq!(process::process_state(first), Some(process::ProcessState::Zombie));
+    assert_eq!(process::process_state(second), Some(process::ProcessState::Zombie));
+    assert_eq!(ASID_THREADS_DONE.load(Ordering::SeqCst), 2);
+}
+
+static FIRST_THREAD_ASID: AtomicU16 = AtomicU16::new(0);
+static ASID_THREADS_DONE: AtomicU32 = AtomicU32::new(0);
+
+// Both threads map a page at the same address and switch back and forth;
+// each must keep seeing its own page under its own ASID
+fn asid_thread() {
+    let pid = process::get_current_pid().expect("current process");
+    let rw = (process::MemoryPermissions::READ | process::MemoryPermissions::WRITE).bits() as u64;
+    let private = (process::MapFlags::PRIVATE | process::MapFlags::ANONYMOUS).bits() as u64;
+    let page = process::sys_mmap(0, 4096, rw, private, -1, 0).expect("map page") as *mut u64;
+    unsafe { page.write_volatile(pid as u64) };
+
+    let asid = mmu::current_asid();
+    assert_ne!(asid, 0, "user address spaces carry an ASID");
+    if let Err(first) = FIRST_THREAD_ASID.compare_exchange(0, asid, Ordering::SeqCst, Ordering::SeqCst) {
+        assert_ne!(first, asid);
+    }
+
+    process::sys_yield();
+    assert_eq!(process::get_current_pid(), Some(pid));
+    assert_eq!(mmu::current_asid(), asid, "ASID survives the round trip");
+    assert_eq!(unsafe { page.read_volatile() }, pid as u64);
+    ASID_THREADS_DONE.fetch_add(1, Ordering::SeqCst);
+}
+
+fn gic_dispatches_requested_irqs() {
+    const SGI: u32 = 3;
+    assert!(gic::request_irq(gic::MAX_IRQS as u32, count_test_irq).is_err());
+    gic::request_irq(SGI, count_test_irq).expect("request SGI");
+    assert!(gic::request_irq(SGI, count_test_irq).is_err(), "IRQ taken twice");
+    gic::set_priority(SGI, 0x80).expect("set priority");
+    assert_eq!(gic::priority(SGI), Ok(0x80));
+
+    let handled = gic::stats().handled;
+    gic::send_sgi_to_self(SGI).expect("send SGI");
+    while TEST_IRQS.load(Ordering::SeqCst) == 0 {
+        timer::wait_for_interrupt();
+    }
+    assert_eq!(TEST_IRQS.load(Ordering::SeqCst), 1);
+    assert_eq!(gic::irq_count(SGI), 1);
+    assert!(gic::stats().handled > handled);
+
+    // A freed IRQ no longer reaches the handler
+    gic::free_irq(SGI).expect("free SGI");
+    gic::send_sgi_to_self(SGI).expect("send SGI");
+    let ticks = timer::ticks();
+    while timer::ticks() == ticks {
+        timer::wait_for_interrupt();
+    }
+    assert_eq!(TEST_IRQS.load(Ordering::SeqCst), 1);
+    gic::request_irq(SGI, count_test_irq).expect("request freed SGI again");
+    gic::free_irq(SGI).expect("free SGI");
+}
+
+static TEST_IRQS: AtomicU32 = AtomicU32::new(0);
+
+fn count_test_irq(irq: u32) {
+    assert_eq!(irq, 3);
+    TEST_IRQS.fetch_add(1, Ordering::SeqCst);
+}
+
+fn timer_ticks_advance_monotonic_clock() {
+    assert!(timer::set_tick_rate(0).is_err());
+    let period = 1_000_000_000 / timer::tick_rate() as u64;
+    let start_ticks = timer::ticks();
+    let start = timer::uptime_ns();
+    while timer::ticks() < start_ticks + 3 {
+        timer::wait_for_interrupt();
+    }
+    let elapsed = timer::uptime_ns() - start;
+    assert!(elapsed >= 2 * period, "3 ticks took only {} ns", elapsed);
+    assert!(timer::uptime_ms() >= start / 1_000_000);
+}
+
+fn timer_preempts_spinning_process() {
+    let spinner = spawn_program(spin_program(), &0u64.to_le_bytes());
+    SPINNER_PID.store(spinner, Ordering::SeqCst);
+    let thread = process::spawn_kernel_thread(release_spinner).expect("spawn thread");
+    process::schedule();
+
+    assert!(SPINNER_PREEMPTED.load(Ordering::SeqCst), "spinner was still runnable");
+    assert_eq!(process::process_state(thread), Some(process::ProcessState::Zombie));
+    assert_eq!(process::process_state(spinner), Some(process::ProcessState::Zombie));
+}
+
+static SPINNER_PID: AtomicU32 = AtomicU32::new(0);
+static SPINNER_PREEMPTED: AtomicBool = AtomicBool::new(false);
+
+// Runs only if the tick takes the CPU away from the spinner, which never
+// yields; lets it exit afterwards
+fn release_spinner() {
+    let spinner = SPINNER_PID.load(Ordering::SeqCst);
+    let preempted = process::process_state(spinner) == Some(process::ProcessState::Ready);
+    SPINNER_PREEMPTED.store(preempted, Ordering::SeqCst);
+
+    let phys = process::translate(spinner, DATA_ADDR).expect("spinner data mapped");
+    unsafe { (memory::phys_to_virt(phys).as_u64() as *mut u64).write_volatile(1) };
+}
+
+fn mlfq_favours_low_nice_and_demotes_cpu_hogs() {
+    use rustos::scheduler::{Enqueue, Mlfq, SchedPolicy};
+
+    let mut mlfq = Mlfq::new();
+    mlfq.enqueue(1, 10, Enqueue::New);
+    mlfq.enqueue(2, 0, Enqueue::New);
+    mlfq.enqueue(3, -10, Enqueue::New);
+    assert_eq!(mlfq.pick_next(), Some(3), "lowest nice runs first");
+    assert_eq!(mlfq.pick_next(), Some(2));
+    assert_eq!(mlfq.runnable(), 1);
+
+    // Using up the allotment drops a level and gives way to the queue
+    let level = mlfq.level(2).expect("queued before");
+    for _ in 1..Mlfq::allotment(level) {
+        assert!(!mlfq.tick(2));
+    }
+    assert!(mlfq.tick(2));
+    assert_eq!(mlfq.level(2), Some(level + 1));
+
+    // Waking moves a blocked process back up
+    mlfq.enqueue(2, 0, Enqueue::Woken);
+    assert_eq!(mlfq.level(2), Some(level));
+
+    mlfq.set_nice(1, 19);
+    assert_eq!(mlfq.level(1), Some(Mlfq::base_level(19)));
+    mlfq.remove(1);
+    assert_eq!(mlfq.level(1), None);
+
+    // A stolen process keeps its level on the CPU that took it, and lower
+    // levels queued there do not preempt it
+    let stolen = mlfq.steal().expect("process to steal");
+    assert_eq!(stolen.pid, 2);
+    assert_eq!(mlfq.level(2), None);
+    let mut thief = Mlfq::new();
+    thief.adopt(stolen);
+    thief.enqueue(4, 19, Enqueue::New);
+    assert_eq!(thief.level(2), Some(level));
+    assert!(!thief.tick(2));
+
+    // A sunk process returns to its starting level at the next boost
+    let mut mlfq = Mlfq::new();
+    mlfq.enqueue(3, -10, Enqueue::New);
+    assert_eq!(mlfq.pick_next(), Some(3));
+    for _ in 1..Mlfq::BOOST_INTERVAL {
+        mlfq.tick(3);
+    }
+    assert_eq!(mlfq.level(3), Some(Mlfq::LEVELS - 1));
+    mlfq.tick(3);
+    assert_eq!(mlfq.level(3), Some(Mlfq::base_level(-10)));
+}
+
+fn signal_handlers_run_and_return_through_sigreturn() {
+    let pid = spawn_program(program(&raw const user_signal_program, &raw const user_signal_program_end), &[]);
+    process::schedule();
+
+    assert_eq!(read_user_u64(pid, DATA_ADDR + 8), SIGUSR1 as u64, "handler got the signal number");
+    assert_eq!(read_user_u64(pid, DATA_ADDR + 16), pid as u64, "siginfo names the sender");
+    assert_eq!(read_user_u64(pid, DATA_ADDR + 24), 0, "kill returned normally after the handler");
+    assert_eq!(read_user_u64(pid, DATA_ADDR + 32), 1, "a blocked signal waits");
+    assert_eq!(read_user_u64(pid, DATA_ADDR), 2, "unblocking delivered it");
+    // The null store at the end takes the default action for SIGSEGV
+    assert_eq!(process::reap(pid), Ok(signal::signal_status(SIGSEGV)));
+}
+
+fn signals_stop_continue_and_kill_processes() {
+    let pid = spawn_program(spin_program(), &0u64.to_le_bytes());
+    process::send_signal(pid, signal::SIGSTOP).expect("stop spinner");
+    process::schedule();
+    assert_eq!(process::process_state(pid), Some(process::ProcessState::Stopped));
+
+    process::send_signal(pid, signal::SIGCONT).expect("continue spinner");
+    assert_eq!(process::process_state(pid), Some(process::ProcessState::Ready));
+
+    // The spinner never enters the kernel; the tick delivers the signal
+    process::send_signal(pid, SIGTERM).expect("terminate spinner");
+    process::schedule();
+    assert_eq!(process::reap(pid), Ok(signal::signal_status(SIGTERM)));
+}
+
+fn writing_to_a_closed_pipe_raises_sigpipe() {
+    let pid = spawn_program(program(&raw const user_pipe_program, &raw const user_pipe_program_end), &[]);
+    process::schedule();
+    assert_eq!(read_user_u64(pid, DATA_ADDR), 0, "the write never returned");
+    assert_eq!(process::reap(pid), Ok(signal::signal_status(SIGPIPE)));
+}
+
+fn pipe_reads_sleep_until_data_arrives() {
+    let pid = spawn_program(program(&raw const user_blocking_pipe_program, &raw const user_blocking_pipe_program_end), &[]);
+    process::schedule();
+    assert_eq!(read_user_u64(pid, DATA_ADDR), -error::EAGAIN as u64, "an empty O_NONBLOCK pipe fails the read");
+    assert_eq!(read_user_u64(pid, DATA_ADDR + 8), 8, "the parent slept until the child wrote");
+    assert_eq!(read_user_u64(pid, DATA_ADDR + 16), 0x77);
+    assert_eq!(process::process_state(pid), Some(process::ProcessState::Blocked), "a read nothing will satisfy sleeps");
+
+    // The signal ends the read, then the process
+    process::send_signal(pid, SIGTERM).expect("interrupt the read");
+    process::schedule();
+    assert_eq!(process::reap(pid), Ok(signal::signal_status(SIGTERM)));
+}
+
+fn bad_user_pointers_fail_with_efault() {
+    let pid = spawn_program(program(&raw const user_bad_pointer_program, &raw const user_bad_pointer_program_end), &[b'a'; 8192]);
+    process::schedule();
+    let efault = -error::EFAULT as u64;
+    assert_eq!(read_user_u64(pid, DATA_ADDR), efault, "write from a kernel address");
+    assert_eq!(read_user_u64(pid, DATA_ADDR + 8), efault, "write from an unmapped address");
+    assert_eq!(read_user_u64(pid, DATA_ADDR + 16), efault, "openat of an unmapped path");
+    assert_eq!(read_user_u64(pid, DATA_ADDR + 24), efault, "pipe2 into read-only text");
+    assert_eq!(read_user_u64(pid, DATA_ADDR + 32), -error::ENAMETOOLONG as u64, "a path past PATH_MAX");
+    assert_eq!(process::reap(pid), Ok(process::exit_status(0)));
+}
+
+fn clone_threads_share_memory_and_keep_their_own_tls() {
+    let pid = spawn_program(program(&raw const user_thread_program, &raw const user_thread_program_end), &[0; 4096]);
+    process::schedule();
+
+    let tid = read_user_u64(pid, DATA_ADDR + 32);
+    assert!(tid > pid as u64, "clone returns the thread id");
+    assert_eq!(read_user_u64(pid, DATA_ADDR + 40), tid, "CLONE_PARENT_SETTID");
+    assert_eq!(read_user_u64(pid, DATA_ADDR + 8), 0x1234, "the thread starts with the CLONE_SETTLS pointer");
+    assert_eq!(read_user_u64(pid, DATA_ADDR + 16), tid, "gettid in the thread");
+    assert_eq!(read_user_u64(pid, DATA_ADDR + 24), pid as u64, "getpid in the thread names the process");
+    assert_eq!(read_user_u64(pid, DATA_ADDR + 48), 0, "exit cleared the CLONE_CHILD_CLEARTID word");
+    assert_eq!(read_user_u64(pid, DATA_ADDR + 56), 0x5678, "the leader kept its own TPIDR_EL0");
+
+    // The exited thread was freed without a wait; the process waits for one
+    assert_eq!(process::process_state(tid as u32), None);
+    assert_eq!(process::reap(pid), Ok(process::exit_status(5)));
+}
+
+fn exec_maps_elf_images_with_arguments_and_auxv() {
+    let image = exec_test_image();
+    assert!(userspace::UserProgram::load_elf(&image[..100]).is_err(), "program headers past the end of the file");
+    let path = "/bin/exec-test";
+    let flags = (OpenFlags::O_CREAT | OpenFlags::O_WRONLY | OpenFlags::O_TRUNC).bits();
+    let fd = fs::open(path, flags, 0).expect("create executable");
+    fs::write(fd, &image).expect("write executable");
+    fs::close(fd).expect("close executable");
+
+    // execve(path, argv, envp) with everything in the data page
+    let mut data = [0u8; 0x200];
+    let words = [DATA_ADDR + 0x100, DATA_ADDR + 0x40, DATA_ADDR + 0x60];
+    let pointers = [(0x40, DATA_ADDR + 0x120), (0x48, DATA_ADDR + 0x130), (0x60, DATA_ADDR + 0x140)];
+    for (index, word) in words.iter().enumerate() {
+        data[index * 8..index * 8 + 8].copy_from_slice(&word.to_le_bytes());
+    }
+    for (offset, pointer) in pointers {
+        data[offset..offset + 8].copy_from_slice(&pointer.to_le_bytes());
+    }
+    for (offset, string) in [(0x100, path), (0x120, "exec-test"), (0x130, "hello"), (0x140, "HOME=/")] {
+        data[offset..offset + string.len()].copy_from_slice(string.as_bytes());
+    }
+
+    let pid = spawn_program(program(&raw const user_execve_program, &raw const user_execve_program_end), &data);
+    process::schedule();
+    assert_eq!(read_user_u64(pid, DATA_ADDR + 8), EXEC_IMAGE_DATA, "exec loaded the image's data");
+    assert_eq!(read_user_u64(pid, DATA_ADDR + 16), 0, ".bss past the file's pages is zero");
+
+    let sp = read_user_u64(pid, DATA_ADDR);
+    assert_eq!(sp % 16, 0);
+    assert_eq!(read_user_u64(pid, sp), 2, "argc");
+    assert!(user_string_is(pid, read_user_u64(pid, sp + 8), "exec-test"));
+    assert!(user_string_is(pid, read_user_u64(pid, sp + 16), "hello"));
+    assert_eq!(read_user_u64(pid, sp + 24), 0);
+    assert!(user_string_is(pid, read_user_u64(pid, sp + 32), "HOME=/"));
+    assert_eq!(read_user_u64(pid, sp + 40), 0);
+
+    // Bit n set once auxv type n was seen
+    let mut seen = 0u64;
+    let mut entry = sp + 48;
+    loop {
+        let (kind, value) = (read_user_u64(pid, entry), read_user_u64(pid, entry + 8));
+        match kind {
+            userspace::AT_NULL => break,
+            userspace::AT_PHDR => assert_eq!(value, CODE_ADDR + 64),
+            userspace::AT_PAGESZ => assert_eq!(value, 4096),
+            userspace::AT_ENTRY => assert_eq!(value, CODE_ADDR + 0x100),
+            userspace::AT_RANDOM => assert!(value > sp && value + 16 <= process::USER_STACK_TOP),
+            _ => {}
+        }
+        seen |= 1 << kind;
+        entry += 16;
+    }
+    let expected = [userspace::AT_PHDR, userspace::AT_PAGESZ, userspace::AT_ENTRY, userspace::AT_RANDOM];
+    assert!(expected.iter().all(|&kind| seen & 1 << kind != 0), "auxv is missing an entry");
+    assert_eq!(process::reap(pid), Ok(process::exit_status(2)));
+}
+
+fn pie_executables_are_relocated_at_their_load_base() {
+    let pid = process::create_process_from_elf(&pie_test_image(), &["pie"], &[]).expect("start PIE");
+    process::schedule();
+    let base = userspace::ET_DYN_BASE;
+    assert_eq!(read_user_u64(pid, base + PIE_DATA), base + PIE_CODE, "R_AARCH64_RELATIVE");
+    let relro = process::find_region(pid, base + PIE_DATA).expect("data mapped");
+    assert_eq!(relro.permissions, process::MemoryPermissions::READ, "PT_GNU_RELRO");
+    let stack = process::find_region(pid, process::USER_STACK_TOP - 8).expect("stack mapped");
+    assert!(stack.permissions.contains(process::MemoryPermissions::EXECUTE), "PT_GNU_STACK");
+    // The program checks the pointer against where it runs
+    assert_eq!(process::reap(pid), Ok(process::exit_status(0)));
+}
+
 fn file_round_trip_preserves_payload() {
     let path = "/tmp/kernel-test.txt";
     let _ = fs::remove_file(path);
@@ -92,6 +623,670 @@ fn pipe_transports_data_between_ends() {
     fs::close(write_fd).expect("close write fd");
 }
 
+fn secondary_cpus_come_online_and_run_processes() {
+    let started = smp::start_secondaries().expect("start secondary CPUs");
+    assert!(started >= 1, "QEMU runs with more than one CPU");
+    assert_eq!(smp::online_cpus(), started + 1);
+
+    // Spinners never give up their CPU, so each lands on a CPU of its own
+    // and the idle ones are woken by an IPI to take theirs
+    let spinners = [(); 4].map(|_| spawn_program(spin_program(), &0u64.to_le_bytes()));
+    let expected = started.min(spinners.len() - 1) as u32;
+    let deadline = timer::uptime_ms() + 1000;
+    loop {
+        let cpus = spinners.iter()
+            .filter_map(|&pid| process::running_cpu(pid))
+            .fold(0u64, |mask, cpu| mask | 1 << cpu);
+        if (cpus & !1).count_ones() == expected {
+            break;
+        }
+        assert!(timer::uptime_ms() < deadline, "spinners did not spread over the CPUs");
+        core::hint::spin_loop();
+    }
+    assert!(gic::irq_count(smp::IPI_RESCHEDULE) > 0);
+
+    for &pid in &spinners {
+        let phys = process::translate(pid, DATA_ADDR).expect("spinner data mapped");
+        unsafe { (memory::phys_to_virt(phys).as_u64() as *mut u64).write_volatile(1) };
+    }
+    // One is still queued here
+    process::schedule();
+
+    // The others exit on their own CPUs and can be reaped once off them
+    for pid in spinners {
+        let deadline = timer::uptime_ms() + 1000;
+        loop {
+            match process::reap(pid) {
+                Ok(status) => {
+                    assert_eq!(status, process::exit_status(0));
+                    break;
+                }
+                Err(_) => assert!(timer::uptime_ms() < deadline, "spinner did not exit"),
+            }
+        }
+    }
+}
+
+// User programs run at EL0 with their text at CODE_ADDR and a data page
+// at DATA_ADDR
+const CODE_ADDR: u64 = 0x40_0000;
+const DATA_ADDR: u64 = 0x50_0000;
+
+fn spawn_program(code: &[u8], data: &[u8]) -> u32 {
+    let rx = process::MemoryPermissions::READ | process::MemoryPermissions::EXECUTE;
+    let rw = process::MemoryPermissions::READ | process::MemoryPermissions::WRITE;
+    let pid = process::create_process(CODE_ADDR, 4096).expect("create process");
+    process::load_segment(pid, CODE_ADDR, code, rx).expect("load text");
+    process::load_segment(pid, DATA_ADDR, data, rw).expect("load data");
+    pid
+}
+
+fn read_user_u64(pid: u32, addr: u64) -> u64 {
+    let phys = process::translate(pid, addr).expect("user address mapped");
+    unsafe { (memory::phys_to_virt(phys).as_u64() as *const u64).read_volatile() }
+}
+
+// Whether the NUL-terminated string at `addr` is `expected`
+fn user_string_is(pid: u32, addr: u64, expected: &str) -> bool {
+    expected.bytes().chain([0]).enumerate().all(|(index, byte)| {
+        let phys = process::translate(pid, addr + index as u64).expect("user address mapped");
+        unsafe { (memory::phys_to_virt(phys).as_u64() as *const u8).read_volatile() == byte }
+    })
+}
+
+const EXEC_IMAGE_DATA: u64 = 0x1234_5678_9abc_def0;
+
+// A static executable of user_exec_image: text at CODE_ADDR with the
+// headers in front of the code at 0x100, and 8 bytes of data at 0x200
+// followed by two pages of .bss
+fn exec_test_image() -> [u8; 0x208] {
+    let code = program(&raw const user_exec_image, &raw const user_exec_image_end);
+    assert!(code.len() <= 0x100);
+    let header = elf_header(2, CODE_ADDR + 0x100, 2);
+    let text = program_header(1, 5, 0, CODE_ADDR, 0x100 + code.len() as u64, 0x100 + code.len() as u64);
+    let data = program_header(1, 6, 0x200, DATA_ADDR + 0x200, 8, 0x2000);
+
+    let mut image = [0u8; 0x208];
+    image[..64].copy_from_slice(struct_bytes(&header));
+    image[64..120].copy_from_slice(struct_bytes(&text));
+    image[120..176].copy_from_slice(struct_bytes(&data));
+    image[0x100..0x100 + code.len()].copy_from_slice(code);
+    image[0x200..].copy_from_slice(&EXEC_IMAGE_DATA.to_le_bytes());
+    image
+}
+
+// A static-PIE of user_pie_image linked at 0: text holding the headers,
+// the code at 0x160, .dynamic at 0x1a0 and .rela.dyn at 0x1e0, then a
+// read-only-after-relocation data page at 0x1200 holding one pointer to
+// the code. The stack is asked to be executable.
+fn pie_test_image() -> [u8; 0x208] {
+    let code = program(&raw const user_pie_image, &raw const user_pie_image_end);
+    assert!(code.len() <= 0x40);
+    let header = elf_header(3, PIE_CODE, 5);
+    let headers = [
+        program_header(1, 5, 0, 0, 0x1f8, 0x1f8),
+        program_header(1, 6, 0x200, PIE_DATA, 8, 8),
+        program_header(2, 6, 0x1a0, 0x1a0, 0x40, 0x40),
+        program_header(0x6474_e552, 4, 0x200, PIE_DATA, 8, 0xe00),
+        program_header(0x6474_e551, 7, 0, 0, 0, 0),
+    ];
+    // DT_RELA, DT_RELASZ, DT_RELAENT, DT_NULL
+    let dynamic: [u64; 8] = [7, 0x1e0, 8, 24, 9, 24, 0, 0];
+    // R_AARCH64_RELATIVE of the data word, to the code
+    let rela: [u64; 3] = [PIE_DATA, 1027, PIE_CODE];
+
+    let mut image = [0u8; 0x208];
+    image[..64].copy_from_slice(struct_bytes(&header));
+    for (index, program_header) in headers.iter().enumerate() {
+        image[64 + index * 56..120 + index * 56].copy_from_slice(struct_bytes(program_header));
+    }
+    image[0x160..0x160 + code.len()].copy_from_slice(code);
+    image[0x1a0..0x1e0].copy_from_slice(struct_bytes(&dynamic));
+    image[0x1e0..0x1f8].copy_from_slice(struct_bytes(&rela));
+    image
+}
+
+// Link-time addresses in pie_test_image
+const PIE_CODE: u64 = 0x160;
+const PIE_DATA: u64 = 0x1200;
+
+fn elf_header(e_type: u16, e_entry: u64, e_phnum: u16) -> userspace::ElfHeader {
+    let mut e_ident = [0; 16];
+    e_ident[..7].copy_from_slice(b"\x7fELF\x02\x01\x01");
+    userspace::ElfHeader {
+        e_ident,
+        e_type,
+        e_machine: 183,
+        e_version: 1,
+        e_entry,
+        e_phoff: 64,
+        e_shoff: 0,
+        e_flags: 0,
+        e_ehsize: 64,
+        e_phentsize: 56,
+        e_phnum,
+        e_shentsize: 0,
+        e_shnum: 0,
+        e_shstrndx: 0,
+    }
+}
+
+fn program_header(p_type: u32, p_flags: u32, p_offset: u64, p_vaddr: u64, p_filesz: u64, p_memsz: u64) -> userspace::ProgramHeader {
+    userspace::ProgramHeader {
+        p_type,
+        p_flags,
+        p_offset,
+        p_vaddr,
+        p_paddr: p_vaddr,
+        p_filesz,
+        p_memsz,
+        p_align: 4096,
+    }
+}
+
+fn struct_bytes<T>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, core::mem::size_of::<T>()) }
+}
+
+extern "C" {
+    static user_stack_program: u8;
+    static user_stack_program_end: u8;
+    static user_fork_program: u8;
+    static user_fork_program_end: u8;
+    static user_wait_program: u8;
+    static user_wait_program_end: u8;
+    static user_signal_program: u8;
+    static user_signal_program_end: u8;
+    static user_pipe_program: u8;
+    static user_pipe_program_end: u8;
+    static user_blocking_pipe_program: u8;
+    static user_blocking_pipe_program_end: u8;
+    static user_bad_pointer_program: u8;
+    static user_bad_pointer_program_end: u8;
+    static user_thread_program: u8;
+    static user_thread_program_end: u8;
+    static user_execve_program: u8;
+    static user_execve_program_end: u8;
+    static user_exec_image: u8;
+    static user_exec_image_end: u8;
+    static user_pie_image: u8;
+    static user_pie_image_end: u8;
+    static user_yield_program: u8;
+    static user_yield_program_end: u8;
+    static user_spin_program: u8;
+    static user_spin_program_end: u8;
+}
+
+fn yield_program() -> &'static [u8] {
+    program(&raw const user_yield_program, &raw const user_yield_program_end)
+}
+
+fn spin_program() -> &'static [u8] {
+    program(&raw const user_spin_program, &raw const user_spin_program_end)
+}
+
+fn program(start: *const u8, end: *const u8) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(start, end as usize - start as usize) }
+}
+
+// Position independent EL0 programs, copied into processes by the tests
+// above. Results go to the data page; system call numbers come from the
+// kernel's table.
+core::arch::global_asm!(
+    r#"
+.arch_extension fp
+.arch_extension simd
+.section .rodata.user_programs, "a"
+.balign 4
+
+// Writes to the stack top and three pages below, then past the largest
+// stack into the guard
+.globl user_stack_program
+user_stack_program:
+    movz x6, #{data_hi}, lsl #16
+    mov x1, #0xfeed
+    stur x1, [sp, #-8]
+    sub x2, sp, #0x3000
+    mov x3, #0xbeef
+    stur x3, [x2, #-8]
+    ldur x4, [sp, #-8]
+    ldur x5, [x2, #-8]
+    stp x4, x5, [x6]
+    movz x7, #{max_stack_hi}, lsl #16
+    sub x2, sp, x7
+    mov x3, #1
+    stur x3, [x2, #-8]
+    str x3, [x6, #16]
+    mov x0, #0
+    mov x8, #{exit}
+    svc #0
+.globl user_stack_program_end
+user_stack_program_end:
+
+// Forks with clone(SIGCHLD), and both sides store its return value
+.globl user_fork_program
+user_fork_program:
+    movz x6, #{data_hi}, lsl #16
+    mov x0, #{sigchld}
+    mov x1, xzr
+    mov x8, #{clone}
+    svc #0
+    str x0, [x6]
+    mov x0, #0
+    mov x8, #{exit}
+    svc #0
+.globl user_fork_program_end
+user_fork_program_end:
+
+// Polls wait4 with no children, forks a child that exits with 42, waits
+// for it, then waits again with no children left
+.globl user_wait_program
+user_wait_program:
+    movz x6, #{data_hi}, lsl #16
+    mov x0, #-1
+    mov x1, xzr
+    mov x2, #{wnohang}
+    mov x3, xzr
+    mov x8, #{wait4}
+    svc #0
+    str x0, [x6]
+    mov x0, #{sigchld}
+    mov x1, xzr
+    mov x8, #{clone}
+    svc #0
+    cbnz x0, 1f
+    mov x0, #42
+    mov x8, #{exit}
+    svc #0
+1:
+    str x0, [x6, #8]
+    mov x0, #-1
+    add x1, x6, #24
+    mov x2, xzr
+    mov x3, xzr
+    mov x8, #{wait4}
+    svc #0
+    str x0, [x6, #16]
+    mov x0, #-1
+    mov x1, xzr
+    mov x2, xzr
+    mov x3, xzr
+    mov x8, #{wait4}
+    svc #0
+    str x0, [x6, #32]
+    mov x0, #7
+    mov x8, #{exit}
+    svc #0
+.globl user_wait_program_end
+user_wait_program_end:
+
+// Catches SIGUSR1 and sends it to itself, then again while it is
+// blocked, then unblocks it and dereferences null. The handler counts its
+// calls, records the signal and sender, and clobbers x6 for sigreturn to
+// restore.
+.globl user_signal_program
+user_signal_program:
+    movz x6, #{data_hi}, lsl #16
+    sub sp, sp, #32
+    adr x1, 2f
+    movz x2, #{sa_restorer_hi}, lsl #16
+    orr x2, x2, #{sa_siginfo}
+    adr x3, 3f
+    stp x1, x2, [sp]
+    stp x3, xzr, [sp, #16]
+    mov x0, #{sigusr1}
+    mov x1, sp
+    mov x2, xzr
+    mov x3, #8
+    mov x8, #{rt_sigaction}
+    svc #0
+    add sp, sp, #32
+    mov x8, #{getpid}
+    svc #0
+    mov x19, x0
+    mov x1, #{sigusr1}
+    mov x8, #{kill}
+    svc #0
+    str x0, [x6, #24]
+    sub sp, sp, #16
+    mov x1, #{sigusr1_mask}
+    str x1, [sp]
+    mov x0, #{sig_block}
+    mov x1, sp
+    mov x2, xzr
+    mov x3, #8
+    mov x8, #{rt_sigprocmask}
+    svc #0
+    mov x0, x19
+    mov x1, #{sigusr1}
+    mov x8, #{kill}
+    svc #0
+    ldr x1, [x6]
+    str x1, [x6, #32]
+    mov x0, #{sig_unblock}
+    mov x1, sp
+    mov x2, xzr
+    mov x3, #8
+    mov x8, #{rt_sigprocmask}
+    svc #0
+    add sp, sp, #16
+    mov x1, xzr
+    str x1, [x1]
+    mov x0, #0
+    mov x8, #{exit}
+    svc #0
+2:
+    movz x9, #{data_hi}, lsl #16
+    ldr x10, [x9]
+    add x10, x10, #1
+    str x10, [x9]
+    str x0, [x9, #8]
+    ldr w11, [x1, #16]
+    str x11, [x9, #16]
+    mov x6, xzr
+    ret
+3:
+    mov x8, #{rt_sigreturn}
+    svc #0
+.globl user_signal_program_end
+user_signal_program_end:
+
+// Closes the read end of a pipe and writes to the other
+.globl user_pipe_program
+user_pipe_program:
+    movz x6, #{data_hi}, lsl #16
+    sub sp, sp, #16
+    mov x0, sp
+    mov x1, xzr
+    mov x8, #{pipe2}
+    svc #0
+    ldr w0, [sp]
+    mov x8, #{close}
+    svc #0
+    ldr w0, [sp, #4]
+    mov x1, sp
+    mov x2, #1
+    mov x8, #{write}
+    svc #0
+    mov x1, #1
+    str x1, [x6]
+    mov x0, #0
+    mov x8, #{exit}
+    svc #0
+.globl user_pipe_program_end
+user_pipe_program_end:
+
+// Reads an empty O_NONBLOCK pipe, then forks a child that writes to a
+// blocking pipe the parent is reading, reaps it, and reads the pipe again
+// with nothing left to write to it
+.globl user_blocking_pipe_program
+user_blocking_pipe_program:
+    movz x6, #{data_hi}, lsl #16
+    sub sp, sp, #16
+    mov x0, sp
+    mov x1, #{o_nonblock}
+    mov x8, #{pipe2}
+    svc #0
+    ldr w0, [sp]
+    add x1, x6, #32
+    mov x2, #8
+    mov x8, #{read}
+    svc #0
+    str x0, [x6]
+    mov x0, sp
+    mov x1, xzr
+    mov x8, #{pipe2}
+    svc #0
+    mov x0, #{sigchld}
+    mov x1, xzr
+    mov x8, #{clone}
+    svc #0
+    cbnz x0, 1f
+    mov x1, #0x77
+    str x1, [x6, #40]
+    ldr w0, [sp, #4]
+    add x1, x6, #40
+    mov x2, #8
+    mov x8, #{write}
+    svc #0
+    mov x0, #0
+    mov x8, #{exit}
+    svc #0
+1:
+    ldr w0, [sp]
+    add x1, x6, #16
+    mov x2, #8
+    mov x8, #{read}
+    svc #0
+    str x0, [x6, #8]
+    mov x0, #-1
+    mov x1, xzr
+    mov x2, xzr
+    mov x3, xzr
+    mov x8, #{wait4}
+    svc #0
+    ldr w0, [sp]
+    add x1, x6, #24
+    mov x2, #8
+    mov x8, #{read}
+    svc #0
+    mov x0, #0
+    mov x8, #{exit}
+    svc #0
+.globl user_blocking_pipe_program_end
+user_blocking_pipe_program_end:
+
+// Hands system calls pointers into the kernel, into nothing and into its
+// own text, then a path of 'a's that runs on past PATH_MAX. The path is
+// opened first, before results overwrite the start of the data page.
+.globl user_bad_pointer_program
+user_bad_pointer_program:
+    movz x6, #{data_hi}, lsl #16
+    mov x0, #{at_fdcwd}
+    add x1, x6, #64
+    mov x2, xzr
+    mov x3, xzr
+    mov x8, #{openat}
+    svc #0
+    str x0, [x6, #32]
+    mov x0, #1
+    movz x1, #0xffff, lsl #48
+    mov x2, #8
+    mov x8, #{write}
+    svc #0
+    str x0, [x6]
+    mov x0, #1
+    movz x1, #0x10, lsl #16
+    mov x2, #8
+    mov x8, #{write}
+    svc #0
+    str x0, [x6, #8]
+    mov x0, #{at_fdcwd}
+    movz x1, #0x10, lsl #16
+    mov x2, xzr
+    mov x3, xzr
+    mov x8, #{openat}
+    svc #0
+    str x0, [x6, #16]
+    movz x0, #{code_hi}, lsl #16
+    mov x1, xzr
+    mov x8, #{pipe2}
+    svc #0
+    str x0, [x6, #24]
+    mov x0, #0
+    mov x8, #{exit}
+    svc #0
+.globl user_bad_pointer_program_end
+user_bad_pointer_program_end:
+
+// Starts a thread on a stack at the end of the data page. The thread
+// records its thread pointer and ids and exits; the leader yields until
+// the exit clears the thread's tid word, then ends the process.
+.globl user_thread_program
+user_thread_program:
+    movz x6, #{data_hi}, lsl #16
+    mov x1, #0x5678
+    msr tpidr_el0, x1
+    movz x0, #{clone_flags_lo}
+    movk x0, #{clone_flags_hi}, lsl #16
+    add x1, x6, #4096
+    add x2, x6, #40
+    mov x3, #0x1234
+    add x4, x6, #48
+    mov x8, #{clone}
+    svc #0
+    cbnz x0, 1f
+    mrs x1, tpidr_el0
+    str x1, [x6, #8]
+    mov x8, #{gettid}
+    svc #0
+    str x0, [x6, #16]
+    mov x8, #{getpid}
+    svc #0
+    str x0, [x6, #24]
+    mov x0, #0
+    mov x8, #{exit}
+    svc #0
+1:
+    str x0, [x6, #32]
+2:
+    ldr w1, [x6, #48]
+    cbz w1, 3f
+    mov x8, #{sched_yield}
+    svc #0
+    b 2b
+3:
+    mrs x1, tpidr_el0
+    str x1, [x6, #56]
+    mov x0, #5
+    mov x8, #{exit_group}
+    svc #0
+.globl user_thread_program_end
+user_thread_program_end:
+
+// Execs the path, argv and envp its data page points to, storing the
+// error if that returns
+.globl user_execve_program
+user_execve_program:
+    movz x6, #{data_hi}, lsl #16
+    ldp x0, x1, [x6]
+    ldr x2, [x6, #16]
+    mov x8, #{execve}
+    svc #0
+    str x0, [x6, #24]
+    mov x0, #1
+    mov x8, #{exit}
+    svc #0
+.globl user_execve_program_end
+user_execve_program_end:
+
+// Started by exec: records its stack pointer, its initialised data and
+// the last word of its .bss, and exits with argc
+.globl user_exec_image
+user_exec_image:
+    movz x6, #{data_hi}, lsl #16
+    mov x1, sp
+    str x1, [x6]
+    ldr x1, [x6, #0x200]
+    str x1, [x6, #8]
+    add x7, x6, #0x2000
+    ldr x1, [x7, #0x1f8]
+    str x1, [x6, #16]
+    ldr x0, [sp]
+    mov x8, #{exit}
+    svc #0
+.globl user_exec_image_end
+user_exec_image_end:
+
+// Started as a position-independent executable: exits with 0 if the
+// word its relocation filled in points back at it
+.globl user_pie_image
+user_pie_image:
+    adr x1, user_pie_image
+    adr x2, user_pie_image + {pie_data_offset}
+    ldr x3, [x2]
+    cmp x3, x1
+    cset x0, ne
+    mov x8, #{exit}
+    svc #0
+.globl user_pie_image_end
+user_pie_image_end:
+
+// Loads a seed into GPRs and SIMD registers, yields, and stores what is
+// left in them afterwards
+.globl user_yield_program
+user_yield_program:
+    movz x6, #{data_hi}, lsl #16
+    ldr x1, [x6]
+    fmov d0, x1
+    ins v31.d[1], x1
+    add x19, x1, #1
+    add x28, x1, #2
+    mov x8, #{sched_yield}
+    svc #0
+    fmov x2, d0
+    umov x3, v31.d[1]
+    stp x2, x3, [x6, #8]
+    stp x19, x28, [x6, #24]
+    mov x0, #0
+    mov x8, #{exit}
+    svc #0
+.globl user_yield_program_end
+user_yield_program_end:
+
+// Spins without entering the kernel until its data word becomes nonzero
+.globl user_spin_program
+user_spin_program:
+    movz x6, #{data_hi}, lsl #16
+1:
+    ldr x1, [x6]
+    cbz x1, 1b
+    mov x0, #0
+    mov x8, #{exit}
+    svc #0
+.globl user_spin_program_end
+user_spin_program_end:
+"#,
+    data_hi = const DATA_ADDR >> 16,
+    code_hi = const CODE_ADDR >> 16,
+    max_stack_hi = const process::MAX_STACK_SIZE >> 16,
+    exit = const syscall::SYS_EXIT,
+    sigchld = const signal::SIGCHLD,
+    wait4 = const syscall::SYS_WAIT4,
+    wnohang = const process::WNOHANG,
+    sched_yield = const syscall::SYS_SCHED_YIELD,
+    getpid = const syscall::SYS_GETPID,
+    kill = const syscall::SYS_KILL,
+    rt_sigaction = const syscall::SYS_RT_SIGACTION,
+    rt_sigprocmask = const syscall::SYS_RT_SIGPROCMASK,
+    rt_sigreturn = const syscall::SYS_RT_SIGRETURN,
+    pipe2 = const syscall::SYS_PIPE2,
+    openat = const syscall::SYS_OPENAT,
+    at_fdcwd = const syscall::AT_FDCWD,
+    read = const syscall::SYS_READ,
+    o_nonblock = const OpenFlags::O_NONBLOCK.bits(),
+    close = const syscall::SYS_CLOSE,
+    write = const syscall::SYS_WRITE,
+    sigusr1 = const SIGUSR1,
+    sigusr1_mask = const signal::sigmask(SIGUSR1),
+    sa_restorer_hi = const signal::SA_RESTORER >> 16,
+    sa_siginfo = const signal::SA_SIGINFO,
+    sig_block = const signal::SIG_BLOCK,
+    sig_unblock = const signal::SIG_UNBLOCK,
+    clone = const syscall::SYS_CLONE,
+    clone_flags_lo = const THREAD_CLONE_FLAGS & 0xffff,
+    clone_flags_hi = const THREAD_CLONE_FLAGS >> 16,
+    gettid = const syscall::SYS_GETTID,
+    exit_group = const syscall::SYS_EXIT_GROUP,
+    execve = const syscall::SYS_EXECVE,
+    pie_data_offset = const PIE_DATA - PIE_CODE,
+);
+
+// What a pthread_create makes of clone
+const THREAD_CLONE_FLAGS: u64 = process::CLONE_VM | process::CLONE_FS | process::CLONE_FILES | process::CLONE_SIGHAND
+    | process::CLONE_THREAD | process::CLONE_SYSVSEM | process::CLONE_SETTLS | process::CLONE_PARENT_SETTID
+    | process::CLONE_CHILD_SETTID | process::CLONE_CHILD_CLEARTID;
+
 fn exit_qemu(code: u64) -> ! {
     unsafe {
         asm!(
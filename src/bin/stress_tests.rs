@@ -1,13 +1,18 @@
 #![no_std]
 #![no_main]
 
+extern crate alloc;
 extern crate rustos;
 
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
 use core::arch::asm;
 use core::panic::PanicInfo;
 
 use rustos::fs::{self, OpenFlags};
-use rustos::{ipc, memory, panic as panic_runtime, process, syscall, uart, userspace};
+use rustos::scheduler::PolicyKind;
+use rustos::{gic, heap, ipc, memory, panic as panic_runtime, process, syscall, timer, uart, userspace};
 
 type TestFn = fn();
 
@@ -15,6 +20,9 @@ const TESTS: &[TestFn] = &[
     bulk_page_allocations_remain_unique,
     sustained_pipe_throughput_succeeds,
     file_create_write_read_remove_cycles,
+    slab_objects_are_recycled,
+    general_heap_grows_past_boot_region,
+    scheduling_policies_share_cpu_between_hogs,
 ];
 
 #[no_mangle]
@@ -23,6 +31,8 @@ pub extern "C" fn kernel_main() -> ! {
     memory::init();
     process::init();
     syscall::init();
+    gic::init().expect("initialize GIC");
+    timer::init().expect("start timer");
     fs::init();
     ipc::init();
     userspace::init();
@@ -92,6 +102,115 @@ fn file_create_write_read_remove_cycles() {
     }
 }
 
+fn slab_objects_are_recycled() {
+    const OBJECTS: usize = 512;
+    let class = heap::SLAB_SIZES.iter().position(|&size| size == 64).expect("64-byte class");
+    let before = heap::stats().caches[class];
+
+    for _ in 0..4 {
+        let objects: Vec<Box<[u8; 64]>> = (0..OBJECTS).map(|i| Box::new([i as u8; 64])).collect();
+        for (i, object) in objects.iter().enumerate() {
+            assert_eq!(object[63], i as u8);
+        }
+        let during = heap::stats().caches[class];
+        assert!(during.objects_in_use >= before.objects_in_use + OBJECTS);
+    }
+
+    // Freed objects are reused instead of pulling in more slabs every round
+    let after = heap::stats().caches[class];
+    assert_eq!(after.objects_in_use, before.objects_in_use);
+    assert!(after.high_water >= before.objects_in_use + OBJECTS);
+    assert!(after.slabs <= before.slabs + OBJECTS * 64 / 4096 + 1);
+}
+
+fn general_heap_grows_past_boot_region() {
+    let before = heap::stats().heap;
+    let mut buffer: Vec<u8> = Vec::with_capacity(2 * 1024 * 1024);
+    buffer.resize(buffer.capacity(), 0xa5);
+    assert!(buffer.iter().all(|&byte| byte == 0xa5));
+
+    let grown = heap::stats().heap;
+    assert!(grown.grows > before.grows, "a 2 MiB buffer does not fit the 1 MiB boot heap");
+    assert!(grown.size >= before.size + buffer.len());
+    drop(buffer);
+    assert!(heap::stats().heap.used < grown.used);
+}
+
+// Runs the same CPU-bound load under each policy and reports how the CPU
+// was shared, for comparing policies
+fn scheduling_policies_share_cpu_between_hogs() {
+    const NICE: [i32; 3] = [0, 0, 10];
+    const ITERATIONS: u64 = 10_000_000;
+
+    for policy in [PolicyKind::RoundRobin, PolicyKind::Mlfq] {
+        process::set_scheduler_policy(policy);
+        let pids: Vec<u32> = NICE.iter().map(|_| spawn_spinner(ITERATIONS)).collect();
+        for (&pid, &nice) in pids.iter().zip(NICE.iter()) {
+            process::set_priority(pid, nice).expect("set nice");
+        }
+
+        let start = timer::uptime_ns();
+        process::schedule();
+        let wall = timer::uptime_ns() - start;
+
+        let mut total = 0;
+        for (&pid, &nice) in pids.iter().zip(NICE.iter()) {
+            assert_eq!(process::process_state(pid), Some(process::ProcessState::Zombie));
+            let cpu = process::cpu_time(pid).expect("cpu time");
+            assert!(cpu > 0);
+            total += cpu;
+            rustos::println!("{}: pid {} nice {} used {} us", process::scheduler_policy(), pid, nice, cpu / 1000);
+        }
+        assert!(total <= wall, "CPU time is only charged while running");
+        rustos::println!("{}: wall time {} us", process::scheduler_policy(), wall / 1000);
+    }
+    process::set_scheduler_policy(PolicyKind::Mlfq);
+}
+
+// Spinner programs run at EL0 with their text at CODE_ADDR and the
+// iteration count at DATA_ADDR
+const CODE_ADDR: u64 = 0x40_0000;
+const DATA_ADDR: u64 = 0x50_0000;
+
+fn spawn_spinner(iterations: u64) -> u32 {
+    extern "C" {
+        static user_spin_program: u8;
+        static user_spin_program_end: u8;
+    }
+    let code = unsafe {
+        let start = &raw const user_spin_program;
+        core::slice::from_raw_parts(start, &raw const user_spin_program_end as usize - start as usize)
+    };
+    let rx = process::MemoryPermissions::READ | process::MemoryPermissions::EXECUTE;
+    let rw = process::MemoryPermissions::READ | process::MemoryPermissions::WRITE;
+    let pid = process::create_process(CODE_ADDR, 4096).expect("create process");
+    process::load_segment(pid, CODE_ADDR, code, rx).expect("load text");
+    process::load_segment(pid, DATA_ADDR, &iterations.to_le_bytes(), rw).expect("load data");
+    pid
+}
+
+core::arch::global_asm!(
+    r#"
+.section .rodata.user_programs, "a"
+.balign 4
+// Counts down from its data word, then exits
+.globl user_spin_program
+user_spin_program:
+    movz x6, #{data_hi}, lsl #16
+    ldr x1, [x6]
+1:
+    subs x1, x1, #1
+    b.ne 1b
+    mov x0, #0
+    mov x8, #{exit}
+    svc #0
+.globl user_spin_program_end
+user_spin_program_end:
+"#,
+    data_hi = const DATA_ADDR >> 16,
+    exit = const syscall::SYS_EXIT,
+);
+
 fn format_path(iteration: usize, buffer: &mut [u8; 32]) -> &str {
     let template = b"/tmp/stress";
     let mut len = 0;
@@ -6,15 +6,30 @@ extern crate rustos;
 use core::arch::asm;
 use core::panic::PanicInfo;
 
+use rustos::error::{self, KernelError};
 use rustos::fs::{self, OpenFlags};
-use rustos::{ipc, memory, panic as panic_runtime, process, syscall, uart, userspace};
+use rustos::graphics::GraphicsError;
+use rustos::input::InputError;
+use rustos::process::{MapFlags, MemoryPermissions};
+use rustos::wayland::WaylandError;
+use rustos::{ipc, memory, panic as panic_runtime, process, syscall, uaccess, uart, userspace};
 
 type TestFn = fn();
 
 const TESTS: &[TestFn] = &[
     unknown_syscall_returns_error,
+    kernel_errors_map_to_errno,
     sys_open_write_read_file_via_handler,
     sys_pipe_roundtrip_via_handler,
+    linux_file_calls_via_handler,
+    clock_random_and_uname_via_handler,
+    user_strings_are_bounded_and_validated,
+    || run_in_process(sys_brk_grows_and_shrinks_the_heap),
+    || run_in_process(sys_mmap_munmap_splits_regions),
+    || run_in_process(sys_memory_calls_reject_overflowing_lengths),
+    || run_in_process(sys_mmap_shared_file_writes_back),
+    || run_in_process(sys_rlimits_bound_descriptors_and_memory),
+    || run_in_process(sys_setpriority_adjusts_nice_and_rusage_reports_cpu_time),
 ];
 
 #[no_mangle]
@@ -43,7 +58,16 @@ fn run_tests() {
 
 fn unknown_syscall_returns_error() {
     let result = syscall::syscall_handler(9_999, 0, 0, 0, 0, 0, 0);
-    assert_eq!(result, u64::MAX);
+    assert_eq!(result, -error::ENOSYS as u64);
+}
+
+fn kernel_errors_map_to_errno() {
+    assert_eq!(KernelError::NotFound.errno(), error::ENOENT);
+    assert_eq!(KernelError::StackOverflow.errno(), error::EFAULT);
+    assert_eq!(KernelError::BrokenPipe.to_syscall_return(), -error::EPIPE as u64);
+    assert_eq!(KernelError::from(WaylandError::InvalidSocket), KernelError::BadFileDescriptor);
+    assert_eq!(KernelError::from(GraphicsError::OutOfMemory), KernelError::OutOfMemory);
+    assert_eq!(KernelError::from(InputError::BufferFull), KernelError::WouldBlock);
 }
 
 fn sys_open_write_read_file_via_handler() {
@@ -52,13 +76,13 @@ fn sys_open_write_read_file_via_handler() {
 
     let write_flags = (OpenFlags::O_CREAT | OpenFlags::O_WRONLY | OpenFlags::O_TRUNC).bits() as u64;
     let fd = syscall::syscall_handler(
-        syscall::SYS_OPEN,
+        syscall::SYS_OPENAT,
+        syscall::AT_FDCWD as u64,
         path.as_ptr() as u64,
         write_flags,
         0,
         0,
         0,
-        0,
     ) as i32;
     assert!(fd >= 0);
 
@@ -77,13 +101,13 @@ fn sys_open_write_read_file_via_handler() {
     syscall::syscall_handler(syscall::SYS_CLOSE, fd as u64, 0, 0, 0, 0, 0);
 
     let read_fd = syscall::syscall_handler(
-        syscall::SYS_OPEN,
+        syscall::SYS_OPENAT,
+        syscall::AT_FDCWD as u64,
         path.as_ptr() as u64,
         OpenFlags::O_RDONLY.bits() as u64,
         0,
         0,
         0,
-        0,
     ) as i32;
     assert!(read_fd >= 0);
 
@@ -106,7 +130,7 @@ fn sys_open_write_read_file_via_handler() {
 fn sys_pipe_roundtrip_via_handler() {
     let mut pipe_fd = [0i32; 2];
     let result = syscall::syscall_handler(
-        syscall::SYS_PIPE,
+        syscall::SYS_PIPE2,
         (&mut pipe_fd) as *mut _ as u64,
         0,
         0,
@@ -145,6 +169,269 @@ fn sys_pipe_roundtrip_via_handler() {
     syscall::syscall_handler(syscall::SYS_CLOSE, pipe_fd[1] as u64, 0, 0, 0, 0, 0);
 }
 
+fn linux_file_calls_via_handler() {
+    let call = |number, arg1, arg2, arg3, arg4| syscall::syscall_handler(number, arg1, arg2, arg3, arg4, 0, 0);
+    let path = b"/tmp/linux.txt\0";
+    let _ = fs::remove_file("/tmp/linux.txt");
+
+    // musl adds O_LARGEFILE and often O_CLOEXEC
+    let flags = OpenFlags::O_CREAT | OpenFlags::O_RDWR;
+    let flags = (flags.bits() | 0o400000 | fs::O_CLOEXEC) as u64;
+    let fd = call(syscall::SYS_OPENAT, syscall::AT_FDCWD as u64, path.as_ptr() as u64, flags, 0o644);
+    assert!(!is_error(fd));
+
+    let (head, tail) = (b"gather ", b"write");
+    let iov = [
+        syscall::IoVec { base: head.as_ptr() as u64, len: head.len() as u64 },
+        syscall::IoVec { base: 0, len: 0 },
+        syscall::IoVec { base: tail.as_ptr() as u64, len: tail.len() as u64 },
+    ];
+    assert_eq!(call(syscall::SYS_WRITEV, fd, iov.as_ptr() as u64, iov.len() as u64, 0), 12);
+
+    let mut status = fs::Stat::default();
+    assert_eq!(call(syscall::SYS_FSTAT, fd, &mut status as *mut _ as u64, 0, 0), 0);
+    assert_eq!(status.mode & 0o170000, fs::S_IFREG);
+    assert_eq!(status.size, 12);
+    let mut by_path = fs::Stat::default();
+    assert_eq!(call(syscall::SYS_NEWFSTATAT, syscall::AT_FDCWD as u64, path.as_ptr() as u64, &mut by_path as *mut _ as u64, 0), 0);
+    assert_eq!(by_path, status);
+    assert_eq!(call(syscall::SYS_FSTAT, 1, &mut status as *mut _ as u64, 0, 0), 0);
+    assert_eq!(status.mode & 0o170000, fs::S_IFCHR);
+
+    assert_eq!(call(syscall::SYS_LSEEK, fd, -5i64 as u64, fs::SEEK_END as u64, 0), 7);
+    let mut buffer = [0u8; 16];
+    assert_eq!(call(syscall::SYS_READ, fd, buffer.as_mut_ptr() as u64, buffer.len() as u64, 0), 5);
+    assert_eq!(&buffer[..5], b"write");
+    assert_eq!(call(syscall::SYS_LSEEK, fd, -1i64 as u64, fs::SEEK_SET as u64, 0), -error::EINVAL as u64);
+    assert_eq!(call(syscall::SYS_LSEEK, 0, 0, fs::SEEK_CUR as u64, 0), -error::ESPIPE as u64);
+
+    // dup3 onto a chosen descriptor, and F_DUPFD onto the lowest one past a floor
+    assert_eq!(call(syscall::SYS_DUP3, fd, 20, fs::O_CLOEXEC as u64, 0), 20);
+    assert_eq!(call(syscall::SYS_DUP3, fd, fd, 0, 0), -error::EINVAL as u64);
+    assert_eq!(call(syscall::SYS_FCNTL, fd, fs::F_DUPFD as u64, 10, 0), 10);
+
+    // The console is a terminal and files are not
+    let mut size = syscall::WinSize::default();
+    assert_eq!(call(syscall::SYS_IOCTL, 1, syscall::TIOCGWINSZ as u64, &mut size as *mut _ as u64, 0), 0);
+    assert_eq!((size.rows, size.cols), (24, 80));
+    assert_eq!(call(syscall::SYS_IOCTL, fd, syscall::TIOCGWINSZ as u64, &mut size as *mut _ as u64, 0), -error::ENOTTY as u64);
+
+    for fd in [fd, 10, 20] {
+        assert_eq!(call(syscall::SYS_CLOSE, fd, 0, 0, 0), 0);
+    }
+    assert_eq!(call(syscall::SYS_CLOSE, fd, 0, 0, 0), -error::EBADF as u64);
+    let missing = b"/tmp/missing.txt\0";
+    let flags = OpenFlags::O_RDONLY.bits() as u64;
+    assert_eq!(call(syscall::SYS_OPENAT, syscall::AT_FDCWD as u64, missing.as_ptr() as u64, flags, 0), -error::ENOENT as u64);
+}
+
+fn user_strings_are_bounded_and_validated() {
+    let call = |number, arg1, arg2| syscall::syscall_handler(number, syscall::AT_FDCWD as u64, arg1, arg2, 0, 0, 0);
+    let flags = OpenFlags::O_RDONLY.bits() as u64;
+    assert_eq!(call(syscall::SYS_OPENAT, 0, flags), -error::EFAULT as u64);
+
+    let mut long = [b'a'; uaccess::PATH_MAX + 1];
+    long[uaccess::PATH_MAX] = 0;
+    assert_eq!(call(syscall::SYS_OPENAT, long.as_ptr() as u64, flags), -error::ENAMETOOLONG as u64);
+    let invalid = b"/tmp/\xff\xfe\0";
+    assert_eq!(call(syscall::SYS_OPENAT, invalid.as_ptr() as u64, flags), -error::EINVAL as u64);
+
+    // A path that just fits, NUL included, gets as far as the lookup
+    long[uaccess::PATH_MAX - 1] = 0;
+    assert_eq!(call(syscall::SYS_OPENAT, long.as_ptr() as u64, flags), -error::ENOENT as u64);
+    assert_eq!(uaccess::user_path(long.as_ptr() as u64).map(|path| path.len()), Ok(uaccess::PATH_MAX - 1));
+}
+
+fn clock_random_and_uname_via_handler() {
+    let call = |number, arg1, arg2, arg3| syscall::syscall_handler(number, arg1, arg2, arg3, 0, 0, 0);
+
+    let mut first = syscall::Timespec::default();
+    let mut second = syscall::Timespec::default();
+    assert_eq!(call(syscall::SYS_CLOCK_GETTIME, syscall::CLOCK_MONOTONIC as u64, &mut first as *mut _ as u64, 0), 0);
+    assert_eq!(call(syscall::SYS_CLOCK_GETTIME, syscall::CLOCK_MONOTONIC as u64, &mut second as *mut _ as u64, 0), 0);
+    assert!(first.nsec < 1_000_000_000);
+    assert!((second.sec, second.nsec) >= (first.sec, first.nsec));
+    assert_eq!(call(syscall::SYS_CLOCK_GETTIME, 99, &mut first as *mut _ as u64, 0), -error::EINVAL as u64);
+
+    let mut bytes = [0u8; 37];
+    assert_eq!(call(syscall::SYS_GETRANDOM, bytes.as_mut_ptr() as u64, bytes.len() as u64, 0), 37);
+    assert!(bytes.iter().any(|&byte| byte != 0));
+    assert_eq!(call(syscall::SYS_GETRANDOM, bytes.as_mut_ptr() as u64, 1, 0x80), -error::EINVAL as u64);
+
+    let mut name = core::mem::MaybeUninit::<syscall::UtsName>::uninit();
+    assert_eq!(call(syscall::SYS_UNAME, name.as_mut_ptr() as u64, 0, 0), 0);
+    let name = unsafe { name.assume_init() };
+    assert_eq!(&name.machine[..8], b"aarch64\0");
+    assert_eq!(call(syscall::SYS_GETUID, 0, 0, 0), 0);
+}
+
+fn sys_brk_grows_and_shrinks_the_heap() {
+    let pid = process::get_current_pid().expect("current process");
+    let brk = |addr| syscall::syscall_handler(syscall::SYS_BRK, addr, 0, 0, 0, 0, 0);
+
+    let start = brk(0);
+    assert_eq!(start & 0xfff, 0, "the heap starts on a page boundary");
+    assert_eq!(brk(start - 1), start, "the break cannot move below the heap");
+    assert_eq!(brk(start + 5000), start + 5000);
+    unsafe { ((start + 4096) as *mut u64).write_volatile(0x4242) };
+    assert_eq!(brk(start + 3 * 4096), start + 3 * 4096);
+    assert_eq!(process::find_region(pid, start).expect("heap").size, 3 * 4096, "growth extends one region");
+    assert_eq!(unsafe { ((start + 4096) as *const u64).read_volatile() }, 0x4242);
+
+    // Shrinking unmaps the pages past the new break
+    assert_eq!(brk(start + 4096), start + 4096);
+    assert!(process::find_region(pid, start + 4096).is_none());
+    assert_eq!(brk(0), start + 4096);
+
+    // A mapping in the way keeps the break where it is
+    let rw = (MemoryPermissions::READ | MemoryPermissions::WRITE).bits() as u64;
+    let fixed = (MapFlags::PRIVATE | MapFlags::ANONYMOUS | MapFlags::FIXED).bits() as u64;
+    let blocker = start + 4 * 4096;
+    assert_eq!(syscall::syscall_handler(syscall::SYS_MMAP, blocker, 4096, rw, fixed, u64::MAX, 0), blocker);
+    assert_eq!(brk(start + 8 * 4096), start + 4096);
+}
+
+fn sys_memory_calls_reject_overflowing_lengths() {
+    let call = |number, arg1, arg2, arg3, arg4| syscall::syscall_handler(number, arg1, arg2, arg3, arg4, u64::MAX, 0);
+    let rw = (MemoryPermissions::READ | MemoryPermissions::WRITE).bits() as u64;
+    let private = (MapFlags::PRIVATE | MapFlags::ANONYMOUS).bits() as u64;
+    assert_eq!(call(syscall::SYS_MMAP, 0, u64::MAX, rw, private), -error::ENOMEM as u64);
+
+    let addr = call(syscall::SYS_MMAP, 0, 4096, rw, private);
+    assert!(!is_error(addr));
+    assert_eq!(call(syscall::SYS_MPROTECT, addr, u64::MAX, rw, 0), -error::EINVAL as u64);
+    assert_eq!(call(syscall::SYS_MUNMAP, addr, u64::MAX, 0, 0), -error::EINVAL as u64);
+    assert_eq!(call(syscall::SYS_MUNMAP, addr, 4096, 0, 0), 0);
+}
+
+fn sys_mmap_munmap_splits_regions() {
+    let pid = process::get_current_pid().expect("current process");
+    let rw = (MemoryPermissions::READ | MemoryPermissions::WRITE).bits() as u64;
+    let private = (MapFlags::PRIVATE | MapFlags::ANONYMOUS).bits() as u64;
+    let addr = syscall::syscall_handler(syscall::SYS_MMAP, 0, 3 * 4096, rw, private, u64::MAX, 0);
+    assert_eq!(addr & 0xfff, 0);
+    unsafe { ((addr + 2 * 4096) as *mut u64).write_volatile(0x5a5a) };
+
+    // Unmapping the middle page leaves two regions around a hole
+    assert_eq!(syscall::syscall_handler(syscall::SYS_MUNMAP, addr + 4096, 4096, 0, 0, 0, 0), 0);
+    assert!(process::find_region(pid, addr + 4096).is_none());
+    assert_eq!(process::find_region(pid, addr).expect("head").size, 4096);
+    assert_eq!(process::find_region(pid, addr + 2 * 4096).expect("tail").start, addr + 2 * 4096);
+    assert_eq!(unsafe { ((addr + 2 * 4096) as *const u64).read_volatile() }, 0x5a5a);
+
+    let read_only = MemoryPermissions::READ.bits() as u64;
+    assert_eq!(syscall::syscall_handler(syscall::SYS_MPROTECT, addr, 4096, read_only, 0, 0, 0), 0);
+    assert_eq!(process::find_region(pid, addr).expect("head").permissions, MemoryPermissions::READ);
+    assert_eq!(syscall::syscall_handler(syscall::SYS_MPROTECT, addr + 4096, 4096, read_only, 0, 0, 0), -error::ENOMEM as u64);
+
+    // MAP_FIXED fills the hole exactly
+    let fixed = private | MapFlags::FIXED.bits() as u64;
+    let hole = syscall::syscall_handler(syscall::SYS_MMAP, addr + 4096, 4096, rw, fixed, u64::MAX, 0);
+    assert_eq!(hole, addr + 4096);
+}
+
+fn sys_mmap_shared_file_writes_back() {
+    let path = "/tmp/mapped.txt";
+    fs::create_file(path).expect("create mapped file");
+    let fd = fs::open(path, OpenFlags::O_RDWR.bits(), 0).expect("open mapped file");
+    fs::write(fd, b"mapped-file").expect("fill mapped file");
+
+    let rw = (MemoryPermissions::READ | MemoryPermissions::WRITE).bits() as u64;
+    let shared = syscall::syscall_handler(syscall::SYS_MMAP, 0, 4096, rw, MapFlags::SHARED.bits() as u64, fd as u64, 0);
+    let private = syscall::syscall_handler(syscall::SYS_MMAP, 0, 4096, rw, MapFlags::PRIVATE.bits() as u64, fd as u64, 0);
+    assert!(!is_error(shared) && !is_error(private));
+
+    let shared_bytes = unsafe { core::slice::from_raw_parts_mut(shared as *mut u8, 11) };
+    let private_bytes = unsafe { core::slice::from_raw_parts_mut(private as *mut u8, 11) };
+    assert_eq!(shared_bytes, b"mapped-file");
+    assert_eq!(private_bytes, b"mapped-file");
+    shared_bytes[0] = b'M';
+    private_bytes[1] = b'X';
+
+    assert_eq!(syscall::syscall_handler(syscall::SYS_MUNMAP, shared, 4096, 0, 0, 0, 0), 0);
+    assert_eq!(syscall::syscall_handler(syscall::SYS_MUNMAP, private, 4096, 0, 0, 0, 0), 0);
+    fs::close(fd).expect("close mapped file");
+    assert_eq!(fs::read_file(path).expect("read back"), "Mapped-file");
+}
+
+fn sys_rlimits_bound_descriptors_and_memory() {
+    let pid = process::get_current_pid().expect("current process");
+    let mut limit = process::Rlimit { cur: 0, max: 0 };
+    let get = |resource, limit: &mut process::Rlimit| {
+        syscall::syscall_handler(syscall::SYS_GETRLIMIT, resource as u64, limit as *mut _ as u64, 0, 0, 0, 0)
+    };
+    let set = |resource, limit: &process::Rlimit| {
+        syscall::syscall_handler(syscall::SYS_SETRLIMIT, resource as u64, limit as *const _ as u64, 0, 0, 0, 0)
+    };
+
+    assert_eq!(get(process::RLIMIT_NOFILE, &mut limit), 0);
+    assert_eq!(limit.cur, process::DEFAULT_NOFILE);
+    assert_eq!(set(process::RLIMIT_NOFILE, &process::Rlimit { cur: limit.max + 1, max: limit.max }), -error::EINVAL as u64);
+    assert_eq!(set(process::RLIMIT_NOFILE, &process::Rlimit { cur: 1, max: limit.max + 1 }), -error::EPERM as u64);
+
+    // stdin, stdout and stderr leave room for two more descriptors
+    assert_eq!(set(process::RLIMIT_NOFILE, &process::Rlimit { cur: 5, max: limit.max }), 0);
+    let _ = fs::create_file("/tmp/rlimit.txt");
+    let first = fs::open("/tmp/rlimit.txt", OpenFlags::O_RDONLY.bits(), 0).expect("fd 3");
+    let second = fs::open("/tmp/rlimit.txt", OpenFlags::O_RDONLY.bits(), 0).expect("fd 4");
+    assert_eq!((first, second), (3, 4));
+    assert_eq!(fs::open("/tmp/rlimit.txt", OpenFlags::O_RDONLY.bits(), 0), Err(KernelError::TooManyOpenFiles));
+    fs::close(first).expect("close fd 3");
+    assert_eq!(fs::open("/tmp/rlimit.txt", OpenFlags::O_RDONLY.bits(), 0), Ok(3), "lowest fd is reused");
+
+    // RLIMIT_AS caps the virtual size; RSS follows touched pages
+    let usage = process::memory_usage(pid).expect("usage");
+    let address_space = process::Rlimit { cur: usage.virtual_size + 2 * 4096, max: process::RLIM_INFINITY };
+    assert_eq!(set(process::RLIMIT_AS, &address_space), 0);
+    let rw = (MemoryPermissions::READ | MemoryPermissions::WRITE).bits() as u64;
+    let private = (MapFlags::PRIVATE | MapFlags::ANONYMOUS).bits() as u64;
+    assert_eq!(syscall::syscall_handler(syscall::SYS_MMAP, 0, 4 * 4096, rw, private, u64::MAX, 0), -error::ENOMEM as u64);
+    let addr = syscall::syscall_handler(syscall::SYS_MMAP, 0, 2 * 4096, rw, private, u64::MAX, 0);
+    assert!(!is_error(addr));
+    unsafe { (addr as *mut u64).write_volatile(1) };
+    let after = process::memory_usage(pid).expect("usage");
+    assert_eq!(after.virtual_size, usage.virtual_size + 2 * 4096);
+    assert_eq!(after.resident, usage.resident + 4096);
+}
+
+fn sys_setpriority_adjusts_nice_and_rusage_reports_cpu_time() {
+    let call = |number, arg1, arg2, arg3| syscall::syscall_handler(number, arg1, arg2, arg3, 0, 0, 0);
+    let prio = process::PRIO_PROCESS as u64;
+
+    assert_eq!(call(syscall::SYS_GETPRIORITY, prio, 0, 0), 20, "20 - nice 0");
+    assert_eq!(call(syscall::SYS_SETPRIORITY, prio, 0, 5), 0);
+    assert_eq!(process::sys_getpriority(process::PRIO_PROCESS, 0), Ok(5));
+    assert_eq!(process::sys_nice(3), Ok(8));
+    assert_eq!(process::sys_nice(100), Ok(19), "clamped to the lowest priority");
+    assert_eq!(call(syscall::SYS_SETPRIORITY, prio, 0, -30i64 as u64), 0);
+    assert_eq!(call(syscall::SYS_GETPRIORITY, prio, 0, 0), 40, "clamped to nice -20");
+    assert_eq!(call(syscall::SYS_SETPRIORITY, 7, 0, 0), -error::EINVAL as u64, "only PRIO_PROCESS");
+    assert_eq!(call(syscall::SYS_GETPRIORITY, prio, 0xffff, 0), -error::ESRCH as u64, "no such process");
+
+    let mut spin = 0u64;
+    for i in 0..100_000u64 {
+        spin = core::hint::black_box(spin.wrapping_add(i));
+    }
+    let mut usage = process::Rusage::default();
+    assert_eq!(call(syscall::SYS_GETRUSAGE, process::RUSAGE_SELF as u64, &mut usage as *mut _ as u64, 0), 0);
+    assert!(usage.utime.sec > 0 || usage.utime.usec > 0, "CPU time accumulates");
+    assert!(usage.utime.usec < 1_000_000);
+}
+
+// Whether a system call's return value is a negated errno
+fn is_error(value: u64) -> bool {
+    value > -4096i64 as u64
+}
+
+// Runs `test` in a kernel thread, which has an address space and
+// descriptor table of its own, and waits for it to finish
+fn run_in_process(test: fn()) {
+    let pid = process::spawn_kernel_thread(test).expect("spawn kernel thread");
+    process::schedule();
+    assert_eq!(process::process_state(pid), Some(process::ProcessState::Zombie));
+    process::reap(pid).expect("reap test process");
+}
+
 fn exit_qemu(code: u64) -> ! {
     unsafe {
         asm!(
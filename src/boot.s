@@ -1,17 +1,70 @@
+// Boot code runs at the kernel's physical load address with the MMU off,
+// so everything before the jump to higher_half must use PC-relative addressing.
+
+// Block descriptors for the boot map (1 GiB blocks at level 1)
+.equ BLOCK_DEVICE, 0x0060000000000405   // AttrIndx=1, AF, PXN, UXN
+.equ BLOCK_NORMAL, 0x0000000000000701   // AttrIndx=0, inner shareable, AF
+.equ TABLE_DESC, 0x3
+
+// Normal WB (idx 0), Device-nGnRE (idx 1), Normal non-cacheable (idx 2)
+.equ MAIR_VALUE, 0x00000000004404ff
+
+// T0SZ=T1SZ=16, 4 KiB granules, inner shareable write-back walks
+.equ TCR_VALUE, 0x00000000b5103510
+
+// RES1 bits plus M (MMU), C (data cache) and I (instruction cache)
+.equ SCTLR_VALUE, 0x0000000030d01805
+
+// Drops from EL2 to EL1 if the firmware left us in hypervisor mode, then
+// continues at \target. Clobbers x1.
+.macro drop_to_el1 target
+    mrs x1, CurrentEL
+    cmp x1, #(2 << 2)
+    b.ne \target
+    mov x1, #(1 << 31)          // HCR_EL2.RW: EL1 is AArch64
+    msr hcr_el2, x1
+    mov x1, #0x33ff             // CPTR_EL2: RES1 bits, no FP/SIMD traps
+    msr cptr_el2, x1
+    mov x1, #3                  // CNTHCTL_EL2: EL1 may use the physical timer
+    msr cnthctl_el2, x1
+    msr cntvoff_el2, xzr        // Virtual counter reads the physical count
+    mrs x1, id_aa64pfr0_el1
+    ubfx x1, x1, #24, #4        // GICv3 system register interface?
+    cbz x1, 1f
+    mov x1, #0xf                // ICC_SRE_EL2: SRE, and EL1 may use it too
+    msr icc_sre_el2, x1
+    isb
+1:
+    mov x1, #0x3c5              // EL1h with DAIF masked
+    msr spsr_el2, x1
+    adr x1, \target
+    msr elr_el2, x1
+    eret
+.endm
+
 .section .text.boot
 .global _start
 
 _start:
     // Disable interrupts
     msr daifset, #0xf
-    
-    // Set up stack pointer
-    ldr x1, =__stack_end
-    mov sp, x1
-    
+
+    // Keep the device tree pointer passed by the loader
+    mov x19, x0
+
+    drop_to_el1 in_el1
+
+in_el1:
+    // Let EL0 and EL1 use FP/SIMD. The kernel is soft-float and only
+    // touches these registers to save and restore user state.
+    mov x1, #(3 << 20)          // CPACR_EL1.FPEN
+    msr cpacr_el1, x1
+
     // Clear BSS section
-    ldr x1, =__bss_start
-    ldr x2, =__bss_end
+    adrp x1, __bss_start
+    add x1, x1, :lo12:__bss_start
+    adrp x2, __bss_end
+    add x2, x2, :lo12:__bss_end
 clear_bss:
     cmp x1, x2
     b.eq clear_bss_done
@@ -19,10 +72,122 @@ clear_bss:
     b clear_bss
 clear_bss_done:
 
+    // Boot map: the first 4 GiB of physical memory, used both as an
+    // identity map (TTBR0) and at KERNEL_VIRT_OFFSET (TTBR1). 0-1 GiB holds
+    // the devices on the QEMU virt machine, RAM starts at 1 GiB.
+    adrp x0, boot_l0
+    adrp x1, boot_l1
+    orr x2, x1, #TABLE_DESC
+    str x2, [x0]
+
+    ldr x2, =BLOCK_DEVICE
+    str x2, [x1]
+    ldr x2, =BLOCK_NORMAL
+    mov x3, #1
+map_ram:
+    orr x4, x2, x3, lsl #30
+    str x4, [x1, x3, lsl #3]
+    add x3, x3, #1
+    cmp x3, #4
+    b.ne map_ram
+
+    ldr x1, =MAIR_VALUE
+    msr mair_el1, x1
+
+    // Use the largest physical address size the CPU supports
+    ldr x1, =TCR_VALUE
+    mrs x2, id_aa64mmfr0_el1
+    bfi x1, x2, #32, #3
+    msr tcr_el1, x1
+
+    msr ttbr0_el1, x0
+    msr ttbr1_el1, x0
+    isb
+    tlbi vmalle1
+    dsb nsh
+
+    ldr x1, =SCTLR_VALUE
+    msr sctlr_el1, x1
+    isb
+
+    // Continue in the higher half
+    ldr x1, =higher_half
+    br x1
+
+higher_half:
+    // Set up stack pointer
+    ldr x1, =__stack_end
+    mov sp, x1
+
+    // This is CPU 0 (smp::cpu_id)
+    msr tpidr_el1, xzr
+
+    adrp x1, __boot_dtb
+    str x19, [x1, :lo12:__boot_dtb]
+
     // Jump to Rust code
     bl kernel_main
 
     // Halt if kernel_main returns
 halt:
     wfe
-    b halt
\ No newline at end of file
+    b halt
+
+// Secondary CPUs start here through PSCI CPU_ON with the MMU off and the
+// physical address of their SecondaryBoot (smp.rs) in x0:
+// [0] CPU index, [8] stack top, [16] kernel TTBR1, [24] TCR
+.global secondary_entry
+secondary_entry:
+    msr daifset, #0xf
+    mov x19, x0
+
+    drop_to_el1 secondary_el1
+
+secondary_el1:
+    mov x1, #(3 << 20)          // CPACR_EL1.FPEN
+    msr cpacr_el1, x1
+
+    ldr x1, =MAIR_VALUE
+    msr mair_el1, x1
+    ldr x1, [x19, #24]
+    msr tcr_el1, x1
+
+    // The boot map's identity half until the jump, the kernel's own
+    // tables from then on
+    adrp x0, boot_l0
+    msr ttbr0_el1, x0
+    ldr x1, [x19, #16]
+    msr ttbr1_el1, x1
+    isb
+    tlbi vmalle1
+    dsb nsh
+
+    ldr x1, =SCTLR_VALUE
+    msr sctlr_el1, x1
+    isb
+
+    ldr x1, =secondary_higher_half
+    br x1
+
+secondary_higher_half:
+    // x19 is still reachable through the identity map
+    ldr x1, [x19, #8]
+    mov sp, x1
+    ldr x1, [x19]
+    msr tpidr_el1, x1
+
+    bl secondary_main
+    b halt
+
+.section .data
+.balign 8
+.global __boot_dtb
+__boot_dtb:
+    .quad 0
+
+.section .bss.boot_tables, "aw", %nobits
+.balign 4096
+boot_l0:
+    .space 4096
+boot_l1:
+    .space 4096
@@ -0,0 +1,105 @@
+#![allow(dead_code)]
+
+// Kernel-side execution state of a task. Switching tasks only has to swap
+// the callee-saved registers, the stack pointer and the return address:
+// everything else is on the task's kernel stack, including the TrapFrame a
+// user process returns to EL0 through. The scheduler switches with the
+// process table locked, and every task releases the lock once it runs
+// (`schedule_tail`), new ones on their way to their first instruction.
+
+/// Registers preserved across `switch_to`. The layout is shared with
+/// `cpu_switch_to` below.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Context {
+    pub x19_x28: [u64; 10],
+    pub fp: u64,
+    pub lr: u64,
+    pub sp: u64,
+}
+
+extern "C" {
+    fn cpu_switch_to(prev: *mut Context, next: *const Context);
+    fn user_task_start();
+    fn kernel_thread_start();
+}
+
+impl Context {
+    /// Context whose first run restores the TrapFrame at `frame` and drops
+    /// to EL0 through it
+    pub fn user(frame: u64) -> Self {
+        Context {
+            lr: user_task_start as *const () as u64,
+            sp: frame,
+            ..Context::default()
+        }
+    }
+    
+    /// Context whose first run calls `entry` on the stack ending at
+    /// `stack_top` and exits the thread once it returns
+    pub fn kernel_thread(stack_top: u64, entry: fn()) -> Self {
+        let mut x19_x28 = [0; 10];
+        x19_x28[0] = entry as usize as u64;
+        Context {
+            x19_x28,
+            lr: kernel_thread_start as *const () as u64,
+            sp: stack_top,
+            ..Context::default()
+        }
+    }
+}
+
+/// Saves the running task's context to `prev` and resumes `next`. Returns
+/// once something switches back to `prev`.
+///
+/// # Safety
+///
+/// Both pointers must stay valid until the switch back, and `next` must
+/// have been saved by `switch_to` or built by a `Context` constructor.
+pub unsafe fn switch_to(prev: *mut Context, next: *const Context) {
+    cpu_switch_to(prev, next);
+}
+
+#[no_mangle]
+extern "C" fn kernel_thread_main(entry: usize) -> ! {
+    let entry: fn() = unsafe { core::mem::transmute(entry) };
+    entry();
+    crate::process::sys_exit(0)
+}
+
+core::arch::global_asm!(r#"
+.section .text
+.globl cpu_switch_to
+cpu_switch_to:
+    stp x19, x20, [x0, #0]
+    stp x21, x22, [x0, #16]
+    stp x23, x24, [x0, #32]
+    stp x25, x26, [x0, #48]
+    stp x27, x28, [x0, #64]
+    stp x29, x30, [x0, #80]
+    mov x9, sp
+    str x9, [x0, #96]
+    
+    ldp x19, x20, [x1, #0]
+    ldp x21, x22, [x1, #16]
+    ldp x23, x24, [x1, #32]
+    ldp x25, x26, [x1, #48]
+    ldp x27, x28, [x1, #64]
+    ldp x29, x30, [x1, #80]
+    ldr x9, [x1, #96]
+    mov sp, x9
+    ret
+
+// First code of a user task; sp points at its TrapFrame
+.globl user_task_start
+user_task_start:
+    bl schedule_tail
+    b exception_return
+
+// First code of a kernel thread; x19 holds its entry function
+.globl kernel_thread_start
+kernel_thread_start:
+    bl schedule_tail
+    mov x0, x19
+    bl kernel_thread_main
+"#);
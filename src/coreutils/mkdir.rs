@@ -28,6 +28,6 @@ fn create_directory(path: &str) -> Result<(), &'static str> {
             println!("Created directory: {}", path);
             Ok(())
         }
-        Err(e) => Err(e)
+        Err(e) => Err(e.into())
     }
 }
\ No newline at end of file
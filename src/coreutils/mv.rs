@@ -27,6 +27,6 @@ fn move_file(source: &str, dest: &str) -> Result<(), &'static str> {
             println!("'{}' -> '{}'", source, dest);
             Ok(())
         }
-        Err(e) => Err(e)
+        Err(e) => Err(e.into())
     }
 }
\ No newline at end of file
@@ -28,6 +28,6 @@ fn remove_file(path: &str) -> Result<(), &'static str> {
             println!("Removed: {}", path);
             Ok(())
         }
-        Err(e) => Err(e)
+        Err(e) => Err(e.into())
     }
 }
\ No newline at end of file
@@ -28,6 +28,6 @@ fn create_file(path: &str) -> Result<(), &'static str> {
             println!("Created file: {}", path);
             Ok(())
         }
-        Err(e) => Err(e)
+        Err(e) => Err(e.into())
     }
 }
\ No newline at end of file
@@ -234,7 +234,7 @@ impl CosmicShell {
             id: notification_id,
             title,
             body,
-            timestamp: timer::get_ticks(),
+            timestamp: crate::timer::uptime_ms(),
             urgency,
         };
 
@@ -483,15 +483,6 @@ impl CosmicCompositor {
     }
 }
 
-// Module for timer functions (simplified for this example)
-mod timer {
-    static mut TICK_COUNT: u64 = 0;
-    
-    pub fn get_ticks() -> u64 {
-        unsafe { TICK_COUNT }
-    }
-}
-
 // Public API functions
 pub fn cosmic_init(display_width: u32, display_height: u32) -> CosmicResult<()> {
     unsafe {
@@ -0,0 +1,213 @@
+#![allow(dead_code)]
+
+use core::fmt;
+use crate::graphics::GraphicsError;
+use crate::input::InputError;
+use crate::wayland::WaylandError;
+
+// errno values. A failed system call returns one negated.
+pub const EPERM: i64 = 1;
+pub const ENOENT: i64 = 2;
+pub const ESRCH: i64 = 3;
+pub const EINTR: i64 = 4;
+pub const EIO: i64 = 5;
+pub const E2BIG: i64 = 7;
+pub const ENOEXEC: i64 = 8;
+pub const EBADF: i64 = 9;
+pub const ECHILD: i64 = 10;
+pub const EAGAIN: i64 = 11;
+pub const ENOMEM: i64 = 12;
+pub const EACCES: i64 = 13;
+pub const EFAULT: i64 = 14;
+pub const EBUSY: i64 = 16;
+pub const EEXIST: i64 = 17;
+pub const ENODEV: i64 = 19;
+pub const EINVAL: i64 = 22;
+pub const EMFILE: i64 = 24;
+pub const ENOTTY: i64 = 25;
+pub const ESPIPE: i64 = 29;
+pub const EPIPE: i64 = 32;
+pub const ERANGE: i64 = 34;
+pub const ENAMETOOLONG: i64 = 36;
+pub const ENOSYS: i64 = 38;
+pub const EPROTO: i64 = 71;
+pub const EOPNOTSUPP: i64 = 95;
+
+/// Why a kernel operation failed. Each kind reports one errno to user
+/// space; a few that share one are kept apart because the kernel acts on
+/// the difference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelError {
+    /// EPERM
+    NotPermitted,
+    /// ENOENT: no such file
+    NotFound,
+    /// ESRCH: no such process or thread, or none running
+    NoSuchProcess,
+    /// EINTR: a sleep was cut short by a signal
+    Interrupted,
+    /// EIO: the hardware reported an error
+    Io,
+    /// E2BIG: exec arguments and environment over ARG_MAX
+    ArgumentListTooLong,
+    /// ENOEXEC: not an executable this kernel can load
+    NotExecutable,
+    /// EBADF
+    BadFileDescriptor,
+    /// ECHILD: nothing to wait for
+    NoChildren,
+    /// EAGAIN: the operation would have to sleep, with O_NONBLOCK or
+    /// when there is no process to put to sleep, or a limit was hit
+    WouldBlock,
+    /// ENOMEM: out of memory or address space, or a range is not mapped
+    OutOfMemory,
+    /// EACCES
+    PermissionDenied,
+    /// EFAULT: an address is not mapped or not accessible
+    BadAddress,
+    /// EFAULT: a stack fault landed in the guard below the stack
+    StackOverflow,
+    /// EBUSY: still in use
+    Busy,
+    /// EEXIST: a file or mapping is already there
+    AlreadyExists,
+    /// ENODEV: the device or file does not support the operation
+    NoDevice,
+    /// EINVAL
+    InvalidArgument,
+    /// EMFILE: RLIMIT_NOFILE descriptors are open
+    TooManyOpenFiles,
+    /// ENOTTY: an ioctl on something that is not a terminal
+    NotATerminal,
+    /// ESPIPE: lseek on a pipe or terminal
+    IllegalSeek,
+    /// EPIPE: a write to a pipe nobody reads
+    BrokenPipe,
+    /// ERANGE: a result does not fit the buffer
+    OutOfRange,
+    /// ENAMETOOLONG: a path runs past PATH_MAX
+    NameTooLong,
+    /// ENOSYS: no such system call
+    NotImplemented,
+    /// EPROTO
+    Protocol,
+    /// EOPNOTSUPP
+    NotSupported,
+}
+
+pub type KernelResult<T> = Result<T, KernelError>;
+
+impl KernelError {
+    pub const fn errno(self) -> i64 {
+        match self {
+            KernelError::NotPermitted => EPERM,
+            KernelError::NotFound => ENOENT,
+            KernelError::NoSuchProcess => ESRCH,
+            KernelError::Interrupted => EINTR,
+            KernelError::Io => EIO,
+            KernelError::ArgumentListTooLong => E2BIG,
+            KernelError::NotExecutable => ENOEXEC,
+            KernelError::BadFileDescriptor => EBADF,
+            KernelError::NoChildren => ECHILD,
+            KernelError::WouldBlock => EAGAIN,
+            KernelError::OutOfMemory => ENOMEM,
+            KernelError::PermissionDenied => EACCES,
+            KernelError::BadAddress | KernelError::StackOverflow => EFAULT,
+            KernelError::Busy => EBUSY,
+            KernelError::AlreadyExists => EEXIST,
+            KernelError::NoDevice => ENODEV,
+            KernelError::InvalidArgument => EINVAL,
+            KernelError::TooManyOpenFiles => EMFILE,
+            KernelError::NotATerminal => ENOTTY,
+            KernelError::IllegalSeek => ESPIPE,
+            KernelError::BrokenPipe => EPIPE,
+            KernelError::OutOfRange => ERANGE,
+            KernelError::NameTooLong => ENAMETOOLONG,
+            KernelError::NotImplemented => ENOSYS,
+            KernelError::Protocol => EPROTO,
+            KernelError::NotSupported => EOPNOTSUPP,
+        }
+    }
+    
+    /// Value a failed system call returns: the errno, negated
+    pub const fn to_syscall_return(self) -> u64 {
+        (-self.errno()) as u64
+    }
+    
+    pub const fn description(self) -> &'static str {
+        match self {
+            KernelError::NotPermitted => "Operation not permitted",
+            KernelError::NotFound => "No such file or directory",
+            KernelError::NoSuchProcess => "No such process",
+            KernelError::Interrupted => "Interrupted system call",
+            KernelError::Io => "Input/output error",
+            KernelError::ArgumentListTooLong => "Argument list too long",
+            KernelError::NotExecutable => "Exec format error",
+            KernelError::BadFileDescriptor => "Bad file descriptor",
+            KernelError::NoChildren => "No child processes",
+            KernelError::WouldBlock => "Resource temporarily unavailable",
+            KernelError::OutOfMemory => "Cannot allocate memory",
+            KernelError::PermissionDenied => "Permission denied",
+            KernelError::BadAddress => "Bad address",
+            KernelError::StackOverflow => "Stack overflow",
+            KernelError::Busy => "Device or resource busy",
+            KernelError::AlreadyExists => "File exists",
+            KernelError::NoDevice => "No such device",
+            KernelError::InvalidArgument => "Invalid argument",
+            KernelError::TooManyOpenFiles => "Too many open files",
+            KernelError::NotATerminal => "Inappropriate ioctl for device",
+            KernelError::IllegalSeek => "Illegal seek",
+            KernelError::BrokenPipe => "Broken pipe",
+            KernelError::OutOfRange => "Numerical result out of range",
+            KernelError::NameTooLong => "File name too long",
+            KernelError::NotImplemented => "Function not implemented",
+            KernelError::Protocol => "Protocol error",
+            KernelError::NotSupported => "Operation not supported",
+        }
+    }
+}
+
+impl fmt::Display for KernelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+// For the boot-time code (gic, smp, heap) that still reports plain strings
+impl From<KernelError> for &'static str {
+    fn from(err: KernelError) -> Self {
+        err.description()
+    }
+}
+
+impl From<WaylandError> for KernelError {
+    fn from(err: WaylandError) -> Self {
+        match err {
+            WaylandError::InvalidResource => KernelError::InvalidArgument,
+            WaylandError::ProtocolError => KernelError::Protocol,
+            WaylandError::OutOfMemory => KernelError::OutOfMemory,
+            WaylandError::InvalidSocket => KernelError::BadFileDescriptor,
+        }
+    }
+}
+
+impl From<GraphicsError> for KernelError {
+    fn from(err: GraphicsError) -> Self {
+        match err {
+            GraphicsError::InvalidFramebuffer => KernelError::NoDevice,
+            GraphicsError::UnsupportedFormat => KernelError::NotSupported,
+            GraphicsError::OutOfMemory => KernelError::OutOfMemory,
+            GraphicsError::InvalidDimensions => KernelError::InvalidArgument,
+        }
+    }
+}
+
+impl From<InputError> for KernelError {
+    fn from(err: InputError) -> Self {
+        match err {
+            InputError::BufferFull => KernelError::WouldBlock,
+            InputError::InvalidDevice => KernelError::NoDevice,
+            InputError::UnsupportedEvent => KernelError::NotSupported,
+        }
+    }
+}
@@ -0,0 +1,463 @@
+#![allow(dead_code)]
+
+use core::arch::asm;
+use crate::error::KernelError;
+use crate::process;
+use crate::signal::{self, SigInfo};
+use crate::syscall;
+use crate::println;
+
+/// Register state saved on every exception entry. The layout is shared
+/// with the vector code at the bottom of this file.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TrapFrame {
+    pub regs: [u64; 31], // x0-x30
+    pub sp_el0: u64,
+    pub elr: u64,
+    pub spsr: u64,
+    pub fp: FpState,
+}
+
+/// FP/SIMD registers. The kernel is built soft-float, so these only ever
+/// hold user state.
+#[repr(C, align(16))]
+#[derive(Debug, Clone, Copy)]
+pub struct FpState {
+    pub q: [u128; 32],
+    pub fpcr: u64,
+    pub fpsr: u64,
+}
+
+// Frame size and FP offset, hard-coded in the assembly below
+const TRAP_FRAME_SIZE: usize = 800;
+const _: () = assert!(core::mem::size_of::<TrapFrame>() == TRAP_FRAME_SIZE);
+const _: () = assert!(core::mem::offset_of!(TrapFrame, fp) == 272);
+
+// SPSR for entering EL0 in AArch64 with interrupts unmasked
+pub const SPSR_EL0T: u64 = 0;
+// Exception level and stack pointer the exception was taken from
+const SPSR_MODE_MASK: u64 = 0x1f;
+
+impl TrapFrame {
+    pub const fn zeroed() -> Self {
+        TrapFrame {
+            regs: [0; 31],
+            sp_el0: 0,
+            elr: 0,
+            spsr: 0,
+            fp: FpState {
+                q: [0; 32],
+                fpcr: 0,
+                fpsr: 0,
+            },
+        }
+    }
+    
+    /// Frame that starts user code at `entry` with stack pointer `sp`
+    pub const fn user(entry: u64, sp: u64) -> Self {
+        let mut frame = Self::zeroed();
+        frame.sp_el0 = sp;
+        frame.elr = entry;
+        frame.spsr = SPSR_EL0T;
+        frame
+    }
+}
+
+// ESR_EL1 exception classes
+const EC_SVC64: u64 = 0x15;
+const EC_INSTRUCTION_ABORT_LOWER: u64 = 0x20;
+const EC_INSTRUCTION_ABORT_SAME: u64 = 0x21;
+const EC_PC_ALIGNMENT: u64 = 0x22;
+const EC_DATA_ABORT_LOWER: u64 = 0x24;
+const EC_DATA_ABORT_SAME: u64 = 0x25;
+const EC_SP_ALIGNMENT: u64 = 0x26;
+
+// Addresses below this belong to the TTBR0 (user) half
+pub const USER_ADDRESS_LIMIT: u64 = 0x0001_0000_0000_0000;
+
+// Bit of SP the kernel vectors test for stack overflow, hard-coded in the
+// assembly below
+const KERNEL_STACK_SHIFT: u32 = 16;
+const _: () = assert!(crate::mmu::KERNEL_STACK_SIZE == 1 << KERNEL_STACK_SHIFT);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultAccess {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    Translation(u8),
+    AccessFlag(u8),
+    Permission(u8),
+    Alignment,
+    Other(u8),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FaultInfo {
+    pub address: u64,
+    pub pc: u64,
+    pub access: FaultAccess,
+    pub kind: FaultKind,
+    pub from_user: bool,
+}
+
+impl FaultInfo {
+    fn decode(esr: u64, far: u64, pc: u64) -> Self {
+        let ec = esr >> 26;
+        let status = (esr & 0x3f) as u8;
+        let access = match ec {
+            EC_INSTRUCTION_ABORT_LOWER | EC_INSTRUCTION_ABORT_SAME => FaultAccess::Execute,
+            _ if esr & (1 << 6) != 0 => FaultAccess::Write, // WnR
+            _ => FaultAccess::Read,
+        };
+        let kind = match status {
+            0b000100..=0b000111 => FaultKind::Translation(status & 0x3),
+            0b001001..=0b001011 => FaultKind::AccessFlag(status & 0x3),
+            0b001101..=0b001111 => FaultKind::Permission(status & 0x3),
+            0b100001 => FaultKind::Alignment,
+            _ => FaultKind::Other(status),
+        };
+        
+        FaultInfo {
+            address: far,
+            pc,
+            access,
+            kind,
+            from_user: ec == EC_INSTRUCTION_ABORT_LOWER || ec == EC_DATA_ABORT_LOWER,
+        }
+    }
+}
+
+pub fn init() {
+    extern "C" {
+        fn exception_vector_table();
+    }
+    
+    unsafe {
+        asm!(
+            "msr vbar_el1, {}",
+            "isb",
+            in(reg) exception_vector_table as *const () as u64
+        );
+    }
+}
+
+fn read_esr() -> u64 {
+    let esr: u64;
+    unsafe { asm!("mrs {}, esr_el1", out(reg) esr) };
+    esr
+}
+
+fn read_far() -> u64 {
+    let far: u64;
+    unsafe { asm!("mrs {}, far_el1", out(reg) far) };
+    far
+}
+
+#[no_mangle]
+extern "C" fn handle_lower_sync(frame: &mut TrapFrame) {
+    let esr = read_esr();
+    
+    match esr >> 26 {
+        EC_SVC64 => {
+            let regs = &frame.regs;
+            frame.regs[0] = syscall::syscall_handler(regs[8], regs[0], regs[1], regs[2], regs[3], regs[4], regs[5]);
+        }
+        EC_INSTRUCTION_ABORT_LOWER | EC_DATA_ABORT_LOWER => {
+            let fault = FaultInfo::decode(esr, read_far(), frame.elr);
+            if let Err(reason) = process::handle_page_fault(&fault) {
+                signal_fault(&fault, reason);
+            }
+        }
+        EC_PC_ALIGNMENT | EC_SP_ALIGNMENT => {
+            let mut fault = FaultInfo::decode(esr, read_far(), frame.elr);
+            fault.kind = FaultKind::Alignment;
+            signal_fault(&fault, KernelError::BadAddress);
+        }
+        ec => {
+            let pid = process::get_current_pid().unwrap_or(0);
+            println!("pid {}: unhandled exception class {:#x} at pc {:#x}", pid, ec, frame.elr);
+            process::force_signal(SigInfo::fault(signal::SIGILL, signal::ILL_ILLOPC, frame.elr));
+        }
+    }
+    
+    process::deliver_signals(frame);
+}
+
+#[no_mangle]
+extern "C" fn handle_current_sync(frame: &mut TrapFrame) {
+    let esr = read_esr();
+    let ec = esr >> 26;
+    
+    if ec == EC_DATA_ABORT_SAME || ec == EC_INSTRUCTION_ABORT_SAME {
+        let fault = FaultInfo::decode(esr, read_far(), frame.elr);
+        if crate::mmu::is_stack_guard(fault.address) {
+            panic!("Kernel stack overflow: fault at {:#x}, pc {:#x}", fault.address, fault.pc);
+        }
+        // The kernel touching user memory on behalf of the current process
+        // is demand paged like an access from EL0
+        if fault.address < USER_ADDRESS_LIMIT && process::handle_page_fault(&fault).is_ok() {
+            return;
+        }
+        // A copy to or from user memory gives up and reports how far it got
+        if let Some(fixup) = crate::uaccess::fixup(frame.elr) {
+            frame.elr = fixup;
+            return;
+        }
+        panic!(
+            "Kernel page fault: {:?} {:?} at {:#x}, pc {:#x}",
+            fault.kind, fault.access, fault.address, fault.pc
+        );
+    }
+    
+    panic!("Unhandled kernel exception: ESR {:#x}, FAR {:#x}, pc {:#x}", esr, read_far(), frame.elr);
+}
+
+#[no_mangle]
+extern "C" fn handle_irq(frame: &mut TrapFrame) {
+    crate::gic::handle_irq();
+    
+    // The kernel is not preemptible: only a process interrupted in user
+    // mode gives up the CPU to the tick
+    if frame.spsr & SPSR_MODE_MASK == SPSR_EL0T {
+        process::preempt();
+        process::deliver_signals(frame);
+    }
+}
+
+// Entered on the exception stack when a kernel exception found SP below
+// the base of its stack
+#[no_mangle]
+extern "C" fn handle_kernel_stack_overflow(sp: u64, far: u64, pc: u64) -> ! {
+    panic!("Kernel stack overflow: sp {:#x}, fault at {:#x}, pc {:#x}", sp, far, pc);
+}
+
+#[no_mangle]
+extern "C" fn handle_unexpected(frame: &mut TrapFrame, vector: u64) {
+    panic!(
+        "Unexpected exception vector {}: ESR {:#x}, FAR {:#x}, pc {:#x}",
+        vector,
+        read_esr(),
+        read_far(),
+        frame.elr
+    );
+}
+
+// Raises SIGSEGV, or SIGBUS for misalignment, for a fault the process
+// cannot recover from. It dies unless it handles the signal.
+fn signal_fault(fault: &FaultInfo, reason: KernelError) {
+    let pid = process::get_current_pid().unwrap_or(0);
+    let info = match fault.kind {
+        FaultKind::Alignment => SigInfo::fault(signal::SIGBUS, signal::BUS_ADRALN, fault.address),
+        FaultKind::Permission(_) => SigInfo::fault(signal::SIGSEGV, signal::SEGV_ACCERR, fault.address),
+        _ => SigInfo::fault(signal::SIGSEGV, signal::SEGV_MAPERR, fault.address),
+    };
+    if reason == KernelError::StackOverflow {
+        println!(
+            "stack overflow in pid {}: access at {:#x} (pc {:#x}) hit the stack guard",
+            pid, fault.address, fault.pc
+        );
+        process::force_signal(info);
+        return;
+    }
+    
+    let access = match fault.access {
+        FaultAccess::Read => "read",
+        FaultAccess::Write => "write",
+        FaultAccess::Execute => "execute",
+    };
+    let kind = if info.signal() == signal::SIGBUS { "Bus error" } else { "Segmentation fault" };
+    println!(
+        "{}: pid {} {} at {:#x} (pc {:#x}): {}",
+        kind, pid, access, fault.address, fault.pc, reason
+    );
+    process::force_signal(info);
+}
+
+// Exception vector table. Every entry saves a TrapFrame on the current
+// stack and calls into Rust with a pointer to it.
+core::arch::global_asm!(r#"
+.arch_extension fp
+.arch_extension simd
+
+.macro SAVE_FRAME
+    sub sp, sp, #800
+    SAVE_REGS
+.endm
+
+.macro SAVE_REGS
+    stp x0, x1, [sp, #0]
+    stp x2, x3, [sp, #16]
+    stp x4, x5, [sp, #32]
+    stp x6, x7, [sp, #48]
+    stp x8, x9, [sp, #64]
+    stp x10, x11, [sp, #80]
+    stp x12, x13, [sp, #96]
+    stp x14, x15, [sp, #112]
+    stp x16, x17, [sp, #128]
+    stp x18, x19, [sp, #144]
+    stp x20, x21, [sp, #160]
+    stp x22, x23, [sp, #176]
+    stp x24, x25, [sp, #192]
+    stp x26, x27, [sp, #208]
+    stp x28, x29, [sp, #224]
+    mrs x21, sp_el0
+    mrs x22, elr_el1
+    mrs x23, spsr_el1
+    stp x30, x21, [sp, #240]
+    stp x22, x23, [sp, #256]
+.endm
+
+.macro VECTOR handler
+    .align 7
+    SAVE_FRAME
+    bl save_fp_state
+    mov x0, sp
+    bl \handler
+    b exception_return
+.endm
+
+// Like VECTOR, but first checks that the kernel stack has not overflowed.
+// Kernel stacks are aligned to twice their size, so bit 16 of SP is set
+// only once SP has dropped below the stack base. x0 and SP are swapped
+// through arithmetic to test it without a scratch register.
+.macro KERNEL_VECTOR handler
+    .align 7
+    sub sp, sp, #800
+    add sp, sp, x0
+    sub x0, sp, x0
+    tbnz x0, #16, kernel_stack_overflow
+    sub x0, sp, x0
+    sub sp, sp, x0
+    SAVE_REGS
+    bl save_fp_state
+    mov x0, sp
+    bl \handler
+    b exception_return
+.endm
+
+.macro UNEXPECTED_VECTOR index
+    .align 7
+    SAVE_FRAME
+    bl save_fp_state
+    mov x0, sp
+    mov x1, #\index
+    bl handle_unexpected
+    b exception_return
+.endm
+
+.section .text
+.globl exception_vector_table
+.align 11
+exception_vector_table:
+    // Current EL with SP0
+    UNEXPECTED_VECTOR 0
+    UNEXPECTED_VECTOR 1
+    UNEXPECTED_VECTOR 2
+    UNEXPECTED_VECTOR 3
+    
+    // Current EL with SPx
+    KERNEL_VECTOR handle_current_sync
+    KERNEL_VECTOR handle_irq
+    UNEXPECTED_VECTOR 6
+    UNEXPECTED_VECTOR 7
+    
+    // Lower EL using AArch64
+    VECTOR handle_lower_sync
+    VECTOR handle_irq
+    UNEXPECTED_VECTOR 10
+    UNEXPECTED_VECTOR 11
+    
+    // Lower EL using AArch32
+    UNEXPECTED_VECTOR 12
+    UNEXPECTED_VECTOR 13
+    UNEXPECTED_VECTOR 14
+    UNEXPECTED_VECTOR 15
+
+// x0 holds the overflowed SP. The saved state is lost; report the fault
+// from the exception stack.
+kernel_stack_overflow:
+    ldr x1, =__exception_stack_end
+    mov sp, x1
+    mrs x1, far_el1
+    mrs x2, elr_el1
+    bl handle_kernel_stack_overflow
+
+// Called right after SAVE_REGS, so every general purpose register is free
+save_fp_state:
+    add x0, sp, #272
+    stp q0, q1, [x0, #0]
+    stp q2, q3, [x0, #32]
+    stp q4, q5, [x0, #64]
+    stp q6, q7, [x0, #96]
+    stp q8, q9, [x0, #128]
+    stp q10, q11, [x0, #160]
+    stp q12, q13, [x0, #192]
+    stp q14, q15, [x0, #224]
+    stp q16, q17, [x0, #256]
+    stp q18, q19, [x0, #288]
+    stp q20, q21, [x0, #320]
+    stp q22, q23, [x0, #352]
+    stp q24, q25, [x0, #384]
+    stp q26, q27, [x0, #416]
+    stp q28, q29, [x0, #448]
+    stp q30, q31, [x0, #480]
+    mrs x1, fpcr
+    mrs x2, fpsr
+    str x1, [x0, #512]
+    str x2, [x0, #520]
+    ret
+
+// Restores the TrapFrame at SP and returns from the exception. Also the
+// first code a new user process runs (see context.rs).
+.globl exception_return
+exception_return:
+    add x0, sp, #272
+    ldp q0, q1, [x0, #0]
+    ldp q2, q3, [x0, #32]
+    ldp q4, q5, [x0, #64]
+    ldp q6, q7, [x0, #96]
+    ldp q8, q9, [x0, #128]
+    ldp q10, q11, [x0, #160]
+    ldp q12, q13, [x0, #192]
+    ldp q14, q15, [x0, #224]
+    ldp q16, q17, [x0, #256]
+    ldp q18, q19, [x0, #288]
+    ldp q20, q21, [x0, #320]
+    ldp q22, q23, [x0, #352]
+    ldp q24, q25, [x0, #384]
+    ldp q26, q27, [x0, #416]
+    ldp q28, q29, [x0, #448]
+    ldp q30, q31, [x0, #480]
+    ldr x1, [x0, #512]
+    ldr x2, [x0, #520]
+    msr fpcr, x1
+    msr fpsr, x2
+    ldp x22, x23, [sp, #256]
+    ldp x30, x21, [sp, #240]
+    msr sp_el0, x21
+    msr elr_el1, x22
+    msr spsr_el1, x23
+    ldp x0, x1, [sp, #0]
+    ldp x2, x3, [sp, #16]
+    ldp x4, x5, [sp, #32]
+    ldp x6, x7, [sp, #48]
+    ldp x8, x9, [sp, #64]
+    ldp x10, x11, [sp, #80]
+    ldp x12, x13, [sp, #96]
+    ldp x14, x15, [sp, #112]
+    ldp x16, x17, [sp, #128]
+    ldp x18, x19, [sp, #144]
+    ldp x20, x21, [sp, #160]
+    ldp x22, x23, [sp, #176]
+    ldp x24, x25, [sp, #192]
+    ldp x26, x27, [sp, #208]
+    ldp x28, x29, [sp, #224]
+    add sp, sp, #800
+    eret
+"#);
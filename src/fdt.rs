@@ -0,0 +1,579 @@
+#![allow(dead_code)]
+
+use spin::Mutex;
+use crate::memory::{self, PhysAddr};
+
+// Flattened device tree (DTB) format, version 17
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+const FDT_HEADER_SIZE: usize = 40;
+
+// QEMU places the DTB at the start of RAM for bare-metal ELF kernels
+const FALLBACK_DTB_ADDRESS: u64 = 0x4000_0000;
+
+// The boot map only covers the first 4 GiB of physical memory
+const BOOT_MAP_LIMIT: u64 = 0x1_0000_0000;
+
+pub const MAX_PLATFORM_REGIONS: usize = 8;
+pub const MAX_PLATFORM_CPUS: usize = 8;
+
+extern "C" {
+    // Device tree address handed over in x0, saved by boot.s
+    static __boot_dtb: u64;
+}
+
+#[derive(Clone, Copy)]
+pub struct Fdt<'a> {
+    data: &'a [u8],
+    structure: &'a [u8],
+    strings: &'a [u8],
+    reservations: &'a [u8],
+}
+
+impl<'a> Fdt<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, &'static str> {
+        if data.len() < FDT_HEADER_SIZE {
+            return Err("Device tree too small");
+        }
+        if be32(data, 0) != Some(FDT_MAGIC) {
+            return Err("Bad device tree magic");
+        }
+
+        let header = |index: usize| be32(data, index * 4).unwrap_or(0) as usize;
+        let total_size = header(1);
+        let struct_offset = header(2);
+        let strings_offset = header(3);
+        let reservations_offset = header(4);
+        let strings_size = header(8);
+        let struct_size = header(9);
+
+        if total_size > data.len() {
+            return Err("Truncated device tree");
+        }
+        let data = &data[..total_size];
+        let structure = data
+            .get(struct_offset..struct_offset.checked_add(struct_size).ok_or("Bad structure block")?)
+            .ok_or("Bad structure block")?;
+        let strings = data
+            .get(strings_offset..strings_offset.checked_add(strings_size).ok_or("Bad strings block")?)
+            .ok_or("Bad strings block")?;
+        let reservations = data.get(reservations_offset..).ok_or("Bad reservation block")?;
+
+        Ok(Fdt { data, structure, strings, reservations })
+    }
+
+    /// Parses the blob at a physical address.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must be mapped through the linear map and, if it starts with
+    /// the device tree magic, the whole blob must be readable.
+    pub unsafe fn from_phys(addr: PhysAddr) -> Result<Fdt<'static>, &'static str> {
+        // Only what the boot map covers can be read
+        let in_boot_map = |size: usize| addr.as_u64().checked_add(size as u64).is_some_and(|end| end <= BOOT_MAP_LIMIT);
+        if !in_boot_map(FDT_HEADER_SIZE) {
+            return Err("Device tree outside the boot map");
+        }
+
+        let ptr = memory::phys_to_virt(addr).as_u64() as *const u8;
+        let header = core::slice::from_raw_parts(ptr, FDT_HEADER_SIZE);
+        if be32(header, 0) != Some(FDT_MAGIC) {
+            return Err("Bad device tree magic");
+        }
+        let total_size = be32(header, 4).unwrap_or(0) as usize;
+        if total_size < FDT_HEADER_SIZE {
+            return Err("Device tree too small");
+        }
+        if !in_boot_map(total_size) {
+            return Err("Device tree outside the boot map");
+        }
+        Fdt::new(core::slice::from_raw_parts(ptr, total_size))
+    }
+
+    pub fn total_size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn root(&self) -> Option<FdtNode<'a>> {
+        let mut offset = 0;
+        loop {
+            match be32(self.structure, offset)? {
+                FDT_NOP => offset += 4,
+                FDT_BEGIN_NODE => return self.node_at(offset, 2, 1),
+                _ => return None,
+            }
+        }
+    }
+
+    /// Finds a node by path, e.g. "/chosen" or "/soc/uart". Path components
+    /// without a unit address match any unit address.
+    pub fn find_node(&self, path: &str) -> Option<FdtNode<'a>> {
+        let mut node = self.root()?;
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            node = node.children().find(|child| child.name_matches(component))?;
+        }
+        Some(node)
+    }
+
+    /// Depth-first search for the first node compatible with `compatible`
+    pub fn find_compatible(&self, compatible: &str) -> Option<FdtNode<'a>> {
+        let mut found = None;
+        self.for_each_node(|node| {
+            if found.is_none() && node.is_compatible(compatible) {
+                found = Some(node);
+            }
+        });
+        found
+    }
+
+    pub fn for_each_node(&self, mut f: impl FnMut(FdtNode<'a>)) {
+        fn visit<'a>(node: FdtNode<'a>, f: &mut impl FnMut(FdtNode<'a>)) {
+            f(node);
+            for child in node.children() {
+                visit(child, f);
+            }
+        }
+
+        if let Some(root) = self.root() {
+            visit(root, &mut f);
+        }
+    }
+
+    /// Entries of the /memreserve/ block as (address, size) pairs
+    pub fn memory_reservations(&self) -> impl Iterator<Item = (u64, u64)> + 'a {
+        let block = self.reservations;
+        (0..)
+            .map(move |index| (be64(block, index * 16), be64(block, index * 16 + 8)))
+            .take_while(|entry| matches!(entry, (Some(_), Some(_))))
+            .map(|(addr, size)| (addr.unwrap_or(0), size.unwrap_or(0)))
+            .take_while(|&(addr, size)| addr != 0 || size != 0)
+    }
+
+    fn node_at(&self, offset: usize, address_cells: u32, size_cells: u32) -> Option<FdtNode<'a>> {
+        let name_start = offset + 4;
+        let name_len = self.structure.get(name_start..)?.iter().position(|&b| b == 0)?;
+        let name = core::str::from_utf8(&self.structure[name_start..name_start + name_len]).ok()?;
+
+        Some(FdtNode {
+            fdt: *self,
+            name,
+            body: align4(name_start + name_len + 1),
+            address_cells,
+            size_cells,
+        })
+    }
+
+    fn string_at(&self, offset: usize) -> Option<&'a str> {
+        let bytes = self.strings.get(offset..)?;
+        let len = bytes.iter().position(|&b| b == 0)?;
+        core::str::from_utf8(&bytes[..len]).ok()
+    }
+
+    // Offset just past the FDT_END_NODE matching the node starting at `offset`
+    fn skip_node(&self, offset: usize) -> Option<usize> {
+        let mut depth = 0;
+        let mut offset = offset;
+        loop {
+            match be32(self.structure, offset)? {
+                FDT_BEGIN_NODE => {
+                    depth += 1;
+                    let name_len = self.structure.get(offset + 4..)?.iter().position(|&b| b == 0)?;
+                    offset = align4(offset + 4 + name_len + 1);
+                }
+                FDT_END_NODE => {
+                    depth -= 1;
+                    offset += 4;
+                    if depth == 0 {
+                        return Some(offset);
+                    }
+                }
+                FDT_PROP => {
+                    let len = be32(self.structure, offset + 4)? as usize;
+                    offset = align4(offset + 12 + len);
+                }
+                FDT_NOP => offset += 4,
+                _ => return None,
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct FdtNode<'a> {
+    fdt: Fdt<'a>,
+    pub name: &'a str,
+    // Offset of the first token after the node name
+    body: usize,
+    // Cell sizes inherited from the parent, used to decode `reg`
+    address_cells: u32,
+    size_cells: u32,
+}
+
+impl<'a> FdtNode<'a> {
+    pub fn properties(&self) -> FdtProperties<'a> {
+        FdtProperties { fdt: self.fdt, offset: self.body }
+    }
+
+    pub fn property(&self, name: &str) -> Option<&'a [u8]> {
+        self.properties().find(|prop| prop.name == name).map(|prop| prop.value)
+    }
+
+    pub fn property_u32(&self, name: &str) -> Option<u32> {
+        be32(self.property(name)?, 0)
+    }
+
+    pub fn property_str(&self, name: &str) -> Option<&'a str> {
+        let value = self.property(name)?;
+        let len = value.iter().position(|&b| b == 0).unwrap_or(value.len());
+        core::str::from_utf8(&value[..len]).ok()
+    }
+
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.property("compatible")
+            .map(|value| value.split(|&b| b == 0).any(|entry| entry == compatible.as_bytes()))
+            .unwrap_or(false)
+    }
+
+    pub fn children(&self) -> FdtChildren<'a> {
+        let address_cells = self.property_u32("#address-cells").unwrap_or(2);
+        let size_cells = self.property_u32("#size-cells").unwrap_or(1);
+
+        // Children follow the node's properties
+        let mut properties = self.properties();
+        while properties.next().is_some() {}
+
+        FdtChildren {
+            fdt: self.fdt,
+            offset: properties.offset,
+            address_cells,
+            size_cells,
+        }
+    }
+
+    /// Decoded `reg` entries as (address, size) pairs
+    pub fn reg(&self) -> FdtReg<'a> {
+        FdtReg {
+            value: self.property("reg").unwrap_or(&[]),
+            offset: 0,
+            address_cells: self.address_cells as usize,
+            size_cells: self.size_cells as usize,
+        }
+    }
+
+    /// Compares the node name, ignoring the unit address unless `name` has one
+    pub fn name_matches(&self, name: &str) -> bool {
+        if name.contains('@') {
+            self.name == name
+        } else {
+            self.name.split('@').next() == Some(name)
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct FdtProperty<'a> {
+    pub name: &'a str,
+    pub value: &'a [u8],
+}
+
+pub struct FdtProperties<'a> {
+    fdt: Fdt<'a>,
+    offset: usize,
+}
+
+impl<'a> Iterator for FdtProperties<'a> {
+    type Item = FdtProperty<'a>;
+
+    fn next(&mut self) -> Option<FdtProperty<'a>> {
+        loop {
+            match be32(self.fdt.structure, self.offset)? {
+                FDT_NOP => self.offset += 4,
+                FDT_PROP => {
+                    let len = be32(self.fdt.structure, self.offset + 4)? as usize;
+                    let name_offset = be32(self.fdt.structure, self.offset + 8)? as usize;
+                    let value_start = self.offset + 12;
+                    let value = self.fdt.structure.get(value_start..value_start + len)?;
+                    self.offset = align4(value_start + len);
+
+                    return Some(FdtProperty {
+                        name: self.fdt.string_at(name_offset)?,
+                        value,
+                    });
+                }
+                _ => return None,
+            }
+        }
+    }
+}
+
+pub struct FdtChildren<'a> {
+    fdt: Fdt<'a>,
+    offset: usize,
+    address_cells: u32,
+    size_cells: u32,
+}
+
+impl<'a> Iterator for FdtChildren<'a> {
+    type Item = FdtNode<'a>;
+
+    fn next(&mut self) -> Option<FdtNode<'a>> {
+        loop {
+            match be32(self.fdt.structure, self.offset)? {
+                FDT_NOP => self.offset += 4,
+                FDT_BEGIN_NODE => {
+                    let node = self.fdt.node_at(self.offset, self.address_cells, self.size_cells)?;
+                    self.offset = self.fdt.skip_node(self.offset)?;
+                    return Some(node);
+                }
+                _ => return None,
+            }
+        }
+    }
+}
+
+pub struct FdtReg<'a> {
+    value: &'a [u8],
+    offset: usize,
+    address_cells: usize,
+    size_cells: usize,
+}
+
+impl Iterator for FdtReg<'_> {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<(u64, u64)> {
+        let address = read_cells(self.value, self.offset, self.address_cells)?;
+        self.offset += self.address_cells * 4;
+        let size = read_cells(self.value, self.offset, self.size_cells)?;
+        self.offset += self.size_cells * 4;
+        Some((address, size))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GicVersion {
+    V2,
+    V3,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct GicInfo {
+    pub version: GicVersion,
+    pub distributor: u64,
+    // GICC for v2, the first redistributor for v3
+    pub cpu_interface: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TimerInfo {
+    // PPI numbers for the secure, non-secure physical, virtual and
+    // hypervisor timers, in device tree order
+    pub ppis: [u32; 4],
+    pub frequency: Option<u32>,
+}
+
+/// How PSCI calls reach the firmware
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsciConduit {
+    Hvc,
+    Smc,
+}
+
+/// Hardware discovered from the device tree
+#[derive(Debug, Clone)]
+pub struct PlatformInfo {
+    pub dtb: Option<(u64, u64)>,
+    pub memory: heapless::Vec<(u64, u64), MAX_PLATFORM_REGIONS>,
+    pub reserved: heapless::Vec<(u64, u64), MAX_PLATFORM_REGIONS>,
+    pub uart: Option<u64>,
+    // Interrupt ID of the UART
+    pub uart_irq: Option<u32>,
+    pub gic: Option<GicInfo>,
+    pub timer: Option<TimerInfo>,
+    // MPIDR affinity of every CPU, in device tree order
+    pub cpus: heapless::Vec<u64, MAX_PLATFORM_CPUS>,
+    pub psci: Option<PsciConduit>,
+}
+
+impl PlatformInfo {
+    pub const fn empty() -> Self {
+        PlatformInfo {
+            dtb: None,
+            memory: heapless::Vec::new(),
+            reserved: heapless::Vec::new(),
+            uart: None,
+            uart_irq: None,
+            gic: None,
+            timer: None,
+            cpus: heapless::Vec::new(),
+            psci: None,
+        }
+    }
+}
+
+static PLATFORM: Mutex<PlatformInfo> = Mutex::new(PlatformInfo::empty());
+static BOOT_FDT: Mutex<Option<Fdt<'static>>> = Mutex::new(None);
+
+// Locates the boot device tree and records the hardware it describes.
+// Runs before the frame allocator exists, so nothing here may allocate.
+pub fn init() {
+    let fdt = match boot_fdt() {
+        Some(fdt) => fdt,
+        None => return,
+    };
+
+    let platform = discover(&fdt);
+    if let Some(uart) = platform.uart {
+        crate::uart::set_base(uart);
+    }
+
+    *PLATFORM.lock() = platform;
+    *BOOT_FDT.lock() = Some(fdt);
+}
+
+pub fn platform() -> PlatformInfo {
+    PLATFORM.lock().clone()
+}
+
+pub fn boot_tree() -> Option<Fdt<'static>> {
+    *BOOT_FDT.lock()
+}
+
+fn boot_fdt() -> Option<Fdt<'static>> {
+    let passed = unsafe { core::ptr::read_volatile(&__boot_dtb) };
+
+    [passed, FALLBACK_DTB_ADDRESS]
+        .into_iter()
+        .filter(|&addr| addr != 0 && addr % 8 == 0 && addr < BOOT_MAP_LIMIT)
+        .find_map(|addr| unsafe { Fdt::from_phys(PhysAddr::new(addr)) }.ok())
+}
+
+fn discover(fdt: &Fdt<'static>) -> PlatformInfo {
+    let mut platform = PlatformInfo::empty();
+    let dtb_addr = memory::virt_to_phys(memory::VirtAddr::new(fdt.data.as_ptr() as u64)).as_u64();
+    platform.dtb = Some((dtb_addr, fdt.total_size() as u64));
+
+    fdt.for_each_node(|node| {
+        if node.property_str("device_type") == Some("memory") {
+            for (addr, size) in node.reg().filter(|&(_, size)| size > 0) {
+                let _ = platform.memory.push((addr, size));
+            }
+        }
+    });
+
+    let _ = platform.reserved.push((dtb_addr, fdt.total_size() as u64));
+    for reservation in fdt.memory_reservations() {
+        let _ = platform.reserved.push(reservation);
+    }
+    if let Some(reserved_memory) = fdt.find_node("/reserved-memory") {
+        for child in reserved_memory.children() {
+            for region in child.reg() {
+                let _ = platform.reserved.push(region);
+            }
+        }
+    }
+
+    let uart = stdout_node(fdt)
+        .filter(|node| node.is_compatible("arm,pl011"))
+        .or_else(|| fdt.find_compatible("arm,pl011"));
+    platform.uart = uart.and_then(|node| node.reg().next()).map(|(addr, _)| addr);
+    platform.uart_irq = uart.and_then(|node| interrupt_id(node.property("interrupts")?, 0));
+
+    platform.gic = discover_gic(fdt);
+    platform.timer = fdt.find_compatible("arm,armv8-timer").map(|node| {
+        let mut ppis = [0; 4];
+        // Each interrupt is <type number flags>
+        if let Some(interrupts) = node.property("interrupts") {
+            for (index, ppi) in ppis.iter_mut().enumerate() {
+                *ppi = be32(interrupts, index * 12 + 4).unwrap_or(0);
+            }
+        }
+        TimerInfo {
+            ppis,
+            frequency: node.property_u32("clock-frequency"),
+        }
+    });
+
+    if let Some(cpus) = fdt.find_node("/cpus") {
+        for cpu in cpus.children().filter(|node| node.property_str("device_type") == Some("cpu")) {
+            if let Some((mpidr, _)) = cpu.reg().next() {
+                let _ = platform.cpus.push(mpidr);
+            }
+        }
+    }
+    platform.psci = ["arm,psci-1.0", "arm,psci-0.2", "arm,psci"]
+        .iter()
+        .find_map(|compatible| fdt.find_compatible(compatible))
+        .and_then(|node| match node.property_str("method")? {
+            "hvc" => Some(PsciConduit::Hvc),
+            "smc" => Some(PsciConduit::Smc),
+            _ => None,
+        });
+
+    platform
+}
+
+fn stdout_node(fdt: &Fdt<'static>) -> Option<FdtNode<'static>> {
+    let path = fdt.find_node("/chosen")?.property_str("stdout-path")?;
+    // Strip options such as ":115200n8"
+    let path = path.split(':').next()?;
+    if path.starts_with('/') {
+        fdt.find_node(path)
+    } else {
+        let alias = fdt.find_node("/aliases")?.property_str(path)?;
+        fdt.find_node(alias)
+    }
+}
+
+fn discover_gic(fdt: &Fdt<'static>) -> Option<GicInfo> {
+    let (version, node) = if let Some(node) = fdt.find_compatible("arm,gic-v3") {
+        (GicVersion::V3, node)
+    } else {
+        let node = ["arm,cortex-a15-gic", "arm,gic-400", "arm,cortex-a9-gic"]
+            .iter()
+            .find_map(|compatible| fdt.find_compatible(compatible))?;
+        (GicVersion::V2, node)
+    };
+
+    let mut reg = node.reg();
+    let (distributor, _) = reg.next()?;
+    let (cpu_interface, _) = reg.next()?;
+    Some(GicInfo { version, distributor, cpu_interface })
+}
+
+// Interrupt ID of the `index`th <type number flags> GIC interrupt
+// specifier in `interrupts`
+fn interrupt_id(interrupts: &[u8], index: usize) -> Option<u32> {
+    let number = be32(interrupts, index * 12 + 4)?;
+    match be32(interrupts, index * 12)? {
+        0 => Some(32 + number), // SPI
+        1 => Some(16 + number), // PPI
+        _ => None,
+    }
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+fn be32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn be64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(((be32(data, offset)? as u64) << 32) | be32(data, offset + 4)? as u64)
+}
+
+fn read_cells(data: &[u8], offset: usize, cells: usize) -> Option<u64> {
+    let mut value = 0u64;
+    for cell in 0..cells {
+        value = (value << 32) | be32(data, offset + cell * 4)? as u64;
+    }
+    Some(value)
+}
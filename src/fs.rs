@@ -4,6 +4,9 @@ use alloc::string::String;
 use alloc::string::ToString;
 use spin::Mutex;
 use lazy_static::lazy_static;
+use crate::error::KernelError;
+use crate::memory::{self, PhysFrame, PAGE_SIZE};
+use crate::wait_queue::wait_event;
 
 #[derive(Debug, Clone)]
 pub struct FileDescriptor {
@@ -48,49 +51,175 @@ bitflags::bitflags! {
     }
 }
 
-pub struct FileSystem {
+/// Close-on-exec, which is accepted and ignored: exec keeps every descriptor
+pub const O_CLOEXEC: i32 = 0o2000000;
+// Also accepted by open and ignored: O_NOCTTY, O_DIRECTORY, O_NOFOLLOW and
+// O_LARGEFILE, with their AArch64 values. There is no controlling
+// terminal, directory or link to tell apart.
+const IGNORED_OPEN_FLAGS: i32 = 0o400 | 0o40000 | 0o100000 | 0o400000 | O_CLOEXEC;
+
+// fcntl commands
+pub const F_DUPFD: u32 = 0;
+pub const F_GETFD: u32 = 1;
+pub const F_SETFD: u32 = 2;
+pub const F_GETFL: u32 = 3;
+pub const F_SETFL: u32 = 4;
+pub const F_DUPFD_CLOEXEC: u32 = 1030;
+
+// lseek origins
+pub const SEEK_SET: u32 = 0;
+pub const SEEK_CUR: u32 = 1;
+pub const SEEK_END: u32 = 2;
+
+// File type bits of st_mode
+pub const S_IFIFO: u32 = 0o010000;
+pub const S_IFCHR: u32 = 0o020000;
+pub const S_IFREG: u32 = 0o100000;
+
+/// File status as fstat reports it, laid out like the AArch64 `struct stat`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stat {
+    pub dev: u64,
+    pub ino: u64,
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u64,
+    _pad1: u64,
+    pub size: i64,
+    pub blksize: i32,
+    _pad2: i32,
+    pub blocks: i64,
+    // atime, mtime and ctime as seconds and nanoseconds, which are not kept
+    pub times: [i64; 6],
+    _unused: [u32; 2],
+}
+
+impl FileType {
+    // Everything is owned by root; there are no permission checks
+    fn status(&self, size: usize) -> Stat {
+        let mode = match self {
+            FileType::Regular(_) => S_IFREG | 0o644,
+            FileType::Pipe(_) => S_IFIFO | 0o600,
+            FileType::Device(_) => S_IFCHR | 0o666,
+        };
+        Stat {
+            mode,
+            nlink: 1,
+            size: size as i64,
+            blksize: PAGE_SIZE as i32,
+            blocks: size.div_ceil(512) as i64,
+            ..Stat::default()
+        }
+    }
+}
+
+// Open file descriptors of one process
+#[derive(Debug, Clone)]
+pub struct FdTable {
     open_files: BTreeMap<i32, FileDescriptor>,
-    next_fd: i32,
-    files: BTreeMap<String, Vec<u8>>, // Simple in-memory file system
+    // RLIMIT_NOFILE of the owning process; descriptors stay below it
+    max_fds: u64,
 }
 
-impl FileSystem {
-    pub fn new() -> Self {
-        let mut fs = FileSystem {
+impl FdTable {
+    fn new() -> Self {
+        let mut table = FdTable {
             open_files: BTreeMap::new(),
-            next_fd: 3, // Start after stdin, stdout, stderr
-            files: BTreeMap::new(),
+            max_fds: crate::process::DEFAULT_NOFILE,
         };
         
         // Set up standard file descriptors
-        fs.open_files.insert(0, FileDescriptor {
+        table.open_files.insert(0, FileDescriptor {
             fd: 0,
             file_type: FileType::Device(DeviceType::Stdin),
             offset: 0,
             flags: OpenFlags::O_RDONLY,
         });
         
-        fs.open_files.insert(1, FileDescriptor {
+        table.open_files.insert(1, FileDescriptor {
             fd: 1,
             file_type: FileType::Device(DeviceType::Stdout),
             offset: 0,
             flags: OpenFlags::O_WRONLY,
         });
         
-        fs.open_files.insert(2, FileDescriptor {
+        table.open_files.insert(2, FileDescriptor {
             fd: 2,
             file_type: FileType::Device(DeviceType::Stderr),
             offset: 0,
             flags: OpenFlags::O_WRONLY,
         });
         
-        fs
+        table
+    }
+    
+    // Lowest unused descriptor, as POSIX requires
+    fn allocate_fd(&self) -> Result<i32, KernelError> {
+        self.allocate_fd_from(0)
+    }
+    
+    // Lowest unused descriptor no lower than `min`
+    fn allocate_fd_from(&self, min: i32) -> Result<i32, KernelError> {
+        (min.max(0)..self.max_fds.min(i32::MAX as u64) as i32)
+            .find(|fd| !self.open_files.contains_key(fd))
+            .ok_or(KernelError::TooManyOpenFiles)
+    }
+    
+    // Pipe ends held by this table, for keeping pipe reader/writer counts right
+    fn pipe_ends(&self) -> Vec<PipeEnd> {
+        self.open_files.values()
+            .filter_map(|descriptor| match &descriptor.file_type {
+                FileType::Pipe(end) => Some(end.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+// Descriptor tables are per process, keyed by pid. Pid 0 is used for calls
+// made by the kernel itself when no process is running.
+pub struct FileSystem {
+    fd_tables: BTreeMap<u32, FdTable>,
+    files: BTreeMap<String, Vec<u8>>, // Simple in-memory file system
+    // Frames behind MAP_SHARED file mappings, keyed by path and file offset.
+    // The cache holds one reference to each frame.
+    page_cache: BTreeMap<(String, u64), PhysFrame>,
+}
+
+impl FileSystem {
+    pub fn new() -> Self {
+        FileSystem {
+            fd_tables: BTreeMap::new(),
+            files: BTreeMap::new(),
+            page_cache: BTreeMap::new(),
+        }
+    }
+    
+    fn table(&mut self, pid: u32) -> &mut FdTable {
+        self.fd_tables.entry(pid).or_insert_with(FdTable::new)
+    }
+    
+    // Gives `child` a copy of `parent`'s descriptors, as fork does. Returns
+    // the pipe ends the child now holds so their counts can be raised.
+    pub fn clone_fd_table(&mut self, parent: u32, child: u32) -> Vec<PipeEnd> {
+        let table = self.table(parent).clone();
+        let pipes = table.pipe_ends();
+        self.fd_tables.insert(child, table);
+        pipes
+    }
+    
+    // Drops `pid`'s descriptor table. Returns the pipe ends it held so
+    // their counts can be lowered.
+    pub fn remove_fd_table(&mut self, pid: u32) -> Vec<PipeEnd> {
+        self.fd_tables.remove(&pid).map(|table| table.pipe_ends()).unwrap_or_default()
     }
     
-    pub fn open(&mut self, path: &str, flags: i32, _mode: u32) -> Result<i32, &'static str> {
-        let open_flags = OpenFlags::from_bits(flags).ok_or("Invalid flags")?;
-        let fd = self.next_fd;
-        self.next_fd += 1;
+    pub fn open(&mut self, pid: u32, path: &str, flags: i32, _mode: u32) -> Result<i32, KernelError> {
+        let open_flags = OpenFlags::from_bits(flags & !IGNORED_OPEN_FLAGS).ok_or(KernelError::InvalidArgument)?;
+        let fd = self.table(pid).allocate_fd()?;
         
         // Handle special device files
         let file_type = match path {
@@ -102,7 +231,13 @@ impl FileSystem {
                 }
                 
                 if !self.files.contains_key(path) && !open_flags.contains(OpenFlags::O_CREAT) {
-                    return Err("File not found");
+                    return Err(KernelError::NotFound);
+                }
+                
+                if open_flags.contains(OpenFlags::O_TRUNC) && open_flags.bits() & 3 != OpenFlags::O_RDONLY.bits() {
+                    if let Some(data) = self.files.get_mut(path) {
+                        data.clear();
+                    }
                 }
                 
                 FileType::Regular(path.into())
@@ -116,22 +251,22 @@ impl FileSystem {
             flags: open_flags,
         };
         
-        self.open_files.insert(fd, descriptor);
+        self.table(pid).open_files.insert(fd, descriptor);
         Ok(fd)
     }
     
-    pub fn close(&mut self, fd: i32) -> Result<(), &'static str> {
-        self.open_files.remove(&fd).ok_or("Invalid file descriptor")?;
-        Ok(())
+    pub fn close(&mut self, pid: u32, fd: i32) -> Result<FileDescriptor, KernelError> {
+        self.table(pid).open_files.remove(&fd).ok_or(KernelError::BadFileDescriptor)
     }
     
-    pub fn read(&mut self, fd: i32, buf: &mut [u8]) -> Result<usize, &'static str> {
-        let descriptor = self.open_files.get_mut(&fd).ok_or("Invalid file descriptor")?;
+    pub fn read(&mut self, pid: u32, fd: i32, buf: &mut [u8]) -> Result<usize, KernelError> {
+        let table = self.fd_tables.entry(pid).or_insert_with(FdTable::new);
+        let descriptor = table.open_files.get_mut(&fd).ok_or(KernelError::BadFileDescriptor)?;
         
         match &descriptor.file_type {
             FileType::Regular(path) => {
-                let file_data = self.files.get(path).ok_or("File not found")?;
-                let bytes_to_read = core::cmp::min(buf.len(), file_data.len() - descriptor.offset);
+                let file_data = self.files.get(path).ok_or(KernelError::NotFound)?;
+                let bytes_to_read = core::cmp::min(buf.len(), file_data.len().saturating_sub(descriptor.offset));
                 
                 if bytes_to_read == 0 {
                     return Ok(0); // EOF
@@ -143,27 +278,23 @@ impl FileSystem {
                 descriptor.offset += bytes_to_read;
                 Ok(bytes_to_read)
             }
-            FileType::Device(DeviceType::Stdin) => {
-                // For now, return empty read for stdin
-                Ok(0)
-            }
             FileType::Device(DeviceType::Null) => {
                 Ok(0) // /dev/null always returns EOF on read
             }
-            FileType::Pipe(PipeEnd::Read(pipe_id)) => {
-                crate::ipc::read_pipe(*pipe_id, buf)
-            }
-            _ => Err("Cannot read from this file descriptor"),
+            // Stdin and pipes can sleep, so the wrappers below read them
+            // without the file system locked
+            _ => Err(KernelError::BadFileDescriptor),
         }
     }
     
-    pub fn write(&mut self, fd: i32, buf: &[u8]) -> Result<usize, &'static str> {
-        let descriptor = self.open_files.get_mut(&fd).ok_or("Invalid file descriptor")?;
+    pub fn write(&mut self, pid: u32, fd: i32, buf: &[u8]) -> Result<usize, KernelError> {
+        let table = self.fd_tables.entry(pid).or_insert_with(FdTable::new);
+        let descriptor = table.open_files.get_mut(&fd).ok_or(KernelError::BadFileDescriptor)?;
         
         match &descriptor.file_type {
             FileType::Regular(path) => {
                 let path_clone = path.clone();
-                let file_data = self.files.get_mut(&path_clone).ok_or("File not found")?;
+                let file_data = self.files.get_mut(&path_clone).ok_or(KernelError::NotFound)?;
                 
                 if descriptor.flags.contains(OpenFlags::O_APPEND) {
                     file_data.extend_from_slice(buf);
@@ -189,65 +320,199 @@ impl FileSystem {
             FileType::Device(DeviceType::Null) => {
                 Ok(buf.len()) // /dev/null accepts all writes
             }
-            FileType::Pipe(PipeEnd::Write(pipe_id)) => {
-                crate::ipc::write_pipe(*pipe_id, buf)
-            }
-            _ => Err("Cannot write to this file descriptor"),
+            _ => Err(KernelError::BadFileDescriptor),
         }
     }
     
-    pub fn duplicate_fd(&mut self, fd: i32) -> Result<i32, &'static str> {
-        let descriptor = self.open_files.get(&fd).ok_or("Invalid file descriptor")?.clone();
-        let new_fd = self.next_fd;
-        self.next_fd += 1;
+    pub fn duplicate_fd(&mut self, pid: u32, fd: i32, min: i32) -> Result<i32, KernelError> {
+        let table = self.table(pid);
+        let descriptor = table.open_files.get(&fd).ok_or(KernelError::BadFileDescriptor)?.clone();
+        let new_fd = table.allocate_fd_from(min)?;
         
         let mut new_descriptor = descriptor;
         new_descriptor.fd = new_fd;
         
-        self.open_files.insert(new_fd, new_descriptor);
+        table.open_files.insert(new_fd, new_descriptor);
         Ok(new_fd)
     }
     
-    pub fn duplicate_fd_to(&mut self, oldfd: i32, newfd: i32) -> Result<i32, &'static str> {
-        let descriptor = self.open_files.get(&oldfd).ok_or("Invalid file descriptor")?.clone();
+    pub fn duplicate_fd_to(&mut self, pid: u32, oldfd: i32, newfd: i32) -> Result<i32, KernelError> {
+        let table = self.table(pid);
+        let descriptor = table.open_files.get(&oldfd).ok_or(KernelError::BadFileDescriptor)?.clone();
+        if newfd < 0 || newfd as u64 >= table.max_fds {
+            return Err(KernelError::BadFileDescriptor);
+        }
         
         // Close newfd if it's already open
-        self.open_files.remove(&newfd);
+        table.open_files.remove(&newfd);
         
         let mut new_descriptor = descriptor;
         new_descriptor.fd = newfd;
         
-        self.open_files.insert(newfd, new_descriptor);
+        table.open_files.insert(newfd, new_descriptor);
         Ok(newfd)
     }
     
-    pub fn create_pipe_fds(&mut self, pipe_id: u32) -> Result<(i32, i32), &'static str> {
-        let read_fd = self.next_fd;
-        self.next_fd += 1;
-        let write_fd = self.next_fd;
-        self.next_fd += 1;
+    pub fn descriptor(&mut self, pid: u32, fd: i32) -> Result<FileDescriptor, KernelError> {
+        self.table(pid).open_files.get(&fd).cloned().ok_or(KernelError::BadFileDescriptor)
+    }
+    
+    pub fn status_flags(&mut self, pid: u32, fd: i32) -> Result<i32, KernelError> {
+        Ok(self.descriptor(pid, fd)?.flags.bits())
+    }
+    
+    // Only O_APPEND and O_NONBLOCK can change after open, as on Linux;
+    // other bits are ignored
+    pub fn set_status_flags(&mut self, pid: u32, fd: i32, flags: i32) -> Result<(), KernelError> {
+        let descriptor = self.table(pid).open_files.get_mut(&fd).ok_or(KernelError::BadFileDescriptor)?;
+        let changeable = OpenFlags::O_APPEND | OpenFlags::O_NONBLOCK;
+        let flags = OpenFlags::from_bits_truncate(flags) & changeable.clone();
+        descriptor.flags = (descriptor.flags.clone() - changeable) | flags;
+        Ok(())
+    }
+    
+    pub fn set_fd_limit(&mut self, pid: u32, limit: u64) {
+        self.table(pid).max_fds = limit;
+    }
+    
+    // Moves the offset of `fd` to `offset` past the start, the current
+    // offset or the end, for SEEK_SET, SEEK_CUR and SEEK_END. Seeking past
+    // the end is allowed; a write there fills the gap with zeros.
+    pub fn seek(&mut self, pid: u32, fd: i32, offset: i64, whence: u32) -> Result<u64, KernelError> {
+        let table = self.fd_tables.entry(pid).or_insert_with(FdTable::new);
+        let descriptor = table.open_files.get_mut(&fd).ok_or(KernelError::BadFileDescriptor)?;
+        let size = match &descriptor.file_type {
+            FileType::Regular(path) => self.files.get(path).ok_or(KernelError::NotFound)?.len(),
+            FileType::Device(DeviceType::Null) => return Ok(0),
+            _ => return Err(KernelError::IllegalSeek),
+        };
+        
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => descriptor.offset,
+            SEEK_END => size,
+            _ => return Err(KernelError::InvalidArgument),
+        };
+        let position = (base as i64).checked_add(offset).filter(|&position| position >= 0).ok_or(KernelError::InvalidArgument)?;
+        descriptor.offset = position as usize;
+        Ok(position as u64)
+    }
+    
+    pub fn stat(&mut self, pid: u32, fd: i32) -> Result<Stat, KernelError> {
+        let descriptor = self.descriptor(pid, fd)?;
+        let size = match &descriptor.file_type {
+            FileType::Regular(path) => self.files.get(path).ok_or(KernelError::NotFound)?.len(),
+            _ => 0,
+        };
+        Ok(descriptor.file_type.status(size))
+    }
+    
+    pub fn stat_path(&self, path: &str) -> Result<Stat, KernelError> {
+        match path {
+            "/dev/null" => Ok(FileType::Device(DeviceType::Null).status(0)),
+            _ => {
+                let size = self.files.get(path).ok_or(KernelError::NotFound)?.len();
+                Ok(FileType::Regular(path.into()).status(size))
+            }
+        }
+    }
+    
+    // Path of the regular file behind `fd`, checked for mapping it readable
+    // and, for shared writable mappings, writable
+    pub fn mappable_file(&mut self, pid: u32, fd: i32, writable: bool) -> Result<String, KernelError> {
+        let descriptor = self.table(pid).open_files.get(&fd).ok_or(KernelError::BadFileDescriptor)?;
+        let FileType::Regular(path) = &descriptor.file_type else {
+            return Err(KernelError::NoDevice);
+        };
+        
+        let access = descriptor.flags.bits() & 3;
+        if access == OpenFlags::O_WRONLY.bits() || (writable && access != OpenFlags::O_RDWR.bits()) {
+            return Err(KernelError::PermissionDenied);
+        }
+        Ok(path.clone())
+    }
+    
+    pub fn read_at(&self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, KernelError> {
+        let data = self.files.get(path).ok_or(KernelError::NotFound)?;
+        let start = (offset as usize).min(data.len());
+        let count = buf.len().min(data.len() - start);
+        buf[..count].copy_from_slice(&data[start..start + count]);
+        Ok(count)
+    }
+    
+    // Returns the cached frame for a page of `path`, loading it on first use.
+    // The caller gets its own reference to the frame.
+    pub fn shared_page(&mut self, path: &str, offset: u64) -> Result<PhysFrame, KernelError> {
+        let key = (String::from(path), offset);
+        if let Some(&frame) = self.page_cache.get(&key) {
+            memory::share_frame(frame)?;
+            return Ok(frame);
+        }
+        
+        let frame = memory::allocate_frame().ok_or(KernelError::OutOfMemory)?;
+        let contents = unsafe { page_bytes(frame) };
+        contents.fill(0);
+        if let Err(e) = self.read_at(path, offset, contents) {
+            memory::deallocate_frame(frame);
+            return Err(e);
+        }
+        memory::share_frame(frame)?;
+        self.page_cache.insert(key, frame);
+        Ok(frame)
+    }
+    
+    // Drops a mapping's reference to a cached page after writing its contents
+    // back. Writeback never extends the file, as with MAP_SHARED on Linux.
+    pub fn release_shared_page(&mut self, path: &str, offset: u64, frame: PhysFrame) {
+        if let Some(data) = self.files.get_mut(path) {
+            let start = (offset as usize).min(data.len());
+            let count = PAGE_SIZE.min(data.len() - start);
+            let contents = unsafe { page_bytes(frame) };
+            data[start..start + count].copy_from_slice(&contents[..count]);
+        }
+        
+        memory::deallocate_frame(frame);
+        if memory::frame_ref_count(frame) == 1 {
+            if let Some(frame) = self.page_cache.remove(&(String::from(path), offset)) {
+                memory::deallocate_frame(frame);
+            }
+        }
+    }
+    
+    // `flags` are added to both ends' access modes
+    pub fn create_pipe_fds(&mut self, pid: u32, pipe_id: u32, flags: OpenFlags) -> Result<(i32, i32), KernelError> {
+        let table = self.table(pid);
+        let read_fd = table.allocate_fd()?;
+        let write_fd = (read_fd + 1..table.max_fds.min(i32::MAX as u64) as i32)
+            .find(|fd| !table.open_files.contains_key(fd))
+            .ok_or(KernelError::TooManyOpenFiles)?;
         
         let read_descriptor = FileDescriptor {
             fd: read_fd,
             file_type: FileType::Pipe(PipeEnd::Read(pipe_id)),
             offset: 0,
-            flags: OpenFlags::O_RDONLY,
+            flags: OpenFlags::O_RDONLY | flags.clone(),
         };
         
         let write_descriptor = FileDescriptor {
             fd: write_fd,
             file_type: FileType::Pipe(PipeEnd::Write(pipe_id)),
             offset: 0,
-            flags: OpenFlags::O_WRONLY,
+            flags: OpenFlags::O_WRONLY | flags,
         };
         
-        self.open_files.insert(read_fd, read_descriptor);
-        self.open_files.insert(write_fd, write_descriptor);
+        table.open_files.insert(read_fd, read_descriptor);
+        table.open_files.insert(write_fd, write_descriptor);
         
         Ok((read_fd, write_fd))
     }
 }
 
+// # Safety: `frame` must be a frame the caller holds a reference to
+unsafe fn page_bytes(frame: PhysFrame) -> &'static mut [u8] {
+    core::slice::from_raw_parts_mut(memory::phys_to_virt(frame.start_address()).as_u64() as *mut u8, PAGE_SIZE)
+}
+
 lazy_static! {
     static ref FILE_SYSTEM: Mutex<FileSystem> = Mutex::new(FileSystem::new());
 }
@@ -256,51 +521,219 @@ pub fn init() {
     // File system is initialized statically
 }
 
-pub fn open(path: &str, flags: i32, mode: u32) -> Result<i32, &'static str> {
-    FILE_SYSTEM.lock().open(path, flags, mode)
+// The wrappers below act on the descriptor table of the current thread,
+// which is keyed by the pid of the process that created it and shared by
+// threads cloned with CLONE_FILES. The key is looked up before FILE_SYSTEM
+// is locked so the process table lock is never taken while holding it.
+fn current_pid() -> u32 {
+    crate::process::current_files().unwrap_or(0)
 }
 
-pub fn close(fd: i32) -> Result<(), &'static str> {
-    FILE_SYSTEM.lock().close(fd)
+pub fn open(path: &str, flags: i32, mode: u32) -> Result<i32, KernelError> {
+    let pid = current_pid();
+    FILE_SYSTEM.lock().open(pid, path, flags, mode)
 }
 
-pub fn read(fd: i32, buf: &mut [u8]) -> Result<usize, &'static str> {
-    FILE_SYSTEM.lock().read(fd, buf)
+pub fn close(fd: i32) -> Result<(), KernelError> {
+    let pid = current_pid();
+    let descriptor = FILE_SYSTEM.lock().close(pid, fd)?;
+    if let FileType::Pipe(end) = descriptor.file_type {
+        release_pipe_end(&end);
+    }
+    Ok(())
+}
+
+/// Reads from `fd`. Reads of stdin and pipes sleep until there is
+/// something to read unless the descriptor is O_NONBLOCK.
+pub fn read(fd: i32, buf: &mut [u8]) -> Result<usize, KernelError> {
+    let pid = current_pid();
+    let descriptor = FILE_SYSTEM.lock().descriptor(pid, fd)?;
+    let nonblocking = descriptor.flags.contains(OpenFlags::O_NONBLOCK);
+    match descriptor.file_type {
+        FileType::Pipe(PipeEnd::Read(pipe_id)) => {
+            wait_event(nonblocking, |waiter| crate::ipc::read_pipe(pipe_id, buf, waiter))
+        }
+        FileType::Device(DeviceType::Stdin) => {
+            wait_event(nonblocking, |waiter| crate::uart::read_input(buf, waiter))
+        }
+        _ => FILE_SYSTEM.lock().read(pid, fd, buf),
+    }
+}
+
+/// Writes to `fd`. Writes to a full pipe sleep until all of `buf` is
+/// written unless the descriptor is O_NONBLOCK.
+pub fn write(fd: i32, buf: &[u8]) -> Result<usize, KernelError> {
+    let pid = current_pid();
+    let descriptor = FILE_SYSTEM.lock().descriptor(pid, fd)?;
+    let nonblocking = descriptor.flags.contains(OpenFlags::O_NONBLOCK);
+    let result = match descriptor.file_type {
+        FileType::Pipe(PipeEnd::Write(pipe_id)) => write_pipe(pipe_id, buf, nonblocking),
+        _ => FILE_SYSTEM.lock().write(pid, fd, buf),
+    };
+    if result == Err(KernelError::BrokenPipe) {
+        let thread = crate::process::get_current_pid().unwrap_or(0);
+        let _ = crate::process::send_signal(thread, crate::signal::SIGPIPE);
+    }
+    result
 }
 
-pub fn write(fd: i32, buf: &[u8]) -> Result<usize, &'static str> {
-    FILE_SYSTEM.lock().write(fd, buf)
+// A write cut short by a signal or by the readers going away reports what
+// it wrote before that
+fn write_pipe(pipe_id: u32, buf: &[u8], nonblocking: bool) -> Result<usize, KernelError> {
+    let mut written = 0;
+    loop {
+        match wait_event(nonblocking, |waiter| crate::ipc::write_pipe(pipe_id, &buf[written..], waiter)) {
+            Ok(count) => written += count,
+            Err(e) if written == 0 => return Err(e),
+            Err(_) => return Ok(written),
+        }
+        if nonblocking || written == buf.len() {
+            return Ok(written);
+        }
+    }
 }
 
-pub fn duplicate_fd(fd: i32) -> Result<i32, &'static str> {
-    FILE_SYSTEM.lock().duplicate_fd(fd)
+pub fn status_flags(fd: i32) -> Result<i32, KernelError> {
+    let pid = current_pid();
+    FILE_SYSTEM.lock().status_flags(pid, fd)
 }
 
-pub fn duplicate_fd_to(oldfd: i32, newfd: i32) -> Result<i32, &'static str> {
-    FILE_SYSTEM.lock().duplicate_fd_to(oldfd, newfd)
+pub fn set_status_flags(fd: i32, flags: i32) -> Result<(), KernelError> {
+    let pid = current_pid();
+    FILE_SYSTEM.lock().set_status_flags(pid, fd, flags)
 }
 
-pub fn create_pipe_fds(pipe_id: u32) -> Result<(i32, i32), &'static str> {
-    FILE_SYSTEM.lock().create_pipe_fds(pipe_id)
+pub fn duplicate_fd(fd: i32) -> Result<i32, KernelError> {
+    duplicate_fd_from(fd, 0)
+}
+
+/// Duplicates `fd` onto the lowest free descriptor no lower than `min`,
+/// as fcntl's F_DUPFD does
+pub fn duplicate_fd_from(fd: i32, min: i32) -> Result<i32, KernelError> {
+    let pid = current_pid();
+    let new_fd = FILE_SYSTEM.lock().duplicate_fd(pid, fd, min)?;
+    if let Some(FileType::Pipe(end)) = descriptor_type(pid, new_fd) {
+        share_pipe_end(&end);
+    }
+    Ok(new_fd)
+}
+
+pub fn duplicate_fd_to(oldfd: i32, newfd: i32) -> Result<i32, KernelError> {
+    let pid = current_pid();
+    let replaced = descriptor_type(pid, newfd);
+    let fd = FILE_SYSTEM.lock().duplicate_fd_to(pid, oldfd, newfd)?;
+    if let Some(FileType::Pipe(end)) = replaced {
+        release_pipe_end(&end);
+    }
+    if let Some(FileType::Pipe(end)) = descriptor_type(pid, fd) {
+        share_pipe_end(&end);
+    }
+    Ok(fd)
+}
+
+pub fn create_pipe_fds(pipe_id: u32, flags: OpenFlags) -> Result<(i32, i32), KernelError> {
+    let pid = current_pid();
+    FILE_SYSTEM.lock().create_pipe_fds(pid, pipe_id, flags)
+}
+
+pub fn set_fd_limit(pid: u32, limit: u64) {
+    FILE_SYSTEM.lock().set_fd_limit(pid, limit)
+}
+
+pub fn seek(fd: i32, offset: i64, whence: u32) -> Result<u64, KernelError> {
+    let pid = current_pid();
+    FILE_SYSTEM.lock().seek(pid, fd, offset, whence)
+}
+
+pub fn stat(fd: i32) -> Result<Stat, KernelError> {
+    let pid = current_pid();
+    FILE_SYSTEM.lock().stat(pid, fd)
+}
+
+pub fn stat_path(path: &str) -> Result<Stat, KernelError> {
+    FILE_SYSTEM.lock().stat_path(path)
+}
+
+/// Whether `fd` is the console, which is the only terminal
+pub fn is_terminal(fd: i32) -> Result<bool, KernelError> {
+    let pid = current_pid();
+    let descriptor = FILE_SYSTEM.lock().descriptor(pid, fd)?;
+    Ok(matches!(descriptor.file_type, FileType::Device(DeviceType::Stdin | DeviceType::Stdout | DeviceType::Stderr)))
+}
+
+pub fn mappable_file(pid: u32, fd: i32, writable: bool) -> Result<String, KernelError> {
+    FILE_SYSTEM.lock().mappable_file(pid, fd, writable)
+}
+
+pub fn read_at(path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, KernelError> {
+    FILE_SYSTEM.lock().read_at(path, offset, buf)
+}
+
+pub fn shared_page(path: &str, offset: u64) -> Result<PhysFrame, KernelError> {
+    FILE_SYSTEM.lock().shared_page(path, offset)
+}
+
+pub fn release_shared_page(path: &str, offset: u64, frame: PhysFrame) {
+    FILE_SYSTEM.lock().release_shared_page(path, offset, frame)
+}
+
+pub fn clone_fd_table(parent: u32, child: u32) {
+    let pipes = FILE_SYSTEM.lock().clone_fd_table(parent, child);
+    for end in &pipes {
+        share_pipe_end(end);
+    }
+}
+
+/// Closes every descriptor of an exiting process
+pub fn release_fd_table(pid: u32) {
+    let pipes = FILE_SYSTEM.lock().remove_fd_table(pid);
+    for end in &pipes {
+        release_pipe_end(end);
+    }
+}
+
+fn descriptor_type(pid: u32, fd: i32) -> Option<FileType> {
+    let fs = FILE_SYSTEM.lock();
+    fs.fd_tables.get(&pid)?.open_files.get(&fd).map(|descriptor| descriptor.file_type.clone())
+}
+
+// Pipe reference counts live in ipc and are updated after FILE_SYSTEM is released
+fn share_pipe_end(end: &PipeEnd) {
+    let _ = match end {
+        PipeEnd::Read(pipe_id) => crate::ipc::add_pipe_reader(*pipe_id),
+        PipeEnd::Write(pipe_id) => crate::ipc::add_pipe_writer(*pipe_id),
+    };
+}
+
+fn release_pipe_end(end: &PipeEnd) {
+    let _ = match end {
+        PipeEnd::Read(pipe_id) => crate::ipc::close_pipe_read(*pipe_id),
+        PipeEnd::Write(pipe_id) => crate::ipc::close_pipe_write(*pipe_id),
+    };
 }
 
 // Additional functions for coreutils support
 
-pub fn read_file(path: &str) -> Result<String, &'static str> {
+pub fn read_file(path: &str) -> Result<String, KernelError> {
     let fs = FILE_SYSTEM.lock();
     
     // Check if file exists
     if !fs.files.contains_key(path) {
-        return Err("File not found");
+        return Err(KernelError::NotFound);
     }
     
     // Read file content as string
-    let file_data = fs.files.get(path).ok_or("File not found")?;
+    let file_data = fs.files.get(path).ok_or(KernelError::NotFound)?;
     let content = String::from_utf8_lossy(file_data).to_string();
     Ok(content)
 }
 
-pub fn list_directory(path: &str) -> Result<Vec<String>, &'static str> {
+/// Contents of the file at `path`, byte for byte
+pub fn read_file_bytes(path: &str) -> Result<Vec<u8>, KernelError> {
+    FILE_SYSTEM.lock().files.get(path).cloned().ok_or(KernelError::NotFound)
+}
+
+pub fn list_directory(path: &str) -> Result<Vec<String>, KernelError> {
     let fs = FILE_SYSTEM.lock();
     let mut entries = Vec::new();
     
@@ -345,41 +778,41 @@ pub fn list_directory(path: &str) -> Result<Vec<String>, &'static str> {
     Ok(entries)
 }
 
-pub fn get_current_directory() -> Result<String, &'static str> {
+pub fn get_current_directory() -> Result<String, KernelError> {
     Ok("/".to_string())
 }
 
-pub fn create_directory(_path: &str) -> Result<(), &'static str> {
+pub fn create_directory(_path: &str) -> Result<(), KernelError> {
     // For now, just simulate directory creation
     // In a real filesystem, this would create directory metadata
     Ok(())
 }
 
-pub fn create_file(path: &str) -> Result<(), &'static str> {
+pub fn create_file(path: &str) -> Result<(), KernelError> {
     let mut fs = FILE_SYSTEM.lock();
     fs.files.insert(path.to_string(), Vec::new());
     Ok(())
 }
 
-pub fn remove_file(path: &str) -> Result<(), &'static str> {
+pub fn remove_file(path: &str) -> Result<(), KernelError> {
     let mut fs = FILE_SYSTEM.lock();
     if fs.files.remove(path).is_some() {
         Ok(())
     } else {
-        Err("File not found")
+        Err(KernelError::NotFound)
     }
 }
 
-pub fn copy_file(source: &str, dest: &str) -> Result<(), &'static str> {
+pub fn copy_file(source: &str, dest: &str) -> Result<(), KernelError> {
     let mut fs = FILE_SYSTEM.lock();
-    let source_data = fs.files.get(source).ok_or("Source file not found")?.clone();
+    let source_data = fs.files.get(source).ok_or(KernelError::NotFound)?.clone();
     fs.files.insert(dest.to_string(), source_data);
     Ok(())
 }
 
-pub fn move_file(source: &str, dest: &str) -> Result<(), &'static str> {
+pub fn move_file(source: &str, dest: &str) -> Result<(), KernelError> {
     let mut fs = FILE_SYSTEM.lock();
-    let source_data = fs.files.remove(source).ok_or("Source file not found")?;
+    let source_data = fs.files.remove(source).ok_or(KernelError::NotFound)?;
     fs.files.insert(dest.to_string(), source_data);
     Ok(())
 }
@@ -0,0 +1,523 @@
+#![allow(dead_code)]
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use crate::fdt::{self, GicVersion};
+use crate::memory::PhysAddr;
+use crate::println;
+use crate::smp::{self, MAX_CPUS};
+
+// GIC interrupt controller, v2 or v3. The distributor routes shared
+// peripheral interrupts (SPIs) to CPUs; each CPU acknowledges and completes
+// interrupts through its CPU interface, which is memory mapped on v2 and a
+// set of system registers on v3. On v3 the per-CPU SGIs and PPIs are
+// configured in the CPU's redistributor instead of the distributor. SPIs
+// all go to the boot CPU; the others take SGIs (IPIs) and PPIs (their
+// timers) only.
+
+// Used when the device tree does not describe the GIC (QEMU virt layout)
+const DEFAULT_DISTRIBUTOR: u64 = 0x0800_0000;
+const DEFAULT_CPU_INTERFACE: u64 = 0x0801_0000;
+const REGION_SIZE: usize = 0x10000;
+
+// Distributor registers
+const GICD_CTLR: usize = 0x000;
+const GICD_TYPER: usize = 0x004;
+const GICD_IGROUPR: usize = 0x080;
+const GICD_ISENABLER: usize = 0x100;
+const GICD_ICENABLER: usize = 0x180;
+const GICD_IPRIORITYR: usize = 0x400;
+const GICD_ITARGETSR: usize = 0x800;
+const GICD_SGIR: usize = 0xf00;
+const GICD_IROUTER: usize = 0x6000;
+
+const GICD_CTLR_ENABLE: u32 = 1;
+// v3: enable group 1 and affinity routing, in the layout shared by the
+// single and non-secure security states
+const GICD_CTLR_V3: u32 = (1 << 4) | (1 << 1);
+const GICD_CTLR_RWP: u32 = 1 << 31;
+
+// v2 CPU interface registers
+const GICC_CTLR: usize = 0x00;
+const GICC_PMR: usize = 0x04;
+const GICC_BPR: usize = 0x08;
+const GICC_IAR: usize = 0x0c;
+const GICC_EOIR: usize = 0x10;
+
+// v3 redistributor: a control frame followed by the SGI/PPI frame
+const GICR_FRAME_STRIDE: u64 = 0x20000;
+const GICR_SGI_OFFSET: usize = 0x10000;
+const GICR_CTLR: usize = 0x0000;
+const GICR_TYPER: usize = 0x0008;
+const GICR_WAKER: usize = 0x0014;
+const GICR_TYPER_LAST: u64 = 1 << 4;
+const GICR_CTLR_RWP: u32 = 1 << 3;
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+
+/// SGIs are 0-15, PPIs 16-31 and SPIs start at 32
+pub const SGI_COUNT: u32 = 16;
+pub const SPI_BASE: u32 = 32;
+/// The architecture's largest SPI is 1019
+pub const MAX_IRQS: usize = 1020;
+/// Acknowledging returns an ID from 1020 up when nothing is pending
+pub const SPURIOUS_IRQ: u32 = 1023;
+/// Lower values are more urgent
+pub const DEFAULT_PRIORITY: u8 = 0xa0;
+
+pub type IrqHandler = fn(irq: u32);
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IrqStats {
+    pub handled: u64,
+    // Acknowledges that returned a spurious ID
+    pub spurious: u64,
+    // Interrupts nobody requested, which are then disabled
+    pub unhandled: u64,
+}
+
+#[derive(Clone, Copy)]
+struct IrqDesc {
+    handler: Option<IrqHandler>,
+    count: u64,
+}
+
+struct Gic {
+    version: GicVersion,
+    distributor: u64,
+    // GICC on v2, banked per CPU; physical base of the redistributor
+    // frames on v3
+    cpu_interface: u64,
+    // v3: each CPU's redistributor
+    redistributors: [u64; MAX_CPUS],
+    // v2: each CPU's bit in SGI and SPI target lists
+    targets: [u8; MAX_CPUS],
+    // Interrupt IDs the distributor implements
+    lines: u32,
+}
+
+static GIC: Mutex<Option<Gic>> = Mutex::new(None);
+static IRQS: Mutex<[IrqDesc; MAX_IRQS]> = Mutex::new([IrqDesc { handler: None, count: 0 }; MAX_IRQS]);
+// Read on every interrupt, so kept outside the lock
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+static SYSTEM_REGISTERS: AtomicBool = AtomicBool::new(false);
+static CPU_INTERFACE: AtomicU64 = AtomicU64::new(0);
+static HANDLED: AtomicU64 = AtomicU64::new(0);
+static SPURIOUS: AtomicU64 = AtomicU64::new(0);
+static UNHANDLED: AtomicU64 = AtomicU64::new(0);
+
+/// Maps the GIC, enables the distributor and this CPU's interface, and
+/// routes every SPI to this CPU with all interrupts disabled. Requires the
+/// kernel address space.
+pub fn init() -> Result<(), &'static str> {
+    let (version, distributor, cpu_interface) = match fdt::platform().gic {
+        Some(gic) => (gic.version, gic.distributor, gic.cpu_interface),
+        None => (GicVersion::V2, DEFAULT_DISTRIBUTOR, DEFAULT_CPU_INTERFACE),
+    };
+    
+    let distributor = crate::mmu::map_device(PhysAddr::new(distributor), REGION_SIZE)?.as_u64();
+    let lines = unsafe { ((read32(distributor, GICD_TYPER) & 0x1f) + 1) * 32 };
+    let mut gic = Gic {
+        version,
+        distributor,
+        cpu_interface,
+        redistributors: [0; MAX_CPUS],
+        targets: [0; MAX_CPUS],
+        lines,
+    };
+    match version {
+        GicVersion::V2 => {
+            gic.cpu_interface = crate::mmu::map_device(PhysAddr::new(cpu_interface), REGION_SIZE)?.as_u64();
+            gic.targets[0] = unsafe { gic.own_target() };
+        }
+        GicVersion::V3 => gic.redistributors[0] = find_redistributor(cpu_interface)?,
+    }
+    
+    unsafe {
+        gic.init_distributor();
+        gic.init_cpu_interface();
+    }
+    if version == GicVersion::V2 {
+        CPU_INTERFACE.store(gic.cpu_interface, Ordering::SeqCst);
+    }
+    SYSTEM_REGISTERS.store(version == GicVersion::V3, Ordering::SeqCst);
+    *GIC.lock() = Some(gic);
+    INITIALIZED.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Brings up the interface of the calling secondary CPU and enables there
+/// the SGIs and PPIs that have handlers
+pub fn init_cpu() -> Result<(), &'static str> {
+    let cpu = smp::cpu_id();
+    let (version, base) = {
+        let gic = GIC.lock();
+        let gic = gic.as_ref().ok_or("Interrupt controller not initialized")?;
+        (gic.version, gic.cpu_interface)
+    };
+    // Mapping frames takes the kernel address space lock, so not under GIC
+    let redistributor = match version {
+        GicVersion::V2 => 0,
+        GicVersion::V3 => find_redistributor(base)?,
+    };
+    {
+        let mut gic = GIC.lock();
+        let gic = gic.as_mut().ok_or("Interrupt controller not initialized")?;
+        gic.redistributors[cpu] = redistributor;
+        unsafe {
+            if version == GicVersion::V2 {
+                gic.targets[cpu] = gic.own_target();
+            }
+            gic.init_cpu_interface();
+        }
+    }
+    
+    let requested: u32 = {
+        let irqs = IRQS.lock();
+        (0..SPI_BASE).filter(|&irq| irqs[irq as usize].handler.is_some()).fold(0, |mask, irq| mask | 1 << irq)
+    };
+    for irq in (0..SPI_BASE).filter(|irq| requested & 1 << irq != 0) {
+        set_priority(irq, DEFAULT_PRIORITY)?;
+        enable(irq)?;
+    }
+    Ok(())
+}
+
+pub fn is_initialized() -> bool {
+    INITIALIZED.load(Ordering::SeqCst)
+}
+
+pub fn version() -> Option<GicVersion> {
+    GIC.lock().as_ref().map(|gic| gic.version)
+}
+
+/// Installs `handler` for `irq` and enables it at the default priority.
+/// Handlers run in interrupt context with interrupts masked and must not
+/// block or take locks the interrupted code may hold. SGIs and PPIs are
+/// enabled on the calling CPU, and on secondaries as they come up.
+pub fn request_irq(irq: u32, handler: IrqHandler) -> Result<(), &'static str> {
+    check_irq(irq)?;
+    {
+        let mut irqs = IRQS.lock();
+        let desc = &mut irqs[irq as usize];
+        if desc.handler.is_some() {
+            return Err("IRQ already requested");
+        }
+        desc.handler = Some(handler);
+    }
+    set_priority(irq, DEFAULT_PRIORITY)?;
+    enable(irq)
+}
+
+/// Disables `irq` and removes its handler
+pub fn free_irq(irq: u32) -> Result<(), &'static str> {
+    check_irq(irq)?;
+    disable(irq)?;
+    IRQS.lock()[irq as usize].handler = None;
+    Ok(())
+}
+
+pub fn enable(irq: u32) -> Result<(), &'static str> {
+    with_gic(irq, |gic| unsafe {
+        let (base, offset) = gic.banked(irq, GICD_ISENABLER);
+        write32(base, offset + 4 * (irq as usize / 32), 1 << (irq % 32));
+    })
+}
+
+pub fn disable(irq: u32) -> Result<(), &'static str> {
+    with_gic(irq, |gic| unsafe {
+        let (base, offset) = gic.banked(irq, GICD_ICENABLER);
+        write32(base, offset + 4 * (irq as usize / 32), 1 << (irq % 32));
+        gic.wait_for_writes(irq);
+    })
+}
+
+/// Sets the priority of `irq`. The GIC may implement only the upper bits.
+pub fn set_priority(irq: u32, priority: u8) -> Result<(), &'static str> {
+    with_gic(irq, |gic| unsafe {
+        let (base, offset) = gic.banked(irq, GICD_IPRIORITYR);
+        ((base as usize + offset + irq as usize) as *mut u8).write_volatile(priority);
+    })
+}
+
+pub fn priority(irq: u32) -> Result<u8, &'static str> {
+    let mut priority = 0;
+    with_gic(irq, |gic| unsafe {
+        let (base, offset) = gic.banked(irq, GICD_IPRIORITYR);
+        priority = ((base as usize + offset + irq as usize) as *const u8).read_volatile();
+    })?;
+    Ok(priority)
+}
+
+/// Raises software-generated interrupt `sgi` on the calling CPU
+pub fn send_sgi_to_self(sgi: u32) -> Result<(), &'static str> {
+    if sgi >= SGI_COUNT {
+        return Err("Not an SGI");
+    }
+    let gic = GIC.lock();
+    let gic = gic.as_ref().ok_or("Interrupt controller not initialized")?;
+    unsafe {
+        match gic.version {
+            // Target list filter 0b10: the requesting CPU only
+            GicVersion::V2 => write32(gic.distributor, GICD_SGIR, (0b10 << 24) | sgi),
+            GicVersion::V3 => write_sgi1r(read_mpidr(), sgi),
+        }
+    }
+    Ok(())
+}
+
+/// Raises software-generated interrupt `sgi` on CPU `cpu`
+pub fn send_sgi(sgi: u32, cpu: usize) -> Result<(), &'static str> {
+    if sgi >= SGI_COUNT {
+        return Err("Not an SGI");
+    }
+    let gic = GIC.lock();
+    let gic = gic.as_ref().ok_or("Interrupt controller not initialized")?;
+    // Earlier stores must be visible to the target when it takes the SGI
+    unsafe { asm!("dsb ish") };
+    match gic.version {
+        GicVersion::V2 => {
+            let target = gic.targets.get(cpu).copied().filter(|&target| target != 0).ok_or("CPU not online")?;
+            unsafe { write32(gic.distributor, GICD_SGIR, (target as u32) << 16 | sgi) };
+        }
+        GicVersion::V3 => unsafe { write_sgi1r(smp::mpidr(cpu).ok_or("CPU not online")?, sgi) },
+    }
+    Ok(())
+}
+
+/// Acknowledges the pending interrupt, runs its handler and completes it.
+/// Called from the IRQ vectors.
+pub fn handle_irq() {
+    let irq = acknowledge();
+    if irq >= MAX_IRQS as u32 {
+        SPURIOUS.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    
+    let handler = {
+        let mut irqs = IRQS.lock();
+        let desc = &mut irqs[irq as usize];
+        desc.count += 1;
+        desc.handler
+    };
+    match handler {
+        Some(handler) => {
+            HANDLED.fetch_add(1, Ordering::Relaxed);
+            handler(irq);
+        }
+        None => {
+            UNHANDLED.fetch_add(1, Ordering::Relaxed);
+            println!("Unexpected IRQ {}, disabled", irq);
+            let _ = disable(irq);
+        }
+    }
+    end_of_interrupt(irq);
+}
+
+pub fn stats() -> IrqStats {
+    IrqStats {
+        handled: HANDLED.load(Ordering::Relaxed),
+        spurious: SPURIOUS.load(Ordering::Relaxed),
+        unhandled: UNHANDLED.load(Ordering::Relaxed),
+    }
+}
+
+/// Times `irq` has been taken since boot
+pub fn irq_count(irq: u32) -> u64 {
+    IRQS.lock().get(irq as usize).map_or(0, |desc| desc.count)
+}
+
+fn check_irq(irq: u32) -> Result<(), &'static str> {
+    if irq as usize >= MAX_IRQS {
+        return Err("IRQ out of range");
+    }
+    Ok(())
+}
+
+fn with_gic(irq: u32, f: impl FnOnce(&Gic)) -> Result<(), &'static str> {
+    check_irq(irq)?;
+    let gic = GIC.lock();
+    let gic = gic.as_ref().ok_or("Interrupt controller not initialized")?;
+    if irq >= gic.lines {
+        return Err("IRQ not implemented by the GIC");
+    }
+    f(gic);
+    Ok(())
+}
+
+fn acknowledge() -> u32 {
+    let iar: u64;
+    unsafe {
+        if SYSTEM_REGISTERS.load(Ordering::Relaxed) {
+            asm!("mrs {}, icc_iar1_el1", out(reg) iar);
+        } else {
+            iar = read32(CPU_INTERFACE.load(Ordering::Relaxed), GICC_IAR) as u64;
+        }
+    }
+    (iar & 0xffffff) as u32
+}
+
+fn end_of_interrupt(irq: u32) {
+    unsafe {
+        if SYSTEM_REGISTERS.load(Ordering::Relaxed) {
+            asm!("msr icc_eoir1_el1, {}", "isb", in(reg) irq as u64);
+        } else {
+            write32(CPU_INTERFACE.load(Ordering::Relaxed), GICC_EOIR, irq);
+        }
+    }
+}
+
+impl Gic {
+    unsafe fn init_distributor(&self) {
+        match self.version {
+            GicVersion::V2 => {
+                write32(self.distributor, GICD_CTLR, 0);
+                for irq in (SPI_BASE..self.lines).step_by(32) {
+                    write32(self.distributor, GICD_ICENABLER + irq as usize / 8, u32::MAX);
+                }
+                let target = self.own_target();
+                for irq in SPI_BASE..self.lines {
+                    ((self.distributor as usize + GICD_ITARGETSR + irq as usize) as *mut u8).write_volatile(target);
+                }
+                write32(self.distributor, GICD_CTLR, GICD_CTLR_ENABLE);
+            }
+            GicVersion::V3 => {
+                write32(self.distributor, GICD_CTLR, 0);
+                self.wait_for_writes(SPI_BASE);
+                for irq in (SPI_BASE..self.lines).step_by(32) {
+                    write32(self.distributor, GICD_ICENABLER + irq as usize / 8, u32::MAX);
+                    write32(self.distributor, GICD_IGROUPR + irq as usize / 8, u32::MAX);
+                }
+                write32(self.distributor, GICD_CTLR, GICD_CTLR_V3);
+                self.wait_for_writes(SPI_BASE);
+                let affinity = read_mpidr() & 0xff_00ff_ffff;
+                for irq in SPI_BASE..self.lines {
+                    let router = (self.distributor as usize + GICD_IROUTER + 8 * irq as usize) as *mut u64;
+                    router.write_volatile(affinity);
+                }
+            }
+        }
+        for irq in SPI_BASE..self.lines {
+            ((self.distributor as usize + GICD_IPRIORITYR + irq as usize) as *mut u8).write_volatile(DEFAULT_PRIORITY);
+        }
+    }
+    
+    // Sets up this CPU's SGIs and PPIs and its CPU interface
+    unsafe fn init_cpu_interface(&self) {
+        let redistributor = self.redistributor();
+        match self.version {
+            GicVersion::V2 => {
+                write32(self.distributor, GICD_ICENABLER, u32::MAX);
+                // Let every priority through and don't split priorities
+                // into groups
+                write32(self.cpu_interface, GICC_PMR, 0xff);
+                write32(self.cpu_interface, GICC_BPR, 0);
+                write32(self.cpu_interface, GICC_CTLR, 1);
+            }
+            GicVersion::V3 => {
+                let waker = read32(redistributor, GICR_WAKER);
+                write32(redistributor, GICR_WAKER, waker & !GICR_WAKER_PROCESSOR_SLEEP);
+                while read32(redistributor, GICR_WAKER) & GICR_WAKER_CHILDREN_ASLEEP != 0 {
+                    core::hint::spin_loop();
+                }
+                
+                let sgi = redistributor + GICR_SGI_OFFSET as u64;
+                write32(sgi, GICD_ICENABLER, u32::MAX);
+                self.wait_for_writes(0);
+                write32(sgi, GICD_IGROUPR, u32::MAX);
+                for irq in 0..SPI_BASE {
+                    ((sgi as usize + GICD_IPRIORITYR + irq as usize) as *mut u8).write_volatile(DEFAULT_PRIORITY);
+                }
+                
+                // System register interface; boot.s lets EL1 use it
+                let sre: u64;
+                asm!("mrs {}, icc_sre_el1", out(reg) sre);
+                asm!("msr icc_sre_el1, {}", "isb", in(reg) sre | 1);
+                asm!("msr icc_pmr_el1, {}", in(reg) 0xffu64);
+                asm!("msr icc_bpr1_el1, {}", in(reg) 0u64);
+                asm!("msr icc_igrpen1_el1, {}", "isb", in(reg) 1u64);
+            }
+        }
+    }
+    
+    // v2: this CPU's bit in target lists. The SGI target bytes read back
+    // as it, or as 0 on a uniprocessor GIC.
+    unsafe fn own_target(&self) -> u8 {
+        (read32(self.distributor, GICD_ITARGETSR) & 0xff).max(1) as u8
+    }
+    
+    // v3: the calling CPU's redistributor
+    fn redistributor(&self) -> u64 {
+        self.redistributors[smp::cpu_id()]
+    }
+    
+    // Register frame holding the setting of `irq` for a distributor
+    // register at `offset`. SGIs and PPIs live in the calling CPU's
+    // redistributor on v3.
+    fn banked(&self, irq: u32, offset: usize) -> (u64, usize) {
+        if self.version == GicVersion::V3 && irq < SPI_BASE {
+            (self.redistributor() + GICR_SGI_OFFSET as u64, offset)
+        } else {
+            (self.distributor, offset)
+        }
+    }
+    
+    // v3 applies some writes asynchronously; waits until the frame
+    // holding `irq` has finished them
+    unsafe fn wait_for_writes(&self, irq: u32) {
+        if self.version != GicVersion::V3 {
+            return;
+        }
+        let (base, offset, rwp) = if irq < SPI_BASE {
+            (self.redistributor(), GICR_CTLR, GICR_CTLR_RWP)
+        } else {
+            (self.distributor, GICD_CTLR, GICD_CTLR_RWP)
+        };
+        while read32(base, offset) & rwp != 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+// Walks the redistributor frames from `base` for the one serving this CPU
+fn find_redistributor(base: u64) -> Result<u64, &'static str> {
+    let mpidr = read_mpidr();
+    let affinity = (mpidr & 0xff_ffff) | ((mpidr >> 8) & 0xff00_0000);
+    let mut frame = base;
+    loop {
+        let virt = crate::mmu::map_device(PhysAddr::new(frame), GICR_FRAME_STRIDE as usize)?.as_u64();
+        let typer = unsafe { ((virt as usize + GICR_TYPER) as *const u64).read_volatile() };
+        if typer >> 32 == affinity {
+            return Ok(virt);
+        }
+        if typer & GICR_TYPER_LAST != 0 {
+            return Err("No redistributor for this CPU");
+        }
+        frame += GICR_FRAME_STRIDE;
+    }
+}
+
+// v3: raises `sgi` on the CPU with affinity `mpidr`
+unsafe fn write_sgi1r(mpidr: u64, sgi: u32) {
+    let value = (1 << (mpidr & 0xf))
+        | ((mpidr >> 8) & 0xff) << 16
+        | ((mpidr >> 16) & 0xff) << 32
+        | ((mpidr >> 32) & 0xff) << 48
+        | (sgi as u64) << 24;
+    asm!("msr icc_sgi1r_el1, {}", "isb", in(reg) value);
+}
+
+fn read_mpidr() -> u64 {
+    let mpidr: u64;
+    unsafe { asm!("mrs {}, mpidr_el1", out(reg) mpidr) };
+    mpidr
+}
+
+unsafe fn read32(base: u64, offset: usize) -> u32 {
+    ((base as usize + offset) as *const u32).read_volatile()
+}
+
+unsafe fn write32(base: u64, offset: usize, value: u32) {
+    ((base as usize + offset) as *mut u32).write_volatile(value);
+}
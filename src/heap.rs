@@ -0,0 +1,339 @@
+#![allow(dead_code)]
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, Ordering};
+use linked_list_allocator::Heap;
+use spin::Mutex;
+use crate::memory::{self, VirtAddr, PAGE_SIZE};
+
+// Kernel heap. Small allocations are served from size-class slab caches
+// carved out of single frames; everything else comes from a first-fit heap
+// that starts in the linker-provided region and then grows page by page
+// into a reserved range of the kernel address space.
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator::new();
+
+pub const SLAB_SIZES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+pub const SLAB_CLASS_COUNT: usize = SLAB_SIZES.len();
+
+// Virtual range the general heap grows into, above the linear map
+pub const HEAP_GROWTH_BASE: u64 = 0xffff_8000_0000_0000;
+pub const HEAP_GROWTH_LIMIT: usize = 256 * 1024 * 1024;
+// Smallest step the general heap grows by
+const HEAP_GROWTH_STEP: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub object_size: usize,
+    pub slabs: usize,
+    pub objects_total: usize,
+    pub objects_in_use: usize,
+    pub high_water: usize,
+    pub allocations: u64,
+    // Times no slab page could be obtained and the request fell through
+    // to the general heap
+    pub failures: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GeneralHeapStats {
+    pub size: usize,
+    pub used: usize,
+    pub high_water: usize,
+    pub allocations: u64,
+    // Allocations that returned null
+    pub failures: u64,
+    pub grows: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub caches: [CacheStats; SLAB_CLASS_COUNT],
+    pub heap: GeneralHeapStats,
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+// Objects of one size class. Slabs are whole frames split into equally
+// sized objects; free objects are kept on an intrusive list.
+pub struct SlabCache {
+    object_size: usize,
+    free_list: *mut FreeObject,
+    slabs: usize,
+    in_use: usize,
+    high_water: usize,
+    allocations: u64,
+    failures: u64,
+}
+
+// The free list only points into frames owned by the cache
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    pub const fn new(object_size: usize) -> Self {
+        SlabCache {
+            object_size,
+            free_list: ptr::null_mut(),
+            slabs: 0,
+            in_use: 0,
+            high_water: 0,
+            allocations: 0,
+            failures: 0,
+        }
+    }
+    
+    pub fn allocate(&mut self) -> Option<NonNull<u8>> {
+        if self.free_list.is_null() && !self.grow() {
+            self.failures += 1;
+            return None;
+        }
+        
+        let object = self.free_list;
+        unsafe {
+            self.free_list = (*object).next;
+        }
+        self.in_use += 1;
+        self.high_water = self.high_water.max(self.in_use);
+        self.allocations += 1;
+        NonNull::new(object as *mut u8)
+    }
+    
+    /// # Safety
+    ///
+    /// `object` must have been returned by `allocate` on this cache.
+    pub unsafe fn deallocate(&mut self, object: NonNull<u8>) {
+        let object = object.as_ptr() as *mut FreeObject;
+        (*object).next = self.free_list;
+        self.free_list = object;
+        self.in_use -= 1;
+    }
+    
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            object_size: self.object_size,
+            slabs: self.slabs,
+            objects_total: self.slabs * (PAGE_SIZE / self.object_size),
+            objects_in_use: self.in_use,
+            high_water: self.high_water,
+            allocations: self.allocations,
+            failures: self.failures,
+        }
+    }
+    
+    fn grow(&mut self) -> bool {
+        let Some(frame) = memory::allocate_frame() else {
+            return false;
+        };
+        
+        let base = memory::phys_to_virt(frame.start_address()).as_u64() as *mut u8;
+        for index in (0..PAGE_SIZE / self.object_size).rev() {
+            let object = unsafe { base.add(index * self.object_size) } as *mut FreeObject;
+            unsafe {
+                (*object).next = self.free_list;
+            }
+            self.free_list = object;
+        }
+        self.slabs += 1;
+        true
+    }
+}
+
+// First-fit heap for allocations too large for the slab caches. The boot
+// region is fixed; the growable region is mapped on demand.
+struct GeneralHeap {
+    boot: Heap,
+    growable: Heap,
+    mapped: usize,
+    high_water: usize,
+    allocations: u64,
+    failures: u64,
+    grows: u64,
+}
+
+impl GeneralHeap {
+    const fn new() -> Self {
+        GeneralHeap {
+            boot: Heap::empty(),
+            growable: Heap::empty(),
+            mapped: 0,
+            high_water: 0,
+            allocations: 0,
+            failures: 0,
+            grows: 0,
+        }
+    }
+    
+    fn allocate(&mut self, layout: Layout, can_grow: bool) -> *mut u8 {
+        let result = self.boot.allocate_first_fit(layout)
+            .or_else(|_| self.growable.allocate_first_fit(layout))
+            .or_else(|_| {
+                if can_grow && self.grow(layout).is_ok() {
+                    self.growable.allocate_first_fit(layout)
+                } else {
+                    Err(())
+                }
+            });
+        
+        match result {
+            Ok(ptr) => {
+                self.allocations += 1;
+                self.high_water = self.high_water.max(self.used());
+                ptr.as_ptr()
+            }
+            Err(()) => {
+                self.failures += 1;
+                ptr::null_mut()
+            }
+        }
+    }
+    
+    unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let addr = ptr.as_ptr() as usize;
+        if addr >= self.boot.bottom() as usize && addr < self.boot.top() as usize {
+            self.boot.deallocate(ptr, layout);
+        } else {
+            self.growable.deallocate(ptr, layout);
+        }
+    }
+    
+    // Maps enough new pages at the top of the growable region for `layout`
+    fn grow(&mut self, layout: Layout) -> Result<(), &'static str> {
+        let needed = layout.size() + layout.align() + 2 * core::mem::size_of::<usize>();
+        let size = memory::align_up(needed.max(HEAP_GROWTH_STEP) as u64, PAGE_SIZE as u64) as usize;
+        if self.mapped + size > HEAP_GROWTH_LIMIT {
+            return Err("Kernel heap exhausted");
+        }
+        
+        let top = HEAP_GROWTH_BASE + self.mapped as u64;
+        crate::mmu::map_kernel_pages(VirtAddr::new(top), size)?;
+        unsafe {
+            if self.mapped == 0 {
+                self.growable.init(top as *mut u8, size);
+            } else {
+                self.growable.extend(size);
+            }
+        }
+        self.mapped += size;
+        self.grows += 1;
+        Ok(())
+    }
+    
+    fn used(&self) -> usize {
+        self.boot.used() + self.growable.used()
+    }
+    
+    fn stats(&self) -> GeneralHeapStats {
+        GeneralHeapStats {
+            size: self.boot.size() + self.mapped,
+            used: self.used(),
+            high_water: self.high_water,
+            allocations: self.allocations,
+            failures: self.failures,
+            grows: self.grows,
+        }
+    }
+}
+
+pub struct KernelAllocator {
+    caches: [Mutex<SlabCache>; SLAB_CLASS_COUNT],
+    heap: Mutex<GeneralHeap>,
+    // Set once the frame allocator and kernel address space are up
+    ready: AtomicBool,
+}
+
+impl KernelAllocator {
+    const fn new() -> Self {
+        KernelAllocator {
+            caches: [
+                Mutex::new(SlabCache::new(SLAB_SIZES[0])),
+                Mutex::new(SlabCache::new(SLAB_SIZES[1])),
+                Mutex::new(SlabCache::new(SLAB_SIZES[2])),
+                Mutex::new(SlabCache::new(SLAB_SIZES[3])),
+                Mutex::new(SlabCache::new(SLAB_SIZES[4])),
+                Mutex::new(SlabCache::new(SLAB_SIZES[5])),
+                Mutex::new(SlabCache::new(SLAB_SIZES[6])),
+                Mutex::new(SlabCache::new(SLAB_SIZES[7])),
+            ],
+            heap: Mutex::new(GeneralHeap::new()),
+            ready: AtomicBool::new(false),
+        }
+    }
+    
+    fn in_general_heap(&self, addr: usize) -> bool {
+        let (boot_start, boot_end) = boot_heap_range();
+        let growth_start = HEAP_GROWTH_BASE as usize;
+        (addr >= boot_start && addr < boot_end)
+            || (addr >= growth_start && addr < growth_start + HEAP_GROWTH_LIMIT)
+    }
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ready = self.ready.load(Ordering::Acquire);
+        if ready {
+            if let Some(class) = size_class(layout) {
+                if let Some(object) = self.caches[class].lock().allocate() {
+                    return object.as_ptr();
+                }
+            }
+        }
+        self.heap.lock().allocate(layout, ready)
+    }
+    
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let Some(ptr) = NonNull::new(ptr) else {
+            return;
+        };
+        if self.in_general_heap(ptr.as_ptr() as usize) {
+            self.heap.lock().deallocate(ptr, layout);
+        } else if let Some(class) = size_class(layout) {
+            self.caches[class].lock().deallocate(ptr);
+        }
+    }
+}
+
+// Smallest slab class holding `layout`. Objects are aligned to their class
+// size, so alignment up to the class size comes for free.
+fn size_class(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    SLAB_SIZES.iter().position(|&class| size <= class)
+}
+
+extern "C" {
+    static __heap_start: u8;
+    static __heap_end: u8;
+}
+
+fn boot_heap_range() -> (usize, usize) {
+    unsafe { (&__heap_start as *const u8 as usize, &__heap_end as *const u8 as usize) }
+}
+
+/// Hands the linker-provided heap region to the allocator. Only this region
+/// is used until `init` runs.
+pub fn init_early() {
+    let (start, end) = boot_heap_range();
+    unsafe {
+        ALLOCATOR.heap.lock().boot.init(start as *mut u8, end - start);
+    }
+}
+
+/// Enables the slab caches and heap growth. Requires the frame allocator and
+/// the kernel address space.
+pub fn init() {
+    ALLOCATOR.ready.store(true, Ordering::Release);
+}
+
+pub fn stats() -> HeapStats {
+    let mut caches = [CacheStats::default(); SLAB_CLASS_COUNT];
+    for (stats, cache) in caches.iter_mut().zip(ALLOCATOR.caches.iter()) {
+        *stats = cache.lock().stats();
+    }
+    HeapStats {
+        caches,
+        heap: ALLOCATOR.heap.lock().stats(),
+    }
+}
@@ -151,7 +151,7 @@ impl InputEvent {
     pub fn new(event_type: InputEventType, code: u32, value: i32) -> Self {
         InputEvent {
             event_type,
-            timestamp: timer::get_ticks(),
+            timestamp: crate::timer::uptime_ms(),
             device_id: 0,
             code,
             value,
@@ -450,19 +450,6 @@ impl MouseButton {
     }
 }
 
-// Module for timer functions (simplified for this example)
-mod timer {
-    static mut TICK_COUNT: u64 = 0;
-    
-    pub fn get_ticks() -> u64 {
-        unsafe { TICK_COUNT }
-    }
-    
-    pub fn increment_ticks() {
-        unsafe { TICK_COUNT += 1; }
-    }
-}
-
 // Public API functions
 pub fn input_init() -> InputResult<()> {
     unsafe {
@@ -5,6 +5,9 @@ use alloc::vec::Vec;
 use alloc::vec;
 use spin::Mutex;
 use lazy_static::lazy_static;
+use crate::error::KernelError;
+use crate::fs::OpenFlags;
+use crate::wait_queue::{self, WaitQueue};
 
 const PIPE_BUFFER_SIZE: usize = 4096;
 
@@ -16,6 +19,9 @@ pub struct Pipe {
     write_closed: bool,
     readers: u32,
     writers: u32,
+    // Threads sleeping until there is data to read, or room to write
+    read_waiters: WaitQueue,
+    write_waiters: WaitQueue,
 }
 
 impl Pipe {
@@ -27,12 +33,17 @@ impl Pipe {
             write_closed: false,
             readers: 0,
             writers: 0,
+            read_waiters: WaitQueue::new(),
+            write_waiters: WaitQueue::new(),
         }
     }
     
-    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, KernelError> {
         if self.read_closed {
-            return Err("Pipe read end closed");
+            return Err(KernelError::BrokenPipe);
+        }
+        if buf.is_empty() {
+            return Ok(0);
         }
         
         let bytes_to_read = core::cmp::min(buf.len(), self.buffer.len());
@@ -41,7 +52,7 @@ impl Pipe {
             if self.writers == 0 {
                 return Ok(0); // EOF - no writers left
             } else {
-                return Err("Would block"); // No data available but writers exist
+                return Err(KernelError::WouldBlock); // No data available but writers exist
             }
         }
         
@@ -52,18 +63,21 @@ impl Pipe {
         Ok(bytes_to_read)
     }
     
-    pub fn write(&mut self, buf: &[u8]) -> Result<usize, &'static str> {
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, KernelError> {
         if self.write_closed {
-            return Err("Pipe write end closed");
+            return Err(KernelError::BrokenPipe);
         }
         
         if self.readers == 0 {
-            return Err("Broken pipe"); // SIGPIPE in real systems
+            return Err(KernelError::BrokenPipe);
+        }
+        if buf.is_empty() {
+            return Ok(0);
         }
         
         let available_space = PIPE_BUFFER_SIZE - self.buffer.len();
         if available_space == 0 {
-            return Err("Would block"); // Pipe buffer full
+            return Err(KernelError::WouldBlock); // Pipe buffer full
         }
         
         let bytes_to_write = core::cmp::min(buf.len(), available_space);
@@ -75,18 +89,20 @@ impl Pipe {
         Ok(bytes_to_write)
     }
     
+    // An end only closes once every descriptor referring to it (dup'd or
+    // inherited across fork) has been closed
     pub fn close_read(&mut self) {
-        self.read_closed = true;
         if self.readers > 0 {
             self.readers -= 1;
         }
+        self.read_closed = self.readers == 0;
     }
     
     pub fn close_write(&mut self) {
-        self.write_closed = true;
         if self.writers > 0 {
             self.writers -= 1;
         }
+        self.write_closed = self.writers == 0;
     }
     
     pub fn add_reader(&mut self) {
@@ -101,6 +117,8 @@ impl Pipe {
 pub struct IPCManager {
     pipes: BTreeMap<u32, Pipe>,
     next_pipe_id: u32,
+    // Threads to wake once the IPC lock is released
+    woken: Vec<u32>,
 }
 
 impl IPCManager {
@@ -108,10 +126,11 @@ impl IPCManager {
         IPCManager {
             pipes: BTreeMap::new(),
             next_pipe_id: 1,
+            woken: Vec::new(),
         }
     }
     
-    pub fn create_pipe(&mut self) -> Result<(i32, i32), &'static str> {
+    pub fn create_pipe(&mut self) -> u32 {
         let pipe_id = self.next_pipe_id;
         self.next_pipe_id += 1;
         
@@ -120,24 +139,58 @@ impl IPCManager {
         pipe.add_writer();
         
         self.pipes.insert(pipe_id, pipe);
-        
-        // Create file descriptors for the pipe
-        crate::fs::create_pipe_fds(pipe_id)
+        pipe_id
+    }
+    
+    pub fn add_reader(&mut self, pipe_id: u32) -> Result<(), KernelError> {
+        self.pipes.get_mut(&pipe_id).ok_or(KernelError::BadFileDescriptor)?.add_reader();
+        Ok(())
     }
     
-    pub fn read_pipe(&mut self, pipe_id: u32, buf: &mut [u8]) -> Result<usize, &'static str> {
-        let pipe = self.pipes.get_mut(&pipe_id).ok_or("Invalid pipe")?;
-        pipe.read(buf)
+    pub fn add_writer(&mut self, pipe_id: u32) -> Result<(), KernelError> {
+        self.pipes.get_mut(&pipe_id).ok_or(KernelError::BadFileDescriptor)?.add_writer();
+        Ok(())
+    }
+    
+    // A read or write that would block queues `waiter`, if given, to be
+    // woken when the other end makes progress or closes
+    pub fn read_pipe(&mut self, pipe_id: u32, buf: &mut [u8], waiter: Option<u32>) -> Result<usize, KernelError> {
+        let pipe = self.pipes.get_mut(&pipe_id).ok_or(KernelError::BadFileDescriptor)?;
+        let result = pipe.read(buf);
+        match result {
+            Ok(count) if count > 0 => self.woken.append(&mut pipe.write_waiters.take()),
+            Err(KernelError::WouldBlock) => {
+                if let Some(pid) = waiter {
+                    pipe.read_waiters.add(pid);
+                }
+            }
+            _ => {}
+        }
+        result
     }
     
-    pub fn write_pipe(&mut self, pipe_id: u32, buf: &[u8]) -> Result<usize, &'static str> {
-        let pipe = self.pipes.get_mut(&pipe_id).ok_or("Invalid pipe")?;
-        pipe.write(buf)
+    pub fn write_pipe(&mut self, pipe_id: u32, buf: &[u8], waiter: Option<u32>) -> Result<usize, KernelError> {
+        let pipe = self.pipes.get_mut(&pipe_id).ok_or(KernelError::BadFileDescriptor)?;
+        let result = pipe.write(buf);
+        match result {
+            Ok(count) if count > 0 => self.woken.append(&mut pipe.read_waiters.take()),
+            Err(KernelError::WouldBlock) => {
+                if let Some(pid) = waiter {
+                    pipe.write_waiters.add(pid);
+                }
+            }
+            _ => {}
+        }
+        result
     }
     
-    pub fn close_pipe_read(&mut self, pipe_id: u32) -> Result<(), &'static str> {
-        let pipe = self.pipes.get_mut(&pipe_id).ok_or("Invalid pipe")?;
+    pub fn close_pipe_read(&mut self, pipe_id: u32) -> Result<(), KernelError> {
+        let pipe = self.pipes.get_mut(&pipe_id).ok_or(KernelError::BadFileDescriptor)?;
         pipe.close_read();
+        // Blocked writers get a broken pipe
+        if pipe.read_closed {
+            self.woken.append(&mut pipe.write_waiters.take());
+        }
         
         // Remove pipe if both ends are closed
         if pipe.read_closed && pipe.write_closed {
@@ -147,9 +200,13 @@ impl IPCManager {
         Ok(())
     }
     
-    pub fn close_pipe_write(&mut self, pipe_id: u32) -> Result<(), &'static str> {
-        let pipe = self.pipes.get_mut(&pipe_id).ok_or("Invalid pipe")?;
+    pub fn close_pipe_write(&mut self, pipe_id: u32) -> Result<(), KernelError> {
+        let pipe = self.pipes.get_mut(&pipe_id).ok_or(KernelError::BadFileDescriptor)?;
         pipe.close_write();
+        // Blocked readers see end of file
+        if pipe.write_closed {
+            self.woken.append(&mut pipe.read_waiters.take());
+        }
         
         // Remove pipe if both ends are closed
         if pipe.read_closed && pipe.write_closed {
@@ -214,8 +271,8 @@ impl SharedMemoryManager {
         id
     }
     
-    pub fn attach_segment(&mut self, segment_id: u32, process_id: u32) -> Result<*mut u8, &'static str> {
-        let segment = self.segments.get_mut(&segment_id).ok_or("Invalid segment")?;
+    pub fn attach_segment(&mut self, segment_id: u32, process_id: u32) -> Result<*mut u8, KernelError> {
+        let segment = self.segments.get_mut(&segment_id).ok_or(KernelError::InvalidArgument)?;
         
         if !segment.attached_processes.contains(&process_id) {
             segment.attached_processes.push(process_id);
@@ -224,8 +281,8 @@ impl SharedMemoryManager {
         Ok(segment.data.as_mut_ptr())
     }
     
-    pub fn detach_segment(&mut self, segment_id: u32, process_id: u32) -> Result<(), &'static str> {
-        let segment = self.segments.get_mut(&segment_id).ok_or("Invalid segment")?;
+    pub fn detach_segment(&mut self, segment_id: u32, process_id: u32) -> Result<(), KernelError> {
+        let segment = self.segments.get_mut(&segment_id).ok_or(KernelError::InvalidArgument)?;
         
         segment.attached_processes.retain(|&pid| pid != process_id);
         
@@ -235,8 +292,8 @@ impl SharedMemoryManager {
         Ok(())
     }
     
-    pub fn delete_segment(&mut self, segment_id: u32) -> Result<(), &'static str> {
-        self.segments.remove(&segment_id).ok_or("Invalid segment")?;
+    pub fn delete_segment(&mut self, segment_id: u32) -> Result<(), KernelError> {
+        self.segments.remove(&segment_id).ok_or(KernelError::InvalidArgument)?;
         Ok(())
     }
 }
@@ -250,24 +307,63 @@ pub fn init() {
     // IPC managers are initialized statically
 }
 
-pub fn create_pipe() -> Result<(i32, i32), &'static str> {
-    IPC_MANAGER.lock().create_pipe()
+pub fn create_pipe() -> Result<(i32, i32), KernelError> {
+    create_pipe_with_flags(0)
+}
+
+/// Creates a pipe as pipe2 does. O_NONBLOCK is the only flag.
+pub fn create_pipe_with_flags(flags: i32) -> Result<(i32, i32), KernelError> {
+    let flags = OpenFlags::from_bits(flags & !crate::fs::O_CLOEXEC)
+        .filter(|flags| OpenFlags::O_NONBLOCK.contains(flags.clone()))
+        .ok_or(KernelError::InvalidArgument)?;
+    let pipe_id = IPC_MANAGER.lock().create_pipe();
+    
+    // Create file descriptors for the pipe once the IPC lock is dropped
+    crate::fs::create_pipe_fds(pipe_id, flags).inspect_err(|_| {
+        let _ = close_pipe_read(pipe_id);
+        let _ = close_pipe_write(pipe_id);
+    })
+}
+
+pub fn add_pipe_reader(pipe_id: u32) -> Result<(), KernelError> {
+    IPC_MANAGER.lock().add_reader(pipe_id)
+}
+
+pub fn add_pipe_writer(pipe_id: u32) -> Result<(), KernelError> {
+    IPC_MANAGER.lock().add_writer(pipe_id)
+}
+
+/// Reads what the pipe holds. With it empty, fails with `WouldBlock` after
+/// queueing `waiter` to be woken by a write or the last writer closing.
+pub fn read_pipe(pipe_id: u32, buf: &mut [u8], waiter: Option<u32>) -> Result<usize, KernelError> {
+    with_manager(|manager| manager.read_pipe(pipe_id, buf, waiter))
 }
 
-pub fn read_pipe(pipe_id: u32, buf: &mut [u8]) -> Result<usize, &'static str> {
-    IPC_MANAGER.lock().read_pipe(pipe_id, buf)
+/// Writes as much of `buf` as fits. With the pipe full, fails with
+/// `WouldBlock` after queueing `waiter` to be woken by a read or the last
+/// reader closing.
+pub fn write_pipe(pipe_id: u32, buf: &[u8], waiter: Option<u32>) -> Result<usize, KernelError> {
+    with_manager(|manager| manager.write_pipe(pipe_id, buf, waiter))
 }
 
-pub fn write_pipe(pipe_id: u32, buf: &[u8]) -> Result<usize, &'static str> {
-    IPC_MANAGER.lock().write_pipe(pipe_id, buf)
+pub fn close_pipe_read(pipe_id: u32) -> Result<(), KernelError> {
+    with_manager(|manager| manager.close_pipe_read(pipe_id))
 }
 
-pub fn close_pipe_read(pipe_id: u32) -> Result<(), &'static str> {
-    IPC_MANAGER.lock().close_pipe_read(pipe_id)
+pub fn close_pipe_write(pipe_id: u32) -> Result<(), KernelError> {
+    with_manager(|manager| manager.close_pipe_write(pipe_id))
 }
 
-pub fn close_pipe_write(pipe_id: u32) -> Result<(), &'static str> {
-    IPC_MANAGER.lock().close_pipe_write(pipe_id)
+// Runs `f` with the IPC lock held, then wakes the threads it made
+// runnable once the lock is dropped
+fn with_manager<T>(f: impl FnOnce(&mut IPCManager) -> T) -> T {
+    let (result, woken) = {
+        let mut manager = IPC_MANAGER.lock();
+        let result = f(&mut manager);
+        (result, core::mem::take(&mut manager.woken))
+    };
+    wait_queue::wake(woken);
+    result
 }
 
 // Shared memory system calls
@@ -276,14 +372,14 @@ pub fn sys_shmget(size: usize, _flags: i32) -> u32 {
     SHMEM_MANAGER.lock().create_segment(size, permissions)
 }
 
-pub fn sys_shmat(segment_id: u32, process_id: u32) -> Result<*mut u8, &'static str> {
+pub fn sys_shmat(segment_id: u32, process_id: u32) -> Result<*mut u8, KernelError> {
     SHMEM_MANAGER.lock().attach_segment(segment_id, process_id)
 }
 
-pub fn sys_shmdt(segment_id: u32, process_id: u32) -> Result<(), &'static str> {
+pub fn sys_shmdt(segment_id: u32, process_id: u32) -> Result<(), KernelError> {
     SHMEM_MANAGER.lock().detach_segment(segment_id, process_id)
 }
 
-pub fn sys_shmctl_delete(segment_id: u32) -> Result<(), &'static str> {
+pub fn sys_shmctl_delete(segment_id: u32) -> Result<(), KernelError> {
     SHMEM_MANAGER.lock().delete_segment(segment_id)
 }
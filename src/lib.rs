@@ -6,10 +6,24 @@
 
 extern crate alloc;
 
+pub mod error;
 pub mod memory;
+pub mod heap;
+pub mod mmu;
+pub mod fdt;
+pub mod exception;
+pub mod gic;
+pub mod timer;
+pub mod context;
 pub mod uart;
 pub mod process;
+pub mod scheduler;
+pub mod psci;
+pub mod smp;
+pub mod signal;
+pub mod wait_queue;
 pub mod syscall;
+pub mod uaccess;
 pub mod fs;
 pub mod ipc;
 pub mod userspace;
@@ -7,10 +7,24 @@
 extern crate alloc;
 
 use core::panic::PanicInfo;
+mod error;
 mod memory;
+mod heap;
+mod mmu;
+mod fdt;
+mod exception;
+mod gic;
+mod timer;
+mod context;
 mod uart;
 mod process;
+mod scheduler;
+mod psci;
+mod smp;
+mod signal;
+mod wait_queue;
 mod syscall;
+mod uaccess;
 mod fs;
 mod ipc;
 mod userspace;
@@ -44,6 +58,21 @@ pub extern "C" fn kernel_main() -> ! {
     syscall::init();
     println!("System call interface initialized");
     
+    // Initialize the interrupt controller and the scheduler tick
+    match gic::init().and_then(|_| timer::init()) {
+        Ok(()) => println!("Timer initialized at {} Hz", timer::tick_rate()),
+        Err(e) => println!("Warning: no timer interrupts ({}), scheduling is cooperative", e),
+    }
+    if let Err(e) = uart::init_interrupts() {
+        println!("Warning: no console input ({})", e);
+    }
+    
+    // Start the other CPUs, which schedule from run queues of their own
+    match smp::start_secondaries() {
+        Ok(started) => println!("{} CPUs online", started + 1),
+        Err(e) => println!("Warning: running on the boot CPU only ({})", e),
+    }
+    
     // Initialize file system abstraction
     fs::init();
     println!("File system abstraction initialized");
@@ -104,6 +133,11 @@ pub extern "C" fn kernel_main() -> ! {
         
         // Process scheduling and system calls
         process::schedule();
+        
+        // Nothing is runnable; sleep until the next interrupt
+        if timer::irq().is_some() {
+            timer::wait_for_interrupt();
+        }
     }
 }
 
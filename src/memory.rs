@@ -1,23 +1,143 @@
 #![allow(dead_code)]
 
-use linked_list_allocator::LockedHeap;
-
-#[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::error::KernelError;
 
 extern "C" {
-    static __heap_start: u8;
-    static __heap_end: u8;
+    static __kernel_start: u8;
+    static __kernel_end: u8;
 }
 
+pub const PAGE_SIZE: usize = 4096;
+
+// QEMU virt machine places RAM at 1 GiB; 128 MiB is QEMU's default size
+const RAM_BASE: u64 = 0x4000_0000;
+const DEFAULT_RAM_SIZE: u64 = 128 * 1024 * 1024;
+
+// All of physical memory is mapped at this offset in the TTBR1 half, and
+// the kernel image runs from inside that linear map (see linker.ld)
+pub const PHYS_OFFSET: u64 = 0xffff_0000_0000_0000;
+
+pub const MAX_MEMORY_REGIONS: usize = 32;
+
+static FRAME_ALLOCATOR: Mutex<BitmapFrameAllocator> = Mutex::new(BitmapFrameAllocator::empty());
+static MEMORY_MAP: Mutex<heapless::Vec<MemoryRegion, MAX_MEMORY_REGIONS>> = Mutex::new(heapless::Vec::new());
+
 pub fn init() {
-    let heap_start = unsafe { &__heap_start as *const u8 as usize };
-    let heap_end = unsafe { &__heap_end as *const u8 as usize };
-    let heap_size = heap_end - heap_start;
+    crate::heap::init_early();
     
+    crate::fdt::init();
+    let memory_map = platform_memory_map().unwrap_or_else(default_memory_map);
     unsafe {
-        ALLOCATOR.lock().init(heap_start as *mut u8, heap_size);
+        FRAME_ALLOCATOR.lock().init(&memory_map);
+    }
+    
+    if let Err(e) = crate::mmu::init(&memory_map) {
+        panic!("Failed to build kernel address space: {}", e);
+    }
+    *MEMORY_MAP.lock() = memory_map;
+    crate::heap::init();
+}
+
+pub fn memory_map() -> heapless::Vec<MemoryRegion, MAX_MEMORY_REGIONS> {
+    MEMORY_MAP.lock().clone()
+}
+
+fn kernel_image_range() -> (u64, u64) {
+    let start = virt_to_phys(VirtAddr::new(unsafe { &__kernel_start as *const u8 as u64 })).as_u64();
+    let end = virt_to_phys(VirtAddr::new(unsafe { &__kernel_end as *const u8 as u64 })).as_u64();
+    (start, end)
+}
+
+// RAM reported by the device tree, minus the kernel image, the DTB itself
+// and any firmware reservations
+fn platform_memory_map() -> Option<heapless::Vec<MemoryRegion, MAX_MEMORY_REGIONS>> {
+    let platform = crate::fdt::platform();
+    if platform.memory.is_empty() {
+        return None;
+    }
+    
+    let (kernel_start, kernel_end) = kernel_image_range();
+    let mut reserved: heapless::Vec<(u64, u64), { crate::fdt::MAX_PLATFORM_REGIONS + 1 }> = heapless::Vec::new();
+    let _ = reserved.push((kernel_start, kernel_end - kernel_start));
+    for &region in platform.reserved.iter() {
+        let _ = reserved.push(region);
     }
+    
+    Some(build_memory_map(&platform.memory, &reserved))
+}
+
+// Without a device tree assume QEMU's default RAM size and reserve
+// everything below the end of the kernel image, where QEMU puts the DTB.
+fn default_memory_map() -> heapless::Vec<MemoryRegion, MAX_MEMORY_REGIONS> {
+    let (_, kernel_end) = kernel_image_range();
+    build_memory_map(&[(RAM_BASE, DEFAULT_RAM_SIZE)], &[(RAM_BASE, kernel_end - RAM_BASE)])
+}
+
+/// Splits RAM ranges into disjoint usable and reserved regions. Reserved
+/// ranges are widened to page boundaries; parts outside RAM are dropped.
+pub fn build_memory_map(ram: &[(u64, u64)], reserved: &[(u64, u64)]) -> heapless::Vec<MemoryRegion, MAX_MEMORY_REGIONS> {
+    let mut reserved: heapless::Vec<(u64, u64), MAX_MEMORY_REGIONS> = reserved
+        .iter()
+        .filter(|&&(_, size)| size > 0)
+        .map(|&(start, size)| (align_down(start, PAGE_SIZE as u64), align_up(start + size, PAGE_SIZE as u64)))
+        .collect();
+    reserved.sort_unstable();
+    
+    let mut map = heapless::Vec::new();
+    let mut push = |start: u64, end: u64, region_type: MemoryRegionType| {
+        if end > start {
+            let _ = map.push(MemoryRegion { start, size: end - start, region_type });
+        }
+    };
+    
+    for &(ram_start, ram_size) in ram {
+        let start = align_up(ram_start, PAGE_SIZE as u64);
+        let end = align_down(ram_start + ram_size, PAGE_SIZE as u64);
+        let mut cursor = start;
+        
+        for &(reserved_start, reserved_end) in reserved.iter() {
+            if reserved_end <= cursor || reserved_start >= end {
+                continue;
+            }
+            push(cursor, reserved_start.max(cursor), MemoryRegionType::Usable);
+            push(reserved_start.max(cursor), reserved_end.min(end), MemoryRegionType::Reserved);
+            cursor = reserved_end.min(end);
+        }
+        push(cursor, end, MemoryRegionType::Usable);
+    }
+    
+    map
+}
+
+pub fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
+
+/// `align_up` for values that may come from user space, None past u64::MAX
+pub fn checked_align_up(value: u64, align: u64) -> Option<u64> {
+    value.checked_add(align - 1).map(|value| value & !(align - 1))
+}
+
+pub fn align_down(value: u64, align: u64) -> u64 {
+    value & !(align - 1)
+}
+
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(addr.as_u64() + PHYS_OFFSET)
+}
+
+pub fn virt_to_phys(addr: VirtAddr) -> PhysAddr {
+    PhysAddr::new(addr.as_u64() - PHYS_OFFSET)
+}
+
+pub trait FrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame>;
+}
+
+pub trait FrameDeallocator {
+    fn deallocate_frame(&mut self, frame: PhysFrame);
 }
 
 pub struct BootInfoFrameAllocator {
@@ -57,6 +177,288 @@ impl BootInfoFrameAllocator {
     }
 }
 
+impl FrameAllocator for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.usable_frames().nth(self.next);
+        self.next += 1;
+        frame
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    pub total_frames: usize,
+    pub free_frames: usize,
+    pub used_frames: usize,
+}
+
+// Bitmap frame allocator covering every frame between the lowest and the
+// highest usable address. A set bit means the frame is in use (or not RAM).
+// Each frame also has a reference count so pages can be shared between
+// address spaces. Both tables live in the first usable region large enough
+// to hold them.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    refcounts: &'static mut [u16],
+    base: u64,
+    frame_count: usize,
+    usable_frames: usize,
+    free_frames: usize,
+    next_free: usize,
+    // Frames still allowed out, for tests that run code out of memory
+    limit: Option<usize>,
+}
+
+impl BitmapFrameAllocator {
+    pub const fn empty() -> Self {
+        BitmapFrameAllocator {
+            bitmap: &mut [],
+            refcounts: &mut [],
+            base: 0,
+            frame_count: 0,
+            usable_frames: 0,
+            free_frames: 0,
+            next_free: 0,
+            limit: None,
+        }
+    }
+    
+    /// Builds the allocator from a memory map.
+    ///
+    /// # Safety
+    ///
+    /// Every usable region must be real, unused RAM reachable through
+    /// `phys_to_virt`; part of it is overwritten with the allocator's tables.
+    pub unsafe fn init(&mut self, memory_map: &[MemoryRegion]) {
+        let usable = || memory_map.iter().filter(|r| r.region_type == MemoryRegionType::Usable);
+        
+        let base = match usable().map(|r| align_up(r.start, PAGE_SIZE as u64)).min() {
+            Some(base) => base,
+            None => return,
+        };
+        let end = usable().map(|r| align_down(r.start + r.size, PAGE_SIZE as u64)).max().unwrap_or(base);
+        let frame_count = ((end - base) / PAGE_SIZE as u64) as usize;
+        let words = frame_count.div_ceil(64);
+        let bitmap_bytes = align_up((words * 8 + frame_count * 2) as u64, PAGE_SIZE as u64);
+        
+        let bitmap_region = match usable().find(|r| {
+            align_down(r.start + r.size, PAGE_SIZE as u64) >= align_up(r.start, PAGE_SIZE as u64) + bitmap_bytes
+        }) {
+            Some(region) => align_up(region.start, PAGE_SIZE as u64),
+            None => return,
+        };
+        
+        let bitmap_ptr = phys_to_virt(PhysAddr::new(bitmap_region)).as_u64() as *mut u64;
+        self.bitmap = core::slice::from_raw_parts_mut(bitmap_ptr, words);
+        self.bitmap.fill(u64::MAX);
+        self.refcounts = core::slice::from_raw_parts_mut(bitmap_ptr.add(words) as *mut u16, frame_count);
+        self.refcounts.fill(0);
+        self.base = base;
+        self.frame_count = frame_count;
+        self.usable_frames = 0;
+        self.free_frames = 0;
+        self.next_free = 0;
+        
+        for region in usable() {
+            let start = align_up(region.start, PAGE_SIZE as u64);
+            let end = align_down(region.start + region.size, PAGE_SIZE as u64);
+            if end <= start {
+                continue;
+            }
+            let first = self.index_of(start);
+            let count = ((end - start) / PAGE_SIZE as u64) as usize;
+            for index in first..first + count {
+                if self.is_used(index) {
+                    self.clear(index);
+                    self.usable_frames += 1;
+                    self.free_frames += 1;
+                }
+            }
+        }
+        
+        // Reserve the frames holding the bitmap and reference counts
+        let bitmap_first = self.index_of(bitmap_region);
+        let bitmap_frames = (bitmap_bytes / PAGE_SIZE as u64) as usize;
+        for index in bitmap_first..bitmap_first + bitmap_frames {
+            self.set(index);
+            self.free_frames -= 1;
+        }
+    }
+    
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        self.allocate_contiguous_aligned(count, 1)
+    }
+    
+    /// Like `allocate_contiguous`, but the first frame's physical address is
+    /// a multiple of `align` frames
+    pub fn allocate_contiguous_aligned(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        if count == 0 || count > self.free_frames || align == 0 {
+            return None;
+        }
+        if self.limit.is_some_and(|limit| count > limit) {
+            return None;
+        }
+        
+        let start = self.find_free_run(self.next_free, count, align)
+            .or_else(|| self.find_free_run(0, count, align))?;
+        
+        for index in start..start + count {
+            self.set(index);
+            self.refcounts[index] = 1;
+        }
+        self.free_frames -= count;
+        self.next_free = start + count;
+        if let Some(limit) = &mut self.limit {
+            *limit -= count;
+        }
+        
+        Some(PhysFrame::containing_address(self.address_of(start)))
+    }
+    
+    pub fn deallocate_contiguous(&mut self, frame: PhysFrame, count: usize) -> Result<(), KernelError> {
+        let addr = frame.start_address().as_u64();
+        if addr < self.base {
+            return Err(KernelError::InvalidArgument);
+        }
+        let first = self.index_of(addr);
+        if first + count > self.frame_count {
+            return Err(KernelError::InvalidArgument);
+        }
+        if (first..first + count).any(|index| !self.is_used(index)) {
+            return Err(KernelError::InvalidArgument);
+        }
+        
+        for index in first..first + count {
+            self.clear(index);
+            self.refcounts[index] = 0;
+        }
+        self.free_frames += count;
+        if first < self.next_free {
+            self.next_free = first;
+        }
+        
+        Ok(())
+    }
+    
+    /// Adds a reference to an allocated frame that is about to be shared
+    pub fn share(&mut self, frame: PhysFrame) -> Result<(), KernelError> {
+        let index = self.allocated_index(frame)?;
+        self.refcounts[index] = self.refcounts[index].checked_add(1).ok_or(KernelError::OutOfMemory)?;
+        Ok(())
+    }
+    
+    /// Drops a reference and frees the frame once nobody uses it. Returns
+    /// whether the frame was freed.
+    pub fn release(&mut self, frame: PhysFrame) -> Result<bool, KernelError> {
+        let index = self.allocated_index(frame)?;
+        if self.refcounts[index] > 1 {
+            self.refcounts[index] -= 1;
+            return Ok(false);
+        }
+        self.deallocate_contiguous(frame, 1)?;
+        Ok(true)
+    }
+    
+    pub fn ref_count(&self, frame: PhysFrame) -> usize {
+        self.allocated_index(frame).map(|index| self.refcounts[index] as usize).unwrap_or(0)
+    }
+    
+    fn allocated_index(&self, frame: PhysFrame) -> Result<usize, KernelError> {
+        let addr = frame.start_address().as_u64();
+        if addr < self.base || self.index_of(addr) >= self.frame_count {
+            return Err(KernelError::InvalidArgument);
+        }
+        let index = self.index_of(addr);
+        if !self.is_used(index) {
+            return Err(KernelError::InvalidArgument);
+        }
+        Ok(index)
+    }
+    
+    /// Fails allocations once `limit` more frames have been handed out,
+    /// until the limit is lifted with None
+    pub fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+    }
+    
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total_frames: self.usable_frames,
+            free_frames: self.free_frames,
+            used_frames: self.usable_frames - self.free_frames,
+        }
+    }
+    
+    fn find_free_run(&self, from: usize, count: usize, align: usize) -> Option<usize> {
+        let mut index = from;
+        let mut run_start = from;
+        let mut run_length = 0;
+        
+        while index < self.frame_count {
+            if run_length == 0 {
+                // Runs only start on an aligned physical frame
+                let misalignment = ((self.base / PAGE_SIZE as u64 + index as u64) % align as u64) as usize;
+                if misalignment != 0 {
+                    index += align - misalignment;
+                    run_start = index;
+                    continue;
+                }
+                // Skip over fully used words quickly
+                if index.is_multiple_of(64) && self.bitmap[index / 64] == u64::MAX {
+                    index += 64;
+                    run_start = index;
+                    continue;
+                }
+            }
+            
+            if self.is_used(index) {
+                run_length = 0;
+                run_start = index + 1;
+            } else {
+                run_length += 1;
+                if run_length == count {
+                    return Some(run_start);
+                }
+            }
+            index += 1;
+        }
+        
+        None
+    }
+    
+    fn index_of(&self, addr: u64) -> usize {
+        ((addr - self.base) / PAGE_SIZE as u64) as usize
+    }
+    
+    fn address_of(&self, index: usize) -> PhysAddr {
+        PhysAddr::new(self.base + (index * PAGE_SIZE) as u64)
+    }
+    
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / 64] & (1 << (index % 64)) != 0
+    }
+    
+    fn set(&mut self, index: usize) {
+        self.bitmap[index / 64] |= 1 << (index % 64);
+    }
+    
+    fn clear(&mut self, index: usize) {
+        self.bitmap[index / 64] &= !(1 << (index % 64));
+    }
+}
+
+impl FrameAllocator for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate_contiguous(1)
+    }
+}
+
+impl FrameDeallocator for BitmapFrameAllocator {
+    fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let _ = self.release(frame);
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct PhysAddr(u64);
 
@@ -81,6 +483,15 @@ impl VirtAddr {
     pub fn as_u64(self) -> u64 {
         self.0
     }
+    
+    // Index into the translation table at `level` (0 = root, 3 = leaf)
+    pub fn page_table_index(self, level: usize) -> usize {
+        ((self.0 >> (12 + 9 * (3 - level))) & 0x1ff) as usize
+    }
+    
+    pub fn page_offset(self) -> u64 {
+        self.0 & 0xfff
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -115,14 +526,92 @@ impl Page {
     pub fn start_address(self) -> VirtAddr {
         self.start_address
     }
+    
+    pub fn next(self) -> Page {
+        Page::containing_address(VirtAddr::new(self.start_address.as_u64() + PAGE_SIZE as u64))
+    }
 }
 
-// Simple page table management for ARM64
+// AArch64 translation tables: 4 KiB granule, 48-bit virtual addresses,
+// four levels of 512 entries each.
+
+// MAIR_EL1 attribute indices referenced by the AttrIndx descriptor field
+pub const MAIR_IDX_NORMAL: u64 = 0;
+pub const MAIR_IDX_DEVICE: u64 = 1;
+pub const MAIR_IDX_NORMAL_NC: u64 = 2;
+
+// Normal write-back RW-allocate, Device-nGnRE, Normal non-cacheable
+pub const MAIR_VALUE: u64 = (0xff << (8 * MAIR_IDX_NORMAL))
+    | (0x04 << (8 * MAIR_IDX_DEVICE))
+    | (0x44 << (8 * MAIR_IDX_NORMAL_NC));
+
+const DESCRIPTOR_ADDR_MASK: u64 = 0x0000_ffff_ffff_f000;
+
+/// Sizes a single descriptor can map: a page at level 3 or a block at
+/// level 2 or 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+impl PageSize {
+    pub const fn bytes(self) -> u64 {
+        match self {
+            PageSize::Size4KiB => 1 << 12,
+            PageSize::Size2MiB => 1 << 21,
+            PageSize::Size1GiB => 1 << 30,
+        }
+    }
+    
+    // Translation table level holding descriptors of this size
+    pub const fn level(self) -> usize {
+        match self {
+            PageSize::Size4KiB => 3,
+            PageSize::Size2MiB => 2,
+            PageSize::Size1GiB => 1,
+        }
+    }
+    
+    fn at_level(level: usize) -> Self {
+        match level {
+            1 => PageSize::Size1GiB,
+            2 => PageSize::Size2MiB,
+            _ => PageSize::Size4KiB,
+        }
+    }
+}
+
+#[repr(C, align(4096))]
 pub struct PageTable {
     entries: [PageTableEntry; 512],
 }
 
+impl PageTable {
+    pub fn zero(&mut self) {
+        for entry in self.entries.iter_mut() {
+            entry.set_unused();
+        }
+    }
+}
+
+impl core::ops::Index<usize> for PageTable {
+    type Output = PageTableEntry;
+    
+    fn index(&self, index: usize) -> &PageTableEntry {
+        &self.entries[index]
+    }
+}
+
+impl core::ops::IndexMut<usize> for PageTable {
+    fn index_mut(&mut self, index: usize) -> &mut PageTableEntry {
+        &mut self.entries[index]
+    }
+}
+
 #[derive(Clone, Copy)]
+#[repr(transparent)]
 pub struct PageTableEntry {
     entry: u64,
 }
@@ -136,39 +625,583 @@ impl PageTableEntry {
         self.entry == 0
     }
     
+    pub fn is_valid(&self) -> bool {
+        self.entry & PageTableFlags::VALID.bits() != 0
+    }
+    
+    // Only meaningful for levels 0-2; at level 3 the same bit marks a page
+    pub fn is_table(&self) -> bool {
+        self.is_valid() && self.entry & PageTableFlags::TABLE.bits() != 0
+    }
+    
+    pub fn addr(&self) -> PhysAddr {
+        PhysAddr::new(self.entry & DESCRIPTOR_ADDR_MASK)
+    }
+    
+    pub fn flags(&self) -> PageTableFlags {
+        PageTableFlags::from_bits_truncate(self.entry & !DESCRIPTOR_ADDR_MASK)
+    }
+    
+    pub fn raw(&self) -> u64 {
+        self.entry
+    }
+    
+    pub fn set_addr(&mut self, addr: PhysAddr, flags: PageTableFlags) {
+        self.entry = (addr.as_u64() & DESCRIPTOR_ADDR_MASK) | flags.bits();
+    }
+    
     pub fn set_frame(&mut self, frame: PhysFrame, flags: PageTableFlags) {
-        self.entry = frame.start_address().as_u64() | flags.bits();
+        self.set_addr(frame.start_address(), flags);
+    }
+    
+    pub fn set_flags(&mut self, flags: PageTableFlags) {
+        self.entry = (self.entry & DESCRIPTOR_ADDR_MASK) | flags.bits();
+    }
+    
+    pub fn set_unused(&mut self) {
+        self.entry = 0;
     }
 }
 
 bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct PageTableFlags: u64 {
-        const PRESENT = 1;
-        const WRITABLE = 1 << 1;
-        const USER_ACCESSIBLE = 1 << 2;
-        const WRITE_THROUGH = 1 << 3;
-        const NO_CACHE = 1 << 4;
-        const ACCESSED = 1 << 5;
-        const DIRTY = 1 << 6;
-        const HUGE_PAGE = 1 << 7;
-        const GLOBAL = 1 << 8;
-        const NO_EXECUTE = 1 << 63;
+        const VALID = 1;
+        // Table descriptor at levels 0-2, page descriptor at level 3
+        const TABLE = 1 << 1;
+        const PAGE = 1 << 1;
+        // AttrIndx[2:0], selects a MAIR_EL1 attribute
+        const ATTR_NORMAL = MAIR_IDX_NORMAL << 2;
+        const ATTR_DEVICE = MAIR_IDX_DEVICE << 2;
+        const ATTR_NORMAL_NC = MAIR_IDX_NORMAL_NC << 2;
+        // AP[1]: accessible from EL0
+        const USER_ACCESSIBLE = 1 << 6;
+        // AP[2]: read-only at every exception level
+        const READ_ONLY = 1 << 7;
+        const OUTER_SHAREABLE = 2 << 8;
+        const INNER_SHAREABLE = 3 << 8;
+        // Access flag; a clear AF faults on first access
+        const ACCESSED = 1 << 10;
+        // nG: the translation is tagged with the current ASID
+        const NOT_GLOBAL = 1 << 11;
+        const CONTIGUOUS = 1 << 52;
+        // Privileged and unprivileged execute-never
+        const PXN = 1 << 53;
+        const UXN = 1 << 54;
+        // Software bit: read-only because the frame is shared copy-on-write
+        const COPY_ON_WRITE = 1 << 55;
+    }
+}
+
+impl PageTableFlags {
+    /// Kernel read/write data, never executable
+    pub const KERNEL_DATA: Self = Self::ATTR_NORMAL
+        .union(Self::INNER_SHAREABLE)
+        .union(Self::PXN)
+        .union(Self::UXN);
+    
+    /// Kernel read-only data
+    pub const KERNEL_RODATA: Self = Self::KERNEL_DATA.union(Self::READ_ONLY);
+    
+    /// Kernel code: read-only and executable at EL1 only
+    pub const KERNEL_TEXT: Self = Self::ATTR_NORMAL
+        .union(Self::INNER_SHAREABLE)
+        .union(Self::READ_ONLY)
+        .union(Self::UXN);
+    
+    /// Memory-mapped device registers
+    pub const KERNEL_DEVICE: Self = Self::ATTR_DEVICE
+        .union(Self::PXN)
+        .union(Self::UXN);
+    
+    /// Builds user mapping flags from read/write/execute permissions.
+    /// Unreadable user pages are not expressible with AP bits and must be
+    /// left unmapped by the caller.
+    pub fn user(writable: bool, executable: bool) -> Self {
+        let mut flags = Self::ATTR_NORMAL
+            | Self::INNER_SHAREABLE
+            | Self::USER_ACCESSIBLE
+            | Self::NOT_GLOBAL
+            | Self::PXN;
+        if !writable {
+            flags |= Self::READ_ONLY;
+        }
+        if !executable {
+            flags |= Self::UXN;
+        }
+        flags
+    }
+    
+    pub fn attr_index(self) -> u64 {
+        (self.bits() >> 2) & 0x7
+    }
+    
+    pub fn is_writable(self) -> bool {
+        !self.contains(Self::READ_ONLY)
+    }
+    
+    pub fn is_user(self) -> bool {
+        self.contains(Self::USER_ACCESSIBLE)
+    }
+}
+
+/// Walks and edits a four-level translation table through the kernel's
+/// view of physical memory.
+pub struct Mapper {
+    root: PhysFrame,
+    // ASID the tables are installed with, or 0 to invalidate TLB entries
+    // for every ASID
+    asid: u16,
+}
+
+impl Mapper {
+    /// Creates an empty address space with a freshly allocated root table
+    pub fn new(allocator: &mut impl FrameAllocator) -> Result<Self, KernelError> {
+        let root = allocate_table(allocator)?;
+        Ok(Mapper { root, asid: 0 })
+    }
+    
+    /// Wraps an existing root table.
+    ///
+    /// # Safety
+    ///
+    /// `root` must point to a valid level 0 translation table that is not
+    /// edited through another `Mapper` at the same time.
+    pub unsafe fn from_root(root: PhysFrame) -> Self {
+        Mapper { root, asid: 0 }
+    }
+    
+    /// Restricts TLB maintenance for edits to entries tagged with `asid`
+    pub fn with_asid(mut self, asid: u16) -> Self {
+        self.asid = asid;
+        self
+    }
+    
+    pub fn root_frame(&self) -> PhysFrame {
+        self.root
+    }
+    
+    pub fn map_to(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+        allocator: &mut impl FrameAllocator,
+    ) -> Result<(), KernelError> {
+        let entry = self.leaf_entry_create(page, allocator)?;
+        if entry.is_valid() {
+            return Err(KernelError::AlreadyExists);
+        }
+        
+        entry.set_frame(frame, flags | PageTableFlags::VALID | PageTableFlags::PAGE | PageTableFlags::ACCESSED);
+        Ok(())
+    }
+    
+    /// Maps `size` bytes at `virt` to `phys` with a single page or block
+    /// descriptor. Both addresses must be aligned to `size`.
+    pub fn map_block(
+        &mut self,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        size: PageSize,
+        flags: PageTableFlags,
+        allocator: &mut impl FrameAllocator,
+    ) -> Result<(), KernelError> {
+        if !virt.as_u64().is_multiple_of(size.bytes()) || !phys.as_u64().is_multiple_of(size.bytes()) {
+            return Err(KernelError::InvalidArgument);
+        }
+        if size == PageSize::Size4KiB {
+            return self.map_to(Page::containing_address(virt), PhysFrame::containing_address(phys), flags, allocator);
+        }
+        
+        let entry = self.entry_create(virt, size.level(), allocator)?;
+        if entry.is_valid() {
+            return Err(KernelError::AlreadyExists);
+        }
+        
+        // Block descriptors leave bit 1 clear
+        let flags = (flags - PageTableFlags::TABLE) | PageTableFlags::VALID | PageTableFlags::ACCESSED;
+        entry.set_addr(phys, flags);
+        Ok(())
+    }
+    
+    /// Removes the block mapping at `virt` and returns the physical address
+    /// it pointed to
+    pub fn unmap_block(&mut self, virt: VirtAddr, size: PageSize) -> Result<PhysAddr, KernelError> {
+        if size == PageSize::Size4KiB {
+            return self.unmap(Page::containing_address(virt)).map(PhysFrame::start_address);
+        }
+        
+        let (entry, level) = self.entry_mut(virt).ok_or(KernelError::BadAddress)?;
+        if level != size.level() || !virt.as_u64().is_multiple_of(size.bytes()) {
+            return Err(KernelError::InvalidArgument);
+        }
+        
+        let phys = entry.addr();
+        entry.set_unused();
+        // A TLB entry for a block is hit by any address inside it
+        flush_tlb(self.asid, virt);
+        Ok(phys)
+    }
+    
+    /// Size of the page or block mapping covering `addr`
+    pub fn mapping_size(&self, addr: VirtAddr) -> Option<PageSize> {
+        let mut table = unsafe { table_at(self.root) };
+        for level in 0..4 {
+            let entry = &table[addr.page_table_index(level)];
+            if !entry.is_valid() {
+                return None;
+            }
+            if level == 3 || !entry.is_table() {
+                return Some(PageSize::at_level(level));
+            }
+            table = unsafe { table_at(PhysFrame::containing_address(entry.addr())) };
+        }
+        None
+    }
+    
+    pub fn unmap(&mut self, page: Page) -> Result<PhysFrame, KernelError> {
+        let entry = self.leaf_entry_mut(page).ok_or(KernelError::BadAddress)?;
+        if !entry.is_valid() {
+            return Err(KernelError::BadAddress);
+        }
+        
+        let frame = PhysFrame::containing_address(entry.addr());
+        entry.set_unused();
+        flush_tlb(self.asid, page.start_address());
+        Ok(frame)
+    }
+    
+    /// Points an existing mapping at a different frame and returns the old one
+    pub fn remap(&mut self, page: Page, frame: PhysFrame, flags: PageTableFlags) -> Result<PhysFrame, KernelError> {
+        let asid = self.asid;
+        let entry = self.leaf_entry_mut(page).ok_or(KernelError::BadAddress)?;
+        if !entry.is_valid() {
+            return Err(KernelError::BadAddress);
+        }
+        
+        let old = PhysFrame::containing_address(entry.addr());
+        entry.set_unused();
+        flush_tlb(asid, page.start_address());
+        entry.set_frame(frame, flags | PageTableFlags::VALID | PageTableFlags::PAGE | PageTableFlags::ACCESSED);
+        Ok(old)
+    }
+    
+    /// Frees the translation tables, including the root. The pages they map
+    /// are left alone.
+    ///
+    /// # Safety
+    ///
+    /// The tables must not be installed on any CPU or used again.
+    pub unsafe fn free_tables(self, allocator: &mut impl FrameDeallocator) {
+        fn walk(frame: PhysFrame, level: usize, allocator: &mut impl FrameDeallocator) {
+            if level < 3 {
+                let table = unsafe { table_at(frame) };
+                for index in 0..512 {
+                    let entry = &table[index];
+                    if entry.is_valid() && entry.is_table() {
+                        walk(PhysFrame::containing_address(entry.addr()), level + 1, allocator);
+                    }
+                }
+            }
+            allocator.deallocate_frame(frame);
+        }
+        
+        walk(self.root, 0, allocator);
+    }
+    
+    /// Leaf mappings with `start <= address < end`, skipping empty subtrees
+    pub fn mappings(&self, start: VirtAddr, end: VirtAddr) -> Vec<(Page, PhysFrame, PageTableFlags)> {
+        fn walk(
+            table: &PageTable,
+            level: usize,
+            base: u64,
+            start: u64,
+            end: u64,
+            out: &mut Vec<(Page, PhysFrame, PageTableFlags)>,
+        ) {
+            let span = 1u64 << (12 + 9 * (3 - level));
+            for index in 0..512 {
+                let entry_start = base + index as u64 * span;
+                if entry_start >= end || entry_start + span <= start {
+                    continue;
+                }
+                let entry = &table[index];
+                if !entry.is_valid() {
+                    continue;
+                }
+                if level == 3 {
+                    out.push((
+                        Page::containing_address(VirtAddr::new(entry_start)),
+                        PhysFrame::containing_address(entry.addr()),
+                        entry.flags(),
+                    ));
+                } else if entry.is_table() {
+                    let next = unsafe { table_at(PhysFrame::containing_address(entry.addr())) };
+                    walk(next, level + 1, entry_start, start, end, out);
+                }
+            }
+        }
+        
+        let mut out = Vec::new();
+        let root = unsafe { table_at(self.root) };
+        // TTBR0 tables cover the low 48 bits; sign extension does not matter
+        let mask = (1u64 << 48) - 1;
+        walk(root, 0, 0, start.as_u64() & mask, end.as_u64() & mask, &mut out);
+        out
+    }
+    
+    /// Replaces the permission and attribute bits of an existing mapping
+    pub fn update_flags(&mut self, page: Page, flags: PageTableFlags) -> Result<(), KernelError> {
+        let entry = self.leaf_entry_mut(page).ok_or(KernelError::BadAddress)?;
+        if !entry.is_valid() {
+            return Err(KernelError::BadAddress);
+        }
+        
+        entry.set_flags(flags | PageTableFlags::VALID | PageTableFlags::PAGE | PageTableFlags::ACCESSED);
+        flush_tlb(self.asid, page.start_address());
+        Ok(())
+    }
+    
+    pub fn translate_page(&self, page: Page) -> Option<(PhysFrame, PageTableFlags)> {
+        let mut table = unsafe { table_at(self.root) };
+        let addr = page.start_address();
+        
+        for level in 0..4 {
+            let entry = &table[addr.page_table_index(level)];
+            if !entry.is_valid() {
+                return None;
+            }
+            if level == 3 || !entry.is_table() {
+                // Page descriptor, or a block descriptor at level 1/2
+                let block_size = 1u64 << (12 + 9 * (3 - level));
+                let offset = addr.as_u64() & (block_size - 1);
+                let frame = PhysFrame::containing_address(PhysAddr::new(entry.addr().as_u64() + offset));
+                return Some((frame, entry.flags()));
+            }
+            table = unsafe { table_at(PhysFrame::containing_address(entry.addr())) };
+        }
+        
+        None
+    }
+    
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        let (frame, _) = self.translate_page(Page::containing_address(addr))?;
+        Some(PhysAddr::new(frame.start_address().as_u64() + addr.page_offset()))
+    }
+    
+    fn leaf_entry_create(
+        &mut self,
+        page: Page,
+        allocator: &mut impl FrameAllocator,
+    ) -> Result<&mut PageTableEntry, KernelError> {
+        self.entry_create(page.start_address(), 3, allocator)
+    }
+    
+    // Entry for `addr` at `target` level, creating intermediate tables
+    fn entry_create(
+        &mut self,
+        addr: VirtAddr,
+        target: usize,
+        allocator: &mut impl FrameAllocator,
+    ) -> Result<&mut PageTableEntry, KernelError> {
+        let mut table = unsafe { table_at(self.root) };
+        
+        for level in 0..target {
+            let entry = &mut table[addr.page_table_index(level)];
+            if !entry.is_valid() {
+                let frame = allocate_table(allocator)?;
+                entry.set_frame(frame, PageTableFlags::VALID | PageTableFlags::TABLE);
+            } else if !entry.is_table() {
+                return Err(KernelError::InvalidArgument);
+            }
+            table = unsafe { table_at(PhysFrame::containing_address(entry.addr())) };
+        }
+        
+        Ok(&mut table[addr.page_table_index(target)])
+    }
+    
+    // Page or block descriptor covering `addr`, with its level
+    fn entry_mut(&mut self, addr: VirtAddr) -> Option<(&mut PageTableEntry, usize)> {
+        let mut table = unsafe { table_at(self.root) };
+        for level in 0..4 {
+            let entry = &mut table[addr.page_table_index(level)];
+            if !entry.is_valid() {
+                return None;
+            }
+            if level == 3 || !entry.is_table() {
+                return Some((entry, level));
+            }
+            table = unsafe { table_at(PhysFrame::containing_address(entry.addr())) };
+        }
+        None
+    }
+    
+    fn leaf_entry_mut(&mut self, page: Page) -> Option<&mut PageTableEntry> {
+        let mut table = unsafe { table_at(self.root) };
+        let addr = page.start_address();
+        
+        for level in 0..3 {
+            let entry = &table[addr.page_table_index(level)];
+            if !entry.is_table() {
+                return None;
+            }
+            table = unsafe { table_at(PhysFrame::containing_address(entry.addr())) };
+        }
+        
+        Some(&mut table[addr.page_table_index(3)])
+    }
+}
+
+fn allocate_table(allocator: &mut impl FrameAllocator) -> Result<PhysFrame, KernelError> {
+    let frame = allocator.allocate_frame().ok_or(KernelError::OutOfMemory)?;
+    unsafe { table_at(frame) }.zero();
+    Ok(frame)
+}
+
+unsafe fn table_at(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *(phys_to_virt(frame.start_address()).as_u64() as *mut PageTable)
+}
+
+// Mapper edits: a zero ASID means the tables are not tagged with one
+fn flush_tlb(asid: u16, addr: VirtAddr) {
+    if asid == 0 {
+        flush_tlb_page(addr);
+    } else {
+        flush_tlb_page_asid(asid, addr);
+    }
+}
+
+pub fn flush_tlb_page(addr: VirtAddr) {
+    unsafe {
+        core::arch::asm!(
+            "dsb ishst",
+            "tlbi vaae1is, {}",
+            "dsb ish",
+            "isb",
+            in(reg) addr.as_u64() >> 12
+        );
+    }
+}
+
+/// Invalidates the translation of `addr` tagged with `asid` only
+pub fn flush_tlb_page_asid(asid: u16, addr: VirtAddr) {
+    let operand = ((asid as u64) << 48) | ((addr.as_u64() >> 12) & ((1 << 44) - 1));
+    unsafe {
+        core::arch::asm!(
+            "dsb ishst",
+            "tlbi vae1is, {}",
+            "dsb ish",
+            "isb",
+            in(reg) operand
+        );
     }
 }
 
+/// Invalidates every non-global translation tagged with `asid`
+pub fn flush_tlb_asid(asid: u16) {
+    unsafe {
+        core::arch::asm!(
+            "dsb ishst",
+            "tlbi aside1is, {}",
+            "dsb ish",
+            "isb",
+            in(reg) (asid as u64) << 48
+        );
+    }
+}
+
+pub fn flush_tlb_all() {
+    unsafe {
+        core::arch::asm!(
+            "dsb ishst",
+            "tlbi vmalle1is",
+            "dsb ish",
+            "isb",
+        );
+    }
+}
+
+/// Makes instructions written through the data cache visible to
+/// instruction fetch, after loading code into memory
+pub fn sync_instruction_cache() {
+    unsafe {
+        core::arch::asm!(
+            "dsb ish",
+            "ic ialluis",
+            "dsb ish",
+            "isb",
+        );
+    }
+}
+
+/// `FrameAllocator` backed by the global frame allocator
+pub struct GlobalFrameAllocator;
+
+impl FrameAllocator for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        allocate_frame()
+    }
+}
+
+impl FrameDeallocator for GlobalFrameAllocator {
+    fn deallocate_frame(&mut self, frame: PhysFrame) {
+        deallocate_frame(frame)
+    }
+}
+
+// Physical frame allocation
+
+pub fn allocate_frame() -> Option<PhysFrame> {
+    FRAME_ALLOCATOR.lock().allocate_frame()
+}
+
+pub fn deallocate_frame(frame: PhysFrame) {
+    FRAME_ALLOCATOR.lock().deallocate_frame(frame)
+}
+
+pub fn share_frame(frame: PhysFrame) -> Result<(), KernelError> {
+    FRAME_ALLOCATOR.lock().share(frame)
+}
+
+pub fn frame_ref_count(frame: PhysFrame) -> usize {
+    FRAME_ALLOCATOR.lock().ref_count(frame)
+}
+
+pub fn frame_stats() -> FrameStats {
+    FRAME_ALLOCATOR.lock().stats()
+}
+
+/// Lets at most `limit` more frames be allocated, or any number with None
+pub fn set_frame_limit(limit: Option<usize>) {
+    FRAME_ALLOCATOR.lock().set_limit(limit)
+}
+
 // Additional memory management functions for system calls
-pub fn allocate_pages(size: usize) -> Result<u64, &'static str> {
-    // Simple page allocation - align to page boundary
-    let pages = (size + 4095) / 4096;
-    static mut NEXT_ADDR: u64 = 0x60000000;
+
+/// Allocates zeroed, physically contiguous pages and returns the kernel
+/// virtual address of the first one. Allocations of 2 MiB or more start on
+/// a 2 MiB boundary so the linear map covers them with block descriptors.
+pub fn allocate_pages(size: usize) -> Result<u64, KernelError> {
+    let pages = size.div_ceil(PAGE_SIZE);
+    let block = PageSize::Size2MiB.bytes() as usize;
+    let align = if size >= block { block / PAGE_SIZE } else { 1 };
+    let frame = FRAME_ALLOCATOR.lock()
+        .allocate_contiguous_aligned(pages.max(1), align)
+        .ok_or(KernelError::OutOfMemory)?;
+    
+    let addr = phys_to_virt(frame.start_address()).as_u64();
     unsafe {
-        let addr = NEXT_ADDR;
-        NEXT_ADDR += pages as u64 * 4096;
-        Ok(addr)
+        core::ptr::write_bytes(addr as *mut u8, 0, pages.max(1) * PAGE_SIZE);
     }
+    Ok(addr)
 }
 
-pub fn deallocate_pages(_addr: u64, _size: usize) -> Result<(), &'static str> {
-    // Simple deallocation - in a real kernel this would free the pages
-    Ok(())
+pub fn deallocate_pages(addr: u64, size: usize) -> Result<(), KernelError> {
+    if !addr.is_multiple_of(PAGE_SIZE as u64) {
+        return Err(KernelError::InvalidArgument);
+    }
+    let pages = size.div_ceil(PAGE_SIZE);
+    let frame = PhysFrame::containing_address(virt_to_phys(VirtAddr::new(addr)));
+    FRAME_ALLOCATOR.lock().deallocate_contiguous(frame, pages.max(1))
 }
@@ -0,0 +1,446 @@
+#![allow(dead_code)]
+
+use core::arch::asm;
+use spin::Mutex;
+use crate::error::KernelError;
+use crate::memory::{
+    self, GlobalFrameAllocator, Mapper, MemoryRegion, Page, PageSize, PageTableFlags, PhysAddr,
+    PhysFrame, VirtAddr, PAGE_SIZE,
+};
+use crate::smp::{self, CpuMutex, MAX_CPUS};
+
+// Section boundaries from linker.ld, all page aligned
+extern "C" {
+    static __kernel_start: u8;
+    static __text_end: u8;
+    static __rodata_end: u8;
+    static __stack_guard: u8;
+    static __stack_start: u8;
+    static __kernel_end: u8;
+}
+
+// Kernel stacks are aligned to twice their size so the exception vectors
+// can spot an overflowed stack pointer by a single address bit
+pub const KERNEL_STACK_SIZE: usize = 64 * 1024;
+const KERNEL_STACK_SLOT: u64 = 2 * KERNEL_STACK_SIZE as u64;
+// Stacks allocated after boot live here. Each slot is an unmapped guard
+// of the stack's size followed by the stack itself.
+pub const KERNEL_STACK_AREA: u64 = 0xffff_8000_4000_0000;
+const KERNEL_STACK_SLOTS: usize = 1024;
+
+struct KernelAddressSpace {
+    mapper: Mapper,
+    // Installed in TTBR0 whenever no user process owns it
+    empty_user_root: PhysFrame,
+    stack_slots: [u64; KERNEL_STACK_SLOTS / 64],
+}
+
+/// A kernel stack with an unmapped guard region below it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelStack {
+    slot: usize,
+}
+
+impl KernelStack {
+    pub fn base(&self) -> u64 {
+        KERNEL_STACK_AREA + (self.slot as u64 + 1) * KERNEL_STACK_SLOT
+    }
+    
+    pub fn top(&self) -> u64 {
+        self.base() + KERNEL_STACK_SIZE as u64
+    }
+    
+    pub fn guard(&self) -> (u64, u64) {
+        (self.base() - KERNEL_STACK_SIZE as u64, self.base())
+    }
+}
+
+// Growing the heap maps pages here, so an allocation made while this CPU
+// holds the lock has to fail instead of spinning
+static KERNEL_SPACE: CpuMutex<Option<KernelAddressSpace>> = CpuMutex::new(None);
+
+// ASIDs tag user translations in the TLB so that switching address spaces
+// needs no flush. They are handed out in generations: once a generation
+// runs out every process takes a new ASID the next time it runs, and each
+// CPU flushes its TLB before it next installs one. ASIDs that other CPUs
+// are running at the rollover stay reserved, keeping their number in the
+// new generation. ASID 0 belongs to the empty user table.
+struct AsidAllocator {
+    bits: u32,
+    generation: u64,
+    next: u64,
+    // ASID each CPU last installed; cleared at a rollover
+    active: [u64; MAX_CPUS],
+    reserved: [u64; MAX_CPUS],
+    // CPUs whose TLB may hold entries of the previous generation
+    flush_pending: u64,
+}
+
+// Processes store their ASID with the generation above it
+const ASID_GENERATION_SHIFT: u32 = 16;
+const ASID_MASK: u64 = (1 << ASID_GENERATION_SHIFT) - 1;
+
+static ASIDS: Mutex<AsidAllocator> = Mutex::new(AsidAllocator {
+    bits: 8,
+    generation: 1,
+    next: 1,
+    active: [0; MAX_CPUS],
+    reserved: [0; MAX_CPUS],
+    flush_pending: 0,
+});
+
+impl AsidAllocator {
+    // Keeps `current` if it belongs to this generation or was reserved,
+    // otherwise hands out a new ASID. Returns the ASID and whether a new
+    // generation started.
+    fn refresh(&mut self, current: u64) -> (u64, bool) {
+        if current >> ASID_GENERATION_SHIFT == self.generation {
+            return (current, false);
+        }
+        
+        let current_generation = self.generation << ASID_GENERATION_SHIFT;
+        if current != 0 && self.reserved.contains(&current) {
+            let asid = current_generation | (current & ASID_MASK);
+            for reserved in self.reserved.iter_mut().filter(|reserved| **reserved == current) {
+                *reserved = asid;
+            }
+            return (asid, false);
+        }
+        
+        let mut rollover = false;
+        loop {
+            if self.next == 1 << self.bits {
+                self.rollover();
+                rollover = true;
+            }
+            let number = self.next;
+            self.next += 1;
+            if !self.reserved.iter().any(|reserved| reserved & ASID_MASK == number) {
+                return ((self.generation << ASID_GENERATION_SHIFT) | number, rollover);
+            }
+        }
+    }
+    
+    fn rollover(&mut self) {
+        self.generation += 1;
+        self.next = 1;
+        // A CPU that installed nothing since the last rollover is still
+        // running the ASID it had reserved then
+        for (active, reserved) in self.active.iter_mut().zip(self.reserved.iter_mut()) {
+            let asid = core::mem::take(active);
+            if asid != 0 {
+                *reserved = asid;
+            }
+        }
+        self.flush_pending = u64::MAX;
+    }
+    
+    // Whether `cpu` still has to flush its TLB, which it then does
+    fn take_flush(&mut self, cpu: usize) -> bool {
+        let pending = self.flush_pending & (1 << cpu) != 0;
+        self.flush_pending &= !(1 << cpu);
+        pending
+    }
+}
+
+// Replaces the coarse boot map from boot.s with the final kernel address
+// space in TTBR1: the kernel image with per-section permissions, the
+// remaining RAM as a non-executable linear map and the early devices.
+// TTBR0 no longer maps anything afterwards and is left to user processes.
+pub fn init(memory_map: &[MemoryRegion]) -> Result<(), KernelError> {
+    let mut allocator = GlobalFrameAllocator;
+    let mut mapper = Mapper::new(&mut allocator)?;
+    let empty_user_root = Mapper::new(&mut allocator)?.root_frame();
+    
+    let kernel_start = section_phys(unsafe { &__kernel_start });
+    let text_end = section_phys(unsafe { &__text_end });
+    let rodata_end = section_phys(unsafe { &__rodata_end });
+    let stack_guard = section_phys(unsafe { &__stack_guard });
+    let stack_start = section_phys(unsafe { &__stack_start });
+    let kernel_end = memory::align_up(section_phys(unsafe { &__kernel_end }), PAGE_SIZE as u64);
+    
+    // The stack-sized guard below the boot stack is left unmapped
+    map_linear(&mut mapper, kernel_start, text_end, PageTableFlags::KERNEL_TEXT)?;
+    map_linear(&mut mapper, text_end, rodata_end, PageTableFlags::KERNEL_RODATA)?;
+    map_linear(&mut mapper, rodata_end, stack_guard, PageTableFlags::KERNEL_DATA)?;
+    map_linear(&mut mapper, stack_start, kernel_end, PageTableFlags::KERNEL_DATA)?;
+    
+    for region in memory_map {
+        let start = memory::align_down(region.start, PAGE_SIZE as u64);
+        let end = memory::align_up(region.start + region.size, PAGE_SIZE as u64);
+        map_linear(&mut mapper, start, end.min(kernel_start), PageTableFlags::KERNEL_DATA)?;
+        map_linear(&mut mapper, start.max(kernel_end), end, PageTableFlags::KERNEL_DATA)?;
+    }
+    
+    let uart = memory::align_down(crate::uart::phys_base(), PAGE_SIZE as u64);
+    map_linear(&mut mapper, uart, uart + PAGE_SIZE as u64, PageTableFlags::KERNEL_DEVICE)?;
+    
+    // Use 16-bit ASIDs where the CPU has them; the TLB flush below covers
+    // the TCR change
+    let mmfr0: u64;
+    unsafe { asm!("mrs {}, id_aa64mmfr0_el1", out(reg) mmfr0) };
+    if (mmfr0 >> 4) & 0xf == 0b0010 {
+        unsafe {
+            asm!(
+                "mrs {tmp}, tcr_el1",
+                "orr {tmp}, {tmp}, #(1 << 36)",
+                "msr tcr_el1, {tmp}",
+                tmp = out(reg) _,
+            );
+        }
+        ASIDS.lock().bits = 16;
+    }
+    
+    unsafe {
+        asm!(
+            "dsb ishst",
+            "msr ttbr1_el1, {kernel}",
+            "msr ttbr0_el1, {user}",
+            "isb",
+            "tlbi vmalle1",
+            "dsb nsh",
+            "isb",
+            kernel = in(reg) mapper.root_frame().start_address().as_u64(),
+            user = in(reg) empty_user_root.start_address().as_u64(),
+        );
+    }
+    
+    *KERNEL_SPACE.lock() = Some(KernelAddressSpace {
+        mapper,
+        empty_user_root,
+        stack_slots: [0; KERNEL_STACK_SLOTS / 64],
+    });
+    Ok(())
+}
+
+/// Maps a device's registers into the kernel address space and returns
+/// their virtual address. Pages that are already mapped are left alone.
+pub fn map_device(phys: PhysAddr, size: usize) -> Result<VirtAddr, KernelError> {
+    let mut space = KERNEL_SPACE.lock();
+    let space = space.as_mut().ok_or(KernelError::InvalidArgument)?;
+    
+    let start = memory::align_down(phys.as_u64(), PAGE_SIZE as u64);
+    let end = memory::align_up(phys.as_u64() + size as u64, PAGE_SIZE as u64);
+    let mut addr = start;
+    while addr < end {
+        let page = Page::containing_address(memory::phys_to_virt(PhysAddr::new(addr)));
+        if space.mapper.translate_page(page).is_none() {
+            space.mapper.map_to(
+                page,
+                PhysFrame::containing_address(PhysAddr::new(addr)),
+                PageTableFlags::KERNEL_DEVICE,
+                &mut GlobalFrameAllocator,
+            )?;
+        }
+        addr += PAGE_SIZE as u64;
+    }
+    
+    Ok(memory::phys_to_virt(phys))
+}
+
+/// Backs `size` bytes at `start` with fresh frames as kernel data. Used by
+/// the heap to grow; fails if this CPU is already changing the address
+/// space.
+pub fn map_kernel_pages(start: VirtAddr, size: usize) -> Result<(), KernelError> {
+    if KERNEL_SPACE.is_held_here() {
+        return Err(KernelError::Busy);
+    }
+    let mut space = KERNEL_SPACE.lock();
+    let space = space.as_mut().ok_or(KernelError::InvalidArgument)?;
+    
+    let first = Page::containing_address(start);
+    let mut page = first;
+    let mut mapped = 0;
+    while mapped < size {
+        let result = memory::allocate_frame().ok_or(KernelError::OutOfMemory).and_then(|frame| {
+            space.mapper.map_to(page, frame, PageTableFlags::KERNEL_DATA, &mut GlobalFrameAllocator)
+                .inspect_err(|_| memory::deallocate_frame(frame))
+        });
+        if let Err(e) = result {
+            // Give back what this call mapped so far
+            let mut undo = first;
+            while undo != page {
+                if let Ok(frame) = space.mapper.unmap(undo) {
+                    memory::deallocate_frame(frame);
+                }
+                undo = undo.next();
+            }
+            return Err(e);
+        }
+        page = page.next();
+        mapped += PAGE_SIZE;
+    }
+    
+    unsafe {
+        asm!("dsb ishst", "isb");
+    }
+    Ok(())
+}
+
+/// Allocates a kernel stack in the stack area, backed by fresh frames
+pub fn allocate_kernel_stack() -> Result<KernelStack, KernelError> {
+    let slot = {
+        let mut space = KERNEL_SPACE.lock();
+        let space = space.as_mut().ok_or(KernelError::InvalidArgument)?;
+        let slot = (0..KERNEL_STACK_SLOTS)
+            .find(|&slot| space.stack_slots[slot / 64] & (1 << (slot % 64)) == 0)
+            .ok_or(KernelError::OutOfMemory)?;
+        space.stack_slots[slot / 64] |= 1 << (slot % 64);
+        slot
+    };
+    
+    let stack = KernelStack { slot };
+    if let Err(e) = map_kernel_pages(VirtAddr::new(stack.base()), KERNEL_STACK_SIZE) {
+        release_stack_slot(slot);
+        return Err(e);
+    }
+    Ok(stack)
+}
+
+pub fn free_kernel_stack(stack: KernelStack) {
+    {
+        let mut space = KERNEL_SPACE.lock();
+        let Some(space) = space.as_mut() else {
+            return;
+        };
+        let mut page = Page::containing_address(VirtAddr::new(stack.base()));
+        while page.start_address().as_u64() < stack.top() {
+            if let Ok(frame) = space.mapper.unmap(page) {
+                memory::deallocate_frame(frame);
+            }
+            page = page.next();
+        }
+    }
+    release_stack_slot(stack.slot);
+}
+
+/// Whether `addr` lies in the guard below the boot stack or below one of
+/// the stacks in the kernel stack area
+pub fn is_stack_guard(addr: u64) -> bool {
+    let boot_guard = unsafe { &__stack_guard as *const u8 as u64 };
+    if addr >= boot_guard && addr < boot_guard + KERNEL_STACK_SIZE as u64 {
+        return true;
+    }
+    
+    let area_end = KERNEL_STACK_AREA + (KERNEL_STACK_SLOTS as u64 + 1) * KERNEL_STACK_SLOT;
+    addr >= KERNEL_STACK_AREA && addr < area_end && (addr - KERNEL_STACK_AREA) % KERNEL_STACK_SLOT >= KERNEL_STACK_SIZE as u64
+}
+
+fn release_stack_slot(slot: usize) {
+    if let Some(space) = KERNEL_SPACE.lock().as_mut() {
+        space.stack_slots[slot / 64] &= !(1 << (slot % 64));
+    }
+}
+
+/// Installs a user address space in TTBR0, tagged with the ASID stored in
+/// `asid`. A fresh ASID is written back if the stored one is from an older
+/// generation.
+pub fn activate_user_space(root: PhysFrame, asid: &mut u64) {
+    let cpu = smp::cpu_id();
+    let (tagged, rollover, flush) = {
+        let mut asids = ASIDS.lock();
+        let (tagged, rollover) = asids.refresh(*asid);
+        asids.active[cpu] = tagged;
+        (tagged, rollover, asids.take_flush(cpu))
+    };
+    *asid = tagged;
+    
+    let ttbr = root.start_address().as_u64() | ((tagged & 0xffff) << 48);
+    unsafe {
+        asm!(
+            "dsb ishst",
+            "msr ttbr0_el1, {}",
+            "isb",
+            in(reg) ttbr
+        );
+    }
+    // ASIDs of the previous generation are reused from here on. Flushing
+    // after the switch also drops anything cached through the old table.
+    if flush {
+        flush_local_tlb();
+    }
+    if rollover {
+        smp::send_tlb_flush();
+    }
+}
+
+/// Flushes this CPU's TLB if an ASID rollover left entries of the previous
+/// generation in it. Other CPUs run this on the IPI sent at the rollover.
+pub fn flush_stale_asids() {
+    if ASIDS.lock().take_flush(smp::cpu_id()) {
+        flush_local_tlb();
+    }
+}
+
+/// Replaces the identity map boot.s started a secondary CPU with by the
+/// empty user table. TTBR1 already holds the kernel's.
+pub fn init_cpu() {
+    deactivate_user_space();
+    flush_local_tlb();
+}
+
+fn flush_local_tlb() {
+    unsafe { asm!("dsb nshst", "tlbi vmalle1", "dsb nsh", "isb") };
+}
+
+/// Installs the empty user table with the reserved ASID 0, for when no
+/// process is running
+pub fn deactivate_user_space() {
+    let Some(root) = empty_user_root() else {
+        return;
+    };
+    unsafe {
+        asm!(
+            "msr ttbr0_el1, {}",
+            "isb",
+            in(reg) root.start_address().as_u64()
+        );
+    }
+}
+
+/// ASID the current TTBR0 translations are tagged with
+pub fn current_asid() -> u16 {
+    let ttbr: u64;
+    unsafe { asm!("mrs {}, ttbr0_el1", out(reg) ttbr) };
+    (ttbr >> 48) as u16
+}
+
+pub fn asid_bits() -> u32 {
+    ASIDS.lock().bits
+}
+
+pub fn kernel_translate(addr: VirtAddr) -> Option<PhysAddr> {
+    KERNEL_SPACE.lock().as_ref()?.mapper.translate(addr)
+}
+
+pub fn kernel_mapping_size(addr: VirtAddr) -> Option<PageSize> {
+    KERNEL_SPACE.lock().as_ref()?.mapper.mapping_size(addr)
+}
+
+pub fn kernel_root() -> Option<PhysFrame> {
+    KERNEL_SPACE.lock().as_ref().map(|space| space.mapper.root_frame())
+}
+
+pub fn empty_user_root() -> Option<PhysFrame> {
+    KERNEL_SPACE.lock().as_ref().map(|space| space.empty_user_root)
+}
+
+// Maps [start, end) at its linear map address, using 1 GiB and 2 MiB
+// blocks wherever the range is aligned for them
+fn map_linear(mapper: &mut Mapper, start: u64, end: u64, flags: PageTableFlags) -> Result<(), KernelError> {
+    let mut addr = start;
+    while addr < end {
+        let size = [PageSize::Size1GiB, PageSize::Size2MiB]
+            .into_iter()
+            .find(|size| addr.is_multiple_of(size.bytes()) && addr + size.bytes() <= end)
+            .unwrap_or(PageSize::Size4KiB);
+        let phys = PhysAddr::new(addr);
+        mapper.map_block(memory::phys_to_virt(phys), phys, size, flags, &mut GlobalFrameAllocator)?;
+        addr += size.bytes();
+    }
+    Ok(())
+}
+
+fn section_phys(symbol: &u8) -> u64 {
+    memory::virt_to_phys(VirtAddr::new(symbol as *const u8 as u64)).as_u64()
+}
@@ -1,29 +1,199 @@
 #![allow(dead_code)]
 
-use alloc::collections::VecDeque;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
 use alloc::vec::Vec;
-use spin::Mutex;
 use lazy_static::lazy_static;
 use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::context::{self, Context};
+use crate::error::KernelError;
+use crate::exception::{FaultAccess, FaultInfo, FaultKind, TrapFrame, USER_ADDRESS_LIMIT};
+use crate::memory::{self, GlobalFrameAllocator, Mapper, Page, PageTableFlags, PhysAddr, PhysFrame, VirtAddr, PAGE_SIZE};
+use crate::mmu::{self, KernelStack};
+use crate::scheduler::{self, Enqueue, PolicyKind, SchedPolicy};
+use crate::signal::{self, DefaultAction, SigAction, SigInfo, SigSet, SignalState};
+use crate::smp::{self, CpuMutex, MAX_CPUS};
+use crate::userspace::{self, UserProgram};
+
+// User address space layout
+pub const USER_STACK_TOP: u64 = 0x0000_7fff_ffff_0000;
+pub const MAX_STACK_SIZE: u64 = 8 * 1024 * 1024;
+// Unmapped gap kept below every user stack, as Linux's stack_guard_gap
+pub const STACK_GUARD_SIZE: u64 = 256 * PAGE_SIZE as u64;
+// mmap places mappings without a usable hint at the lowest free address
+// above this, leaving room below the stack top for growth and its guard
+pub const USER_MMAP_BASE: u64 = 0x0000_0010_0000_0000;
+pub const USER_MMAP_LIMIT: u64 = USER_STACK_TOP - MAX_STACK_SIZE - STACK_GUARD_SIZE;
+
+// Resource limit numbers, as on Linux
+pub const RLIMIT_STACK: u32 = 3;
+pub const RLIMIT_NPROC: u32 = 6;
+pub const RLIMIT_NOFILE: u32 = 7;
+pub const RLIMIT_AS: u32 = 9;
+pub const RLIM_INFINITY: u64 = u64::MAX;
+
+// Open descriptors a process may hold unless it changes RLIMIT_NOFILE
+pub const DEFAULT_NOFILE: u64 = 1024;
+
+// `which` values for getpriority/setpriority
+pub const PRIO_PROCESS: u32 = 0;
+pub const RUSAGE_SELF: i32 = 0;
+
+// wait4 options
+pub const WNOHANG: u32 = 1;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProcessState {
     Ready,
     Running,
     Blocked,
-    Terminated,
+    // Stopped by a signal until SIGCONT
+    Stopped,
+    // Exited and waiting for its parent to collect the exit status
+    Zombie,
+}
+
+/// Wait status of a process that exited with `code`, as wait4 reports it
+pub const fn exit_status(code: i32) -> i32 {
+    (code & 0xff) << 8
 }
 
 #[derive(Debug)]
 pub struct Process {
+    // Thread id. Every thread is a Process of its own.
     pub pid: u32,
+    // Thread group (process) id: the pid of the group leader, whose entry
+    // holds the address space, exit status and parent the group shares
+    pub tgid: u32,
+    // None for processes the kernel started, for orphans when there is no
+    // init process to adopt them, and for threads other than the leader
+    pub parent: Option<u32>,
     pub state: ProcessState,
-    pub priority: u8,
-    pub stack_pointer: u64,
+    // Wait status once the process is a zombie
+    pub exit_status: i32,
+    // Blocked in wait4 until a child exits
+    waiting_for_child: bool,
+    // -20 (most favoured) to 19
+    pub nice: i8,
     pub page_table: u64,
-    pub registers: [u64; 31], // ARM64 general purpose registers
+    // ASID tagging this address space in the TLB, with its allocator
+    // generation (see mmu::activate_user_space); 0 until first run
+    pub asid: u64,
+    // Stack the process runs on inside the kernel. A user process keeps
+    // its TrapFrame at the top.
+    pub kernel_stack: KernelStack,
+    // Saved while the process is switched out
+    context: Box<Context>,
+    // Runs only at EL1 and never enters user space
+    pub kernel_thread: bool,
     pub entry_point: u64,
     pub memory_regions: Vec<MemoryRegion>,
+    // Pages currently mapped in the address space
+    pub resident_pages: u64,
+    // The heap brk grows runs from `heap_start` up to the program break.
+    // Both are 0 until the first brk for processes without an ELF image.
+    pub heap_start: u64,
+    pub brk: u64,
+    pub limits: ResourceLimits,
+    // Nanoseconds spent on the CPU, up to the last switch away from it
+    pub cpu_time: u64,
+    pub signals: SignalState,
+    // TPIDR_EL0, saved while the thread is switched out
+    pub tls: u64,
+    // Zeroed when the thread exits, as set by set_tid_address
+    pub clear_child_tid: u64,
+    // Key of the descriptor table in the file system, shared by processes
+    // created with CLONE_FILES
+    pub files: u32,
+    // CPU whose run queue the process is on, or that it last ran on
+    pub cpu: usize,
+}
+
+// Layout matches the timeval and rusage structures filled by getrusage
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timeval {
+    pub sec: i64,
+    pub usec: i64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rusage {
+    pub utime: Timeval,
+    pub stime: Timeval,
+    // maxrss through nivcsw, which are not tracked yet
+    pub counters: [i64; 14],
+}
+
+impl Timeval {
+    pub fn from_ns(ns: u64) -> Self {
+        Timeval {
+            sec: (ns / 1_000_000_000) as i64,
+            usec: (ns % 1_000_000_000 / 1000) as i64,
+        }
+    }
+}
+
+// Layout matches the rlimit structure passed to getrlimit/setrlimit
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rlimit {
+    pub cur: u64,
+    pub max: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceLimits {
+    pub stack: Rlimit,
+    pub nproc: Rlimit,
+    pub nofile: Rlimit,
+    pub address_space: Rlimit,
+}
+
+impl ResourceLimits {
+    pub const fn new() -> Self {
+        ResourceLimits {
+            stack: Rlimit { cur: MAX_STACK_SIZE, max: RLIM_INFINITY },
+            nproc: Rlimit { cur: 256, max: 256 },
+            nofile: Rlimit { cur: DEFAULT_NOFILE, max: 4096 },
+            address_space: Rlimit { cur: RLIM_INFINITY, max: RLIM_INFINITY },
+        }
+    }
+    
+    pub fn get(&self, resource: u32) -> Option<Rlimit> {
+        match resource {
+            RLIMIT_STACK => Some(self.stack),
+            RLIMIT_NPROC => Some(self.nproc),
+            RLIMIT_NOFILE => Some(self.nofile),
+            RLIMIT_AS => Some(self.address_space),
+            _ => None,
+        }
+    }
+    
+    fn get_mut(&mut self, resource: u32) -> Option<&mut Rlimit> {
+        match resource {
+            RLIMIT_STACK => Some(&mut self.stack),
+            RLIMIT_NPROC => Some(&mut self.nproc),
+            RLIMIT_NOFILE => Some(&mut self.nofile),
+            RLIMIT_AS => Some(&mut self.address_space),
+            _ => None,
+        }
+    }
+}
+
+impl Default for ResourceLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub resident: u64,
+    pub virtual_size: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -31,10 +201,63 @@ pub struct MemoryRegion {
     pub start: u64,
     pub size: u64,
     pub permissions: MemoryPermissions,
+    pub kind: RegionKind,
+    // MAP_SHARED: pages stay shared across fork and writes reach the file
+    pub shared: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegionKind {
+    // Zero-filled on first touch
+    Anonymous,
+    // Anonymous, and grows down on faults just below it
+    Stack,
+    // Filled from the file at `path`, `offset` bytes in at `start`
+    File { path: String, offset: u64 },
+}
+
+impl MemoryRegion {
+    pub fn end(&self) -> u64 {
+        self.start + self.size
+    }
+    
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.start && addr < self.end()
+    }
+    
+    pub fn allows(&self, access: FaultAccess) -> bool {
+        match access {
+            // Writable pages are always readable on AArch64
+            FaultAccess::Read => self.permissions.intersects(MemoryPermissions::READ | MemoryPermissions::WRITE),
+            FaultAccess::Write => self.permissions.contains(MemoryPermissions::WRITE),
+            FaultAccess::Execute => self.permissions.contains(MemoryPermissions::EXECUTE),
+        }
+    }
+    
+    pub fn page_flags(&self) -> PageTableFlags {
+        let flags = PageTableFlags::user(
+            self.permissions.contains(MemoryPermissions::WRITE),
+            self.permissions.contains(MemoryPermissions::EXECUTE),
+        );
+        if self.permissions.is_empty() {
+            // PROT_NONE: keep the pages but make them unreachable from EL0
+            flags - PageTableFlags::USER_ACCESSIBLE
+        } else {
+            flags
+        }
+    }
+    
+    // Offset into the backing file of the page at `addr`
+    fn file_offset(&self, addr: u64) -> Option<(&str, u64)> {
+        match &self.kind {
+            RegionKind::File { path, offset } => Some((path.as_str(), offset + (addr - self.start))),
+            _ => None,
+        }
+    }
 }
 
 bitflags::bitflags! {
-    #[derive(Debug, Clone)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct MemoryPermissions: u8 {
         const READ = 1;
         const WRITE = 2;
@@ -42,72 +265,729 @@ bitflags::bitflags! {
     }
 }
 
+bitflags::bitflags! {
+    // mmap flags, with the Linux values
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct MapFlags: u32 {
+        const SHARED = 0x01;
+        const PRIVATE = 0x02;
+        const FIXED = 0x10;
+        const ANONYMOUS = 0x20;
+    }
+}
+
+impl Process {
+    pub fn find_region(&self, addr: u64) -> Option<usize> {
+        self.memory_regions.iter().position(|region| region.contains(addr))
+    }
+    
+    pub fn virtual_size(&self) -> u64 {
+        self.memory_regions.iter().map(|region| region.size).sum()
+    }
+    
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            resident: self.resident_pages * PAGE_SIZE as u64,
+            virtual_size: self.virtual_size(),
+        }
+    }
+    
+    // Whether the address space may grow by `bytes` under RLIMIT_AS
+    fn can_grow_by(&self, bytes: u64) -> bool {
+        self.virtual_size().saturating_add(bytes) <= self.limits.address_space.cur
+    }
+    
+    // Bytes of existing regions inside [start, end)
+    fn mapped_bytes_in(&self, start: u64, end: u64) -> u64 {
+        self.memory_regions.iter()
+            .map(|region| region.end().min(end).saturating_sub(region.start.max(start)))
+            .sum()
+    }
+    
+    /// User register state, saved here on every entry from EL0 and
+    /// restored on the way back. Not meaningful for kernel threads.
+    pub fn trap_frame(&self) -> *mut TrapFrame {
+        (self.kernel_stack.top() - core::mem::size_of::<TrapFrame>() as u64) as *mut TrapFrame
+    }
+    
+    /// # Safety
+    ///
+    /// The returned mapper aliases this process's page table; only one may
+    /// be used at a time.
+    pub unsafe fn mapper(&self) -> Mapper {
+        Mapper::from_root(PhysFrame::containing_address(PhysAddr::new(self.page_table)))
+            .with_asid(self.asid as u16)
+    }
+    
+    // Extends a stack region down to cover `addr`. Faults in the guard zone
+    // (past MAX_STACK_SIZE, or within STACK_GUARD_SIZE of the next region
+    // down) are reported as `StackOverflow`.
+    fn grow_stack(&mut self, addr: u64) -> Result<usize, KernelError> {
+        let page_start = memory::align_down(addr, PAGE_SIZE as u64);
+        let index = self.memory_regions.iter()
+            .enumerate()
+            .filter(|(_, region)| region.start > addr)
+            .min_by_key(|(_, region)| region.start)
+            .filter(|(_, region)| region.kind == RegionKind::Stack)
+            .map(|(index, _)| index)
+            .ok_or(KernelError::OutOfMemory)?;
+        
+        // RLIMIT_STACK can only shrink the stack; the layout reserves MAX_STACK_SIZE
+        let max_stack = self.limits.stack.cur.min(MAX_STACK_SIZE);
+        let stack_end = self.memory_regions[index].end();
+        if stack_end - page_start > max_stack {
+            return if stack_end - page_start <= MAX_STACK_SIZE + STACK_GUARD_SIZE {
+                Err(KernelError::StackOverflow)
+            } else {
+                Err(KernelError::OutOfMemory)
+            };
+        }
+        if self.memory_regions.iter().any(|region| region.start < page_start && region.end() + STACK_GUARD_SIZE > page_start) {
+            return Err(KernelError::StackOverflow);
+        }
+        if !self.can_grow_by(self.memory_regions[index].start - page_start) {
+            return Err(KernelError::StackOverflow);
+        }
+        
+        let region = &mut self.memory_regions[index];
+        region.size += region.start - page_start;
+        region.start = page_start;
+        Ok(index)
+    }
+    
+    fn is_free(&self, start: u64, end: u64) -> bool {
+        !self.memory_regions.iter().any(|region| region.start < end && region.end() > start)
+    }
+    
+    fn is_covered(&self, start: u64, end: u64) -> bool {
+        let mut addr = start;
+        while addr < end {
+            match self.find_region(addr) {
+                Some(index) => addr = self.memory_regions[index].end(),
+                None => return false,
+            }
+        }
+        true
+    }
+    
+    // Lowest free range of `size` bytes between USER_MMAP_BASE and USER_MMAP_LIMIT
+    fn find_free_range(&self, size: u64) -> Option<u64> {
+        let mut candidate = USER_MMAP_BASE;
+        loop {
+            let end = candidate.checked_add(size).filter(|&end| end <= USER_MMAP_LIMIT)?;
+            match self.memory_regions.iter()
+                .filter(|region| region.start < end && region.end() > candidate)
+                .map(|region| region.end())
+                .max()
+            {
+                Some(next) => candidate = next,
+                None => return Some(candidate),
+            }
+        }
+    }
+    
+    // Maps `data` at `addr` as a private region, copied in up front rather
+    // than faulted in. The region covers whole pages; the rest of them is
+    // zero.
+    fn load_segment(&mut self, addr: u64, data: &[u8], permissions: MemoryPermissions) -> Result<(), KernelError> {
+        let start = memory::align_down(addr, PAGE_SIZE as u64);
+        let end = addr.checked_add(data.len().max(1) as u64)
+            .map(|end| memory::align_up(end, PAGE_SIZE as u64))
+            .filter(|&end| end <= USER_ADDRESS_LIMIT)
+            .ok_or(KernelError::InvalidArgument)?;
+        let size = end - start;
+        if !self.is_free(start, end) {
+            return Err(KernelError::AlreadyExists);
+        }
+        if !self.can_grow_by(size) {
+            return Err(KernelError::OutOfMemory);
+        }
+        
+        let region = MemoryRegion {
+            start,
+            size,
+            permissions,
+            kind: RegionKind::Anonymous,
+            shared: false,
+        };
+        let mut mapper = unsafe { self.mapper() };
+        let lead = (addr - start) as usize;
+        for index in 0..(size / PAGE_SIZE as u64) as usize {
+            // The part of `data` that lands in this page
+            let page_offset = index * PAGE_SIZE;
+            let from = page_offset.saturating_sub(lead).min(data.len());
+            let to = (page_offset + PAGE_SIZE - lead).min(data.len());
+            let page = Page::containing_address(VirtAddr::new(start + page_offset as u64));
+            let result = zeroed_frame().and_then(|frame| {
+                if from < to {
+                    let dest = lead + from - page_offset;
+                    let bytes = unsafe { frame_bytes(frame) };
+                    bytes[dest..dest + (to - from)].copy_from_slice(&data[from..to]);
+                }
+                mapper.map_to(page, frame, region.page_flags(), &mut GlobalFrameAllocator)
+                    .inspect_err(|_| memory::deallocate_frame(frame))
+            });
+            if let Err(e) = result {
+                let _ = unmap_region_pages(&mut mapper, &region);
+                return Err(e);
+            }
+        }
+        
+        if permissions.contains(MemoryPermissions::EXECUTE) {
+            memory::sync_instruction_cache();
+        }
+        self.resident_pages += size / PAGE_SIZE as u64;
+        self.memory_regions.push(region);
+        Ok(())
+    }
+    
+    // Maps the segments of `program`, whose file is `elf`. File bytes are
+    // copied in relocated; .bss past the pages holding them is demand-zero.
+    // PT_GNU_RELRO is made read-only afterwards.
+    fn map_program(&mut self, program: &UserProgram, elf: &[u8]) -> Result<(), KernelError> {
+        let image = program.relocated(elf);
+        for segment in &program.segments {
+            let data = &image[segment.offset as usize..(segment.offset + segment.file_size) as usize];
+            self.load_segment(segment.vaddr, data, segment.permissions)?;
+            let loaded_end = self.memory_regions.last().map_or(0, MemoryRegion::end);
+            if segment.end() > loaded_end {
+                if !self.can_grow_by(segment.end() - loaded_end) {
+                    return Err(KernelError::OutOfMemory);
+                }
+                self.memory_regions.push(MemoryRegion {
+                    start: loaded_end,
+                    size: segment.end() - loaded_end,
+                    permissions: segment.permissions,
+                    kind: RegionKind::Anonymous,
+                    shared: false,
+                });
+            }
+        }
+        
+        // Only whole pages; a partial last page stays writable
+        if let Some((start, size)) = program.relro {
+            let end = memory::align_down(start.checked_add(size).ok_or(KernelError::InvalidArgument)?, PAGE_SIZE as u64);
+            let start = memory::align_down(start, PAGE_SIZE as u64);
+            if start < end {
+                self.protect_range(start, end, MemoryPermissions::READ)?;
+            }
+        }
+        Ok(())
+    }
+    
+    // Maps the stack a new image starts with, `contents` from `sp` up to
+    // USER_STACK_TOP. It grows down from there on faults.
+    fn load_stack(&mut self, sp: u64, contents: &[u8], permissions: MemoryPermissions) -> Result<(), KernelError> {
+        self.load_segment(sp, contents, permissions)?;
+        if let Some(region) = self.memory_regions.last_mut() {
+            region.kind = RegionKind::Stack;
+        }
+        Ok(())
+    }
+    
+    // Splits the region containing `addr` so that a region starts there
+    fn split_region_at(&mut self, addr: u64) {
+        let Some(index) = self.find_region(addr) else {
+            return;
+        };
+        let region = &mut self.memory_regions[index];
+        if region.start == addr {
+            return;
+        }
+        
+        let head_size = addr - region.start;
+        let mut tail = region.clone();
+        region.size = head_size;
+        tail.start = addr;
+        tail.size -= head_size;
+        if let RegionKind::File { offset, .. } = &mut tail.kind {
+            *offset += head_size;
+        }
+        self.memory_regions.insert(index + 1, tail);
+    }
+    
+    // Removes [start, end) from the address space. Regions straddling either
+    // end are split and keep their outside part.
+    fn unmap_range(&mut self, start: u64, end: u64) -> Result<(), KernelError> {
+        self.split_region_at(start);
+        self.split_region_at(end);
+        
+        let mut mapper = unsafe { self.mapper() };
+        let mut index = 0;
+        while index < self.memory_regions.len() {
+            let region = &self.memory_regions[index];
+            if region.start >= start && region.end() <= end {
+                let region = self.memory_regions.remove(index);
+                self.resident_pages -= unmap_region_pages(&mut mapper, &region)?;
+            } else {
+                index += 1;
+            }
+        }
+        Ok(())
+    }
+    
+    // Applies new permissions to [start, end), which must be fully mapped
+    fn protect_range(&mut self, start: u64, end: u64, permissions: MemoryPermissions) -> Result<(), KernelError> {
+        if !self.is_covered(start, end) {
+            return Err(KernelError::OutOfMemory);
+        }
+        self.split_region_at(start);
+        self.split_region_at(end);
+        
+        let mut mapper = unsafe { self.mapper() };
+        for region in self.memory_regions.iter_mut().filter(|region| region.start >= start && region.end() <= end) {
+            region.permissions = permissions;
+            let flags = region.page_flags();
+            for (page, _, current) in mapper.mappings(VirtAddr::new(region.start), VirtAddr::new(region.end())) {
+                // Shared copy-on-write frames stay read-only until copied
+                let flags = if current.contains(PageTableFlags::COPY_ON_WRITE) {
+                    flags | PageTableFlags::READ_ONLY | PageTableFlags::COPY_ON_WRITE
+                } else {
+                    flags
+                };
+                mapper.update_flags(page, flags)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 pub struct ProcessManager {
     processes: Vec<Process>,
-    ready_queue: VecDeque<u32>,
-    current_pid: Option<u32>,
+    // One run queue per CPU, all under the same policy
+    run_queues: Vec<Box<dyn SchedPolicy>>,
+    // Thread each CPU is running, None while it idles
+    running: [Option<u32>; MAX_CPUS],
+    // Adopts orphaned processes
+    init_pid: Option<u32>,
+    // Receives SIGINT from the console
+    foreground: Option<u32>,
+    // Counter value when each CPU's current task was switched in
+    switched_at: [u64; MAX_CPUS],
     next_pid: u32,
+    // Contexts of the CPUs' idle threads, which run whenever nothing is
+    // ready there. CPU 0's is the boot thread.
+    idle_contexts: Box<[Context; MAX_CPUS]>,
 }
 
 impl ProcessManager {
     pub fn new() -> Self {
         ProcessManager {
             processes: Vec::new(),
-            ready_queue: VecDeque::new(),
-            current_pid: None,
+            run_queues: (0..MAX_CPUS).map(|_| PolicyKind::Mlfq.create()).collect(),
+            running: [None; MAX_CPUS],
+            init_pid: None,
+            foreground: None,
+            switched_at: [0; MAX_CPUS],
             next_pid: 1,
+            idle_contexts: Box::new([Context::default(); MAX_CPUS]),
+        }
+    }
+    
+    /// Thread running on the calling CPU
+    pub fn current_pid(&self) -> Option<u32> {
+        self.running[smp::cpu_id()]
+    }
+    
+    /// CPU `pid` is running on, if any
+    pub fn running_on(&self, pid: u32) -> Option<usize> {
+        self.running.iter().position(|&running| running == Some(pid))
+    }
+    
+    fn is_on_cpu(&self, pid: u32) -> bool {
+        self.running_on(pid).is_some()
+    }
+    
+    // Whether a thread of group `tgid` is on a CPU, exited or not
+    fn group_on_cpu(&self, tgid: u32) -> bool {
+        self.running.iter().flatten().any(|&pid| self.get_process(pid).is_some_and(|p| p.tgid == tgid))
+    }
+    
+    // Whether a thread of `pid`'s group other than `pid` is on a CPU
+    fn others_on_cpu(&self, pid: u32) -> bool {
+        let Some(tgid) = self.get_process(pid).map(|p| p.tgid) else {
+            return false;
+        };
+        self.running.iter().flatten().any(|&other| other != pid && self.get_process(other).is_some_and(|p| p.tgid == tgid))
+    }
+    
+    // Processes running and queued on `cpu`
+    fn load(&self, cpu: usize) -> usize {
+        self.run_queues[cpu].runnable() + self.running[cpu].is_some() as usize
+    }
+    
+    // Queues `pid` on `cpu`, waking that CPU if it idles
+    fn enqueue_on(&mut self, cpu: usize, pid: u32, reason: Enqueue) {
+        let Some(process) = self.get_process_mut(pid) else {
+            return;
+        };
+        process.cpu = cpu;
+        let nice = process.nice;
+        self.run_queues[cpu].enqueue(pid, nice, reason);
+        if self.running[cpu].is_none() {
+            smp::send_reschedule(cpu);
+        }
+    }
+    
+    // Queues a new process on the online CPU with the least to do
+    fn enqueue_new(&mut self, pid: u32) {
+        let cpu = (0..MAX_CPUS)
+            .filter(|&cpu| smp::is_online(cpu))
+            .min_by_key(|&cpu| self.load(cpu))
+            .unwrap_or(0);
+        self.enqueue_on(cpu, pid, Enqueue::New);
+    }
+    
+    // Makes a blocked or stopped process runnable, on the CPU it last ran
+    // on if that has nothing to do, otherwise on one that has nothing to do
+    // if there is one. A process still on its CPU, which marked itself
+    // blocked but has not switched away yet, just carries on running.
+    fn make_runnable(&mut self, pid: u32) {
+        let on_cpu = self.is_on_cpu(pid);
+        let Some(process) = self.get_process_mut(pid) else {
+            return;
+        };
+        if on_cpu {
+            process.state = ProcessState::Running;
+            return;
+        }
+        process.state = ProcessState::Ready;
+        let last = process.cpu;
+        let cpu = if self.load(last) == 0 {
+            last
+        } else {
+            (0..MAX_CPUS).find(|&cpu| smp::is_online(cpu) && self.load(cpu) == 0).unwrap_or(last)
+        };
+        self.enqueue_on(cpu, pid, Enqueue::Woken);
+    }
+    
+    // Takes `pid` off whichever run queue it is on, and out of the
+    // policies' records
+    fn remove_from_run_queues(&mut self, pid: u32) {
+        for queue in self.run_queues.iter_mut() {
+            queue.remove(pid);
+        }
+    }
+    
+    // Takes a process queued on the busiest other CPU for `cpu`, which has
+    // nothing of its own to run
+    fn steal(&mut self, cpu: usize) -> Option<u32> {
+        let busiest = (0..MAX_CPUS)
+            .filter(|&other| other != cpu)
+            .max_by_key(|&other| self.run_queues[other].runnable())?;
+        let stolen = self.run_queues[busiest].steal()?;
+        self.run_queues[cpu].adopt(stolen);
+        Some(stolen.pid)
+    }
+    
+    pub fn create_process(&mut self, entry_point: u64, stack_size: u64) -> Result<u32, KernelError> {
+        let pid = self.new_process(entry_point, stack_size)?;
+        self.enqueue_new(pid);
+        Ok(pid)
+    }
+    
+    /// Creates a process running the ELF executable `program`, whose file
+    /// is `elf`, with `argv` and `envp`
+    pub fn create_process_from_elf(
+        &mut self,
+        program: &UserProgram,
+        elf: &[u8],
+        argv: &[&str],
+        envp: &[&str],
+    ) -> Result<u32, KernelError> {
+        let pid = self.new_process(program.entry_point, PAGE_SIZE as u64)?;
+        match self.replace_image(pid, program, elf, argv, envp) {
+            Ok(old) => {
+                old.free();
+                self.enqueue_new(pid);
+                Ok(pid)
+            }
+            Err(e) => {
+                if let Some(process) = self.get_process_mut(pid) {
+                    process.state = ProcessState::Zombie;
+                }
+                let _ = self.reap(pid);
+                Err(e)
+            }
         }
     }
     
-    pub fn create_process(&mut self, entry_point: u64, stack_size: u64) -> Result<u32, &'static str> {
+    // A user process starting at `entry_point` with `stack_size` of stack
+    // reserved, not yet queued to run
+    fn new_process(&mut self, entry_point: u64, stack_size: u64) -> Result<u32, KernelError> {
+        let limits = self.inherited_limits();
+        self.check_nproc(&limits)?;
+        
+        let (kernel_stack, page_table) = self.create_task_memory()?;
         let pid = self.next_pid;
         self.next_pid += 1;
         
-        // Allocate stack
-        let stack_start = self.allocate_memory(stack_size)?;
-        let stack_pointer = stack_start + stack_size;
+        // The first switch to the process returns to EL0 through this frame
+        let frame = kernel_stack.top() - core::mem::size_of::<TrapFrame>() as u64;
+        unsafe {
+            (frame as *mut TrapFrame).write(TrapFrame::user(entry_point, USER_STACK_TOP));
+        }
         
-        // Create page table for the process
-        let page_table = self.create_page_table()?;
+        // The stack is only reserved here; pages are faulted in on use
+        let stack_size = memory::align_up(stack_size.max(PAGE_SIZE as u64), PAGE_SIZE as u64);
+        let stack_region = MemoryRegion {
+            start: USER_STACK_TOP - stack_size,
+            size: stack_size,
+            permissions: MemoryPermissions::READ | MemoryPermissions::WRITE,
+            kind: RegionKind::Stack,
+            shared: false,
+        };
         
         let process = Process {
             pid,
+            tgid: pid,
+            parent: self.current_tgid(),
             state: ProcessState::Ready,
-            priority: 128, // Default priority
-            stack_pointer,
+            exit_status: 0,
+            waiting_for_child: false,
+            nice: 0,
             page_table,
-            registers: [0; 31],
+            asid: 0,
+            kernel_stack,
+            context: Box::new(Context::user(frame)),
+            kernel_thread: false,
             entry_point,
-            memory_regions: Vec::new(),
+            memory_regions: vec![stack_region],
+            resident_pages: 0,
+            heap_start: 0,
+            brk: 0,
+            limits,
+            cpu_time: 0,
+            signals: SignalState::new(),
+            tls: 0,
+            clear_child_tid: 0,
+            files: pid,
+            cpu: 0,
         };
         
         self.processes.push(process);
-        self.ready_queue.push_back(pid);
+        Ok(pid)
+    }
+    
+    // Gives `pid` a fresh address space holding `program`, whose file is
+    // `elf`, and a stack holding `argv`, `envp` and the auxiliary vector,
+    // and points its registers at the entry point. The old address space
+    // is returned for the caller to free once nothing runs on it. On
+    // failure the process is left as it was.
+    fn replace_image(
+        &mut self,
+        pid: u32,
+        program: &UserProgram,
+        elf: &[u8],
+        argv: &[&str],
+        envp: &[&str],
+    ) -> Result<AddressSpace, KernelError> {
+        let (sp, stack) = userspace::initial_stack(USER_STACK_TOP, argv, envp, &program.auxv())?;
+        let page_table = self.create_page_table()?;
+        let process = self.address_space_mut(pid).ok_or(KernelError::NoSuchProcess)?;
+        let old = AddressSpace {
+            page_table: core::mem::replace(&mut process.page_table, page_table),
+            asid: core::mem::take(&mut process.asid),
+            regions: core::mem::take(&mut process.memory_regions),
+        };
+        let resident_pages = core::mem::take(&mut process.resident_pages);
+        
+        if let Err(e) = process.map_program(program, elf).and_then(|_| process.load_stack(sp, &stack, program.stack_permissions)) {
+            let new = AddressSpace {
+                page_table: core::mem::replace(&mut process.page_table, old.page_table),
+                asid: core::mem::replace(&mut process.asid, old.asid),
+                regions: core::mem::replace(&mut process.memory_regions, old.regions),
+            };
+            process.resident_pages = resident_pages;
+            new.free();
+            return Err(e);
+        }
+        process.entry_point = program.entry_point;
+        process.heap_start = program.segments.last().map_or(0, |segment| segment.end());
+        process.brk = process.heap_start;
+        unsafe {
+            *process.trap_frame() = TrapFrame::user(program.entry_point, sp);
+        }
+        Ok(old)
+    }
+    
+    /// Creates a kernel thread running `entry`. It gets an empty user
+    /// address space of its own, so it can make system calls on its own
+    /// behalf, and exits when `entry` returns.
+    pub fn spawn_kernel_thread(&mut self, entry: fn()) -> Result<u32, KernelError> {
+        let limits = self.inherited_limits();
+        self.check_nproc(&limits)?;
+        
+        let (kernel_stack, page_table) = self.create_task_memory()?;
+        let pid = self.next_pid;
+        self.next_pid += 1;
         
+        self.processes.push(Process {
+            pid,
+            tgid: pid,
+            parent: self.current_tgid(),
+            state: ProcessState::Ready,
+            exit_status: 0,
+            waiting_for_child: false,
+            nice: 0,
+            page_table,
+            asid: 0,
+            kernel_stack,
+            context: Box::new(Context::kernel_thread(kernel_stack.top(), entry)),
+            kernel_thread: true,
+            entry_point: entry as usize as u64,
+            memory_regions: Vec::new(),
+            resident_pages: 0,
+            heap_start: 0,
+            brk: 0,
+            limits,
+            cpu_time: 0,
+            signals: SignalState::new(),
+            tls: 0,
+            clear_child_tid: 0,
+            files: pid,
+            cpu: 0,
+        });
+        self.enqueue_new(pid);
         Ok(pid)
     }
     
+    // Picks the process the calling CPU runs next, from its own run queue
+    // or, when that is empty, from another CPU's. Returns None when nothing
+    // is runnable and the CPU should go back to its idle thread.
     pub fn schedule(&mut self) -> Option<u32> {
-        if let Some(next_pid) = self.ready_queue.pop_front() {
-            // Mark current process as ready if it's still running
-            if let Some(current_pid) = self.current_pid {
-                if let Some(current_process) = self.get_process_mut(current_pid) {
-                    if current_process.state == ProcessState::Running {
-                        current_process.state = ProcessState::Ready;
-                        self.ready_queue.push_back(current_pid);
-                    }
+        let cpu = smp::cpu_id();
+        // Threads other than a group leader have nobody to wait for them,
+        // so they are freed once they are off their CPU
+        let exited: Vec<u32> = self.processes.iter()
+            .filter(|p| p.state == ProcessState::Zombie && p.pid != p.tgid && !self.is_on_cpu(p.pid))
+            .map(|p| p.pid)
+            .collect();
+        for pid in exited {
+            let _ = self.reap(pid);
+        }
+        
+        let now = crate::timer::counter();
+        let elapsed = crate::timer::cycles_to_ns(now - self.switched_at[cpu]);
+        self.switched_at[cpu] = now;
+        
+        // The current process competes with the queued ones if it can
+        // still run
+        let prev = self.running[cpu];
+        if let Some(current) = prev.and_then(|pid| self.get_process_mut(pid)) {
+            current.cpu_time += elapsed;
+            if current.state == ProcessState::Running {
+                current.state = ProcessState::Ready;
+                let pid = current.pid;
+                self.enqueue_on(cpu, pid, Enqueue::Requeue);
+            }
+        }
+        
+        let next = self.run_queues[cpu].pick_next().or_else(|| self.steal(cpu));
+        self.running[cpu] = next;
+        if let Some(next) = next.and_then(|pid| self.get_process_mut(pid)) {
+            next.state = ProcessState::Running;
+            next.cpu = cpu;
+        }
+        
+        // A parent cannot reap a group while one of its threads is still
+        // on a CPU, so it is woken again once the last one is off
+        if let Some(exited) = prev.and_then(|pid| self.get_process(pid)).filter(|p| p.state == ProcessState::Zombie) {
+            let tgid = exited.tgid;
+            if self.live_threads(tgid).next().is_none() && !self.group_on_cpu(tgid) {
+                if let Some(parent) = self.get_process(tgid).and_then(|leader| leader.parent) {
+                    self.wake_waiting_parent(parent);
                 }
             }
-            
-            // Set new process as running
-            if let Some(next_process) = self.get_process_mut(next_pid) {
-                next_process.state = ProcessState::Running;
-                self.current_pid = Some(next_pid);
-                return Some(next_pid);
+        }
+        next
+    }
+    
+    /// Charges a timer tick to the process running on the calling CPU.
+    /// Returns true when it should give up the CPU.
+    pub fn tick(&mut self) -> bool {
+        let cpu = smp::cpu_id();
+        match self.running[cpu] {
+            Some(pid) => self.run_queues[cpu].tick(pid),
+            None => false,
+        }
+    }
+    
+    /// Makes a blocked process runnable again
+    pub fn wake_process(&mut self, pid: u32) -> Result<(), KernelError> {
+        let process = self.get_process_mut(pid).ok_or(KernelError::NoSuchProcess)?;
+        if process.state != ProcessState::Blocked {
+            return Ok(());
+        }
+        process.waiting_for_child = false;
+        self.make_runnable(pid);
+        Ok(())
+    }
+    
+    /// Replaces the scheduling policy, queueing every runnable process
+    /// with the new one on the CPU it is queued on
+    pub fn set_policy(&mut self, kind: PolicyKind) {
+        let mut run_queues: Vec<Box<dyn SchedPolicy>> = (0..MAX_CPUS).map(|_| kind.create()).collect();
+        for process in self.processes.iter().filter(|p| p.state == ProcessState::Ready) {
+            run_queues[process.cpu].enqueue(process.pid, process.nice, Enqueue::New);
+        }
+        for (cpu, pid) in self.running.iter().enumerate() {
+            if let Some(current) = pid.and_then(|pid| self.get_process(pid)) {
+                // Known to the policy, but not queued while it runs
+                run_queues[cpu].enqueue(current.pid, current.nice, Enqueue::New);
+                run_queues[cpu].dequeue(current.pid);
             }
         }
-        
-        self.current_pid
+        self.run_queues = run_queues;
+    }
+    
+    pub fn policy_name(&self) -> &'static str {
+        self.run_queues[0].name()
+    }
+    
+    pub fn set_nice(&mut self, pid: u32, nice: i32) -> Result<i8, KernelError> {
+        let nice = scheduler::clamp_nice(nice);
+        let process = self.get_process_mut(pid).ok_or(KernelError::NoSuchProcess)?;
+        process.nice = nice;
+        let cpu = process.cpu;
+        self.run_queues[cpu].set_nice(pid, nice);
+        Ok(nice)
+    }
+    
+    /// Nanoseconds `pid` has spent on the CPU, including its current run
+    pub fn cpu_time(&self, pid: u32) -> Option<u64> {
+        let process = self.get_process(pid)?;
+        let mut time = process.cpu_time;
+        if let Some(cpu) = self.running_on(pid) {
+            time += crate::timer::cycles_to_ns(crate::timer::counter() - self.switched_at[cpu]);
+        }
+        Some(time)
+    }
+    
+    // Where the context of `pid`, or of the calling CPU's idle thread, is
+    // saved
+    fn context_ptr(&mut self, pid: Option<u32>) -> *mut Context {
+        match pid.and_then(|pid| self.get_process_mut(pid)) {
+            Some(process) => &mut *process.context,
+            None => &mut self.idle_contexts[smp::cpu_id()],
+        }
+    }
+    
+    // Processes started on behalf of a running process count against its
+    // RLIMIT_NPROC and inherit its limits
+    fn inherited_limits(&self) -> ResourceLimits {
+        match self.current_pid().and_then(|pid| self.get_process(pid)) {
+            Some(current) => current.limits,
+            None => ResourceLimits::new(),
+        }
+    }
+    
+    fn check_nproc(&self, limits: &ResourceLimits) -> Result<(), KernelError> {
+        let live = self.processes.iter().filter(|p| p.state != ProcessState::Zombie).count() as u64;
+        if live >= limits.nproc.cur {
+            return Err(KernelError::WouldBlock);
+        }
+        Ok(())
     }
     
     pub fn get_process(&self, pid: u32) -> Option<&Process> {
@@ -118,117 +998,1344 @@ impl ProcessManager {
         self.processes.iter_mut().find(|p| p.pid == pid)
     }
     
-    pub fn terminate_process(&mut self, pid: u32) -> Result<(), &'static str> {
-        if let Some(process) = self.get_process_mut(pid) {
-            process.state = ProcessState::Terminated;
-            
-            // Remove from ready queue if present
-            self.ready_queue.retain(|&p| p != pid);
-            
-            // If it's the current process, clear current_pid
-            if self.current_pid == Some(pid) {
-                self.current_pid = None;
+    /// Group leader of `pid`'s thread group, which holds the address space
+    /// all of its threads run in
+    pub fn address_space(&self, pid: u32) -> Option<&Process> {
+        let tgid = self.get_process(pid)?.tgid;
+        self.get_process(tgid)
+    }
+    
+    pub fn address_space_mut(&mut self, pid: u32) -> Option<&mut Process> {
+        let tgid = self.get_process(pid)?.tgid;
+        self.get_process_mut(tgid)
+    }
+    
+    fn current_tgid(&self) -> Option<u32> {
+        self.current_pid().and_then(|pid| self.get_process(pid)).map(|p| p.tgid)
+    }
+    
+    /// Threads of group `tgid` that have not exited
+    fn live_threads(&self, tgid: u32) -> impl Iterator<Item = &Process> {
+        self.processes.iter().filter(move |p| p.tgid == tgid && p.state != ProcessState::Zombie)
+    }
+    
+    /// Whether any process still alive uses the descriptor table `files`
+    pub fn files_in_use(&self, files: u32) -> bool {
+        self.processes.iter().any(|p| p.files == files && p.state != ProcessState::Zombie)
+    }
+    
+    /// Ends thread `pid`. A group leader keeps `status` for its parent.
+    /// When the last thread of the group exits, the group's children go to
+    /// init and a parent waiting for it is woken.
+    pub fn terminate_process(&mut self, pid: u32, status: i32) -> Result<(), KernelError> {
+        let process = self.get_process_mut(pid).ok_or(KernelError::NoSuchProcess)?;
+        process.state = ProcessState::Zombie;
+        let tgid = process.tgid;
+        if pid == tgid {
+            process.exit_status = status;
+        }
+        
+        // Remove from the run queue if present. A current process stays
+        // current until it switches away for the last time.
+        self.remove_from_run_queues(pid);
+        if self.live_threads(tgid).next().is_some() {
+            return Ok(());
+        }
+        
+        let leader = self.get_process(tgid).ok_or(KernelError::NoSuchProcess)?;
+        let (parent, status) = (leader.parent, leader.exit_status);
+        let init = self.init_pid.filter(|&init| init != tgid);
+        let mut adopted_zombie = false;
+        for child in self.processes.iter_mut().filter(|p| p.parent == Some(tgid)) {
+            child.parent = init;
+            adopted_zombie |= child.state == ProcessState::Zombie;
+        }
+        if adopted_zombie {
+            if let Some(init) = init {
+                self.wake_waiting_parent(init);
             }
-            
-            Ok(())
-        } else {
-            Err("Process not found")
         }
+        if let Some(parent) = parent {
+            self.wake_waiting_parent(parent);
+            let _ = self.send_signal(parent, SigInfo::child(tgid, status));
+        }
+        Ok(())
     }
     
-    fn allocate_memory(&self, size: u64) -> Result<u64, &'static str> {
-        // Simple memory allocation - in a real kernel this would be more sophisticated
-        // For now, just return a fixed address offset
-        static mut NEXT_ADDR: u64 = 0x50000000;
-        unsafe {
-            let addr = NEXT_ADDR;
-            NEXT_ADDR += size.next_power_of_two();
-            Ok(addr)
+    /// Ends every thread in `pid`'s group, which exits with `status`
+    pub fn terminate_group(&mut self, pid: u32, status: i32) -> Result<(), KernelError> {
+        let tgid = self.get_process(pid).ok_or(KernelError::NoSuchProcess)?.tgid;
+        self.end_other_threads(pid);
+        if pid != tgid {
+            self.get_process_mut(tgid).ok_or(KernelError::NoSuchProcess)?.exit_status = status;
         }
+        self.terminate_process(pid, status)
     }
     
-    fn create_page_table(&self) -> Result<u64, &'static str> {
-        // Create a new page table for the process
-        // This is simplified - real implementation would set up proper page tables
-        self.allocate_memory(4096) // One page for page table
+    // Ends the threads of `pid`'s group other than `pid` itself. Those
+    // running on other CPUs are told to switch away, and none is freed
+    // before it has. Returns the descriptor tables they used.
+    fn end_other_threads(&mut self, pid: u32) -> Vec<u32> {
+        let Some(tgid) = self.get_process(pid).map(|p| p.tgid) else {
+            return Vec::new();
+        };
+        let others: Vec<u32> = self.live_threads(tgid).map(|p| p.pid).filter(|&p| p != pid).collect();
+        let mut files = Vec::new();
+        for thread in others {
+            if let Some(process) = self.get_process_mut(thread) {
+                process.state = ProcessState::Zombie;
+                files.push(process.files);
+            }
+            self.remove_from_run_queues(thread);
+            if let Some(cpu) = self.running_on(thread) {
+                smp::send_reschedule(cpu);
+            }
+        }
+        files
+    }
+    
+    /// Queues `info`'s signal for `pid`. A stopped process is continued by
+    /// SIGCONT and SIGKILL, and a blocked one is woken if the signal can be
+    /// delivered, so that the call it sleeps in returns early.
+    pub fn send_signal(&mut self, pid: u32, info: SigInfo) -> Result<(), KernelError> {
+        let sig = info.signal();
+        // A process whose leader has exited is signalled through one of
+        // its remaining threads
+        let pid = match self.get_process(pid) {
+            Some(p) if p.pid == p.tgid && p.state == ProcessState::Zombie => {
+                self.live_threads(pid).next().map_or(pid, |thread| thread.pid)
+            }
+            _ => pid,
+        };
+        let process = self.get_process_mut(pid).ok_or(KernelError::NoSuchProcess)?;
+        if process.kernel_thread {
+            return Err(KernelError::NotPermitted);
+        }
+        if process.state == ProcessState::Zombie {
+            return Ok(());
+        }
+        
+        let queued = process.signals.queue(info);
+        let state = process.state;
+        let deliverable = process.signals.deliverable() & signal::sigmask(sig) != 0;
+        if (sig == signal::SIGCONT || sig == signal::SIGKILL) && state == ProcessState::Stopped {
+            self.continue_process(pid);
+        } else if queued && deliverable && state == ProcessState::Blocked {
+            self.wake_process(pid)?;
+        }
+        // A target running elsewhere acts on it on its way back to user mode
+        if let Some(cpu) = self.running_on(pid) {
+            smp::send_reschedule(cpu);
+        }
+        Ok(())
+    }
+    
+    fn continue_process(&mut self, pid: u32) {
+        self.make_runnable(pid);
+    }
+    
+    /// Whether `pid` has a signal to act on, which interrupts blocking calls
+    pub fn signal_pending(&self, pid: u32) -> bool {
+        self.get_process(pid).is_some_and(|p| p.signals.deliverable() != 0)
+    }
+    
+    /// Makes `[start, start + len)` of `pid`'s address space present for
+    /// `access`, as faults on it would, so the kernel can touch it without
+    /// faulting. Fails if any of it is outside the process's mappings.
+    pub fn fault_in(&mut self, pid: u32, start: u64, len: u64, access: FaultAccess) -> Result<(), KernelError> {
+        let end = start.checked_add(len).filter(|&end| end <= USER_ADDRESS_LIMIT).ok_or(KernelError::BadAddress)?;
+        let mut addr = memory::align_down(start, PAGE_SIZE as u64);
+        while addr < end {
+            let process = self.address_space(pid).ok_or(KernelError::NoSuchProcess)?;
+            let page = Page::containing_address(VirtAddr::new(addr));
+            let kind = match unsafe { process.mapper() }.translate_page(page) {
+                None => Some(FaultKind::Translation(3)),
+                Some((_, flags)) if access == FaultAccess::Write && !flags.is_writable() => Some(FaultKind::Permission(3)),
+                Some(_) => None,
+            };
+            match kind {
+                Some(kind) => {
+                    let fault = FaultInfo { address: addr, pc: 0, access, kind, from_user: true };
+                    self.handle_page_fault(pid, &fault).map_err(|_| KernelError::BadAddress)?;
+                }
+                None => {
+                    let allowed = process.find_region(addr).is_some_and(|index| process.memory_regions[index].allows(access));
+                    if !allowed {
+                        return Err(KernelError::BadAddress);
+                    }
+                }
+            }
+            addr += PAGE_SIZE as u64;
+        }
+        Ok(())
+    }
+    
+    // Wakes the threads of group `tgid` that are waiting for a child
+    fn wake_waiting_parent(&mut self, tgid: u32) {
+        let waiting: Vec<u32> = self.processes.iter()
+            .filter(|p| p.tgid == tgid && p.waiting_for_child)
+            .map(|p| p.pid)
+            .collect();
+        for pid in waiting {
+            let _ = self.wake_process(pid);
+        }
+    }
+    
+    /// Looks for a child of process `parent` matching `pid` (-1 or 0 for
+    /// any) whose threads have all exited and left their CPUs. Ok(None)
+    /// means matching children exist but are still running.
+    pub fn find_zombie_child(&self, parent: u32, pid: i32) -> Result<Option<u32>, KernelError> {
+        let mut children = self.processes.iter()
+            .filter(|p| p.parent == Some(parent) && p.pid == p.tgid)
+            .filter(|p| pid <= 0 || p.pid == pid as u32)
+            .peekable();
+        if children.peek().is_none() {
+            return Err(KernelError::NoChildren);
+        }
+        Ok(children.find(|p| self.live_threads(p.pid).next().is_none() && !self.group_on_cpu(p.pid)).map(|p| p.pid))
+    }
+    
+    /// Frees everything a zombie still holds and forgets it. Reaping a group
+    /// leader frees the threads and address space of the whole group.
+    /// Returns its wait status and CPU time.
+    pub fn reap(&mut self, pid: u32) -> Result<(i32, u64), KernelError> {
+        let process = self.get_process(pid).ok_or(KernelError::NoSuchProcess)?;
+        let tgid = process.tgid;
+        if process.state != ProcessState::Zombie {
+            return Err(KernelError::Busy);
+        }
+        if self.is_on_cpu(pid) {
+            return Err(KernelError::Busy);
+        }
+        
+        let mut cpu_time = 0;
+        if pid == tgid {
+            if self.live_threads(tgid).next().is_some() {
+                return Err(KernelError::Busy);
+            }
+            if self.group_on_cpu(tgid) {
+                return Err(KernelError::Busy);
+            }
+            let threads: Vec<u32> = self.processes.iter()
+                .filter(|p| p.tgid == tgid && p.pid != tgid)
+                .map(|p| p.pid)
+                .collect();
+            for thread in threads {
+                cpu_time += self.reap(thread)?.1;
+            }
+        }
+        
+        let index = self.processes.iter().position(|p| p.pid == pid).ok_or(KernelError::NoSuchProcess)?;
+        let process = self.processes.remove(index);
+        self.remove_from_run_queues(pid);
+        if pid != tgid {
+            // The address space belongs to the leader
+            mmu::free_kernel_stack(process.kernel_stack);
+            return Ok((process.exit_status, process.cpu_time));
+        }
+        AddressSpace {
+            page_table: process.page_table,
+            asid: process.asid,
+            regions: process.memory_regions,
+        }.free();
+        mmu::free_kernel_stack(process.kernel_stack);
+        Ok((process.exit_status, process.cpu_time + cpu_time))
+    }
+    
+    /// Blocks the current process until one of its children exits
+    fn wait_for_child(&mut self) -> Result<(), KernelError> {
+        let pid = self.current_pid().ok_or(KernelError::NoSuchProcess)?;
+        let process = self.get_process_mut(pid).ok_or(KernelError::NoSuchProcess)?;
+        process.state = ProcessState::Blocked;
+        process.waiting_for_child = true;
+        Ok(())
+    }
+    
+    // Resolves a fault in `pid`'s address space: demand-zero pages for
+    // anonymous regions and stack growth. Anything else is an access
+    // violation and the error describes it.
+    pub fn handle_page_fault(&mut self, pid: u32, fault: &FaultInfo) -> Result<(), KernelError> {
+        let process = self.address_space_mut(pid).ok_or(KernelError::NoSuchProcess)?;
+        let index = match process.find_region(fault.address) {
+            Some(index) => index,
+            None => process.grow_stack(fault.address)?,
+        };
+        
+        let region = &process.memory_regions[index];
+        if !region.allows(fault.access) {
+            return Err(KernelError::PermissionDenied);
+        }
+        
+        let page = Page::containing_address(VirtAddr::new(fault.address));
+        let flags = region.page_flags();
+        let mut mapper = unsafe { process.mapper() };
+        let current = mapper.translate_page(page);
+        
+        match fault.kind {
+            FaultKind::Translation(_) => {
+                let frame = match region.file_offset(page.start_address().as_u64()) {
+                    Some((path, offset)) if region.shared => crate::fs::shared_page(path, offset)?,
+                    Some((path, offset)) => {
+                        let frame = zeroed_frame()?;
+                        let contents = unsafe { frame_bytes(frame) };
+                        crate::fs::read_at(path, offset, contents).inspect_err(|_| {
+                            memory::deallocate_frame(frame);
+                        })?;
+                        frame
+                    }
+                    None => zeroed_frame()?,
+                };
+                mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator).inspect_err(|_| {
+                    memory::deallocate_frame(frame);
+                })?;
+                process.resident_pages += 1;
+                Ok(())
+            }
+            // Keep a copy-on-write page read-only until it is written
+            FaultKind::AccessFlag(_) => match current {
+                Some((_, current)) if current.contains(PageTableFlags::COPY_ON_WRITE) => mapper.update_flags(page, current),
+                _ => mapper.update_flags(page, flags),
+            },
+            FaultKind::Permission(_) if fault.access == FaultAccess::Write => match current {
+                Some((frame, current)) if current.contains(PageTableFlags::COPY_ON_WRITE) => {
+                    break_cow(&mut mapper, page, frame, flags)
+                }
+                _ => Err(KernelError::PermissionDenied),
+            },
+            FaultKind::Permission(_) => Err(KernelError::PermissionDenied),
+            FaultKind::Alignment => Err(KernelError::BadAddress),
+            FaultKind::Other(_) => Err(KernelError::Io),
+        }
+    }
+    
+    // Duplicates the process of thread `parent_pid` with a copy of that
+    // thread. Writable pages are shared read-only between both address
+    // spaces and copied by the first write fault on either side.
+    pub fn fork(&mut self, parent_pid: u32) -> Result<u32, KernelError> {
+        let parent = self.get_process(parent_pid).ok_or(KernelError::NoSuchProcess)?;
+        if parent.kernel_thread {
+            return Err(KernelError::NotPermitted);
+        }
+        self.check_nproc(&parent.limits)?;
+        let space = self.address_space(parent_pid).ok_or(KernelError::NoSuchProcess)?;
+        let (kernel_stack, page_table) = self.create_task_memory()?;
+        
+        let mut parent_mapper = unsafe { space.mapper() };
+        let mut child_mapper = unsafe { Mapper::from_root(PhysFrame::containing_address(PhysAddr::new(page_table))) };
+        if let Err(e) = share_user_pages(&mut parent_mapper, &mut child_mapper, &space.memory_regions) {
+            release_user_pages(&child_mapper, &space.memory_regions);
+            unsafe { child_mapper.free_tables(&mut GlobalFrameAllocator) };
+            mmu::free_kernel_stack(kernel_stack);
+            return Err(e);
+        }
+        
+        // The child returns from the same system call, with 0
+        let mut registers = unsafe { *parent.trap_frame() };
+        registers.regs[0] = 0;
+        let frame = kernel_stack.top() - core::mem::size_of::<TrapFrame>() as u64;
+        unsafe {
+            (frame as *mut TrapFrame).write(registers);
+        }
+        
+        // The parent's thread pointer is live in the register while it runs
+        let tls = if self.current_pid() == Some(parent_pid) { read_thread_pointer() } else { parent.tls };
+        let pid = self.next_pid;
+        let child = Process {
+            pid,
+            tgid: pid,
+            parent: Some(parent.tgid),
+            state: ProcessState::Ready,
+            exit_status: 0,
+            waiting_for_child: false,
+            nice: parent.nice,
+            page_table,
+            asid: 0,
+            kernel_stack,
+            context: Box::new(Context::user(frame)),
+            kernel_thread: false,
+            entry_point: space.entry_point,
+            memory_regions: space.memory_regions.clone(),
+            resident_pages: space.resident_pages,
+            heap_start: space.heap_start,
+            brk: space.brk,
+            limits: parent.limits,
+            cpu_time: 0,
+            signals: parent.signals.fork(),
+            tls,
+            clear_child_tid: 0,
+            files: pid,
+            cpu: 0,
+        };
+        self.next_pid += 1;
+        
+        self.processes.push(child);
+        self.enqueue_new(pid);
+        Ok(pid)
+    }
+    
+    /// Starts a thread in `pid`'s group that returns from the current system
+    /// call with 0, on `stack` unless it is 0, and with thread pointer `tls`.
+    /// It shares the caller's descriptor table if `share_files` is set and
+    /// gets a copy of it otherwise.
+    pub fn spawn_thread(&mut self, pid: u32, stack: u64, tls: u64, share_files: bool) -> Result<u32, KernelError> {
+        let thread = self.get_process(pid).ok_or(KernelError::NoSuchProcess)?;
+        if thread.kernel_thread {
+            return Err(KernelError::NotPermitted);
+        }
+        self.check_nproc(&thread.limits)?;
+        let kernel_stack = mmu::allocate_kernel_stack()?;
+        
+        let mut registers = unsafe { *thread.trap_frame() };
+        registers.regs[0] = 0;
+        if stack != 0 {
+            registers.sp_el0 = stack;
+        }
+        let frame = kernel_stack.top() - core::mem::size_of::<TrapFrame>() as u64;
+        unsafe {
+            (frame as *mut TrapFrame).write(registers);
+        }
+        
+        let new_pid = self.next_pid;
+        let child = Process {
+            pid: new_pid,
+            tgid: thread.tgid,
+            parent: None,
+            state: ProcessState::Ready,
+            exit_status: 0,
+            waiting_for_child: false,
+            nice: thread.nice,
+            page_table: thread.page_table,
+            asid: 0,
+            kernel_stack,
+            context: Box::new(Context::user(frame)),
+            kernel_thread: false,
+            entry_point: thread.entry_point,
+            memory_regions: Vec::new(),
+            resident_pages: 0,
+            heap_start: 0,
+            brk: 0,
+            limits: thread.limits,
+            cpu_time: 0,
+            signals: thread.signals.fork(),
+            tls,
+            clear_child_tid: 0,
+            files: if share_files { thread.files } else { new_pid },
+            cpu: 0,
+        };
+        self.next_pid += 1;
+        
+        self.processes.push(child);
+        self.enqueue_new(new_pid);
+        Ok(new_pid)
+    }
+    
+    // Maps `length` bytes into `pid`'s address space. Pages are only
+    // reserved here and filled in by the fault handler.
+    pub fn mmap(
+        &mut self,
+        pid: u32,
+        addr: u64,
+        length: u64,
+        permissions: MemoryPermissions,
+        flags: MapFlags,
+        file: Option<(String, u64)>,
+    ) -> Result<u64, KernelError> {
+        if length == 0 {
+            return Err(KernelError::InvalidArgument);
+        }
+        if flags.contains(MapFlags::SHARED) == flags.contains(MapFlags::PRIVATE) {
+            return Err(KernelError::InvalidArgument);
+        }
+        
+        let size = memory::checked_align_up(length, PAGE_SIZE as u64).ok_or(KernelError::OutOfMemory)?;
+        let process = self.address_space_mut(pid).ok_or(KernelError::NoSuchProcess)?;
+        let start = if flags.contains(MapFlags::FIXED) {
+            if !addr.is_multiple_of(PAGE_SIZE as u64) || addr.checked_add(size).is_none_or(|end| end > USER_ADDRESS_LIMIT) {
+                return Err(KernelError::InvalidArgument);
+            }
+            // A fixed mapping replaces whatever it overlaps
+            if !process.can_grow_by(size - process.mapped_bytes_in(addr, addr + size)) {
+                return Err(KernelError::OutOfMemory);
+            }
+            process.unmap_range(addr, addr + size)?;
+            addr
+        } else {
+            // A hint is used as-is when it is free, like Linux does
+            let hint = memory::align_down(addr, PAGE_SIZE as u64);
+            if !process.can_grow_by(size) {
+                return Err(KernelError::OutOfMemory);
+            }
+            if hint != 0 && hint.saturating_add(size) <= USER_MMAP_LIMIT && process.is_free(hint, hint + size) {
+                hint
+            } else {
+                process.find_free_range(size).ok_or(KernelError::OutOfMemory)?
+            }
+        };
+        
+        let kind = match file {
+            Some((path, offset)) => RegionKind::File { path, offset },
+            None => RegionKind::Anonymous,
+        };
+        process.memory_regions.push(MemoryRegion {
+            start,
+            size,
+            permissions,
+            kind,
+            shared: flags.contains(MapFlags::SHARED),
+        });
+        Ok(start)
+    }
+    
+    pub fn munmap(&mut self, pid: u32, addr: u64, length: u64) -> Result<(), KernelError> {
+        if !addr.is_multiple_of(PAGE_SIZE as u64) || length == 0 {
+            return Err(KernelError::InvalidArgument);
+        }
+        let end = memory::checked_align_up(length, PAGE_SIZE as u64)
+            .and_then(|size| addr.checked_add(size))
+            .ok_or(KernelError::InvalidArgument)?;
+        self.address_space_mut(pid).ok_or(KernelError::NoSuchProcess)?.unmap_range(addr, end)
+    }
+    
+    /// Moves `pid`'s program break to `addr` and returns the new break, or
+    /// the old one when it cannot move there: below the start of the heap,
+    /// onto another mapping, or past RLIMIT_AS. Pages between the two are
+    /// mapped demand-zero or unmapped.
+    pub fn brk(&mut self, pid: u32, addr: u64) -> Result<u64, KernelError> {
+        let process = self.address_space_mut(pid).ok_or(KernelError::NoSuchProcess)?;
+        if process.heap_start == 0 {
+            // Past everything mapped but the stack
+            process.heap_start = process.memory_regions.iter()
+                .filter(|region| region.kind != RegionKind::Stack)
+                .map(MemoryRegion::end)
+                .max()
+                .unwrap_or(USER_MMAP_BASE);
+            process.brk = process.heap_start;
+        }
+        if addr < process.heap_start || addr > USER_MMAP_LIMIT {
+            return Ok(process.brk);
+        }
+        
+        let old_end = memory::align_up(process.brk, PAGE_SIZE as u64);
+        let new_end = memory::align_up(addr, PAGE_SIZE as u64);
+        if new_end > old_end {
+            if !process.is_free(old_end, new_end) || !process.can_grow_by(new_end - old_end) {
+                return Ok(process.brk);
+            }
+            let heap_start = process.heap_start;
+            let heap = process.memory_regions.iter_mut()
+                .find(|region| region.end() == old_end && region.start >= heap_start && region.kind == RegionKind::Anonymous);
+            match heap {
+                Some(heap) => heap.size += new_end - old_end,
+                None => process.memory_regions.push(MemoryRegion {
+                    start: old_end,
+                    size: new_end - old_end,
+                    permissions: MemoryPermissions::READ | MemoryPermissions::WRITE,
+                    kind: RegionKind::Anonymous,
+                    shared: false,
+                }),
+            }
+        } else if new_end < old_end {
+            process.unmap_range(new_end, old_end)?;
+        }
+        process.brk = addr;
+        Ok(addr)
+    }
+    
+    pub fn mprotect(&mut self, pid: u32, addr: u64, length: u64, permissions: MemoryPermissions) -> Result<(), KernelError> {
+        if !addr.is_multiple_of(PAGE_SIZE as u64) {
+            return Err(KernelError::InvalidArgument);
+        }
+        let end = memory::checked_align_up(length, PAGE_SIZE as u64)
+            .and_then(|size| addr.checked_add(size))
+            .ok_or(KernelError::InvalidArgument)?;
+        self.address_space_mut(pid).ok_or(KernelError::NoSuchProcess)?.protect_range(addr, end, permissions)
+    }
+    
+    fn allocate_memory(&self, size: u64) -> Result<u64, KernelError> {
+        crate::memory::allocate_pages(size as usize)
+    }
+    
+    fn create_page_table(&self) -> Result<u64, KernelError> {
+        // Empty level 0 table; user mappings are added as the process
+        // populates its address space
+        let mapper = Mapper::new(&mut GlobalFrameAllocator)?;
+        Ok(mapper.root_frame().start_address().as_u64())
+    }
+    
+    // Kernel stack and page table for a new process
+    fn create_task_memory(&self) -> Result<(KernelStack, u64), KernelError> {
+        let kernel_stack = mmu::allocate_kernel_stack()?;
+        match self.create_page_table() {
+            Ok(page_table) => Ok((kernel_stack, page_table)),
+            Err(e) => {
+                mmu::free_kernel_stack(kernel_stack);
+                Err(e)
+            }
+        }
+    }
+}
+
+// Page tables and regions taken off a process, until they are freed
+struct AddressSpace {
+    page_table: u64,
+    asid: u64,
+    regions: Vec<MemoryRegion>,
+}
+
+impl AddressSpace {
+    // Unmaps every page and frees the tables. No CPU may still run on them.
+    fn free(self) {
+        let root = PhysFrame::containing_address(PhysAddr::new(self.page_table));
+        let mut mapper = unsafe { Mapper::from_root(root).with_asid(self.asid as u16) };
+        for region in &self.regions {
+            let _ = unmap_region_pages(&mut mapper, region);
+        }
+        if self.asid != 0 {
+            memory::flush_tlb_asid(self.asid as u16);
+        }
+        unsafe { mapper.free_tables(&mut GlobalFrameAllocator) };
+    }
+}
+
+// Gives `page` a private copy of a copy-on-write frame, or takes the frame
+// over outright once no other address space refers to it
+fn break_cow(mapper: &mut Mapper, page: Page, frame: PhysFrame, flags: PageTableFlags) -> Result<(), KernelError> {
+    if memory::frame_ref_count(frame) == 1 {
+        return mapper.update_flags(page, flags);
+    }
+    
+    let copy = memory::allocate_frame().ok_or(KernelError::OutOfMemory)?;
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            memory::phys_to_virt(frame.start_address()).as_u64() as *const u8,
+            memory::phys_to_virt(copy.start_address()).as_u64() as *mut u8,
+            PAGE_SIZE,
+        );
+    }
+    mapper.remap(page, copy, flags)?;
+    memory::deallocate_frame(frame);
+    Ok(())
+}
+
+fn zeroed_frame() -> Result<PhysFrame, KernelError> {
+    let frame = memory::allocate_frame().ok_or(KernelError::OutOfMemory)?;
+    unsafe { frame_bytes(frame) }.fill(0);
+    Ok(frame)
+}
+
+// # Safety: the caller must own `frame`
+unsafe fn frame_bytes(frame: PhysFrame) -> &'static mut [u8] {
+    core::slice::from_raw_parts_mut(memory::phys_to_virt(frame.start_address()).as_u64() as *mut u8, PAGE_SIZE)
+}
+
+// Unmaps every page of `region`, writing MAP_SHARED file pages back
+// first. Returns the number of pages that were mapped.
+fn unmap_region_pages(mapper: &mut Mapper, region: &MemoryRegion) -> Result<u64, KernelError> {
+    let mut unmapped = 0;
+    for (page, frame, _) in mapper.mappings(VirtAddr::new(region.start), VirtAddr::new(region.end())) {
+        mapper.unmap(page)?;
+        match region.file_offset(page.start_address().as_u64()) {
+            Some((path, offset)) if region.shared => crate::fs::release_shared_page(path, offset, frame),
+            _ => memory::deallocate_frame(frame),
+        }
+        unmapped += 1;
+    }
+    Ok(unmapped)
+}
+
+// Maps every page of `regions` in `parent` into `child` as well, marking
+// private writable pages copy-on-write on both sides. On failure the
+// pages mapped so far hold a reference each, for release_user_pages.
+fn share_user_pages(parent: &mut Mapper, child: &mut Mapper, regions: &[MemoryRegion]) -> Result<(), KernelError> {
+    for region in regions {
+        for (page, frame, flags) in parent.mappings(VirtAddr::new(region.start), VirtAddr::new(region.end())) {
+            let shared_flags = if flags.is_writable() && !region.shared {
+                let cow = flags | PageTableFlags::READ_ONLY | PageTableFlags::COPY_ON_WRITE;
+                parent.update_flags(page, cow)?;
+                cow
+            } else {
+                flags
+            };
+            
+            memory::share_frame(frame)?;
+            child.map_to(page, frame, shared_flags, &mut GlobalFrameAllocator)
+                .inspect_err(|_| memory::deallocate_frame(frame))?;
+        }
+    }
+    Ok(())
+}
+
+// Drops this address space's reference to every page mapped in `regions`
+fn release_user_pages(mapper: &Mapper, regions: &[MemoryRegion]) {
+    for region in regions {
+        for (_, frame, _) in mapper.mappings(VirtAddr::new(region.start), VirtAddr::new(region.end())) {
+            memory::deallocate_frame(frame);
+        }
     }
 }
 
+// Lock order: the process table comes before FILE_SYSTEM and IPC (see
+// smp.rs). Faults on user memory resolve under the lock, so the kernel
+// faults such memory in before touching it with the lock held.
 lazy_static! {
-    static ref PROCESS_MANAGER: Mutex<ProcessManager> = Mutex::new(ProcessManager::new());
+    static ref PROCESS_MANAGER: CpuMutex<ProcessManager> = CpuMutex::new(ProcessManager::new());
 }
 
+// Set per CPU by the timer tick or an IPI when the running process should
+// be preempted
+static NEED_RESCHED: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
 pub fn init() {
     // Process manager is initialized statically
 }
 
-pub fn create_process(entry_point: u64, stack_size: u64) -> Result<u32, &'static str> {
+pub fn create_process(entry_point: u64, stack_size: u64) -> Result<u32, KernelError> {
     PROCESS_MANAGER.lock().create_process(entry_point, stack_size)
 }
 
+/// Starts a process running the ELF executable `elf` with `argv` and `envp`
+pub fn create_process_from_elf(elf: &[u8], argv: &[&str], envp: &[&str]) -> Result<u32, KernelError> {
+    let program = UserProgram::load_elf(elf)?;
+    PROCESS_MANAGER.lock().create_process_from_elf(&program, elf, argv, envp)
+}
+
+pub fn spawn_kernel_thread(entry: fn()) -> Result<u32, KernelError> {
+    PROCESS_MANAGER.lock().spawn_kernel_thread(entry)
+}
+
+/// Switches the calling CPU to the next ready process, or back to its idle
+/// thread when the current process can no longer run and nothing else is
+/// ready. Returns when the calling task is switched back in.
 pub fn schedule() {
+    NEED_RESCHED[smp::cpu_id()].store(false, Ordering::SeqCst);
     let mut manager = PROCESS_MANAGER.lock();
-    if let Some(pid) = manager.schedule() {
-        if let Some(process) = manager.get_process(pid) {
-            // Context switch to the selected process
-            context_switch(process);
+    let prev = manager.current_pid();
+    let next = manager.schedule();
+    if next == prev {
+        return;
+    }
+    
+    let prev_context = manager.context_ptr(prev);
+    let next_context = manager.context_ptr(next);
+    if let Some(process) = prev.and_then(|pid| manager.get_process_mut(pid)) {
+        process.tls = read_thread_pointer();
+    }
+    let tls = next.and_then(|pid| manager.get_process(pid)).map_or(0, |process| process.tls);
+    match next.and_then(|pid| manager.address_space_mut(pid)) {
+        Some(process) => context_switch(process),
+        None => mmu::deactivate_user_space(),
+    }
+    write_thread_pointer(tls);
+    
+    // The lock is held across the switch so that no other CPU picks up
+    // `prev` before its context is saved. Whatever runs next releases it.
+    // Contexts are boxed, so the pointers stay valid.
+    core::mem::forget(manager);
+    unsafe { context::switch_to(prev_context, next_context) };
+    schedule_tail();
+}
+
+/// Releases the process table lock `schedule` switched with. Runs first in
+/// every task switched to, new ones included (see context.rs).
+#[no_mangle]
+pub extern "C" fn schedule_tail() {
+    unsafe { PROCESS_MANAGER.force_unlock() };
+}
+
+/// Charges a timer tick to the process running on this CPU. Runs in
+/// interrupt context, so it only notes that a switch is due; `preempt`
+/// makes it.
+pub fn timer_tick() {
+    // Interrupts are only taken in user mode and the idle loop, so the
+    // lock is free here or held by another CPU
+    if PROCESS_MANAGER.lock().tick() {
+        set_need_resched();
+    }
+}
+
+/// Has this CPU reschedule before it next returns to user mode
+pub fn set_need_resched() {
+    NEED_RESCHED[smp::cpu_id()].store(true, Ordering::SeqCst);
+}
+
+/// Switches away from the running process if its time slice has run out
+/// or another CPU asked for it. Called on the way back to user mode, where
+/// no kernel locks are held.
+pub fn preempt() {
+    if NEED_RESCHED[smp::cpu_id()].load(Ordering::SeqCst) {
+        schedule();
+    }
+}
+
+/// Ends the calling thread, or its whole group if `group` is set. The
+/// descriptor tables nothing uses any more are closed.
+pub fn terminate_current_process(status: i32, group: bool) -> Result<(), KernelError> {
+    let (pid, clear_child_tid) = {
+        let manager = PROCESS_MANAGER.lock();
+        let pid = manager.current_pid().ok_or(KernelError::NoSuchProcess)?;
+        (pid, manager.get_process(pid).ok_or(KernelError::NoSuchProcess)?.clear_child_tid)
+    };
+    // Lets a thread joining this one see it is gone. There are no futexes
+    // yet, so joiners have to poll.
+    if clear_child_tid != 0 && !group {
+        let _ = put_user_u32(clear_child_tid, 0);
+    }
+    
+    let unused: Vec<u32> = {
+        let mut manager = PROCESS_MANAGER.lock();
+        let tgid = manager.get_process(pid).ok_or(KernelError::NoSuchProcess)?.tgid;
+        let files: Vec<u32> = manager.processes.iter()
+            .filter(|p| p.tgid == tgid && (group || p.pid == pid))
+            .map(|p| p.files)
+            .collect();
+        if group {
+            manager.terminate_group(pid, status)?;
+        } else {
+            manager.terminate_process(pid, status)?;
         }
+        let mut unused: Vec<u32> = files.into_iter().filter(|&files| !manager.files_in_use(files)).collect();
+        unused.sort_unstable();
+        unused.dedup();
+        unused
+    };
+    for files in unused {
+        crate::fs::release_fd_table(files);
     }
+    Ok(())
 }
 
-pub fn terminate_current_process() -> Result<(), &'static str> {
+/// Makes `pid` the process orphans are reparented to
+pub fn set_init_process(pid: u32) -> Result<(), KernelError> {
     let mut manager = PROCESS_MANAGER.lock();
-    if let Some(current_pid) = manager.current_pid {
-        manager.terminate_process(current_pid)
-    } else {
-        Err("No current process")
+    manager.get_process(pid).ok_or(KernelError::NoSuchProcess)?;
+    manager.init_pid = Some(pid);
+    Ok(())
+}
+
+pub fn parent_pid(pid: u32) -> Option<u32> {
+    PROCESS_MANAGER.lock().get_process(pid)?.parent
+}
+
+/// Frees a zombie the kernel started, which has no parent to wait for it.
+/// Returns its wait status.
+pub fn reap(pid: u32) -> Result<i32, KernelError> {
+    PROCESS_MANAGER.lock().reap(pid).map(|(status, _)| status)
+}
+
+/// Waits for a child of the calling process matching `pid` (-1 or 0 for
+/// any) to exit and reaps it. Returns its pid, wait status and resource
+/// usage, or None under WNOHANG while the children are still running.
+pub fn sys_wait4(pid: i32, options: u32) -> Result<Option<(u32, i32, Rusage)>, KernelError> {
+    if pid < -1 || options & !WNOHANG != 0 {
+        return Err(KernelError::InvalidArgument);
+    }
+    loop {
+        let mut manager = PROCESS_MANAGER.lock();
+        let thread = manager.current_pid().ok_or(KernelError::NoSuchProcess)?;
+        // Any thread may wait for the children of its process
+        let parent = manager.current_tgid().ok_or(KernelError::NoSuchProcess)?;
+        if let Some(child) = manager.find_zombie_child(parent, pid)? {
+            let (status, cpu_time) = manager.reap(child)?;
+            let usage = Rusage {
+                utime: Timeval::from_ns(cpu_time),
+                ..Rusage::default()
+            };
+            return Ok(Some((child, status, usage)));
+        }
+        if options & WNOHANG != 0 {
+            return Ok(None);
+        }
+        if manager.signal_pending(thread) {
+            return Err(KernelError::Interrupted);
+        }
+        manager.wait_for_child()?;
+        drop(manager);
+        schedule();
+    }
+}
+
+/// Makes a blocked thread runnable again
+pub fn wake_process(pid: u32) -> Result<(), KernelError> {
+    PROCESS_MANAGER.lock().wake_process(pid)
+}
+
+/// Marks the calling thread blocked ahead of checking what it waits for
+/// (see wait_queue.rs). Returns its pid, or None when no process is running.
+pub fn prepare_to_wait() -> Option<u32> {
+    let mut manager = PROCESS_MANAGER.lock();
+    let pid = manager.current_pid()?;
+    manager.get_process_mut(pid)?.state = ProcessState::Blocked;
+    Some(pid)
+}
+
+/// Keeps the calling thread running after `prepare_to_wait` when it did
+/// not need to sleep after all
+pub fn finish_wait() {
+    let mut manager = PROCESS_MANAGER.lock();
+    let Some(pid) = manager.current_pid() else {
+        return;
+    };
+    if let Some(process) = manager.get_process_mut(pid).filter(|p| p.state == ProcessState::Blocked) {
+        process.state = ProcessState::Running;
     }
 }
 
+/// Whether the calling thread has a signal to act on
+pub fn signal_pending() -> bool {
+    let manager = PROCESS_MANAGER.lock();
+    manager.current_pid().is_some_and(|pid| manager.signal_pending(pid))
+}
+
+pub fn sys_waitpid(pid: i32, options: u32) -> Result<Option<(u32, i32)>, KernelError> {
+    sys_wait4(pid, options).map(|child| child.map(|(pid, status, _)| (pid, status)))
+}
+
+pub fn sys_getppid() -> u32 {
+    let manager = PROCESS_MANAGER.lock();
+    manager.current_pid().and_then(|pid| manager.address_space(pid)?.parent).unwrap_or(0)
+}
+
+/// Process id of the caller, shared by all of its threads
+pub fn sys_getpid() -> u32 {
+    PROCESS_MANAGER.lock().current_tgid().unwrap_or(0)
+}
+
+/// Thread id of the caller
+pub fn sys_gettid() -> u32 {
+    get_current_pid().unwrap_or(0)
+}
+
+/// Sets the address zeroed when the calling thread exits. Returns its
+/// thread id.
+pub fn sys_set_tid_address(addr: u64) -> Result<u32, KernelError> {
+    let mut manager = PROCESS_MANAGER.lock();
+    let pid = manager.current_pid().ok_or(KernelError::NoSuchProcess)?;
+    manager.get_process_mut(pid).ok_or(KernelError::NoSuchProcess)?.clear_child_tid = addr;
+    Ok(pid)
+}
+
+/// Thread id of the thread running on this CPU
 pub fn get_current_pid() -> Option<u32> {
-    PROCESS_MANAGER.lock().current_pid
+    PROCESS_MANAGER.lock().current_pid()
 }
 
-fn context_switch(process: &Process) {
-    unsafe {
-        // Switch page table
-        asm!(
-            "msr ttbr0_el1, {}",
-            "tlbi vmalle1is",
-            "dsb sy",
-            "isb",
-            in(reg) process.page_table
-        );
-        
-        // This is where we would restore registers and jump to user space
-        // For now, we'll just return to continue kernel execution
+/// The running thread if it runs user code. System calls from kernel
+/// threads pass kernel pointers.
+pub fn current_user_pid() -> Option<u32> {
+    let manager = PROCESS_MANAGER.lock();
+    manager.current_pid().filter(|&pid| manager.get_process(pid).is_some_and(|p| !p.kernel_thread))
+}
+
+/// CPU `pid` is running on, if it is on one
+pub fn running_cpu(pid: u32) -> Option<usize> {
+    PROCESS_MANAGER.lock().running_on(pid)
+}
+
+/// Descriptor table of the running thread
+pub fn current_files() -> Option<u32> {
+    let manager = PROCESS_MANAGER.lock();
+    manager.current_pid().and_then(|pid| manager.get_process(pid)).map(|p| p.files)
+}
+
+pub fn process_state(pid: u32) -> Option<ProcessState> {
+    PROCESS_MANAGER.lock().get_process(pid).map(|process| process.state)
+}
+
+/// Copies `data` into `pid`'s address space at `addr` as a new private
+/// region with `permissions`
+pub fn load_segment(pid: u32, addr: u64, data: &[u8], permissions: MemoryPermissions) -> Result<(), KernelError> {
+    PROCESS_MANAGER.lock()
+        .address_space_mut(pid)
+        .ok_or(KernelError::NoSuchProcess)?
+        .load_segment(addr, data, permissions)
+}
+
+pub fn memory_usage(pid: u32) -> Option<MemoryUsage> {
+    PROCESS_MANAGER.lock().address_space(pid).map(Process::memory_usage)
+}
+
+pub fn getrlimit(pid: u32, resource: u32) -> Result<Rlimit, KernelError> {
+    let manager = PROCESS_MANAGER.lock();
+    let process = manager.get_process(pid).ok_or(KernelError::NoSuchProcess)?;
+    process.limits.get(resource).ok_or(KernelError::InvalidArgument)
+}
+
+// Lowering limits is always allowed; raising the hard limit is not, as
+// there is no notion of a privileged process yet. Limits apply to every
+// thread of the process.
+pub fn setrlimit(pid: u32, resource: u32, limit: Rlimit) -> Result<(), KernelError> {
+    if limit.cur > limit.max {
+        return Err(KernelError::InvalidArgument);
+    }
+    let files = {
+        let mut manager = PROCESS_MANAGER.lock();
+        let process = manager.get_process(pid).ok_or(KernelError::NoSuchProcess)?;
+        let (tgid, files) = (process.tgid, process.files);
+        let current = process.limits.get(resource).ok_or(KernelError::InvalidArgument)?;
+        if limit.max > current.max {
+            return Err(KernelError::NotPermitted);
+        }
+        for thread in manager.processes.iter_mut().filter(|p| p.tgid == tgid) {
+            if let Some(current) = thread.limits.get_mut(resource) {
+                *current = limit;
+            }
+        }
+        files
+    };
+    
+    if resource == RLIMIT_NOFILE {
+        crate::fs::set_fd_limit(files, limit.cur);
+    }
+    Ok(())
+}
+
+pub fn find_region(pid: u32, addr: u64) -> Option<MemoryRegion> {
+    let manager = PROCESS_MANAGER.lock();
+    let process = manager.address_space(pid)?;
+    process.find_region(addr).map(|index| process.memory_regions[index].clone())
+}
+
+// Physical address backing `addr` in `pid`'s address space, if mapped
+pub fn translate(pid: u32, addr: u64) -> Option<PhysAddr> {
+    let manager = PROCESS_MANAGER.lock();
+    let process = manager.address_space(pid)?;
+    unsafe { process.mapper() }.translate(VirtAddr::new(addr))
+}
+
+/// Makes `[start, start + len)` of `pid`'s address space present for
+/// `access`. Fails if any of it is outside the process's mappings.
+pub fn fault_in(pid: u32, start: u64, len: u64, access: FaultAccess) -> Result<(), KernelError> {
+    PROCESS_MANAGER.lock().fault_in(pid, start, len, access)
+}
+
+pub fn handle_page_fault(fault: &FaultInfo) -> Result<(), KernelError> {
+    // A fault taken while this CPU has the process table locked cannot be
+    // resolved
+    if PROCESS_MANAGER.is_held_here() {
+        return Err(KernelError::Busy);
+    }
+    let mut manager = PROCESS_MANAGER.lock();
+    let pid = manager.current_pid().ok_or(KernelError::NoSuchProcess)?;
+    manager.handle_page_fault(pid, fault)
+}
+
+// Installs `process`'s address space. The ASID keeps other processes' TLB
+// entries valid; registers are restored by the switch itself.
+fn context_switch(process: &mut Process) {
+    let root = PhysFrame::containing_address(PhysAddr::new(process.page_table));
+    mmu::activate_user_space(root, &mut process.asid);
+}
+
+// TPIDR_EL0, the user thread pointer
+fn read_thread_pointer() -> u64 {
+    let value: u64;
+    unsafe { asm!("mrs {}, tpidr_el0", out(reg) value) };
+    value
+}
+
+fn write_thread_pointer(value: u64) {
+    unsafe { asm!("msr tpidr_el0, {}", in(reg) value) };
+}
+
+// Stores `value` at `addr` in the calling thread's address space. Call
+// without the table locked, as a fault then could not be resolved.
+fn put_user_u32(addr: u64, value: u32) -> Result<(), KernelError> {
+    if !addr.is_multiple_of(4) {
+        return Err(KernelError::BadAddress);
     }
+    crate::uaccess::write_user(addr, &value)
 }
 
 // System call handlers for process management
-pub fn sys_fork() -> u32 {
-    // Fork implementation would go here
-    0
+pub fn sys_getrlimit(resource: u32) -> Result<Rlimit, KernelError> {
+    let pid = get_current_pid().ok_or(KernelError::NoSuchProcess)?;
+    getrlimit(pid, resource)
+}
+
+pub fn sys_setrlimit(resource: u32, limit: Rlimit) -> Result<(), KernelError> {
+    let pid = get_current_pid().ok_or(KernelError::NoSuchProcess)?;
+    setrlimit(pid, resource, limit)
+}
+
+/// Adds `increment` to the calling process's nice value and returns the
+/// new one
+pub fn sys_nice(increment: i32) -> Result<i8, KernelError> {
+    let mut manager = PROCESS_MANAGER.lock();
+    let pid = manager.current_pid().ok_or(KernelError::NoSuchProcess)?;
+    let nice = manager.get_process(pid).ok_or(KernelError::NoSuchProcess)?.nice;
+    manager.set_nice(pid, nice as i32 + increment)
+}
+
+// `who` 0 means the calling process
+fn priority_target(manager: &ProcessManager, which: u32, who: u32) -> Result<u32, KernelError> {
+    if which != PRIO_PROCESS {
+        return Err(KernelError::InvalidArgument);
+    }
+    match who {
+        0 => manager.current_pid().ok_or(KernelError::NoSuchProcess),
+        pid => manager.get_process(pid).map(|p| p.pid).ok_or(KernelError::NoSuchProcess),
+    }
+}
+
+pub fn sys_getpriority(which: u32, who: u32) -> Result<i8, KernelError> {
+    let manager = PROCESS_MANAGER.lock();
+    let pid = priority_target(&manager, which, who)?;
+    Ok(manager.get_process(pid).ok_or(KernelError::NoSuchProcess)?.nice)
 }
 
-pub fn sys_exec(entry_point: u64) -> Result<(), &'static str> {
+/// Sets the nice value of a process, clamped to -20..=19
+pub fn sys_setpriority(which: u32, who: u32, nice: i32) -> Result<(), KernelError> {
     let mut manager = PROCESS_MANAGER.lock();
-    if let Some(current_pid) = manager.current_pid {
-        if let Some(process) = manager.get_process_mut(current_pid) {
-            process.entry_point = entry_point;
-            // Reset registers and stack
-            process.registers = [0; 31];
-            Ok(())
+    let pid = priority_target(&manager, which, who)?;
+    manager.set_nice(pid, nice).map(|_| ())
+}
+
+/// Sets the nice value of `pid`, clamped to -20..=19
+pub fn set_priority(pid: u32, nice: i32) -> Result<i8, KernelError> {
+    PROCESS_MANAGER.lock().set_nice(pid, nice)
+}
+
+/// Nanoseconds `pid` has spent on the CPU
+pub fn cpu_time(pid: u32) -> Option<u64> {
+    PROCESS_MANAGER.lock().cpu_time(pid)
+}
+
+/// Resource usage of the calling process. Time in the kernel is not told
+/// apart from time in user mode yet, so all of it is user time.
+pub fn sys_getrusage(who: i32) -> Result<Rusage, KernelError> {
+    if who != RUSAGE_SELF {
+        return Err(KernelError::InvalidArgument);
+    }
+    let pid = get_current_pid().ok_or(KernelError::NoSuchProcess)?;
+    let time = cpu_time(pid).ok_or(KernelError::NoSuchProcess)?;
+    Ok(Rusage {
+        utime: Timeval::from_ns(time),
+        ..Rusage::default()
+    })
+}
+
+pub fn set_scheduler_policy(kind: PolicyKind) {
+    PROCESS_MANAGER.lock().set_policy(kind);
+}
+
+pub fn scheduler_policy() -> &'static str {
+    PROCESS_MANAGER.lock().policy_name()
+}
+
+pub fn sys_fork() -> Result<u32, KernelError> {
+    fork(get_current_pid().ok_or(KernelError::NoSuchProcess)?)
+}
+
+/// Forks the process of thread `parent`, descriptors included
+pub fn fork(parent: u32) -> Result<u32, KernelError> {
+    let (files, child) = {
+        let mut manager = PROCESS_MANAGER.lock();
+        let files = manager.get_process(parent).ok_or(KernelError::NoSuchProcess)?.files;
+        (files, manager.fork(parent)?)
+    };
+    
+    crate::fs::clone_fd_table(files, child);
+    Ok(child)
+}
+
+// clone flags, as on Linux
+pub const CLONE_VM: u64 = 0x100;
+pub const CLONE_FS: u64 = 0x200;
+pub const CLONE_FILES: u64 = 0x400;
+pub const CLONE_SIGHAND: u64 = 0x800;
+pub const CLONE_THREAD: u64 = 0x10000;
+pub const CLONE_SYSVSEM: u64 = 0x40000;
+pub const CLONE_SETTLS: u64 = 0x80000;
+pub const CLONE_PARENT_SETTID: u64 = 0x100000;
+pub const CLONE_CHILD_CLEARTID: u64 = 0x200000;
+pub const CLONE_CHILD_SETTID: u64 = 0x1000000;
+// The low byte names the signal sent to the parent on exit. It is
+// accepted, but the parent always gets SIGCHLD.
+const CLONE_EXIT_SIGNAL: u64 = 0xff;
+// CLONE_FS and CLONE_SYSVSEM are accepted and have no effect: there is no
+// per-process working directory or System V semaphores
+const CLONE_SUPPORTED: u64 = CLONE_VM | CLONE_FS | CLONE_FILES | CLONE_SIGHAND | CLONE_THREAD | CLONE_SYSVSEM
+    | CLONE_SETTLS | CLONE_PARENT_SETTID | CLONE_CHILD_CLEARTID | CLONE_CHILD_SETTID | CLONE_EXIT_SIGNAL;
+
+/// Creates a thread in the calling process with CLONE_THREAD, which needs
+/// CLONE_SIGHAND and CLONE_VM as on Linux, or a new process like fork
+/// without it. `stack` replaces the child's stack pointer unless it is 0.
+/// Returns the new thread id.
+pub fn sys_clone(flags: u64, stack: u64, parent_tid: u64, child_tid: u64, tls: u64) -> Result<u32, KernelError> {
+    if flags & !CLONE_SUPPORTED != 0 {
+        return Err(KernelError::InvalidArgument);
+    }
+    if (flags & CLONE_THREAD != 0 && flags & CLONE_SIGHAND == 0) || (flags & CLONE_SIGHAND != 0 && flags & CLONE_VM == 0) {
+        return Err(KernelError::InvalidArgument);
+    }
+    // Separate processes sharing one address space are not supported
+    if flags & CLONE_VM != 0 && flags & CLONE_THREAD == 0 {
+        return Err(KernelError::NotSupported);
+    }
+    
+    let tls = if flags & CLONE_SETTLS != 0 { tls } else { read_thread_pointer() };
+    let share_files = flags & CLONE_FILES != 0;
+    let (child, files) = {
+        let mut manager = PROCESS_MANAGER.lock();
+        let parent = manager.current_pid().ok_or(KernelError::NoSuchProcess)?;
+        let files = manager.get_process(parent).ok_or(KernelError::NoSuchProcess)?.files;
+        let child = if flags & CLONE_THREAD != 0 {
+            manager.spawn_thread(parent, stack, tls, share_files)?
         } else {
-            Err("Current process not found")
+            let child = manager.fork(parent)?;
+            let process = manager.get_process_mut(child).ok_or(KernelError::NoSuchProcess)?;
+            if stack != 0 {
+                unsafe { (*process.trap_frame()).sp_el0 = stack };
+            }
+            process.tls = tls;
+            if share_files {
+                process.files = files;
+            }
+            child
+        };
+        if flags & CLONE_CHILD_CLEARTID != 0 {
+            manager.get_process_mut(child).ok_or(KernelError::NoSuchProcess)?.clear_child_tid = child_tid;
+        }
+        (child, files)
+    };
+    if !share_files {
+        crate::fs::clone_fd_table(files, child);
+    }
+    
+    // Bad tid pointers do not undo the clone, as on Linux
+    if flags & CLONE_PARENT_SETTID != 0 {
+        let _ = put_user_u32(parent_tid, child);
+    }
+    if flags & CLONE_CHILD_SETTID != 0 {
+        if flags & CLONE_VM != 0 {
+            let _ = put_user_u32(child_tid, child);
+        } else {
+            let _ = put_child_u32(child, child_tid, child);
         }
+    }
+    Ok(child)
+}
+
+// Stores `value` at `addr` in the address space of `pid`, which is not
+// installed, through the kernel's mapping of the page
+fn put_child_u32(pid: u32, addr: u64, value: u32) -> Result<(), KernelError> {
+    if !addr.is_multiple_of(4) {
+        return Err(KernelError::BadAddress);
+    }
+    let mut manager = PROCESS_MANAGER.lock();
+    manager.fault_in(pid, addr, 4, FaultAccess::Write)?;
+    let process = manager.address_space(pid).ok_or(KernelError::NoSuchProcess)?;
+    let phys = unsafe { process.mapper() }.translate(VirtAddr::new(addr)).ok_or(KernelError::BadAddress)?;
+    unsafe { (memory::phys_to_virt(phys).as_u64() as *mut u32).write_volatile(value) };
+    Ok(())
+}
+
+pub fn sys_mmap(addr: u64, length: u64, prot: u64, flags: u64, fd: i32, offset: u64) -> Result<u64, KernelError> {
+    let pid = get_current_pid().ok_or(KernelError::NoSuchProcess)?;
+    let files = current_files().ok_or(KernelError::NoSuchProcess)?;
+    let permissions = u8::try_from(prot).ok()
+        .and_then(MemoryPermissions::from_bits)
+        .ok_or(KernelError::InvalidArgument)?;
+    let flags = MapFlags::from_bits_truncate(flags as u32);
+    
+    // Resolve the file before taking the process table lock
+    let file = if flags.contains(MapFlags::ANONYMOUS) {
+        None
     } else {
-        Err("No current process")
+        if !offset.is_multiple_of(PAGE_SIZE as u64) {
+            return Err(KernelError::InvalidArgument);
+        }
+        let writable = flags.contains(MapFlags::SHARED) && permissions.contains(MemoryPermissions::WRITE);
+        Some((crate::fs::mappable_file(files, fd, writable)?, offset))
+    };
+    
+    PROCESS_MANAGER.lock().mmap(pid, addr, length, permissions, flags, file)
+}
+
+pub fn sys_munmap(addr: u64, length: u64) -> Result<(), KernelError> {
+    let mut manager = PROCESS_MANAGER.lock();
+    let pid = manager.current_pid().ok_or(KernelError::NoSuchProcess)?;
+    manager.munmap(pid, addr, length)
+}
+
+pub fn sys_brk(addr: u64) -> Result<u64, KernelError> {
+    let mut manager = PROCESS_MANAGER.lock();
+    let pid = manager.current_pid().ok_or(KernelError::NoSuchProcess)?;
+    manager.brk(pid, addr)
+}
+
+pub fn sys_mprotect(addr: u64, length: u64, prot: u64) -> Result<(), KernelError> {
+    let permissions = u8::try_from(prot).ok()
+        .and_then(MemoryPermissions::from_bits)
+        .ok_or(KernelError::InvalidArgument)?;
+    let mut manager = PROCESS_MANAGER.lock();
+    let pid = manager.current_pid().ok_or(KernelError::NoSuchProcess)?;
+    manager.mprotect(pid, addr, length, permissions)
+}
+
+/// Replaces the calling process's image with the ELF executable `elf`,
+/// started with `argv` and `envp`. Its other threads are ended once the
+/// new image is in place; exec from a thread other than the group leader
+/// is not supported. On failure the caller carries on as it was.
+pub fn sys_exec(elf: &[u8], argv: &[&str], envp: &[&str]) -> Result<(), KernelError> {
+    let program = UserProgram::load_elf(elf)?;
+    let (pid, old, unused) = {
+        let mut manager = PROCESS_MANAGER.lock();
+        let pid = manager.current_pid().ok_or(KernelError::NoSuchProcess)?;
+        let process = manager.get_process(pid).ok_or(KernelError::NoSuchProcess)?;
+        if process.kernel_thread {
+            return Err(KernelError::NotPermitted);
+        }
+        if process.pid != process.tgid {
+            return Err(KernelError::NotSupported);
+        }
+        // Returns from the system call into the new image
+        let old = manager.replace_image(pid, &program, elf, argv, envp)?;
+        let files = manager.end_other_threads(pid);
+        
+        let process = manager.get_process_mut(pid).ok_or(KernelError::NoSuchProcess)?;
+        process.signals.reset_for_exec();
+        process.tls = 0;
+        context_switch(process);
+        let unused: Vec<u32> = files.into_iter().filter(|&files| !manager.files_in_use(files)).collect();
+        (pid, old, unused)
+    };
+    write_thread_pointer(0);
+    for files in unused {
+        crate::fs::release_fd_table(files);
+    }
+    
+    // The ended threads may still be leaving their CPUs on the old tables
+    loop {
+        let manager = PROCESS_MANAGER.lock();
+        if !manager.others_on_cpu(pid) {
+            old.free();
+            return Ok(());
+        }
+        drop(manager);
+        core::hint::spin_loop();
     }
 }
 
-pub fn sys_exit(_exit_code: i32) -> ! {
-    if let Ok(_) = terminate_current_process() {
+pub fn sys_yield() {
+    schedule();
+}
+
+/// Ends the calling thread. The process exits with `exit_code` once its
+/// last thread has.
+pub fn sys_exit(exit_code: i32) -> ! {
+    exit_current(exit_status(exit_code), false)
+}
+
+/// Ends every thread of the calling process
+pub fn sys_exit_group(exit_code: i32) -> ! {
+    exit_current(exit_status(exit_code), true)
+}
+
+/// Ends the calling thread, or its whole process if `group` is set, with
+/// wait status `status`
+fn exit_current(status: i32, group: bool) -> ! {
+    if terminate_current_process(status, group).is_ok() {
         schedule();
     }
     
@@ -239,3 +2346,201 @@ pub fn sys_exit(_exit_code: i32) -> ! {
         }
     }
 }
+
+/// Sends `sig` to `pid` on behalf of the kernel
+pub fn send_signal(pid: u32, sig: u32) -> Result<(), KernelError> {
+    if !signal::is_valid(sig) {
+        return Err(KernelError::InvalidArgument);
+    }
+    PROCESS_MANAGER.lock().send_signal(pid, SigInfo::kernel(sig))
+}
+
+/// Raises a fault signal in the calling process. It is acted on before
+/// the process returns to user mode, even if blocked or ignored.
+pub fn force_signal(info: SigInfo) {
+    let mut manager = PROCESS_MANAGER.lock();
+    if let Some(process) = manager.current_pid().and_then(|pid| manager.get_process_mut(pid)) {
+        process.signals.force(info);
+    }
+}
+
+/// Makes `pid` the process that gets SIGINT when Ctrl-C is typed on the
+/// console
+pub fn set_foreground_process(pid: Option<u32>) {
+    PROCESS_MANAGER.lock().foreground = pid;
+}
+
+/// Sends `sig` to the console's foreground process. Runs in interrupt
+/// context.
+pub fn signal_foreground(sig: u32) {
+    let mut manager = PROCESS_MANAGER.lock();
+    if let Some(pid) = manager.foreground {
+        let _ = manager.send_signal(pid, SigInfo::kernel(sig));
+    }
+}
+
+/// Sends `sig` to `pid`, or to every other user process except init when
+/// `pid` is -1. Signal 0 only checks that the target exists. There are no
+/// process groups, so other pids below 1 are rejected.
+pub fn sys_kill(pid: i32, sig: u32) -> Result<(), KernelError> {
+    if sig != 0 && !signal::is_valid(sig) {
+        return Err(KernelError::InvalidArgument);
+    }
+    let mut manager = PROCESS_MANAGER.lock();
+    let sender = manager.current_pid().unwrap_or(0);
+    let sender_tgid = manager.current_tgid().unwrap_or(0);
+    let targets: Vec<u32> = match pid {
+        1.. => vec![pid as u32],
+        -1 => manager.processes.iter()
+            .filter(|p| p.pid == p.tgid && !p.kernel_thread && p.pid != sender_tgid && Some(p.pid) != manager.init_pid)
+            .map(|p| p.pid)
+            .collect(),
+        _ => return Err(KernelError::InvalidArgument),
+    };
+    if targets.is_empty() {
+        return Err(KernelError::NoSuchProcess);
+    }
+    for target in targets {
+        if sig == 0 {
+            manager.get_process(target).ok_or(KernelError::NoSuchProcess)?;
+        } else {
+            manager.send_signal(target, SigInfo::user(sig, sender))?;
+        }
+    }
+    Ok(())
+}
+
+/// Installs `action` for `sig` in every thread of the calling process if
+/// given, and returns the previous action
+pub fn sys_sigaction(sig: u32, action: Option<SigAction>) -> Result<SigAction, KernelError> {
+    if !signal::is_valid(sig) {
+        return Err(KernelError::InvalidArgument);
+    }
+    let mut manager = PROCESS_MANAGER.lock();
+    let pid = manager.current_pid().ok_or(KernelError::NoSuchProcess)?;
+    let process = manager.get_process_mut(pid).ok_or(KernelError::NoSuchProcess)?;
+    let tgid = process.tgid;
+    let Some(action) = action else {
+        return Ok(process.signals.action(sig));
+    };
+    let previous = process.signals.set_action(sig, action)?;
+    for thread in manager.processes.iter_mut().filter(|p| p.tgid == tgid && p.pid != pid) {
+        let _ = thread.signals.set_action(sig, action);
+    }
+    Ok(previous)
+}
+
+/// Changes the blocked set with `how` if `set` is given, and returns the
+/// previous one
+pub fn sys_sigprocmask(how: u32, set: Option<SigSet>) -> Result<SigSet, KernelError> {
+    let mut manager = PROCESS_MANAGER.lock();
+    let pid = manager.current_pid().ok_or(KernelError::NoSuchProcess)?;
+    let process = manager.get_process_mut(pid).ok_or(KernelError::NoSuchProcess)?;
+    match set {
+        Some(set) => process.signals.set_blocked(how, set),
+        None => Ok(process.signals.blocked),
+    }
+}
+
+/// Returns from a signal handler, restoring the registers and mask saved
+/// in the frame at the stack pointer. The result is the interrupted x0,
+/// which the system call return puts back.
+pub fn sys_sigreturn() -> Result<u64, KernelError> {
+    let manager = PROCESS_MANAGER.lock();
+    let pid = manager.current_pid().ok_or(KernelError::NoSuchProcess)?;
+    let process = manager.get_process(pid).ok_or(KernelError::NoSuchProcess)?;
+    if process.kernel_thread {
+        return Err(KernelError::NotPermitted);
+    }
+    let registers = process.trap_frame();
+    let addr = unsafe { (*registers).sp_el0 };
+    
+    // Read unlocked, as the copy may fault the frame in
+    drop(manager);
+    let frame = match addr % 16 {
+        0 => signal::read_frame(addr),
+        _ => Err(KernelError::BadAddress),
+    };
+    let mut manager = PROCESS_MANAGER.lock();
+    let process = manager.get_process_mut(pid).ok_or(KernelError::NoSuchProcess)?;
+    let (saved, blocked) = frame.inspect_err(|_| {
+        // There is nothing sensible to return to
+        process.signals.force(SigInfo::kernel(signal::SIGSEGV));
+    })?;
+    unsafe { *registers = saved };
+    process.signals.blocked = blocked;
+    Ok(saved.regs[0])
+}
+
+/// Acts on the calling process's pending signals before it returns to
+/// user mode through `frame`. Default actions run here; a caught signal
+/// gets a frame on the user stack and `frame` is pointed at its handler.
+pub fn deliver_signals(frame: &mut TrapFrame) {
+    loop {
+        let mut manager = PROCESS_MANAGER.lock();
+        let Some(pid) = manager.current_pid() else {
+            return;
+        };
+        let Some(process) = manager.get_process_mut(pid) else {
+            return;
+        };
+        // Ended by another thread of its group, on another CPU
+        if process.state == ProcessState::Zombie {
+            drop(manager);
+            schedule();
+            return;
+        }
+        let Some(info) = process.signals.dequeue() else {
+            return;
+        };
+        let sig = info.signal();
+        let action = process.signals.action(sig);
+        let blocked = process.signals.blocked;
+        
+        match action.handler {
+            signal::SIG_IGN => continue,
+            signal::SIG_DFL => match signal::default_action(sig) {
+                DefaultAction::Ignore | DefaultAction::Continue => continue,
+                DefaultAction::Stop => {
+                    process.state = ProcessState::Stopped;
+                    drop(manager);
+                    schedule();
+                }
+                DefaultAction::Terminate | DefaultAction::Core => {
+                    drop(manager);
+                    exit_current(signal::signal_status(sig), true);
+                }
+            },
+            _ => {
+                // Written unlocked, as the copy may fault the stack in
+                drop(manager);
+                let entered = signal::frame_address(frame.sp_el0)
+                    .ok_or(KernelError::BadAddress)
+                    .and_then(|addr| signal::enter_handler(frame, addr, &info, &action, blocked));
+                let mut manager = PROCESS_MANAGER.lock();
+                let Some(process) = manager.get_process_mut(pid) else {
+                    return;
+                };
+                if entered.is_err() {
+                    // No room for the frame. A SIGSEGV handler could not
+                    // run either, so that one falls back to the default.
+                    if sig == signal::SIGSEGV {
+                        let _ = process.signals.set_action(sig, SigAction::default());
+                    }
+                    process.signals.force(SigInfo::kernel(signal::SIGSEGV));
+                    continue;
+                }
+                
+                let mut mask = action.mask;
+                if action.flags & signal::SA_NODEFER == 0 {
+                    mask |= signal::sigmask(sig);
+                }
+                let _ = process.signals.set_blocked(signal::SIG_BLOCK, mask);
+                if action.flags & signal::SA_RESETHAND != 0 {
+                    let _ = process.signals.set_action(sig, SigAction::default());
+                }
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,113 @@
+#![allow(dead_code)]
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::fdt::{self, PsciConduit};
+
+// Power State Coordination Interface. Firmware (or the hypervisor, on QEMU
+// without EL3) starts and stops CPUs on the kernel's behalf; calls go
+// through HVC or SMC as the device tree says.
+
+// SMC64 function IDs
+const PSCI_VERSION: u32 = 0x8400_0000;
+const CPU_ON: u32 = 0xc400_0003;
+const AFFINITY_INFO: u32 = 0xc400_0004;
+const SYSTEM_OFF: u32 = 0x8400_0008;
+
+// Return codes
+const SUCCESS: i64 = 0;
+const NOT_SUPPORTED: i64 = -1;
+const INVALID_PARAMETERS: i64 = -2;
+const DENIED: i64 = -3;
+const ALREADY_ON: i64 = -4;
+const ON_PENDING: i64 = -5;
+const INTERNAL_FAILURE: i64 = -6;
+const NOT_PRESENT: i64 = -7;
+const DISABLED: i64 = -8;
+const INVALID_ADDRESS: i64 = -9;
+
+/// AFFINITY_INFO states
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AffinityState {
+    On,
+    Off,
+    OnPending,
+}
+
+static SMC: AtomicBool = AtomicBool::new(false);
+
+/// Picks the conduit from the device tree. HVC, which QEMU uses when it
+/// has no EL3 firmware, is assumed otherwise.
+pub fn init() -> Result<(), &'static str> {
+    let conduit = fdt::platform().psci.ok_or("No PSCI in the device tree")?;
+    SMC.store(conduit == PsciConduit::Smc, Ordering::SeqCst);
+    Ok(())
+}
+
+/// (major, minor) version of the firmware's PSCI
+pub fn version() -> (u16, u16) {
+    let version = call(PSCI_VERSION, 0, 0, 0) as u32;
+    ((version >> 16) as u16, version as u16)
+}
+
+/// Starts the CPU with affinity `mpidr` at physical address `entry`, with
+/// MMU and caches off and `context` in x0
+pub fn cpu_on(mpidr: u64, entry: u64, context: u64) -> Result<(), &'static str> {
+    match call(CPU_ON, mpidr, entry, context) {
+        SUCCESS => Ok(()),
+        ALREADY_ON => Err("CPU already on"),
+        ON_PENDING => Err("CPU already starting"),
+        INVALID_PARAMETERS | NOT_PRESENT => Err("No such CPU"),
+        INVALID_ADDRESS => Err("Invalid entry point"),
+        NOT_SUPPORTED => Err("CPU_ON not supported"),
+        DENIED | DISABLED => Err("CPU_ON denied"),
+        INTERNAL_FAILURE => Err("PSCI internal failure"),
+        _ => Err("Unknown PSCI error"),
+    }
+}
+
+/// Power state of the CPU with affinity `mpidr`
+pub fn affinity_info(mpidr: u64) -> Result<AffinityState, &'static str> {
+    match call(AFFINITY_INFO, mpidr, 0, 0) {
+        0 => Ok(AffinityState::On),
+        1 => Ok(AffinityState::Off),
+        2 => Ok(AffinityState::OnPending),
+        _ => Err("No such CPU"),
+    }
+}
+
+/// Powers the machine off
+pub fn system_off() -> ! {
+    call(SYSTEM_OFF, 0, 0, 0);
+    loop {
+        unsafe { asm!("wfe") };
+    }
+}
+
+// SMCCC 1.0 leaves x4-x17 unpredictable after the call
+fn call(function: u32, arg0: u64, arg1: u64, arg2: u64) -> i64 {
+    let mut result = function as u64;
+    macro_rules! conduit {
+        ($instruction:literal) => {
+            asm!(
+                $instruction,
+                inout("x0") result,
+                inout("x1") arg0 => _,
+                inout("x2") arg1 => _,
+                inout("x3") arg2 => _,
+                out("x4") _, out("x5") _, out("x6") _, out("x7") _,
+                out("x8") _, out("x9") _, out("x10") _, out("x11") _,
+                out("x12") _, out("x13") _, out("x14") _, out("x15") _,
+                out("x16") _, out("x17") _,
+            )
+        };
+    }
+    unsafe {
+        if SMC.load(Ordering::Relaxed) {
+            conduit!("smc #0");
+        } else {
+            conduit!("hvc #0");
+        }
+    }
+    result as i64
+}
@@ -0,0 +1,319 @@
+#![allow(dead_code)]
+
+use alloc::collections::{BTreeMap, VecDeque};
+
+// Scheduling policies. The process manager tells the policy which
+// processes are runnable and charges it timer ticks; the policy decides
+// who runs next and when the running process should be preempted. Each CPU
+// has a run queue of its own, which is one instance of the policy.
+
+pub const NICE_MIN: i8 = -20;
+pub const NICE_MAX: i8 = 19;
+
+/// Why a process is being queued
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Enqueue {
+    New,
+    // Switched out while still runnable, by a yield or preemption
+    Requeue,
+    // Was blocked and can run again
+    Woken,
+}
+
+pub trait SchedPolicy: Send {
+    fn name(&self) -> &'static str;
+    
+    /// Makes `pid` runnable
+    fn enqueue(&mut self, pid: u32, nice: i8, reason: Enqueue);
+    
+    /// Takes `pid` off the run queue, keeping what the policy knows about it
+    fn dequeue(&mut self, pid: u32);
+    
+    /// Forgets an exited process
+    fn remove(&mut self, pid: u32);
+    
+    /// Takes the process to run next off the run queue
+    fn pick_next(&mut self) -> Option<u32>;
+    
+    /// Gives up a queued process to another CPU with nothing to run: the
+    /// one that would run last. The policy forgets it and hands over what
+    /// it knew, for `adopt` on the other CPU.
+    fn steal(&mut self) -> Option<Stolen>;
+    
+    /// Takes over a process stolen from another CPU's instance of this
+    /// policy. It runs here next, so is not queued.
+    fn adopt(&mut self, stolen: Stolen);
+    
+    /// Charges a timer tick to the running process `pid`. Returns true when
+    /// it should give up the CPU to a queued process.
+    fn tick(&mut self, pid: u32) -> bool;
+    
+    fn set_nice(&mut self, pid: u32, nice: i8);
+    
+    /// Number of queued processes
+    fn runnable(&self) -> usize;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyKind {
+    RoundRobin,
+    Mlfq,
+}
+
+impl PolicyKind {
+    pub fn create(self) -> alloc::boxed::Box<dyn SchedPolicy> {
+        match self {
+            PolicyKind::RoundRobin => alloc::boxed::Box::new(RoundRobin::new()),
+            PolicyKind::Mlfq => alloc::boxed::Box::new(Mlfq::new()),
+        }
+    }
+}
+
+/// A process `steal` took off a run queue, with the policy's record of it
+#[derive(Debug, Clone, Copy)]
+pub struct Stolen {
+    pub pid: u32,
+    mlfq: Option<MlfqEntry>,
+}
+
+pub fn clamp_nice(nice: i32) -> i8 {
+    nice.clamp(NICE_MIN as i32, NICE_MAX as i32) as i8
+}
+
+/// First in, first out with a fixed time slice. Ignores nice values.
+pub struct RoundRobin {
+    queue: VecDeque<u32>,
+    // Ticks the running process has left
+    slice_left: u32,
+}
+
+impl RoundRobin {
+    pub const TIME_SLICE_TICKS: u32 = 5;
+    
+    pub fn new() -> Self {
+        RoundRobin {
+            queue: VecDeque::new(),
+            slice_left: Self::TIME_SLICE_TICKS,
+        }
+    }
+}
+
+impl Default for RoundRobin {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SchedPolicy for RoundRobin {
+    fn name(&self) -> &'static str {
+        "round-robin"
+    }
+    
+    fn enqueue(&mut self, pid: u32, _nice: i8, _reason: Enqueue) {
+        self.queue.push_back(pid);
+    }
+    
+    fn dequeue(&mut self, pid: u32) {
+        self.queue.retain(|&p| p != pid);
+    }
+    
+    fn remove(&mut self, pid: u32) {
+        self.dequeue(pid);
+    }
+    
+    fn pick_next(&mut self) -> Option<u32> {
+        self.slice_left = Self::TIME_SLICE_TICKS;
+        self.queue.pop_front()
+    }
+    
+    fn steal(&mut self) -> Option<Stolen> {
+        self.queue.pop_back().map(|pid| Stolen { pid, mlfq: None })
+    }
+    
+    fn adopt(&mut self, _stolen: Stolen) {}
+    
+    fn tick(&mut self, _pid: u32) -> bool {
+        self.slice_left = self.slice_left.saturating_sub(1);
+        self.slice_left == 0 && !self.queue.is_empty()
+    }
+    
+    fn set_nice(&mut self, _pid: u32, _nice: i8) {}
+    
+    fn runnable(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+/// Multilevel feedback queue. Level 0 runs first. A process starts at the
+/// level its nice value gives it and drops a level each time it uses up
+/// that level's allotment, so CPU-bound processes sink while processes that
+/// block or yield early stay near the top. Waking from a block moves a
+/// process up a level, and every `BOOST_INTERVAL` ticks everything returns
+/// to its starting level so sunk processes are not starved.
+pub struct Mlfq {
+    queues: [VecDeque<u32>; Mlfq::LEVELS],
+    entries: BTreeMap<u32, MlfqEntry>,
+    // Ticks until the next boost
+    boost_in: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct MlfqEntry {
+    nice: i8,
+    level: usize,
+    // Ticks used at the current level, kept across yields so a process
+    // cannot stay up by yielding just before its allotment runs out
+    used: u32,
+}
+
+impl Mlfq {
+    pub const LEVELS: usize = 8;
+    pub const BOOST_INTERVAL: u32 = 100;
+    
+    pub fn new() -> Self {
+        Mlfq {
+            queues: Default::default(),
+            entries: BTreeMap::new(),
+            boost_in: Self::BOOST_INTERVAL,
+        }
+    }
+    
+    /// Level a process with `nice` starts at and is boosted back to. Each
+    /// level spans five nice values, so nice 0 starts at level 4.
+    pub fn base_level(nice: i8) -> usize {
+        ((nice as i32 - NICE_MIN as i32) / 5) as usize
+    }
+    
+    /// Ticks a process may run at `level` before dropping a level
+    pub fn allotment(level: usize) -> u32 {
+        2 << (level / 2)
+    }
+    
+    pub fn level(&self, pid: u32) -> Option<usize> {
+        self.entries.get(&pid).map(|entry| entry.level)
+    }
+    
+    fn highest_queued(&self) -> Option<usize> {
+        self.queues.iter().position(|queue| !queue.is_empty())
+    }
+    
+    fn boost(&mut self) {
+        let mut queued = alloc::vec::Vec::new();
+        for queue in self.queues.iter_mut() {
+            queued.extend(queue.drain(..));
+        }
+        for entry in self.entries.values_mut() {
+            entry.level = Self::base_level(entry.nice);
+            entry.used = 0;
+        }
+        for pid in queued {
+            let level = self.entries[&pid].level;
+            self.queues[level].push_back(pid);
+        }
+    }
+}
+
+impl Default for Mlfq {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SchedPolicy for Mlfq {
+    fn name(&self) -> &'static str {
+        "mlfq"
+    }
+    
+    fn enqueue(&mut self, pid: u32, nice: i8, reason: Enqueue) {
+        let base = Self::base_level(nice);
+        let entry = self.entries.entry(pid).or_insert(MlfqEntry { nice, level: base, used: 0 });
+        entry.nice = nice;
+        match reason {
+            Enqueue::New => {
+                entry.level = base;
+                entry.used = 0;
+            }
+            Enqueue::Woken if entry.level > base => {
+                entry.level -= 1;
+                entry.used = 0;
+            }
+            _ => {}
+        }
+        let level = entry.level;
+        self.queues[level].push_back(pid);
+    }
+    
+    fn dequeue(&mut self, pid: u32) {
+        for queue in self.queues.iter_mut() {
+            queue.retain(|&p| p != pid);
+        }
+    }
+    
+    fn remove(&mut self, pid: u32) {
+        self.dequeue(pid);
+        self.entries.remove(&pid);
+    }
+    
+    fn pick_next(&mut self) -> Option<u32> {
+        let level = self.highest_queued()?;
+        self.queues[level].pop_front()
+    }
+    
+    fn steal(&mut self) -> Option<Stolen> {
+        let level = self.queues.iter().rposition(|queue| !queue.is_empty())?;
+        let pid = self.queues[level].pop_back()?;
+        let entry = self.entries.remove(&pid);
+        Some(Stolen { pid, mlfq: entry })
+    }
+    
+    fn adopt(&mut self, stolen: Stolen) {
+        if let Some(entry) = stolen.mlfq {
+            self.entries.insert(stolen.pid, entry);
+        }
+    }
+    
+    fn tick(&mut self, pid: u32) -> bool {
+        self.boost_in -= 1;
+        if self.boost_in == 0 {
+            self.boost_in = Self::BOOST_INTERVAL;
+            self.boost();
+        }
+        
+        let highest = self.highest_queued();
+        let Some(entry) = self.entries.get_mut(&pid) else {
+            return highest.is_some();
+        };
+        entry.used += 1;
+        if entry.used >= Self::allotment(entry.level) {
+            entry.level = (entry.level + 1).min(Self::LEVELS - 1);
+            entry.used = 0;
+            return highest.is_some();
+        }
+        // Something more urgent became runnable
+        highest.is_some_and(|level| level < entry.level)
+    }
+    
+    fn set_nice(&mut self, pid: u32, nice: i8) {
+        let Some(entry) = self.entries.get_mut(&pid) else {
+            return;
+        };
+        entry.nice = nice;
+        let level = Self::base_level(nice);
+        if level == entry.level {
+            return;
+        }
+        entry.level = level;
+        entry.used = 0;
+        
+        // Move it if it is waiting to run
+        let queued = self.queues.iter().position(|queue| queue.contains(&pid));
+        if let Some(old) = queued {
+            self.queues[old].retain(|&p| p != pid);
+            self.queues[level].push_back(pid);
+        }
+    }
+    
+    fn runnable(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+}
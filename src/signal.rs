@@ -0,0 +1,383 @@
+#![allow(dead_code)]
+
+use alloc::collections::BTreeMap;
+use crate::error::KernelError;
+use crate::exception::{TrapFrame, SPSR_EL0T};
+use crate::uaccess;
+
+// POSIX signals. Numbers, the sigaction layout and flags follow Linux on
+// AArch64. Each process carries a `SignalState`; the process manager
+// queues signals into it and delivers them on the way back to user mode.
+
+pub const SIGHUP: u32 = 1;
+pub const SIGINT: u32 = 2;
+pub const SIGQUIT: u32 = 3;
+pub const SIGILL: u32 = 4;
+pub const SIGTRAP: u32 = 5;
+pub const SIGABRT: u32 = 6;
+pub const SIGBUS: u32 = 7;
+pub const SIGFPE: u32 = 8;
+pub const SIGKILL: u32 = 9;
+pub const SIGUSR1: u32 = 10;
+pub const SIGSEGV: u32 = 11;
+pub const SIGUSR2: u32 = 12;
+pub const SIGPIPE: u32 = 13;
+pub const SIGALRM: u32 = 14;
+pub const SIGTERM: u32 = 15;
+pub const SIGSTKFLT: u32 = 16;
+pub const SIGCHLD: u32 = 17;
+pub const SIGCONT: u32 = 18;
+pub const SIGSTOP: u32 = 19;
+pub const SIGTSTP: u32 = 20;
+pub const SIGTTIN: u32 = 21;
+pub const SIGTTOU: u32 = 22;
+pub const SIGURG: u32 = 23;
+pub const SIGXCPU: u32 = 24;
+pub const SIGXFSZ: u32 = 25;
+pub const SIGVTALRM: u32 = 26;
+pub const SIGPROF: u32 = 27;
+pub const SIGWINCH: u32 = 28;
+pub const SIGIO: u32 = 29;
+pub const SIGPWR: u32 = 30;
+pub const SIGSYS: u32 = 31;
+// Highest signal number, counting the real-time signals
+pub const NSIG: u32 = 64;
+
+// Special handler values
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+// sigaction flags
+pub const SA_SIGINFO: u64 = 0x4;
+pub const SA_RESTORER: u64 = 0x0400_0000;
+pub const SA_NODEFER: u64 = 0x4000_0000;
+pub const SA_RESETHAND: u64 = 0x8000_0000;
+
+// sigprocmask operations
+pub const SIG_BLOCK: u32 = 0;
+pub const SIG_UNBLOCK: u32 = 1;
+pub const SIG_SETMASK: u32 = 2;
+
+// si_code values
+pub const SI_USER: i32 = 0;
+pub const SI_KERNEL: i32 = 0x80;
+pub const SEGV_MAPERR: i32 = 1;
+pub const SEGV_ACCERR: i32 = 2;
+pub const BUS_ADRALN: i32 = 1;
+pub const ILL_ILLOPC: i32 = 1;
+pub const CLD_EXITED: i32 = 1;
+pub const CLD_KILLED: i32 = 2;
+
+/// Set of signals, with bit `sig - 1` standing for `sig`
+pub type SigSet = u64;
+
+pub const fn sigmask(sig: u32) -> SigSet {
+    1 << (sig - 1)
+}
+
+// Cannot be caught, blocked or ignored
+pub const UNBLOCKABLE: SigSet = sigmask(SIGKILL) | sigmask(SIGSTOP);
+const STOP_SIGNALS: SigSet = sigmask(SIGSTOP) | sigmask(SIGTSTP) | sigmask(SIGTTIN) | sigmask(SIGTTOU);
+
+pub fn is_valid(sig: u32) -> bool {
+    (1..=NSIG).contains(&sig)
+}
+
+/// Wait status of a process killed by `sig`. No core files are written,
+/// so the core dump bit is never set.
+pub const fn signal_status(sig: u32) -> i32 {
+    (sig & 0x7f) as i32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefaultAction {
+    Terminate,
+    // Terminate, where a core file would be written
+    Core,
+    Stop,
+    Continue,
+    Ignore,
+}
+
+pub fn default_action(sig: u32) -> DefaultAction {
+    match sig {
+        SIGQUIT | SIGILL | SIGTRAP | SIGABRT | SIGBUS | SIGFPE | SIGSEGV | SIGXCPU | SIGXFSZ | SIGSYS => {
+            DefaultAction::Core
+        }
+        SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => DefaultAction::Stop,
+        SIGCONT => DefaultAction::Continue,
+        SIGCHLD | SIGURG | SIGWINCH => DefaultAction::Ignore,
+        _ => DefaultAction::Terminate,
+    }
+}
+
+/// Layout of the kernel's struct sigaction
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SigAction {
+    pub handler: u64,
+    pub flags: u64,
+    pub restorer: u64,
+    pub mask: SigSet,
+}
+
+/// Layout of siginfo_t. Which union fields are meaningful depends on the
+/// signal; the constructors fill in the ones Linux does.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SigInfo {
+    pub signo: i32,
+    pub errno: i32,
+    pub code: i32,
+    _pad: i32,
+    // si_pid and si_uid, then si_status for SIGCHLD; si_addr for faults
+    pub fields: [u64; 14],
+}
+
+const _: () = assert!(core::mem::size_of::<SigInfo>() == 128);
+
+impl SigInfo {
+    fn new(sig: u32, code: i32) -> Self {
+        SigInfo {
+            signo: sig as i32,
+            errno: 0,
+            code,
+            _pad: 0,
+            fields: [0; 14],
+        }
+    }
+    
+    /// Sent with kill by `sender`
+    pub fn user(sig: u32, sender: u32) -> Self {
+        let mut info = Self::new(sig, SI_USER);
+        info.fields[0] = sender as u64;
+        info
+    }
+    
+    /// Raised by the kernel itself
+    pub fn kernel(sig: u32) -> Self {
+        Self::new(sig, SI_KERNEL)
+    }
+    
+    /// Raised by a fault on `addr`
+    pub fn fault(sig: u32, code: i32, addr: u64) -> Self {
+        let mut info = Self::new(sig, code);
+        info.fields[0] = addr;
+        info
+    }
+    
+    /// SIGCHLD for `child` exiting with wait status `status`
+    pub fn child(child: u32, status: i32) -> Self {
+        let (code, status) = match status & 0x7f {
+            0 => (CLD_EXITED, (status >> 8) & 0xff),
+            sig => (CLD_KILLED, sig),
+        };
+        let mut info = Self::new(SIGCHLD, code);
+        info.fields[0] = child as u64;
+        info.fields[1] = status as u32 as u64;
+        info
+    }
+    
+    pub fn signal(&self) -> u32 {
+        self.signo as u32
+    }
+}
+
+/// Signal dispositions, mask and pending set of one process
+#[derive(Debug, Clone)]
+pub struct SignalState {
+    actions: [SigAction; NSIG as usize],
+    pub blocked: SigSet,
+    pub pending: SigSet,
+    // Signals do not queue: a pending signal keeps the info it was first
+    // raised with
+    info: BTreeMap<u32, SigInfo>,
+}
+
+impl SignalState {
+    pub fn new() -> Self {
+        SignalState {
+            actions: [SigAction::default(); NSIG as usize],
+            blocked: 0,
+            pending: 0,
+            info: BTreeMap::new(),
+        }
+    }
+    
+    pub fn action(&self, sig: u32) -> SigAction {
+        self.actions[sig as usize - 1]
+    }
+    
+    /// Installs `action` for `sig` and returns the old one
+    pub fn set_action(&mut self, sig: u32, action: SigAction) -> Result<SigAction, KernelError> {
+        if !is_valid(sig) || sigmask(sig) & UNBLOCKABLE != 0 {
+            return Err(KernelError::InvalidArgument);
+        }
+        let old = core::mem::replace(&mut self.actions[sig as usize - 1], action);
+        // Ignoring a signal discards it if pending
+        if self.ignores(sig) {
+            self.discard(sigmask(sig));
+        }
+        Ok(old)
+    }
+    
+    /// Whether `sig` would be thrown away on arrival
+    pub fn ignores(&self, sig: u32) -> bool {
+        match self.action(sig).handler {
+            SIG_IGN => sigmask(sig) & UNBLOCKABLE == 0,
+            SIG_DFL => default_action(sig) == DefaultAction::Ignore,
+            _ => false,
+        }
+    }
+    
+    /// Whether a handler runs for `sig`
+    pub fn is_caught(&self, sig: u32) -> bool {
+        !matches!(self.action(sig).handler, SIG_DFL | SIG_IGN)
+    }
+    
+    /// Marks `info`'s signal pending. Returns false if it was ignored.
+    /// SIGCONT and the stop signals cancel each other's pending instances.
+    pub fn queue(&mut self, info: SigInfo) -> bool {
+        let sig = info.signal();
+        if sig == SIGCONT {
+            self.discard(STOP_SIGNALS);
+        } else if sigmask(sig) & STOP_SIGNALS != 0 {
+            self.discard(sigmask(SIGCONT));
+        }
+        if self.ignores(sig) {
+            return false;
+        }
+        if self.pending & sigmask(sig) == 0 {
+            self.pending |= sigmask(sig);
+            self.info.insert(sig, info);
+        }
+        true
+    }
+    
+    fn discard(&mut self, set: SigSet) {
+        self.pending &= !set;
+        self.info.retain(|&sig, _| set & sigmask(sig) == 0);
+    }
+    
+    /// Pending signals that are not blocked
+    pub fn deliverable(&self) -> SigSet {
+        self.pending & !self.blocked
+    }
+    
+    /// Takes the lowest numbered deliverable signal off the pending set
+    pub fn dequeue(&mut self) -> Option<SigInfo> {
+        let set = self.deliverable();
+        if set == 0 {
+            return None;
+        }
+        let sig = set.trailing_zeros() + 1;
+        self.pending &= !sigmask(sig);
+        Some(self.info.remove(&sig).unwrap_or_else(|| SigInfo::kernel(sig)))
+    }
+    
+    /// Changes the blocked set as sigprocmask does and returns the old one
+    pub fn set_blocked(&mut self, how: u32, set: SigSet) -> Result<SigSet, KernelError> {
+        let old = self.blocked;
+        let blocked = match how {
+            SIG_BLOCK => old | set,
+            SIG_UNBLOCK => old & !set,
+            SIG_SETMASK => set,
+            _ => return Err(KernelError::InvalidArgument),
+        };
+        self.blocked = blocked & !UNBLOCKABLE;
+        Ok(old)
+    }
+    
+    /// Makes sure a fault signal is acted on: a blocked or ignored one
+    /// falls back to the default action, as on Linux
+    pub fn force(&mut self, info: SigInfo) {
+        let sig = info.signal();
+        if self.blocked & sigmask(sig) != 0 || self.action(sig).handler == SIG_IGN {
+            self.actions[sig as usize - 1].handler = SIG_DFL;
+            self.blocked &= !sigmask(sig);
+        }
+        self.pending &= !sigmask(sig);
+        self.queue(info);
+    }
+    
+    /// Handlers do not survive exec; ignored signals stay ignored
+    pub fn reset_for_exec(&mut self) {
+        for action in self.actions.iter_mut() {
+            if action.handler != SIG_IGN {
+                *action = SigAction::default();
+            }
+        }
+    }
+    
+    /// State a forked child starts with: the parent's dispositions and
+    /// mask, with nothing pending
+    pub fn fork(&self) -> Self {
+        SignalState {
+            actions: self.actions,
+            blocked: self.blocked,
+            pending: 0,
+            info: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for SignalState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Pushed on the user stack when a handler runs and popped by sigreturn.
+/// The handler's third argument points here; it is not a Linux ucontext_t.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SignalFrame {
+    pub registers: TrapFrame,
+    pub info: SigInfo,
+    // Mask to restore on sigreturn
+    pub blocked: SigSet,
+    _pad: u64,
+}
+
+// Condition flags, the only part of SPSR a handler may change
+const SPSR_NZCV: u64 = 0xf000_0000;
+
+/// Where the frame for a handler goes when the interrupted code's stack
+/// pointer is `sp`
+pub fn frame_address(sp: u64) -> Option<u64> {
+    let addr = sp.checked_sub(core::mem::size_of::<SignalFrame>() as u64)?;
+    Some(addr & !15)
+}
+
+/// Saves `registers` in a frame at user address `addr` and redirects them
+/// into the handler for `info`. The handler returns through the restorer,
+/// which must call sigreturn; there is no kernel-provided trampoline.
+/// `registers` are left alone if the frame cannot be written.
+pub fn enter_handler(registers: &mut TrapFrame, addr: u64, info: &SigInfo, action: &SigAction, blocked: SigSet) -> Result<(), KernelError> {
+    let frame = SignalFrame {
+        registers: *registers,
+        info: *info,
+        blocked,
+        _pad: 0,
+    };
+    uaccess::write_user(addr, &frame)?;
+    
+    registers.regs[0] = info.signal() as u64;
+    registers.regs[1] = addr + core::mem::offset_of!(SignalFrame, info) as u64;
+    registers.regs[2] = addr;
+    registers.regs[30] = if action.flags & SA_RESTORER != 0 { action.restorer } else { 0 };
+    registers.sp_el0 = addr;
+    registers.elr = action.handler;
+    Ok(())
+}
+
+/// Registers and mask saved by `enter_handler` at user address `addr`.
+/// The saved SPSR is sanitized so the frame cannot return anywhere but
+/// EL0.
+pub fn read_frame(addr: u64) -> Result<(TrapFrame, SigSet), KernelError> {
+    // Every field is plain integers
+    let frame = unsafe { uaccess::read_user::<SignalFrame>(addr)? };
+    let mut registers = frame.registers;
+    registers.spsr = (registers.spsr & SPSR_NZCV) | SPSR_EL0T;
+    Ok((registers, frame.blocked & !UNBLOCKABLE))
+}
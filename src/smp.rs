@@ -0,0 +1,276 @@
+#![allow(dead_code)]
+
+use core::arch::asm;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use crate::memory::{self, VirtAddr};
+use crate::{exception, fdt, gic, mmu, println, process, psci, timer};
+
+// Symmetric multiprocessing. The boot CPU starts the others through PSCI
+// CPU_ON; each then runs an idle loop of its own and schedules from its own
+// run queue (see ProcessManager). TPIDR_EL1 holds the index of the CPU the
+// code is running on.
+//
+// Locking: every kernel lock is a spin lock, and the kernel runs with IRQs
+// masked, taking interrupts only in user mode and in the idle loop. An
+// interrupt handler therefore never finds a lock held by the CPU it
+// interrupted, only by another CPU that will let go of it, so handlers may
+// take any lock. The synchronous exceptions the kernel takes itself can
+// re-enter a lock on the same CPU: a fault on user memory, and heap growth
+// while the kernel address space is being changed. Those locks are
+// CpuMutexes, which tell that apart from contention with another CPU.
+//
+// Lock order: PROCESS_MANAGER, FILE_SYSTEM, IPC, then the leaf locks
+// (KERNEL_SPACE, ASIDS, GIC, IRQS, the heap, the frame allocator and the
+// console), which never take another lock while held.
+
+pub const MAX_CPUS: usize = fdt::MAX_PLATFORM_CPUS;
+
+// Software-generated interrupts used as IPIs
+pub const IPI_RESCHEDULE: u32 = 0;
+pub const IPI_TLB_FLUSH: u32 = 1;
+
+// How long a started CPU has to come online
+const START_TIMEOUT_MS: u64 = 1000;
+
+const MPIDR_AFFINITY: u64 = 0xff_00ff_ffff;
+
+// Bit per online CPU. The boot CPU is index 0 (boot.s).
+static ONLINE: AtomicU64 = AtomicU64::new(1);
+// MPIDR affinity of each CPU index
+static MPIDRS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(u64::MAX) }; MAX_CPUS];
+
+// Handed to a starting CPU in x0 and read by secondary_entry in boot.s
+// with the MMU off. The field offsets are hard-coded there.
+#[repr(C)]
+struct SecondaryBoot {
+    cpu: u64,
+    stack_top: u64,
+    // TTBR1 value: the kernel's level 0 table
+    kernel_root: u64,
+    tcr: u64,
+}
+
+// In the kernel image, so its physical address is known
+static mut SECONDARY_BOOT: [SecondaryBoot; MAX_CPUS] =
+    [const { SecondaryBoot { cpu: 0, stack_top: 0, kernel_root: 0, tcr: 0 } }; MAX_CPUS];
+
+extern "C" {
+    fn secondary_entry();
+}
+
+/// Index of the CPU running this code; 0 is the boot CPU
+pub fn cpu_id() -> usize {
+    let id: u64;
+    unsafe { asm!("mrs {}, tpidr_el1", out(reg) id, options(nomem, nostack, preserves_flags)) };
+    id as usize
+}
+
+pub fn online_cpus() -> usize {
+    ONLINE.load(Ordering::SeqCst).count_ones() as usize
+}
+
+pub fn is_online(cpu: usize) -> bool {
+    cpu < MAX_CPUS && ONLINE.load(Ordering::SeqCst) & (1 << cpu) != 0
+}
+
+/// MPIDR affinity of CPU `cpu`, once it has been started
+pub fn mpidr(cpu: usize) -> Option<u64> {
+    match MPIDRS.get(cpu)?.load(Ordering::SeqCst) {
+        u64::MAX => None,
+        mpidr => Some(mpidr),
+    }
+}
+
+/// Starts every other CPU the device tree lists, up to MAX_CPUS. Returns
+/// how many came online. Requires the interrupt controller and the tick.
+pub fn start_secondaries() -> Result<usize, &'static str> {
+    psci::init()?;
+    let boot_mpidr = read_mpidr() & MPIDR_AFFINITY;
+    MPIDRS[0].store(boot_mpidr, Ordering::SeqCst);
+    gic::request_irq(IPI_RESCHEDULE, handle_ipi)?;
+    gic::request_irq(IPI_TLB_FLUSH, handle_ipi)?;
+    
+    let kernel_root = mmu::kernel_root().ok_or("Kernel address space not initialized")?;
+    let tcr: u64;
+    unsafe { asm!("mrs {}, tcr_el1", out(reg) tcr) };
+    
+    let mut cpu = 1;
+    for &mpidr in fdt::platform().cpus.iter().filter(|&&mpidr| mpidr != boot_mpidr) {
+        if cpu == MAX_CPUS {
+            break;
+        }
+        match start_cpu(cpu, mpidr, kernel_root.start_address().as_u64(), tcr) {
+            Ok(()) => cpu += 1,
+            Err(e) => println!("CPU {:#x} not started: {}", mpidr, e),
+        }
+    }
+    Ok(cpu - 1)
+}
+
+fn start_cpu(cpu: usize, mpidr: u64, kernel_root: u64, tcr: u64) -> Result<(), &'static str> {
+    // The idle thread's stack. A CPU is never stopped, so it is never freed.
+    let stack = mmu::allocate_kernel_stack()?;
+    let boot = unsafe { &mut *core::ptr::addr_of_mut!(SECONDARY_BOOT[cpu]) };
+    *boot = SecondaryBoot { cpu: cpu as u64, stack_top: stack.top(), kernel_root, tcr };
+    // The CPU reads it with its caches off
+    clean_dcache(boot as *const SecondaryBoot as u64, core::mem::size_of::<SecondaryBoot>());
+    
+    MPIDRS[cpu].store(mpidr, Ordering::SeqCst);
+    let entry = memory::virt_to_phys(VirtAddr::new(secondary_entry as *const () as u64));
+    let context = memory::virt_to_phys(VirtAddr::new(boot as *const SecondaryBoot as u64));
+    if let Err(e) = psci::cpu_on(mpidr, entry.as_u64(), context.as_u64()) {
+        MPIDRS[cpu].store(u64::MAX, Ordering::SeqCst);
+        mmu::free_kernel_stack(stack);
+        return Err(e);
+    }
+    
+    let deadline = timer::uptime_ms() + START_TIMEOUT_MS;
+    while !is_online(cpu) {
+        if timer::uptime_ms() > deadline {
+            // It may still be running on the stack, which is leaked
+            return Err("CPU did not come online");
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+// Where secondary_entry goes once the MMU is on, on the CPU's idle stack
+#[no_mangle]
+extern "C" fn secondary_main() -> ! {
+    let cpu = cpu_id();
+    mmu::init_cpu();
+    exception::init();
+    if let Err(e) = gic::init_cpu() {
+        println!("CPU {}: {}", cpu, e);
+        halt();
+    }
+    timer::init_cpu();
+    ONLINE.fetch_or(1 << cpu, Ordering::SeqCst);
+    
+    loop {
+        process::schedule();
+        timer::wait_for_interrupt();
+    }
+}
+
+/// Makes `cpu` look at its run queue: a process running there is
+/// preempted on its way back to user mode, and an idle CPU wakes up
+pub fn send_reschedule(cpu: usize) {
+    if cpu != cpu_id() && is_online(cpu) {
+        let _ = gic::send_sgi(IPI_RESCHEDULE, cpu);
+    }
+}
+
+/// Has every other online CPU drop TLB entries that an ASID rollover left
+/// stale (see mmu::activate_user_space)
+pub fn send_tlb_flush() {
+    let this = cpu_id();
+    for cpu in (0..MAX_CPUS).filter(|&cpu| cpu != this && is_online(cpu)) {
+        let _ = gic::send_sgi(IPI_TLB_FLUSH, cpu);
+    }
+}
+
+fn handle_ipi(irq: u32) {
+    match irq {
+        IPI_RESCHEDULE => process::set_need_resched(),
+        IPI_TLB_FLUSH => mmu::flush_stale_asids(),
+        _ => {}
+    }
+}
+
+fn read_mpidr() -> u64 {
+    let mpidr: u64;
+    unsafe { asm!("mrs {}, mpidr_el1", out(reg) mpidr) };
+    mpidr
+}
+
+// Writes [addr, addr + size) back to memory for an observer with its
+// caches off
+fn clean_dcache(addr: u64, size: usize) {
+    let ctr: u64;
+    unsafe { asm!("mrs {}, ctr_el0", out(reg) ctr) };
+    let line = 4u64 << ((ctr >> 16) & 0xf);
+    let mut line_addr = addr & !(line - 1);
+    while line_addr < addr + size as u64 {
+        unsafe { asm!("dc cvac, {}", in(reg) line_addr) };
+        line_addr += line;
+    }
+    unsafe { asm!("dsb sy") };
+}
+
+fn halt() -> ! {
+    loop {
+        unsafe { asm!("wfe") };
+    }
+}
+
+/// Spin lock that records which CPU holds it. Code that can run while its
+/// own CPU holds the lock, such as a fault handler, checks `is_held_here`
+/// and backs off instead of spinning forever.
+pub struct CpuMutex<T> {
+    lock: spin::Mutex<T>,
+    // CPU index + 1 of the holder, 0 when free
+    owner: AtomicUsize,
+}
+
+pub struct CpuMutexGuard<'a, T> {
+    guard: spin::MutexGuard<'a, T>,
+    owner: &'a AtomicUsize,
+}
+
+impl<T> CpuMutex<T> {
+    pub const fn new(value: T) -> Self {
+        CpuMutex {
+            lock: spin::Mutex::new(value),
+            owner: AtomicUsize::new(0),
+        }
+    }
+    
+    pub fn lock(&self) -> CpuMutexGuard<'_, T> {
+        let guard = self.lock.lock();
+        self.owner.store(cpu_id() + 1, Ordering::Relaxed);
+        CpuMutexGuard { guard, owner: &self.owner }
+    }
+    
+    pub fn try_lock(&self) -> Option<CpuMutexGuard<'_, T>> {
+        let guard = self.lock.try_lock()?;
+        self.owner.store(cpu_id() + 1, Ordering::Relaxed);
+        Some(CpuMutexGuard { guard, owner: &self.owner })
+    }
+    
+    /// Whether the calling CPU holds the lock
+    pub fn is_held_here(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) == cpu_id() + 1
+    }
+    
+    /// # Safety
+    ///
+    /// The lock must be held by this CPU through a guard that was forgotten
+    pub unsafe fn force_unlock(&self) {
+        self.owner.store(0, Ordering::Relaxed);
+        self.lock.force_unlock();
+    }
+}
+
+impl<T> Deref for CpuMutexGuard<'_, T> {
+    type Target = T;
+    
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for CpuMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for CpuMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // Cleared before `guard` releases the lock
+        self.owner.store(0, Ordering::Relaxed);
+    }
+}
@@ -1,41 +1,142 @@
-use core::arch::asm;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
 use crate::process;
 use crate::fs;
 use crate::ipc;
+use crate::uaccess;
+use crate::userspace;
+use crate::error::KernelError;
+use crate::exception::FaultAccess;
 use crate::println;
+use crate::signal::{SigAction, SigSet};
 
-// System call numbers
-pub const SYS_READ: u64 = 0;
-pub const SYS_WRITE: u64 = 1;
-pub const SYS_OPEN: u64 = 2;
-pub const SYS_CLOSE: u64 = 3;
-pub const SYS_EXIT: u64 = 60;
-pub const SYS_FORK: u64 = 57;
-pub const SYS_EXECVE: u64 = 59;
-pub const SYS_MMAP: u64 = 9;
-pub const SYS_MUNMAP: u64 = 11;
-pub const SYS_GETPID: u64 = 39;
-pub const SYS_PIPE: u64 = 22;
-pub const SYS_DUP: u64 = 32;
-pub const SYS_DUP2: u64 = 33;
+// System call numbers, from the asm-generic table AArch64 Linux uses
+pub const SYS_GETCWD: u64 = 17;
+pub const SYS_DUP: u64 = 23;
+pub const SYS_DUP3: u64 = 24;
+pub const SYS_FCNTL: u64 = 25;
+pub const SYS_IOCTL: u64 = 29;
+pub const SYS_OPENAT: u64 = 56;
+pub const SYS_CLOSE: u64 = 57;
+pub const SYS_PIPE2: u64 = 59;
+pub const SYS_LSEEK: u64 = 62;
+pub const SYS_READ: u64 = 63;
+pub const SYS_WRITE: u64 = 64;
+pub const SYS_READV: u64 = 65;
+pub const SYS_WRITEV: u64 = 66;
+pub const SYS_NEWFSTATAT: u64 = 79;
+pub const SYS_FSTAT: u64 = 80;
+pub const SYS_EXIT: u64 = 93;
+pub const SYS_EXIT_GROUP: u64 = 94;
+pub const SYS_SET_TID_ADDRESS: u64 = 96;
+pub const SYS_CLOCK_GETTIME: u64 = 113;
+pub const SYS_SCHED_YIELD: u64 = 124;
+pub const SYS_KILL: u64 = 129;
+pub const SYS_RT_SIGACTION: u64 = 134;
+pub const SYS_RT_SIGPROCMASK: u64 = 135;
+pub const SYS_RT_SIGRETURN: u64 = 139;
+pub const SYS_SETPRIORITY: u64 = 140;
+pub const SYS_GETPRIORITY: u64 = 141;
+pub const SYS_UNAME: u64 = 160;
+pub const SYS_GETRLIMIT: u64 = 163;
+pub const SYS_SETRLIMIT: u64 = 164;
+pub const SYS_GETRUSAGE: u64 = 165;
+pub const SYS_GETPID: u64 = 172;
+pub const SYS_GETPPID: u64 = 173;
+pub const SYS_GETUID: u64 = 174;
+pub const SYS_GETEUID: u64 = 175;
+pub const SYS_GETGID: u64 = 176;
+pub const SYS_GETEGID: u64 = 177;
+pub const SYS_GETTID: u64 = 178;
+pub const SYS_BRK: u64 = 214;
+pub const SYS_MUNMAP: u64 = 215;
+pub const SYS_CLONE: u64 = 220;
+pub const SYS_EXECVE: u64 = 221;
+pub const SYS_MMAP: u64 = 222;
+pub const SYS_MPROTECT: u64 = 226;
+pub const SYS_WAIT4: u64 = 260;
+pub const SYS_GETRANDOM: u64 = 278;
 
-pub fn init() {
-    // Set up exception vector table for system calls
-    unsafe {
-        setup_exception_vector();
-    }
+// openat's directory for paths relative to the working directory
+pub const AT_FDCWD: i32 = -100;
+// newfstatat flag: an empty path means the descriptor itself
+pub const AT_EMPTY_PATH: u32 = 0x1000;
+
+// clock_gettime clocks. There is no real-time clock, so CLOCK_REALTIME
+// counts from boot like the monotonic clocks.
+pub const CLOCK_REALTIME: u32 = 0;
+pub const CLOCK_MONOTONIC: u32 = 1;
+pub const CLOCK_PROCESS_CPUTIME_ID: u32 = 2;
+pub const CLOCK_THREAD_CPUTIME_ID: u32 = 3;
+pub const CLOCK_MONOTONIC_RAW: u32 = 4;
+pub const CLOCK_REALTIME_COARSE: u32 = 5;
+pub const CLOCK_MONOTONIC_COARSE: u32 = 6;
+pub const CLOCK_BOOTTIME: u32 = 7;
+
+// getrandom flags, both of which make no difference here
+const GRND_NONBLOCK: u32 = 1;
+const GRND_RANDOM: u32 = 2;
+
+// Terminal window size request, the only ioctl there is
+pub const TIOCGWINSZ: u32 = 0x5413;
+
+// Layout matches the timespec structure filled by clock_gettime
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timespec {
+    pub sec: i64,
+    pub nsec: i64,
 }
 
-unsafe fn setup_exception_vector() {
-    // ARM64 exception vector setup
-    extern "C" {
-        fn exception_vector_table();
+impl Timespec {
+    pub fn from_ns(ns: u64) -> Self {
+        Timespec {
+            sec: (ns / 1_000_000_000) as i64,
+            nsec: (ns % 1_000_000_000) as i64,
+        }
     }
-    
-    asm!(
-        "msr vbar_el1, {}",
-        in(reg) exception_vector_table as *const () as u64
-    );
+}
+
+// Layout matches the iovec structure passed to readv/writev
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct IoVec {
+    pub base: u64,
+    pub len: u64,
+}
+
+// Layout matches the utsname structure filled by uname
+#[repr(C)]
+pub struct UtsName {
+    pub sysname: [u8; 65],
+    pub nodename: [u8; 65],
+    pub release: [u8; 65],
+    pub version: [u8; 65],
+    pub machine: [u8; 65],
+    pub domainname: [u8; 65],
+}
+
+// Layout matches the winsize structure filled by TIOCGWINSZ
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WinSize {
+    pub rows: u16,
+    pub cols: u16,
+    pub xpixel: u16,
+    pub ypixel: u16,
+}
+
+// Most iovecs one readv or writev takes, as on Linux
+const IOV_MAX: usize = 1024;
+// Most bytes one read, write or getrandom moves. Longer requests come back
+// short, as POSIX allows.
+const MAX_IO_SIZE: usize = 64 * 1024;
+
+pub fn init() {
+    // SVC from EL0 arrives through the exception vectors
+    crate::exception::init();
 }
 
 #[no_mangle]
@@ -48,206 +149,362 @@ pub extern "C" fn syscall_handler(
     arg5: u64,
     arg6: u64,
 ) -> u64 {
-    match syscall_num {
-        SYS_READ => sys_read(arg1 as i32, arg2 as *mut u8, arg3 as usize),
-        SYS_WRITE => sys_write(arg1 as i32, arg2 as *const u8, arg3 as usize),
-        SYS_OPEN => sys_open(arg1 as *const u8, arg2 as i32, arg3 as u32),
-        SYS_CLOSE => sys_close(arg1 as i32),
+    let result = match syscall_num {
+        SYS_READ => sys_read(arg1 as i32, arg2, arg3 as usize),
+        SYS_WRITE => sys_write(arg1 as i32, arg2, arg3 as usize),
+        SYS_READV => sys_readv(arg1 as i32, arg2, arg3 as usize),
+        SYS_WRITEV => sys_writev(arg1 as i32, arg2, arg3 as usize),
+        SYS_OPENAT => sys_openat(arg1 as i32, arg2, arg3 as i32, arg4 as u32),
+        SYS_CLOSE => fs::close(arg1 as i32).map(|_| 0),
+        SYS_LSEEK => fs::seek(arg1 as i32, arg2 as i64, arg3 as u32),
+        SYS_FSTAT => sys_fstat(arg1 as i32, arg2),
+        SYS_NEWFSTATAT => sys_newfstatat(arg1 as i32, arg2, arg3, arg4 as u32),
+        SYS_IOCTL => sys_ioctl(arg1 as i32, arg2 as u32, arg3),
+        SYS_GETCWD => sys_getcwd(arg1, arg2 as usize),
         SYS_EXIT => {
             process::sys_exit(arg1 as i32);
         }
-        SYS_FORK => process::sys_fork() as u64,
-        SYS_EXECVE => {
-            match process::sys_exec(arg1) {
-                Ok(_) => 0,
-                Err(_) => u64::MAX, // -1 in two's complement
-            }
+        SYS_EXIT_GROUP => {
+            process::sys_exit_group(arg1 as i32);
         }
-        SYS_GETPID => {
-            process::get_current_pid().unwrap_or(0) as u64
+        // AArch64 passes the TLS pointer before the child tid pointer
+        SYS_CLONE => process::sys_clone(arg1, arg2, arg3, arg5, arg4).map(|tid| tid as u64),
+        SYS_EXECVE => sys_execve(arg1, arg2, arg3),
+        SYS_SCHED_YIELD => {
+            process::sys_yield();
+            Ok(0)
         }
-        SYS_PIPE => sys_pipe(arg1 as *mut [i32; 2]),
-        SYS_DUP => sys_dup(arg1 as i32),
-        SYS_DUP2 => sys_dup2(arg1 as i32, arg2 as i32),
-        SYS_MMAP => sys_mmap(arg1, arg2 as usize, arg3 as i32, arg4 as i32, arg5 as i32, arg6 as i64),
-        SYS_MUNMAP => sys_munmap(arg1, arg2 as usize),
+        SYS_GETPID => Ok(process::sys_getpid() as u64),
+        SYS_GETPPID => Ok(process::sys_getppid() as u64),
+        SYS_GETTID => Ok(process::sys_gettid() as u64),
+        // Everything runs as root
+        SYS_GETUID | SYS_GETEUID | SYS_GETGID | SYS_GETEGID => Ok(0),
+        SYS_SET_TID_ADDRESS => process::sys_set_tid_address(arg1).map(|tid| tid as u64),
+        SYS_WAIT4 => sys_wait4(arg1 as i32, arg2, arg3 as u32, arg4),
+        SYS_KILL => process::sys_kill(arg1 as i32, arg2 as u32).map(|_| 0),
+        SYS_RT_SIGACTION => sys_rt_sigaction(arg1 as u32, arg2, arg3, arg4),
+        SYS_RT_SIGPROCMASK => sys_rt_sigprocmask(arg1 as u32, arg2, arg3, arg4),
+        // Returns the interrupted x0, so the return value is not an error
+        SYS_RT_SIGRETURN => process::sys_sigreturn(),
+        SYS_PIPE2 => sys_pipe2(arg1, arg2 as i32),
+        SYS_FCNTL => sys_fcntl(arg1 as i32, arg2 as u32, arg3),
+        SYS_DUP => fs::duplicate_fd(arg1 as i32).map(|fd| fd as u64),
+        SYS_DUP3 => sys_dup3(arg1 as i32, arg2 as i32, arg3 as i32),
+        SYS_BRK => process::sys_brk(arg1),
+        SYS_MMAP => process::sys_mmap(arg1, arg2, arg3, arg4, arg5 as i32, arg6),
+        SYS_MPROTECT => process::sys_mprotect(arg1, arg2, arg3).map(|_| 0),
+        SYS_MUNMAP => process::sys_munmap(arg1, arg2).map(|_| 0),
+        SYS_GETRLIMIT => sys_getrlimit(arg1 as u32, arg2),
+        SYS_SETRLIMIT => sys_setrlimit(arg1 as u32, arg2),
+        SYS_GETRUSAGE => sys_getrusage(arg1 as i32, arg2),
+        // Returned as 20 - nice so a valid result is never negative
+        SYS_GETPRIORITY => process::sys_getpriority(arg1 as u32, arg2 as u32).map(|nice| (20 - nice as i64) as u64),
+        SYS_SETPRIORITY => process::sys_setpriority(arg1 as u32, arg2 as u32, arg3 as i32).map(|_| 0),
+        SYS_CLOCK_GETTIME => sys_clock_gettime(arg1 as u32, arg2),
+        SYS_GETRANDOM => sys_getrandom(arg1, arg2 as usize, arg3 as u32),
+        SYS_UNAME => sys_uname(arg1),
         _ => {
             println!("Unknown system call: {}", syscall_num);
-            u64::MAX // Return -1 for unknown syscalls
+            Err(KernelError::NotImplemented)
         }
+    };
+    match result {
+        Ok(value) => value,
+        Err(error) => error.to_syscall_return(),
     }
 }
 
-// File I/O system calls
-fn sys_read(fd: i32, buf: *mut u8, count: usize) -> u64 {
-    match fs::read(fd, unsafe { core::slice::from_raw_parts_mut(buf, count) }) {
-        Ok(bytes_read) => bytes_read as u64,
-        Err(_) => u64::MAX,
-    }
+// File I/O system calls. Data goes through a kernel buffer, so the file
+// system never touches user memory. The buffer is checked before a read,
+// which would otherwise lose what it consumed.
+fn sys_read(fd: i32, buf: u64, count: usize) -> Result<u64, KernelError> {
+    let mut data = vec![0; count.min(MAX_IO_SIZE)];
+    uaccess::access_ok(buf, data.len(), FaultAccess::Write)?;
+    let count = fs::read(fd, &mut data)?;
+    uaccess::copy_to_user(buf, &data[..count])?;
+    Ok(count as u64)
+}
+
+fn sys_write(fd: i32, buf: u64, count: usize) -> Result<u64, KernelError> {
+    let mut data = vec![0; count.min(MAX_IO_SIZE)];
+    uaccess::copy_from_user(&mut data, buf)?;
+    fs::write(fd, &data).map(|count| count as u64)
 }
 
-fn sys_write(fd: i32, buf: *const u8, count: usize) -> u64 {
-    match fs::write(fd, unsafe { core::slice::from_raw_parts(buf, count) }) {
-        Ok(bytes_written) => bytes_written as u64,
-        Err(_) => u64::MAX,
+// Moves the buffers in turn, stopping at the first short transfer. An
+// error after some bytes were moved ends the call with what was moved.
+fn sys_readv(fd: i32, iov: u64, iovcnt: usize) -> Result<u64, KernelError> {
+    let mut total = 0;
+    for vec in user_iovecs(iov, iovcnt)? {
+        match sys_read(fd, vec.base, vec.len as usize) {
+            Ok(count) => {
+                total += count;
+                if count < vec.len {
+                    break;
+                }
+            }
+            Err(e) if total == 0 => return Err(e),
+            Err(_) => break,
+        }
     }
+    Ok(total)
 }
 
-fn sys_open(pathname: *const u8, flags: i32, mode: u32) -> u64 {
-    // Convert C string to Rust string
-    let path_str = unsafe {
-        let mut len = 0;
-        let mut ptr = pathname;
-        while *ptr != 0 {
-            len += 1;
-            ptr = ptr.add(1);
+fn sys_writev(fd: i32, iov: u64, iovcnt: usize) -> Result<u64, KernelError> {
+    let mut total = 0;
+    for vec in user_iovecs(iov, iovcnt)? {
+        match sys_write(fd, vec.base, vec.len as usize) {
+            Ok(count) => {
+                total += count;
+                if count < vec.len {
+                    break;
+                }
+            }
+            Err(e) if total == 0 => return Err(e),
+            Err(_) => break,
         }
-        core::str::from_utf8_unchecked(core::slice::from_raw_parts(pathname, len))
+    }
+    Ok(total)
+}
+
+fn user_iovecs(iov: u64, iovcnt: usize) -> Result<Vec<IoVec>, KernelError> {
+    if iovcnt > IOV_MAX {
+        return Err(KernelError::InvalidArgument);
+    }
+    let mut vecs = Vec::with_capacity(iovcnt);
+    for index in 0..iovcnt {
+        let addr = iov.checked_add((index * size_of::<IoVec>()) as u64).ok_or(KernelError::BadAddress)?;
+        vecs.push(unsafe { uaccess::read_user::<IoVec>(addr) }?);
+    }
+    if vecs.iter().try_fold(0u64, |total, vec| total.checked_add(vec.len)).is_none_or(|total| total > isize::MAX as u64) {
+        return Err(KernelError::InvalidArgument);
+    }
+    // Empty buffers may have any base, NULL included, and move nothing
+    Ok(vecs.into_iter().filter(|vec| vec.len != 0).collect())
+}
+
+// There are no directories to open relative to, so `dirfd` must be
+// AT_FDCWD unless the path is absolute
+fn sys_openat(dirfd: i32, pathname: u64, flags: i32, mode: u32) -> Result<u64, KernelError> {
+    let path = uaccess::user_path(pathname)?;
+    if dirfd != AT_FDCWD && !path.starts_with('/') {
+        return Err(KernelError::BadFileDescriptor);
+    }
+    fs::open(&path, flags, mode).map(|fd| fd as u64)
+}
+
+fn sys_fstat(fd: i32, statbuf: u64) -> Result<u64, KernelError> {
+    let status = fs::stat(fd)?;
+    uaccess::write_user(statbuf, &status)?;
+    Ok(0)
+}
+
+fn sys_newfstatat(dirfd: i32, pathname: u64, statbuf: u64, flags: u32) -> Result<u64, KernelError> {
+    let path = uaccess::user_path(pathname)?;
+    let status = if path.is_empty() && flags & AT_EMPTY_PATH != 0 {
+        fs::stat(dirfd)?
+    } else if dirfd != AT_FDCWD && !path.starts_with('/') {
+        return Err(KernelError::BadFileDescriptor);
+    } else {
+        fs::stat_path(&path)?
     };
-    
-    match fs::open(path_str, flags, mode) {
-        Ok(fd) => fd as u64,
-        Err(_) => u64::MAX,
+    uaccess::write_user(statbuf, &status)?;
+    Ok(0)
+}
+
+// The console reports a fixed 80x24 window
+fn sys_ioctl(fd: i32, request: u32, arg: u64) -> Result<u64, KernelError> {
+    if !fs::is_terminal(fd)? {
+        return Err(KernelError::NotATerminal);
+    }
+    match request {
+        TIOCGWINSZ => {
+            uaccess::write_user(arg, &WinSize { rows: 24, cols: 80, ..WinSize::default() })?;
+            Ok(0)
+        }
+        _ => Err(KernelError::InvalidArgument),
     }
 }
 
-fn sys_close(fd: i32) -> u64 {
-    match fs::close(fd) {
-        Ok(_) => 0,
-        Err(_) => u64::MAX,
+// Returns the length of the path, NUL included
+fn sys_getcwd(buf: u64, size: usize) -> Result<u64, KernelError> {
+    let mut path = fs::get_current_directory()?.into_bytes();
+    path.push(0);
+    if path.len() > size {
+        return Err(KernelError::OutOfRange);
     }
+    uaccess::copy_to_user(buf, &path)?;
+    Ok(path.len() as u64)
 }
 
 // IPC system calls
-fn sys_pipe(pipefd: *mut [i32; 2]) -> u64 {
-    match ipc::create_pipe() {
-        Ok((read_fd, write_fd)) => {
-            unsafe {
-                (*pipefd)[0] = read_fd;
-                (*pipefd)[1] = write_fd;
+fn sys_pipe2(pipefd: u64, flags: i32) -> Result<u64, KernelError> {
+    let (read_fd, write_fd) = ipc::create_pipe_with_flags(flags)?;
+    if let Err(error) = uaccess::write_user(pipefd, &[read_fd, write_fd]) {
+        let _ = fs::close(read_fd);
+        let _ = fs::close(write_fd);
+        return Err(error);
+    }
+    Ok(0)
+}
+
+// Descriptor flags only have FD_CLOEXEC, which exec does not act on, so
+// they read back as 0
+fn sys_fcntl(fd: i32, cmd: u32, arg: u64) -> Result<u64, KernelError> {
+    match cmd {
+        fs::F_DUPFD | fs::F_DUPFD_CLOEXEC => fs::duplicate_fd_from(fd, arg as i32).map(|fd| fd as u64),
+        fs::F_GETFD | fs::F_SETFD => fs::status_flags(fd).map(|_| 0),
+        fs::F_GETFL => fs::status_flags(fd).map(|flags| flags as u64),
+        fs::F_SETFL => fs::set_status_flags(fd, arg as i32).map(|_| 0),
+        _ => Err(KernelError::InvalidArgument),
+    }
+}
+
+// dup2 without the same-descriptor case; O_CLOEXEC is the only flag
+fn sys_dup3(oldfd: i32, newfd: i32, flags: i32) -> Result<u64, KernelError> {
+    if oldfd == newfd || flags & !fs::O_CLOEXEC != 0 {
+        return Err(KernelError::InvalidArgument);
+    }
+    fs::duplicate_fd_to(oldfd, newfd).map(|fd| fd as u64)
+}
+
+// Resource limit system calls
+fn sys_getrlimit(resource: u32, limit: u64) -> Result<u64, KernelError> {
+    let value = process::sys_getrlimit(resource)?;
+    uaccess::write_user(limit, &value)?;
+    Ok(0)
+}
+
+fn sys_setrlimit(resource: u32, limit: u64) -> Result<u64, KernelError> {
+    let value = unsafe { uaccess::read_user::<process::Rlimit>(limit) }?;
+    process::sys_setrlimit(resource, value).map(|_| 0)
+}
+
+fn sys_getrusage(who: i32, usage: u64) -> Result<u64, KernelError> {
+    let value = process::sys_getrusage(who)?;
+    uaccess::write_user(usage, &value)?;
+    Ok(0)
+}
+
+// Time, randomness and system information
+fn sys_clock_gettime(clock: u32, tp: u64) -> Result<u64, KernelError> {
+    let ns = match clock {
+        CLOCK_REALTIME | CLOCK_MONOTONIC | CLOCK_MONOTONIC_RAW | CLOCK_REALTIME_COARSE
+            | CLOCK_MONOTONIC_COARSE | CLOCK_BOOTTIME => crate::timer::uptime_ns(),
+        CLOCK_PROCESS_CPUTIME_ID | CLOCK_THREAD_CPUTIME_ID => {
+            let usage = process::sys_getrusage(process::RUSAGE_SELF)?;
+            (usage.utime.sec as u64 * 1_000_000 + usage.utime.usec as u64) * 1000
+        }
+        _ => return Err(KernelError::InvalidArgument),
+    };
+    uaccess::write_user(tp, &Timespec::from_ns(ns))?;
+    Ok(0)
+}
+
+fn sys_getrandom(buf: u64, count: usize, flags: u32) -> Result<u64, KernelError> {
+    if flags & !(GRND_NONBLOCK | GRND_RANDOM) != 0 {
+        return Err(KernelError::InvalidArgument);
+    }
+    let mut bytes = vec![0; count.min(MAX_IO_SIZE)];
+    userspace::fill_random(&mut bytes);
+    uaccess::copy_to_user(buf, &bytes)?;
+    Ok(bytes.len() as u64)
+}
+
+fn sys_uname(buf: u64) -> Result<u64, KernelError> {
+    let field = |value: &str| {
+        let mut bytes = [0; 65];
+        bytes[..value.len()].copy_from_slice(value.as_bytes());
+        bytes
+    };
+    let name = UtsName {
+        sysname: field("rustos"),
+        nodename: field("rustos"),
+        release: field(env!("CARGO_PKG_VERSION")),
+        version: field("rustos"),
+        machine: field("aarch64"),
+        domainname: field("(none)"),
+    };
+    uaccess::write_user(buf, &name)?;
+    Ok(0)
+}
+
+// Process lifecycle system calls
+fn sys_wait4(pid: i32, wstatus: u64, options: u32, usage: u64) -> Result<u64, KernelError> {
+    match process::sys_wait4(pid, options)? {
+        Some((child, status, child_usage)) => {
+            if wstatus != 0 {
+                uaccess::write_user(wstatus, &status)?;
+            }
+            if usage != 0 {
+                uaccess::write_user(usage, &child_usage)?;
             }
-            0
+            Ok(child as u64)
+        }
+        // WNOHANG and no child has exited yet
+        None => Ok(0),
+    }
+}
+
+// The strings are copied out before exec frees the memory they are in
+fn sys_execve(pathname: u64, argv: u64, envp: u64) -> Result<u64, KernelError> {
+    let path = uaccess::user_path(pathname)?;
+    let argv = user_string_array(argv)?;
+    let envp = user_string_array(envp)?;
+    let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+    let envp: Vec<&str> = envp.iter().map(String::as_str).collect();
+    userspace::sys_execve(&path, &argv, &envp)?;
+    // The new image starts with x0 clear
+    Ok(0)
+}
+
+// NULL-terminated array of string pointers at `addr`, which may itself be
+// NULL for none. Stops at ARG_MAX bytes of strings.
+fn user_string_array(addr: u64) -> Result<Vec<String>, KernelError> {
+    let mut strings: Vec<String> = Vec::new();
+    if addr == 0 {
+        return Ok(strings);
+    }
+    let mut size = 0;
+    loop {
+        let entry = addr.checked_add((strings.len() * size_of::<u64>()) as u64).ok_or(KernelError::BadAddress)?;
+        let string = unsafe { uaccess::read_user::<u64>(entry) }?;
+        if string == 0 {
+            return Ok(strings);
+        }
+        let string = uaccess::user_string(string, uaccess::MAX_ARG_STRLEN).map_err(|error| match error {
+            KernelError::NameTooLong => KernelError::ArgumentListTooLong,
+            error => error,
+        })?;
+        size += string.len() + 1;
+        if size > userspace::ARG_MAX {
+            return Err(KernelError::ArgumentListTooLong);
         }
-        Err(_) => u64::MAX,
-    }
-}
-
-fn sys_dup(fd: i32) -> u64 {
-    match fs::duplicate_fd(fd) {
-        Ok(new_fd) => new_fd as u64,
-        Err(_) => u64::MAX,
-    }
-}
-
-fn sys_dup2(oldfd: i32, newfd: i32) -> u64 {
-    match fs::duplicate_fd_to(oldfd, newfd) {
-        Ok(fd) => fd as u64,
-        Err(_) => u64::MAX,
-    }
-}
-
-// Memory management system calls
-fn sys_mmap(_addr: u64, length: usize, _prot: i32, _flags: i32, _fd: i32, _offset: i64) -> u64 {
-    // Simple memory mapping implementation
-    // In a real kernel, this would handle virtual memory mapping
-    match crate::memory::allocate_pages(length) {
-        Ok(allocated_addr) => allocated_addr,
-        Err(_) => u64::MAX,
-    }
-}
-
-fn sys_munmap(addr: u64, length: usize) -> u64 {
-    // Memory unmapping implementation
-    match crate::memory::deallocate_pages(addr, length) {
-        Ok(_) => 0,
-        Err(_) => u64::MAX,
-    }
-}
-
-// Exception vector table in assembly
-core::arch::global_asm!(r#"
-.globl exception_vector_table
-.align 11
-exception_vector_table:
-    // Current EL with SP0
-    .align 7
-    b .
-    .align 7
-    b .
-    .align 7
-    b .
-    .align 7
-    b .
-    
-    // Current EL with SPx
-    .align 7
-    b .
-    .align 7
-    b .
-    .align 7
-    b .
-    .align 7
-    b .
-    
-    // Lower EL using AArch64
-    .align 7
-    b handle_sync_exception
-    .align 7
-    b .
-    .align 7
-    b .
-    .align 7
-    b .
-    
-    // Lower EL using AArch32
-    .align 7
-    b .
-    .align 7
-    b .
-    .align 7
-    b .
-    .align 7
-    b .
-
-handle_sync_exception:
-    // Save registers
-    stp x0, x1, [sp, #-16]!
-    stp x2, x3, [sp, #-16]!
-    stp x4, x5, [sp, #-16]!
-    stp x6, x7, [sp, #-16]!
-    stp x8, x9, [sp, #-16]!
-    stp x30, xzr, [sp, #-16]!
-    
-    // Check if this is a system call (SVC instruction)
-    mrs x9, esr_el1
-    and x9, x9, #0x3f000000
-    mov x10, #0x15000000  // SVC exception code
-    cmp x9, x10
-    b.ne not_syscall
-    
-    // Call syscall handler
-    // x8 contains syscall number, x0-x5 contain arguments
-    mov x9, x8  // Move syscall number to x9
-    bl syscall_handler
-    
-    // Result is in x0, restore registers
-    ldp x30, xzr, [sp], #16
-    ldp x8, x9, [sp], #16
-    ldp x6, x7, [sp], #16
-    ldp x4, x5, [sp], #16
-    ldp x2, x3, [sp], #16
-    ldp x1, xzr, [sp], #16  // Skip x1, keep x0 (return value)
-    
-    eret
-
-not_syscall:
-    // Handle other exceptions
-    ldp x30, xzr, [sp], #16
-    ldp x8, x9, [sp], #16
-    ldp x6, x7, [sp], #16
-    ldp x4, x5, [sp], #16
-    ldp x2, x3, [sp], #16
-    ldp x0, x1, [sp], #16
-    
-    eret
-"#);
+        strings.push(string);
+    }
+}
+
+// Signal system calls. The sigset size must match the kernel's 64 signals.
+fn sys_rt_sigaction(sig: u32, act: u64, oldact: u64, sigsetsize: u64) -> Result<u64, KernelError> {
+    if sigsetsize != size_of::<SigSet>() as u64 {
+        return Err(KernelError::InvalidArgument);
+    }
+    let action = if act == 0 { None } else { Some(unsafe { uaccess::read_user::<SigAction>(act) }?) };
+    let old = process::sys_sigaction(sig, action)?;
+    if oldact != 0 {
+        uaccess::write_user(oldact, &old)?;
+    }
+    Ok(0)
+}
+
+fn sys_rt_sigprocmask(how: u32, set: u64, oldset: u64, sigsetsize: u64) -> Result<u64, KernelError> {
+    if sigsetsize != size_of::<SigSet>() as u64 {
+        return Err(KernelError::InvalidArgument);
+    }
+    let set = if set == 0 { None } else { Some(unsafe { uaccess::read_user::<SigSet>(set) }?) };
+    let old = process::sys_sigprocmask(how, set)?;
+    if oldset != 0 {
+        uaccess::write_user(oldset, &old)?;
+    }
+    Ok(0)
+}
@@ -0,0 +1,160 @@
+#![allow(dead_code)]
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use crate::{fdt, gic, process, smp};
+
+// ARM generic timer. The system counter backs the monotonic clock and the
+// EL1 virtual timer raises the periodic tick that drives preemption.
+
+pub const DEFAULT_TICK_HZ: u32 = 100;
+
+// PPIs start at interrupt ID 16
+const PPI_BASE: u32 = 16;
+// Virtual timer PPI on QEMU virt, used when the device tree has no timer
+const DEFAULT_VIRTUAL_TIMER_PPI: u32 = 11;
+
+const CNTV_CTL_ENABLE: u64 = 1;
+
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static TICK_HZ: AtomicU32 = AtomicU32::new(DEFAULT_TICK_HZ);
+// Counter cycles between ticks
+static TICK_INTERVAL: AtomicU64 = AtomicU64::new(0);
+static TICKS: AtomicU64 = AtomicU64::new(0);
+// Interrupt ID of the virtual timer, 0 until `init`
+static TIMER_IRQ: AtomicU32 = AtomicU32::new(0);
+
+/// Starts the periodic tick at `DEFAULT_TICK_HZ`. Requires the interrupt
+/// controller; the clock functions work without it.
+pub fn init() -> Result<(), &'static str> {
+    if !gic::is_initialized() {
+        return Err("Interrupt controller not initialized");
+    }
+    
+    let timer = fdt::platform().timer;
+    // Some firmware leaves CNTFRQ_EL0 unset and puts the rate in the tree
+    if let Some(frequency) = timer.and_then(|timer| timer.frequency) {
+        FREQUENCY.store(frequency as u64, Ordering::SeqCst);
+    }
+    let ppi = timer.map_or(DEFAULT_VIRTUAL_TIMER_PPI, |timer| timer.ppis[2]);
+    let irq = PPI_BASE + ppi;
+    
+    set_tick_rate(TICK_HZ.load(Ordering::SeqCst))?;
+    gic::request_irq(irq, handle_tick)?;
+    TIMER_IRQ.store(irq, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Starts the tick on a secondary CPU. Each CPU has its own virtual timer;
+/// gic::init_cpu enables the PPI there.
+pub fn init_cpu() {
+    if irq().is_none() {
+        return;
+    }
+    let interval = TICK_INTERVAL.load(Ordering::SeqCst);
+    unsafe {
+        asm!("msr cntv_cval_el0, {}", in(reg) counter() + interval);
+        asm!("msr cntv_ctl_el0, {}", "isb", in(reg) CNTV_CTL_ENABLE);
+    }
+}
+
+/// Changes how many times a second the tick fires. Secondary CPUs pick up
+/// the new rate at their next tick.
+pub fn set_tick_rate(hz: u32) -> Result<(), &'static str> {
+    if hz == 0 || hz as u64 > frequency() {
+        return Err("Invalid tick rate");
+    }
+    
+    let interval = frequency() / hz as u64;
+    TICK_HZ.store(hz, Ordering::SeqCst);
+    TICK_INTERVAL.store(interval, Ordering::SeqCst);
+    unsafe {
+        asm!("msr cntv_cval_el0, {}", in(reg) counter() + interval);
+        asm!("msr cntv_ctl_el0, {}", "isb", in(reg) CNTV_CTL_ENABLE);
+    }
+    Ok(())
+}
+
+pub fn tick_rate() -> u32 {
+    TICK_HZ.load(Ordering::SeqCst)
+}
+
+/// Interrupt ID of the tick, once the timer is running
+pub fn irq() -> Option<u32> {
+    match TIMER_IRQ.load(Ordering::SeqCst) {
+        0 => None,
+        irq => Some(irq),
+    }
+}
+
+/// Counter increments per second
+pub fn frequency() -> u64 {
+    match FREQUENCY.load(Ordering::Relaxed) {
+        0 => {
+            let frequency: u64;
+            unsafe { asm!("mrs {}, cntfrq_el0", out(reg) frequency) };
+            frequency
+        }
+        frequency => frequency,
+    }
+}
+
+/// Current value of the system counter. Never goes backwards.
+pub fn counter() -> u64 {
+    let count: u64;
+    // The isb keeps the read from being hoisted above earlier code
+    unsafe { asm!("isb", "mrs {}, cntvct_el0", out(reg) count) };
+    count
+}
+
+/// Time since boot in nanoseconds
+pub fn uptime_ns() -> u64 {
+    cycles_to_ns(counter())
+}
+
+/// Converts a span of counter cycles to nanoseconds
+pub fn cycles_to_ns(cycles: u64) -> u64 {
+    (cycles as u128 * 1_000_000_000 / frequency() as u128) as u64
+}
+
+/// Time since boot in milliseconds
+pub fn uptime_ms() -> u64 {
+    uptime_ns() / 1_000_000
+}
+
+/// Timer interrupts handled by the boot CPU since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst)
+}
+
+// Arms the next tick and charges this one to the running process
+fn handle_tick(_irq: u32) {
+    let interval = TICK_INTERVAL.load(Ordering::SeqCst);
+    let deadline: u64;
+    unsafe { asm!("mrs {}, cntv_cval_el0", out(reg) deadline) };
+    
+    // Step from the old deadline so the tick does not drift, skipping
+    // deadlines that passed while interrupts were masked
+    let now = counter();
+    let mut next = deadline + interval;
+    if next <= now {
+        next += (now - next) / interval * interval + interval;
+    }
+    unsafe { asm!("msr cntv_cval_el0, {}", in(reg) next) };
+    
+    if smp::cpu_id() == 0 {
+        TICKS.fetch_add(1, Ordering::SeqCst);
+    }
+    process::timer_tick();
+}
+
+/// Sleeps until an interrupt arrives and lets it be handled. The kernel
+/// otherwise runs with IRQs masked, so this is where the idle thread
+/// takes interrupts.
+pub fn wait_for_interrupt() {
+    // WFI wakes on a pending interrupt even while it is masked, so nothing
+    // is lost between the check and the sleep
+    unsafe {
+        asm!("wfi", "msr daifclr, #2", "isb", "msr daifset, #2");
+    }
+}
@@ -0,0 +1,154 @@
+#![allow(dead_code)]
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem::{size_of, MaybeUninit};
+use crate::error::KernelError;
+use crate::exception::FaultAccess;
+use crate::memory::PAGE_SIZE;
+use crate::process;
+
+// Access to user memory from system calls. Handlers take user pointers as
+// plain addresses and go through here for every byte they read or write.
+// For a user process the range must lie in its mappings with the access
+// asked for, and is faulted in before the copy. The copy itself runs in
+// `user_copy` below: a fault inside it, from another thread unmapping the
+// range in the meantime, makes it return early instead of panicking, and
+// the call fails with EFAULT.
+//
+// Kernel threads that call the handlers directly pass kernel pointers,
+// which are only checked for NULL.
+
+/// Longest path, NUL included
+pub const PATH_MAX: usize = 4096;
+/// Longest exec argument or environment string, NUL included
+pub const MAX_ARG_STRLEN: usize = 32 * PAGE_SIZE;
+
+extern "C" {
+    fn user_copy(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn user_copy_fault();
+    fn user_copy_end();
+}
+
+/// Whether the calling thread may access `[addr, addr + len)` for
+/// `access`, which is made present if so
+pub fn access_ok(addr: u64, len: usize, access: FaultAccess) -> Result<(), KernelError> {
+    if len == 0 {
+        return Ok(());
+    }
+    if addr == 0 {
+        return Err(KernelError::BadAddress);
+    }
+    match process::current_user_pid() {
+        // Fails for ranges reaching past the user half too
+        Some(pid) => process::fault_in(pid, addr, len as u64, access).map_err(|_| KernelError::BadAddress),
+        None => Ok(()),
+    }
+}
+
+// Copies `len` bytes from checked memory
+fn copy(dst: *mut u8, src: *const u8, len: usize) -> Result<(), KernelError> {
+    if len == 0 {
+        return Ok(());
+    }
+    match unsafe { user_copy(dst, src, len) } {
+        0 => Ok(()),
+        _ => Err(KernelError::BadAddress),
+    }
+}
+
+/// Fills `dst` from user address `src`
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), KernelError> {
+    access_ok(src, dst.len(), FaultAccess::Read)?;
+    copy(dst.as_mut_ptr(), src as *const u8, dst.len())
+}
+
+/// Copies `src` to user address `dst`
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), KernelError> {
+    access_ok(dst, src.len(), FaultAccess::Write)?;
+    copy(dst as *mut u8, src.as_ptr(), src.len())
+}
+
+/// Reads a `T` from user address `addr`.
+///
+/// # Safety
+///
+/// Every bit pattern must be a valid `T`.
+pub unsafe fn read_user<T: Copy>(addr: u64) -> Result<T, KernelError> {
+    let mut value = MaybeUninit::<T>::uninit();
+    access_ok(addr, size_of::<T>(), FaultAccess::Read)?;
+    copy(value.as_mut_ptr() as *mut u8, addr as *const u8, size_of::<T>())?;
+    Ok(value.assume_init())
+}
+
+/// Writes `value` to user address `addr`, padding included, so `T`
+/// should have its padding as explicit fields
+pub fn write_user<T>(addr: u64, value: &T) -> Result<(), KernelError> {
+    access_ok(addr, size_of::<T>(), FaultAccess::Write)?;
+    copy(addr as *mut u8, value as *const T as *const u8, size_of::<T>())
+}
+
+/// Copies the NUL-terminated string at user address `addr`, which must be
+/// UTF-8 and, NUL included, at most `max` bytes long
+pub fn user_string(addr: u64, max: usize) -> Result<String, KernelError> {
+    let mut bytes = Vec::new();
+    let mut next = addr;
+    while bytes.len() < max {
+        // A page at a time, so a string that ends just before an unmapped
+        // page is still read
+        let chunk = (PAGE_SIZE - next as usize % PAGE_SIZE).min(max - bytes.len());
+        let start = bytes.len();
+        bytes.resize(start + chunk, 0);
+        copy_from_user(&mut bytes[start..], next)?;
+        if let Some(len) = bytes[start..].iter().position(|&byte| byte == 0) {
+            bytes.truncate(start + len);
+            return String::from_utf8(bytes).map_err(|_| KernelError::InvalidArgument);
+        }
+        next = next.checked_add(chunk as u64).ok_or(KernelError::BadAddress)?;
+    }
+    Err(KernelError::NameTooLong)
+}
+
+/// Copies the path at user address `addr`, bounded by PATH_MAX
+pub fn user_path(addr: u64) -> Result<String, KernelError> {
+    user_string(addr, PATH_MAX)
+}
+
+/// Where a kernel fault at `pc` resumes if it is inside `user_copy`. The
+/// copy then returns the bytes it had left.
+pub fn fixup(pc: u64) -> Option<u64> {
+    let start = user_copy as *const () as u64;
+    let end = user_copy_end as *const () as u64;
+    (start..end).contains(&pc).then_some(user_copy_fault as *const () as u64)
+}
+
+// user_copy(dst, src, len) returns the number of bytes not copied: 0, or
+// what was left when an access faulted and the handler moved the PC to
+// user_copy_fault. Eight bytes at a time, then the tail byte by byte;
+// x2 only drops once a store has gone through.
+core::arch::global_asm!(r#"
+.section .text
+.globl user_copy
+.globl user_copy_fault
+.globl user_copy_end
+user_copy:
+    cmp x2, #8
+    b.lo 2f
+1:
+    ldr x3, [x1], #8
+    str x3, [x0], #8
+    sub x2, x2, #8
+    cmp x2, #8
+    b.hs 1b
+2:
+    cbz x2, user_copy_fault
+3:
+    ldrb w3, [x1], #1
+    strb w3, [x0], #1
+    subs x2, x2, #1
+    b.ne 3b
+user_copy_fault:
+    mov x0, x2
+    ret
+user_copy_end:
+"#);
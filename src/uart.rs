@@ -1,8 +1,58 @@
 use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
 use spin::Mutex;
+use crate::error::KernelError;
+use crate::wait_queue::{self, WaitQueue};
 
-// UART base address for ARM64 virt machine
-const UART_BASE: usize = 0x9000000;
+// UART base address for ARM64 virt machine, used until the device tree
+// says otherwise
+pub const UART_PHYS_BASE: u64 = 0x9000000;
+const UART_BASE: usize = (crate::memory::PHYS_OFFSET + UART_PHYS_BASE) as usize;
+
+static UART_PHYS: AtomicU64 = AtomicU64::new(UART_PHYS_BASE);
+
+// PL011 registers
+const UARTDR: usize = 0x00;
+const UARTFR: usize = 0x18;
+const UARTIMSC: usize = 0x38;
+const UARTICR: usize = 0x44;
+// Receive FIFO empty
+const FR_RXFE: u32 = 1 << 4;
+// Receive and receive timeout interrupts
+const INT_RX: u32 = 1 << 4;
+const INT_RT: u32 = 1 << 6;
+
+// UART interrupt on QEMU virt, used when the device tree has none
+const DEFAULT_UART_IRQ: u32 = 33;
+// Ctrl-C
+const INTERRUPT_CHAR: u8 = 0x03;
+const INPUT_BUFFER_SIZE: usize = 256;
+
+struct Input {
+    // Typed characters not yet read
+    buffer: heapless::Deque<u8, INPUT_BUFFER_SIZE>,
+    // Threads sleeping in a read of stdin
+    readers: WaitQueue,
+}
+
+impl Input {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let mut count = 0;
+        while count < buf.len() {
+            match self.buffer.pop_front() {
+                Some(byte) => buf[count] = byte,
+                None => break,
+            }
+            count += 1;
+        }
+        count
+    }
+}
+
+static INPUT: Mutex<Input> = Mutex::new(Input {
+    buffer: heapless::Deque::new(),
+    readers: WaitQueue::new(),
+});
 
 pub struct Uart {
     base_address: usize,
@@ -15,24 +65,27 @@ impl Uart {
     
     fn write_byte(&self, byte: u8) {
         unsafe {
-            let ptr = self.base_address as *mut u8;
+            let ptr = (self.base_address + UARTDR) as *mut u8;
             ptr.write_volatile(byte);
         }
     }
     
-    #[allow(dead_code)]
     fn read_byte(&self) -> Option<u8> {
         unsafe {
-            let status_ptr = (self.base_address + 0x18) as *mut u32;
-            let data_ptr = self.base_address as *mut u8;
+            let status_ptr = (self.base_address + UARTFR) as *mut u32;
+            let data_ptr = (self.base_address + UARTDR) as *mut u8;
             
-            if status_ptr.read_volatile() & (1 << 4) == 0 {
+            if status_ptr.read_volatile() & FR_RXFE == 0 {
                 Some(data_ptr.read_volatile())
             } else {
                 None
             }
         }
     }
+    
+    fn write_register(&self, offset: usize, value: u32) {
+        unsafe { ((self.base_address + offset) as *mut u32).write_volatile(value) }
+    }
 }
 
 impl fmt::Write for Uart {
@@ -50,6 +103,63 @@ pub fn init() {
     // UART initialization is minimal for ARM64 virt machine
 }
 
+/// Takes console input through the receive interrupt. Ctrl-C sends
+/// SIGINT to the foreground process; other characters are kept for
+/// `read_input` and wake threads sleeping in a read of stdin.
+pub fn init_interrupts() -> Result<(), &'static str> {
+    if !crate::gic::is_initialized() {
+        return Err("Interrupt controller not initialized");
+    }
+    let irq = crate::fdt::platform().uart_irq.unwrap_or(DEFAULT_UART_IRQ);
+    crate::gic::request_irq(irq, handle_interrupt)?;
+    UART.lock().write_register(UARTIMSC, INT_RX | INT_RT);
+    Ok(())
+}
+
+fn handle_interrupt(_irq: u32) {
+    let mut typed = false;
+    loop {
+        let byte = UART.lock().read_byte();
+        match byte {
+            Some(INTERRUPT_CHAR) => crate::process::signal_foreground(crate::signal::SIGINT),
+            // Dropped if nobody has read the buffer
+            Some(byte) => {
+                let _ = INPUT.lock().buffer.push_back(byte);
+                typed = true;
+            }
+            None => break,
+        }
+    }
+    UART.lock().write_register(UARTICR, INT_RX | INT_RT);
+    if typed {
+        let readers = INPUT.lock().readers.take();
+        wait_queue::wake(readers);
+    }
+}
+
+/// Moves typed characters into `buf`, returning how many were moved. With
+/// nothing typed yet, fails with `WouldBlock` after queueing `waiter` to be
+/// woken by the next character.
+pub fn read_input(buf: &mut [u8], waiter: Option<u32>) -> Result<usize, KernelError> {
+    let mut input = INPUT.lock();
+    if input.buffer.is_empty() && !buf.is_empty() {
+        if let Some(pid) = waiter {
+            input.readers.add(pid);
+        }
+        return Err(KernelError::WouldBlock);
+    }
+    Ok(input.read(buf))
+}
+
+pub fn set_base(phys: u64) {
+    UART_PHYS.store(phys, Ordering::SeqCst);
+    UART.lock().base_address = (crate::memory::PHYS_OFFSET + phys) as usize;
+}
+
+pub fn phys_base() -> u64 {
+    UART_PHYS.load(Ordering::SeqCst)
+}
+
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
     UART.lock().write_fmt(args).unwrap();
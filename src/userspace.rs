@@ -1,11 +1,17 @@
 #![allow(dead_code)]
 
+use alloc::borrow::Cow;
+use alloc::format;
+use alloc::vec;
 use alloc::vec::Vec;
-use crate::process;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::error::KernelError;
+use crate::memory::{self, PAGE_SIZE};
+use crate::process::{self, MemoryPermissions, USER_MMAP_LIMIT};
 
 // ELF header structures for loading userspace programs
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct ElfHeader {
     pub e_ident: [u8; 16],
     pub e_type: u16,
@@ -24,7 +30,7 @@ pub struct ElfHeader {
 }
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct ProgramHeader {
     pub p_type: u32,
     pub p_flags: u32,
@@ -36,210 +42,452 @@ pub struct ProgramHeader {
     pub p_align: u64,
 }
 
+// Dynamic section entry
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Dyn {
+    pub d_tag: i64,
+    pub d_val: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Rela {
+    pub r_offset: u64,
+    pub r_info: u64,
+    pub r_addend: i64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    pub st_name: u32,
+    pub st_info: u8,
+    pub st_other: u8,
+    pub st_shndx: u16,
+    pub st_value: u64,
+    pub st_size: u64,
+}
+
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_AARCH64: u16 = 183;
+
 const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const PT_PHDR: u32 = 6;
+const PT_GNU_STACK: u32 = 0x6474_e551;
+const PT_GNU_RELRO: u32 = 0x6474_e552;
 const PF_X: u32 = 1;
 const PF_W: u32 = 2;
 const PF_R: u32 = 4;
 
+const DT_NULL: i64 = 0;
+const DT_SYMTAB: i64 = 6;
+const DT_RELA: i64 = 7;
+const DT_RELASZ: i64 = 8;
+const DT_RELAENT: i64 = 9;
+const DT_SYMENT: i64 = 11;
+const DT_REL: i64 = 17;
+
+const R_AARCH64_ABS64: u32 = 257;
+const R_AARCH64_GLOB_DAT: u32 = 1025;
+const R_AARCH64_RELATIVE: u32 = 1027;
+
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xfff1;
+const STB_WEAK: u8 = 2;
+
+/// Where an ET_DYN executable's lowest segment is loaded: two thirds of
+/// the way up the user address space, as on Linux
+pub const ET_DYN_BASE: u64 = 0x5555_5555_0000;
+
+// Auxiliary vector entry types, as on Linux
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_RANDOM: u64 = 25;
+
+// Most bytes of argument and environment strings an image starts with
+pub const ARG_MAX: usize = 128 * 1024;
+
+/// A loadable segment: `file_size` bytes from `offset` in the file go to
+/// `vaddr`, and the rest of `mem_size` (.bss) is zero
+#[derive(Debug, Clone, Copy)]
+pub struct Segment {
+    pub vaddr: u64,
+    pub mem_size: u64,
+    pub offset: u64,
+    pub file_size: u64,
+    pub permissions: MemoryPermissions,
+}
+
+/// A relocation resolved against the load base: `value` goes in the 8
+/// bytes at `offset` in the file
+#[derive(Debug, Clone, Copy)]
+pub struct Relocation {
+    pub offset: u64,
+    pub value: u64,
+}
+
+/// An ELF executable whose headers have been checked against its file,
+/// ready to be mapped. Addresses include the load bias.
 pub struct UserProgram {
     pub entry_point: u64,
-    pub memory_regions: Vec<(u64, u64, u32)>, // (vaddr, size, flags)
-    pub data: Vec<u8>,
+    pub segments: Vec<Segment>,
+    // Where the program headers are once the segments are mapped, or 0 if
+    // no segment holds them
+    pub phdr_addr: u64,
+    pub phnum: u16,
+    // Added to every link-time address: 0 for ET_EXEC
+    pub load_bias: u64,
+    pub relocations: Vec<Relocation>,
+    // Made read-only once relocated (PT_GNU_RELRO), start and size
+    pub relro: Option<(u64, u64)>,
+    // RW, or RWX if PT_GNU_STACK asks for an executable stack
+    pub stack_permissions: MemoryPermissions,
 }
 
 impl UserProgram {
-    pub fn load_elf(data: &[u8]) -> Result<Self, &'static str> {
-        if data.len() < core::mem::size_of::<ElfHeader>() {
-            return Err("Invalid ELF file");
+    /// Checks `data` is a static AArch64 ELF64 executable, fixed-address or
+    /// position-independent, whose segments lie within the file and within
+    /// user space, and collects them and the relocations in .rela.dyn
+    pub fn load_elf(data: &[u8]) -> Result<Self, KernelError> {
+        let header: ElfHeader = read_struct(data, 0)?;
+        let ident = &header.e_ident;
+        if &ident[0..4] != b"\x7fELF" || ident[4] != ELFCLASS64 || ident[5] != ELFDATA2LSB || ident[6] != EV_CURRENT {
+            return Err(KernelError::NotExecutable);
+        }
+        if !matches!(header.e_type, ET_EXEC | ET_DYN) || header.e_machine != EM_AARCH64 || header.e_version != EV_CURRENT as u32 {
+            return Err(KernelError::NotExecutable);
+        }
+        if header.e_phentsize as usize != core::mem::size_of::<ProgramHeader>() || header.e_phnum == 0 {
+            return Err(KernelError::NotExecutable);
         }
         
-        let elf_header = unsafe {
-            &*(data.as_ptr() as *const ElfHeader)
+        let headers_size = header.e_phnum as u64 * header.e_phentsize as u64;
+        let program_headers = (0..header.e_phnum as u64)
+            .map(|index| {
+                let offset = header.e_phoff.checked_add(index * header.e_phentsize as u64).ok_or(KernelError::NotExecutable)?;
+                read_struct::<ProgramHeader>(data, offset)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        // There is no dynamic linker to hand a PT_INTERP program to
+        if program_headers.iter().any(|ph| ph.p_type == PT_INTERP) {
+            return Err(KernelError::NotExecutable);
+        }
+        let loads = program_headers.iter().filter(|ph| ph.p_type == PT_LOAD && ph.p_memsz > 0);
+        let load_bias = match header.e_type {
+            ET_DYN => {
+                let lowest = loads.clone().map(|ph| ph.p_vaddr).min().ok_or(KernelError::NotExecutable)?;
+                ET_DYN_BASE.wrapping_sub(memory::align_down(lowest, PAGE_SIZE as u64))
+            }
+            _ => 0,
         };
         
-        // Verify ELF magic
-        if &elf_header.e_ident[0..4] != b"\x7fELF" {
-            return Err("Not an ELF file");
+        let mut segments: Vec<Segment> = Vec::new();
+        for program_header in loads {
+            let segment = Segment::from_header(program_header, load_bias, data.len())?;
+            // Segments come in address order and may not share pages
+            let start = memory::align_down(segment.vaddr, PAGE_SIZE as u64);
+            if segments.last().is_some_and(|last| last.end() > start) {
+                return Err(KernelError::NotExecutable);
+            }
+            segments.push(segment);
         }
         
-        // Verify it's for ARM64
-        if elf_header.e_machine != 183 { // EM_AARCH64
-            return Err("Not an ARM64 ELF file");
+        let entry_point = header.e_entry.wrapping_add(load_bias);
+        if !segments.iter().any(|s| s.permissions.contains(MemoryPermissions::EXECUTE) && s.contains(entry_point)) {
+            return Err(KernelError::NotExecutable);
         }
-        
-        let mut memory_regions = Vec::new();
-        
-        // Parse program headers
-        for i in 0..elf_header.e_phnum {
-            let ph_offset = elf_header.e_phoff + (i as u64 * elf_header.e_phentsize as u64);
-            if (ph_offset as usize + core::mem::size_of::<ProgramHeader>()) > data.len() {
-                return Err("Invalid program header");
-            }
-            
-            let program_header = unsafe {
-                &*((data.as_ptr() as usize + ph_offset as usize) as *const ProgramHeader)
-            };
-            
-            if program_header.p_type == PT_LOAD {
-                memory_regions.push((
-                    program_header.p_vaddr,
-                    program_header.p_memsz,
-                    program_header.p_flags,
-                ));
+        let mut program = UserProgram {
+            entry_point,
+            segments,
+            phdr_addr: 0,
+            phnum: header.e_phnum,
+            load_bias,
+            relocations: Vec::new(),
+            relro: None,
+            stack_permissions: MemoryPermissions::READ | MemoryPermissions::WRITE,
+        };
+        for program_header in &program_headers {
+            match program_header.p_type {
+                PT_PHDR => program.phdr_addr = program_header.p_vaddr.wrapping_add(load_bias),
+                PT_GNU_STACK if program_header.p_flags & PF_X != 0 => {
+                    program.stack_permissions |= MemoryPermissions::EXECUTE;
+                }
+                PT_GNU_RELRO => {
+                    program.relro = Some((program_header.p_vaddr.wrapping_add(load_bias), program_header.p_memsz));
+                }
+                PT_DYNAMIC => program.relocations = program.read_relocations(data, program_header)?,
+                _ => {}
             }
         }
-        
-        Ok(UserProgram {
-            entry_point: elf_header.e_entry,
-            memory_regions,
-            data: data.to_vec(),
-        })
-    }
-}
-
-// Integration layer for uutils/coreutils
-pub struct CoreUtilsIntegration;
-
-impl CoreUtilsIntegration {
-    pub fn init() {
-        // Set up environment for coreutils programs
-        // This would include setting up proper file descriptors,
-        // environment variables, and command line arguments
-    }
-    
-    pub fn spawn_coreutil(name: &str, args: &[&str]) -> Result<u32, &'static str> {
-        // Map coreutils program names to their implementations
-        match name {
-            "ls" => Self::spawn_ls(args),
-            "cat" => Self::spawn_cat(args),
-            "echo" => Self::spawn_echo(args),
-            "mkdir" => Self::spawn_mkdir(args),
-            "rm" => Self::spawn_rm(args),
-            "cp" => Self::spawn_cp(args),
-            "mv" => Self::spawn_mv(args),
-            "grep" => Self::spawn_grep(args),
-            "wc" => Self::spawn_wc(args),
-            "sort" => Self::spawn_sort(args),
-            "head" => Self::spawn_head(args),
-            "tail" => Self::spawn_tail(args),
-            "cut" => Self::spawn_cut(args),
-            "tr" => Self::spawn_tr(args),
-            "sed" => Self::spawn_sed(args),
-            "awk" => Self::spawn_awk(args),
-            _ => Err("Unknown coreutil"),
+        // Without PT_PHDR, the headers are found in whichever segment
+        // their bytes of the file were loaded with
+        if program.phdr_addr == 0 {
+            program.phdr_addr = program.file_offset_to_addr(header.e_phoff, headers_size).unwrap_or(0);
         }
+        Ok(program)
     }
     
-    fn spawn_ls(_args: &[&str]) -> Result<u32, &'static str> {
-        // Create a process that implements ls functionality
-        // This would load the uutils ls binary and execute it
-        let entry_point = Self::load_coreutil_binary("ls")?;
-        process::create_process(entry_point, 65536) // 64KB stack
+    /// Auxiliary vector entries describing the program. `initial_stack`
+    /// adds AT_RANDOM and AT_NULL.
+    pub fn auxv(&self) -> [(u64, u64); 5] {
+        [
+            (AT_PHDR, self.phdr_addr),
+            (AT_PHENT, core::mem::size_of::<ProgramHeader>() as u64),
+            (AT_PHNUM, self.phnum as u64),
+            (AT_PAGESZ, PAGE_SIZE as u64),
+            (AT_ENTRY, self.entry_point),
+        ]
     }
     
-    fn spawn_cat(_args: &[&str]) -> Result<u32, &'static str> {
-        let entry_point = Self::load_coreutil_binary("cat")?;
-        process::create_process(entry_point, 65536)
+    /// `elf`, the file the program was loaded from, with the relocations
+    /// applied. Only copied if there are any.
+    pub fn relocated<'a>(&self, elf: &'a [u8]) -> Cow<'a, [u8]> {
+        if self.relocations.is_empty() {
+            return Cow::Borrowed(elf);
+        }
+        let mut image = elf.to_vec();
+        for relocation in &self.relocations {
+            let offset = relocation.offset as usize;
+            image[offset..offset + 8].copy_from_slice(&relocation.value.to_le_bytes());
+        }
+        Cow::Owned(image)
     }
     
-    fn spawn_echo(_args: &[&str]) -> Result<u32, &'static str> {
-        let entry_point = Self::load_coreutil_binary("echo")?;
-        process::create_process(entry_point, 32768) // 32KB stack
+    // Resolves the entries of .rela.dyn, which the PT_DYNAMIC segment
+    // `dynamic` locates. Only the relocations a static executable needs are
+    // supported, as nothing is linked in at run time.
+    fn read_relocations(&self, data: &[u8], dynamic: &ProgramHeader) -> Result<Vec<Relocation>, KernelError> {
+        let (mut rela, mut rela_size, mut symtab) = (None, 0, None);
+        let entry_size = core::mem::size_of::<Dyn>() as u64;
+        for index in 0..dynamic.p_filesz / entry_size {
+            let entry: Dyn = read_struct(data, dynamic.p_offset.checked_add(index * entry_size).ok_or(KernelError::NotExecutable)?)?;
+            match entry.d_tag {
+                DT_NULL => break,
+                DT_RELA => rela = Some(entry.d_val),
+                DT_RELASZ => rela_size = entry.d_val,
+                DT_SYMTAB => symtab = Some(entry.d_val),
+                DT_RELAENT if entry.d_val != core::mem::size_of::<Rela>() as u64 => return Err(KernelError::NotExecutable),
+                DT_SYMENT if entry.d_val != core::mem::size_of::<Symbol>() as u64 => return Err(KernelError::NotExecutable),
+                // AArch64 only uses RELA
+                DT_REL => return Err(KernelError::NotExecutable),
+                _ => {}
+            }
+        }
+        let Some(rela) = rela else {
+            return Ok(Vec::new());
+        };
+        
+        let table = self.file_offset(rela, rela_size)?;
+        let entry_size = core::mem::size_of::<Rela>() as u64;
+        (0..rela_size / entry_size)
+            .map(|index| {
+                let entry: Rela = read_struct(data, table + index * entry_size)?;
+                let addend = entry.r_addend as u64;
+                let value = match entry.r_info as u32 {
+                    R_AARCH64_RELATIVE => self.load_bias.wrapping_add(addend),
+                    R_AARCH64_ABS64 | R_AARCH64_GLOB_DAT => {
+                        self.symbol_value(data, symtab, entry.r_info >> 32)?.wrapping_add(addend)
+                    }
+                    _ => return Err(KernelError::NotExecutable),
+                };
+                Ok(Relocation { offset: self.file_offset(entry.r_offset, 8)?, value })
+            })
+            .collect()
     }
     
-    fn spawn_mkdir(_args: &[&str]) -> Result<u32, &'static str> {
-        let entry_point = Self::load_coreutil_binary("mkdir")?;
-        process::create_process(entry_point, 32768)
+    // Run-time address of symbol `index` in the table at `symtab`
+    fn symbol_value(&self, data: &[u8], symtab: Option<u64>, index: u64) -> Result<u64, KernelError> {
+        if index == 0 {
+            return Ok(0);
+        }
+        let entry_size = core::mem::size_of::<Symbol>() as u64;
+        let addr = index.checked_mul(entry_size)
+            .and_then(|offset| symtab?.checked_add(offset))
+            .ok_or(KernelError::NotExecutable)?;
+        let symbol: Symbol = read_struct(data, self.file_offset(addr, entry_size)?)?;
+        match symbol.st_shndx {
+            // Only weak references may be left undefined
+            SHN_UNDEF if symbol.st_info >> 4 == STB_WEAK => Ok(0),
+            SHN_UNDEF => Err(KernelError::NotExecutable),
+            SHN_ABS => Ok(symbol.st_value),
+            _ => Ok(symbol.st_value.wrapping_add(self.load_bias)),
+        }
     }
     
-    fn spawn_rm(_args: &[&str]) -> Result<u32, &'static str> {
-        let entry_point = Self::load_coreutil_binary("rm")?;
-        process::create_process(entry_point, 32768)
+    // Where in the file the `len` bytes at link-time address `vaddr` come
+    // from. All of them must be file bytes of one segment.
+    fn file_offset(&self, vaddr: u64, len: u64) -> Result<u64, KernelError> {
+        let addr = vaddr.wrapping_add(self.load_bias);
+        self.segments.iter()
+            .find(|s| addr >= s.vaddr && addr.checked_add(len).is_some_and(|end| end <= s.vaddr + s.file_size))
+            .map(|s| s.offset + (addr - s.vaddr))
+            .ok_or(KernelError::NotExecutable)
     }
     
-    fn spawn_cp(_args: &[&str]) -> Result<u32, &'static str> {
-        let entry_point = Self::load_coreutil_binary("cp")?;
-        process::create_process(entry_point, 65536)
+    // Where the `len` file bytes at `offset` are once mapped, if one
+    // segment loads them all
+    fn file_offset_to_addr(&self, offset: u64, len: u64) -> Option<u64> {
+        self.segments.iter()
+            .find(|s| offset >= s.offset && offset.checked_add(len).is_some_and(|end| end <= s.offset + s.file_size))
+            .map(|s| s.vaddr + (offset - s.offset))
     }
-    
-    fn spawn_mv(_args: &[&str]) -> Result<u32, &'static str> {
-        let entry_point = Self::load_coreutil_binary("mv")?;
-        process::create_process(entry_point, 32768)
+}
+
+impl Segment {
+    // Checks a PT_LOAD header of a file `file_len` bytes long, loaded
+    // `load_bias` above its link-time addresses
+    fn from_header(header: &ProgramHeader, load_bias: u64, file_len: usize) -> Result<Self, KernelError> {
+        let file_end = header.p_offset.checked_add(header.p_filesz).ok_or(KernelError::NotExecutable)?;
+        if header.p_filesz > header.p_memsz || file_end > file_len as u64 {
+            return Err(KernelError::NotExecutable);
+        }
+        // Below the mmap limit, clear of the stack and its guard
+        let vaddr = header.p_vaddr.wrapping_add(load_bias);
+        let end = vaddr.checked_add(header.p_memsz).ok_or(KernelError::NotExecutable)?;
+        if vaddr < PAGE_SIZE as u64 || end > USER_MMAP_LIMIT {
+            return Err(KernelError::NotExecutable);
+        }
+        
+        let mut permissions = MemoryPermissions::empty();
+        if header.p_flags & PF_R != 0 {
+            permissions |= MemoryPermissions::READ;
+        }
+        if header.p_flags & PF_W != 0 {
+            permissions |= MemoryPermissions::WRITE;
+        }
+        if header.p_flags & PF_X != 0 {
+            permissions |= MemoryPermissions::EXECUTE;
+        }
+        Ok(Segment {
+            vaddr,
+            mem_size: header.p_memsz,
+            offset: header.p_offset,
+            file_size: header.p_filesz,
+            permissions,
+        })
     }
     
-    fn spawn_grep(_args: &[&str]) -> Result<u32, &'static str> {
-        let entry_point = Self::load_coreutil_binary("grep")?;
-        process::create_process(entry_point, 131072) // 128KB stack for regex processing
+    /// End of the last page the segment occupies
+    pub fn end(&self) -> u64 {
+        memory::align_up(self.vaddr + self.mem_size, PAGE_SIZE as u64)
     }
     
-    fn spawn_wc(_args: &[&str]) -> Result<u32, &'static str> {
-        let entry_point = Self::load_coreutil_binary("wc")?;
-        process::create_process(entry_point, 32768)
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.vaddr && addr < self.vaddr + self.mem_size
     }
-    
-    fn spawn_sort(_args: &[&str]) -> Result<u32, &'static str> {
-        let entry_point = Self::load_coreutil_binary("sort")?;
-        process::create_process(entry_point, 131072) // 128KB stack for sorting
+}
+
+// Copies a header out of `data`, failing if it runs past the end. Only for
+// the ELF structures above, which any bytes are a valid value of.
+fn read_struct<T: Copy>(data: &[u8], offset: u64) -> Result<T, KernelError> {
+    let start = usize::try_from(offset).map_err(|_| KernelError::NotExecutable)?;
+    let bytes = start.checked_add(core::mem::size_of::<T>())
+        .and_then(|end| data.get(start..end))
+        .ok_or(KernelError::NotExecutable)?;
+    Ok(unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
+
+/// Lays out the stack an image starts with, ending at `top`, as the AArch64
+/// Linux ABI has it: argc at the stack pointer, then the NULL-terminated
+/// argv and envp pointer arrays and the auxiliary vector, with the strings
+/// and AT_RANDOM's bytes above them. Returns the stack pointer, 16-byte
+/// aligned, and the bytes from there up to `top`.
+pub fn initial_stack(top: u64, argv: &[&str], envp: &[&str], auxv: &[(u64, u64)]) -> Result<(u64, Vec<u8>), KernelError> {
+    let strings_size: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+    if strings_size > ARG_MAX {
+        return Err(KernelError::ArgumentListTooLong);
     }
+    let strings_start = top - strings_size as u64;
+    let random_addr = memory::align_down(strings_start - 16, 16);
+    let word_count = 1 + argv.len() + 1 + envp.len() + 1 + 2 * (auxv.len() + 2);
+    let sp = memory::align_down(random_addr - 8 * word_count as u64, 16);
     
-    fn spawn_head(_args: &[&str]) -> Result<u32, &'static str> {
-        let entry_point = Self::load_coreutil_binary("head")?;
-        process::create_process(entry_point, 32768)
+    let mut stack = vec![0u8; (top - sp) as usize];
+    let mut words = Vec::with_capacity(word_count);
+    words.push(argv.len() as u64);
+    let mut string_addr = strings_start;
+    for strings in [argv, envp] {
+        for string in strings {
+            let offset = (string_addr - sp) as usize;
+            stack[offset..offset + string.len()].copy_from_slice(string.as_bytes());
+            words.push(string_addr);
+            string_addr += string.len() as u64 + 1;
+        }
+        words.push(0);
     }
-    
-    fn spawn_tail(_args: &[&str]) -> Result<u32, &'static str> {
-        let entry_point = Self::load_coreutil_binary("tail")?;
-        process::create_process(entry_point, 32768)
+    for &(kind, value) in auxv.iter().chain(&[(AT_RANDOM, random_addr), (AT_NULL, 0)]) {
+        words.extend([kind, value]);
     }
-    
-    fn spawn_cut(_args: &[&str]) -> Result<u32, &'static str> {
-        let entry_point = Self::load_coreutil_binary("cut")?;
-        process::create_process(entry_point, 32768)
+    for (index, word) in words.iter().enumerate() {
+        stack[index * 8..index * 8 + 8].copy_from_slice(&word.to_le_bytes());
     }
-    
-    fn spawn_tr(_args: &[&str]) -> Result<u32, &'static str> {
-        let entry_point = Self::load_coreutil_binary("tr")?;
-        process::create_process(entry_point, 32768)
+    let offset = (random_addr - sp) as usize;
+    fill_random(&mut stack[offset..offset + 16]);
+    Ok((sp, stack))
+}
+
+static RANDOM_STATE: AtomicU64 = AtomicU64::new(0);
+
+/// Fills `bytes` for AT_RANDOM and getrandom, which libc seeds stack
+/// protectors and pointer guards from. There is no entropy source besides
+/// the counter, so they are unpredictable only to a point.
+pub fn fill_random(bytes: &mut [u8]) {
+    for chunk in bytes.chunks_mut(8) {
+        // splitmix64, stepped by the counter as well as the golden ratio
+        let step = 0x9e37_79b9_7f4a_7c15u64.wrapping_add(crate::timer::counter());
+        let mut z = RANDOM_STATE.fetch_add(step, Ordering::Relaxed).wrapping_add(step);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        let len = chunk.len();
+        chunk.copy_from_slice(&(z ^ (z >> 31)).to_le_bytes()[..len]);
     }
-    
-    fn spawn_sed(_args: &[&str]) -> Result<u32, &'static str> {
-        let entry_point = Self::load_coreutil_binary("sed")?;
-        process::create_process(entry_point, 131072) // 128KB stack for regex processing
+}
+
+// Integration layer for uutils/coreutils
+pub struct CoreUtilsIntegration;
+
+// Where the coreutils binaries are installed, and the environment they
+// start with
+const COREUTILS_DIR: &str = "/bin";
+const COREUTILS_ENV: &[&str] = &["PATH=/bin", "HOME=/"];
+const COREUTILS: &[&str] = &[
+    "ls", "cat", "echo", "mkdir", "rm", "cp", "mv", "grep",
+    "wc", "sort", "head", "tail", "cut", "tr", "sed", "awk",
+];
+
+impl CoreUtilsIntegration {
+    pub fn init() {
+        // Set up environment for coreutils programs
+        // This would include setting up proper file descriptors,
+        // environment variables, and command line arguments
     }
     
-    fn spawn_awk(_args: &[&str]) -> Result<u32, &'static str> {
-        let entry_point = Self::load_coreutil_binary("awk")?;
-        process::create_process(entry_point, 131072) // 128KB stack for script processing
+    /// Starts coreutil `name` as a new process, with `args` after its name
+    /// in argv
+    pub fn spawn_coreutil(name: &str, args: &[&str]) -> Result<u32, KernelError> {
+        if !COREUTILS.contains(&name) {
+            return Err(KernelError::NotFound);
+        }
+        let elf = Self::load_coreutil_binary(name)?;
+        let mut argv = vec![name];
+        argv.extend_from_slice(args);
+        process::create_process_from_elf(&elf, &argv, COREUTILS_ENV)
     }
     
-    fn load_coreutil_binary(name: &str) -> Result<u64, &'static str> {
-        // In a real implementation, this would:
-        // 1. Load the binary from a filesystem or embedded in the kernel
-        // 2. Parse the ELF file
-        // 3. Set up memory mappings
-        // 4. Return the entry point
-        
-        // For now, return a placeholder address
-        // Each coreutil would have its own address space
-        match name {
-            "ls" => Ok(0x400000),
-            "cat" => Ok(0x500000),
-            "echo" => Ok(0x600000),
-            "mkdir" => Ok(0x700000),
-            "rm" => Ok(0x800000),
-            "cp" => Ok(0x900000),
-            "mv" => Ok(0xa00000),
-            "grep" => Ok(0xb00000),
-            "wc" => Ok(0xc00000),
-            "sort" => Ok(0xd00000),
-            "head" => Ok(0xe00000),
-            "tail" => Ok(0xf00000),
-            "cut" => Ok(0x1000000),
-            "tr" => Ok(0x1100000),
-            "sed" => Ok(0x1200000),
-            "awk" => Ok(0x1300000),
-            _ => Err("Unknown binary"),
-        }
+    // The ELF image of coreutil `name`, as installed under COREUTILS_DIR
+    fn load_coreutil_binary(name: &str) -> Result<Vec<u8>, KernelError> {
+        crate::fs::read_file_bytes(&format!("{}/{}", COREUTILS_DIR, name))
     }
 }
 
@@ -247,10 +495,10 @@ impl CoreUtilsIntegration {
 pub struct SimpleShell;
 
 impl SimpleShell {
-    pub fn execute_command(command_line: &str) -> Result<u32, &'static str> {
+    pub fn execute_command(command_line: &str) -> Result<u32, KernelError> {
         let parts: Vec<&str> = command_line.split_whitespace().collect();
         if parts.is_empty() {
-            return Err("Empty command");
+            return Err(KernelError::InvalidArgument);
         }
         
         let program = parts[0];
@@ -259,9 +507,9 @@ impl SimpleShell {
         CoreUtilsIntegration::spawn_coreutil(program, args)
     }
     
-    pub fn pipe_commands(commands: &[&str]) -> Result<(), &'static str> {
+    pub fn pipe_commands(commands: &[&str]) -> Result<(), KernelError> {
         if commands.len() < 2 {
-            return Err("Need at least 2 commands for pipe");
+            return Err(KernelError::InvalidArgument);
         }
         
         let mut previous_pid = None;
@@ -284,7 +532,7 @@ impl SimpleShell {
         Ok(())
     }
     
-    fn connect_processes(_producer: u32, _consumer: u32) -> Result<(), &'static str> {
+    fn connect_processes(_producer: u32, _consumer: u32) -> Result<(), KernelError> {
         // Create a pipe and connect the processes
         let (_read_fd, _write_fd) = crate::ipc::create_pipe()?;
         
@@ -301,18 +549,10 @@ pub fn init() {
     CoreUtilsIntegration::init();
 }
 
-// System call for executing programs
-pub fn sys_execve(path: &str, args: &[&str]) -> Result<(), &'static str> {
-    // Extract program name from path
-    let program_name = path.split('/').last().unwrap_or(path);
-    
-    // Try to execute as a coreutil
-    match CoreUtilsIntegration::spawn_coreutil(program_name, args) {
-        Ok(_pid) => {
-            // Replace current process with new process
-            // This is simplified - real execve would replace the current process
-            Ok(())
-        }
-        Err(e) => Err(e),
-    }
+/// Replaces the calling process's image with the executable at `path`,
+/// started with `argv` and `envp`. On failure the caller carries on as it
+/// was.
+pub fn sys_execve(path: &str, argv: &[&str], envp: &[&str]) -> Result<(), KernelError> {
+    let elf = crate::fs::read_file_bytes(path)?;
+    process::sys_exec(&elf, argv, envp)
 }
@@ -0,0 +1,85 @@
+#![allow(dead_code)]
+
+use alloc::vec::Vec;
+use crate::error::KernelError;
+use crate::process;
+
+// Sleeping until something changes. A thread that cannot make progress
+// adds itself to the WaitQueue of what it waits on, under the lock that
+// guards it, and switches away as ProcessState::Blocked. Whoever changes
+// that state takes the queue's threads and wakes them once the lock is
+// released, since waking takes the process table lock, which comes first
+// in the lock order (see smp.rs).
+//
+// A thread is marked blocked before it looks, so a wakeup that lands
+// between the look and the switch is not lost: the thread is still on its
+// CPU and just carries on running.
+
+#[derive(Debug, Default)]
+pub struct WaitQueue {
+    waiters: Vec<u32>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue { waiters: Vec::new() }
+    }
+    
+    /// Adds thread `pid`, which a later `take` hands out once
+    pub fn add(&mut self, pid: u32) {
+        if !self.waiters.contains(&pid) {
+            self.waiters.push(pid);
+        }
+    }
+    
+    pub fn remove(&mut self, pid: u32) {
+        self.waiters.retain(|&waiter| waiter != pid);
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.waiters.is_empty()
+    }
+    
+    /// Empties the queue, returning its threads for `wake`
+    pub fn take(&mut self) -> Vec<u32> {
+        core::mem::take(&mut self.waiters)
+    }
+}
+
+/// Wakes threads taken from a queue. Call with no locks held. Threads
+/// that have exited or are no longer asleep are left alone.
+pub fn wake(pids: Vec<u32>) {
+    for pid in pids {
+        let _ = process::wake_process(pid);
+    }
+}
+
+/// Runs `attempt` until it stops failing with `WouldBlock`, sleeping in
+/// between. `attempt` gets the calling thread's pid, and before it returns
+/// `WouldBlock` must add that to the queue of whatever it waits for, under
+/// the same lock it found it unready with. With `nonblocking`, or outside
+/// any process, `attempt` runs once with no pid and `WouldBlock` is
+/// returned as is. A signal ends the wait with `Interrupted`.
+pub fn wait_event<T>(
+    nonblocking: bool,
+    mut attempt: impl FnMut(Option<u32>) -> Result<T, KernelError>,
+) -> Result<T, KernelError> {
+    if nonblocking {
+        return attempt(None);
+    }
+    loop {
+        let Some(pid) = process::prepare_to_wait() else {
+            return attempt(None);
+        };
+        let result = attempt(Some(pid));
+        if !matches!(result, Err(KernelError::WouldBlock)) {
+            process::finish_wait();
+            return result;
+        }
+        if process::signal_pending() {
+            process::finish_wait();
+            return Err(KernelError::Interrupted);
+        }
+        process::schedule();
+    }
+}